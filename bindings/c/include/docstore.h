@@ -0,0 +1,88 @@
+/*
+ * C bindings for the docstore resource store.
+ *
+ * All the functions except docstore_close(), docstore_buffer_free() and
+ * docstore_last_error() return a status code. On failure,
+ * docstore_last_error() describes the error until the next failing call on
+ * the same thread.
+ *
+ * Strings are NUL terminated utf-8, and resource paths are '/' separated,
+ * eg. "photos/2023/beach.jpg". A store handle can be used from any thread,
+ * calls being run one at a time.
+ */
+
+#ifndef DOCSTORE_H
+#define DOCSTORE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DOCSTORE_OK 0
+#define DOCSTORE_INVALID_ARGUMENT 1
+#define DOCSTORE_NO_SUCH_RESOURCE 2
+#define DOCSTORE_NO_SUCH_VARIANT 3
+#define DOCSTORE_RESOURCE_EXISTS 4
+#define DOCSTORE_CLOSED 5
+#define DOCSTORE_FAILED 6
+
+typedef struct DocstoreStore DocstoreStore;
+
+/* A buffer owned by the library, to release with docstore_buffer_free(). */
+typedef struct {
+    uint8_t *data;
+    size_t len;
+} DocstoreBuffer;
+
+/* Opens or creates the store located at root_dir, setting *out on success. */
+int32_t docstore_open(const char *root_dir, DocstoreStore **out);
+
+/* Closes the store once the pending calls are done, and frees the handle. */
+void docstore_close(DocstoreStore *store);
+
+void docstore_buffer_free(DocstoreBuffer buffer);
+
+/* Returns the description of the last error on this thread, or NULL. */
+const char *docstore_last_error(void);
+
+/* content can be NULL when len is 0. */
+int32_t docstore_create_resource(const DocstoreStore *store, const char *path,
+                                 const char *desc, const char *mime_type,
+                                 const uint8_t *content, size_t len);
+
+int32_t docstore_delete_resource(const DocstoreStore *store, const char *path);
+
+/* Writes the resource metadata to *out, as json. */
+int32_t docstore_get_metadata(const DocstoreStore *store, const char *path,
+                              DocstoreBuffer *out);
+
+/* Writes the matching resources to *out, as a json array of
+ * { "id": ..., "metadata": ... } objects. */
+int32_t docstore_search(const DocstoreStore *store, const char *text,
+                        DocstoreBuffer *out);
+
+int32_t docstore_add_tag(const DocstoreStore *store, const char *path,
+                         const char *tag);
+
+int32_t docstore_remove_tag(const DocstoreStore *store, const char *path,
+                            const char *tag);
+
+int32_t docstore_add_variant(const DocstoreStore *store, const char *path,
+                             const char *variant, const char *mime_type,
+                             const uint8_t *content, size_t len);
+
+int32_t docstore_delete_variant(const DocstoreStore *store, const char *path,
+                                const char *variant);
+
+/* Writes the content of the variant to *out. */
+int32_t docstore_get_variant(const DocstoreStore *store, const char *path,
+                             const char *variant, DocstoreBuffer *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* DOCSTORE_H */
@@ -0,0 +1,70 @@
+// A minimal Kotlin/Native wrapper over the C bindings. Generate the interop
+// library with docstore.def, and link with libdocstore_ffi.
+@file:OptIn(ExperimentalForeignApi::class, ExperimentalUnsignedTypes::class)
+
+import docstore.ffi.*
+import kotlinx.cinterop.*
+
+class DocStoreException(val code: Int, message: String) : Exception(message)
+
+class DocStore(rootDir: String) : AutoCloseable {
+    private val handle: CPointer<DocstoreStore> = memScoped {
+        val out = alloc<CPointerVar<DocstoreStore>>()
+        check(docstore_open(rootDir, out.ptr))
+        out.value!!
+    }
+
+    override fun close() {
+        docstore_close(handle)
+    }
+
+    fun createResource(path: String, desc: String, mimeType: String, content: ByteArray) {
+        content.asUByteArray().usePinned { pinned ->
+            check(
+                docstore_create_resource(
+                    handle, path, desc, mimeType,
+                    if (content.isEmpty()) null else pinned.addressOf(0), content.size.convert()
+                )
+            )
+        }
+    }
+
+    fun deleteResource(path: String) {
+        check(docstore_delete_resource(handle, path))
+    }
+
+    /// Returns the resource metadata, as json.
+    fun metadata(path: String): String = memScoped {
+        val buffer = alloc<DocstoreBuffer>()
+        check(docstore_get_metadata(handle, path, buffer.ptr))
+        take(buffer).decodeToString()
+    }
+
+    /// Returns the matching resources, as json.
+    fun search(text: String): String = memScoped {
+        val buffer = alloc<DocstoreBuffer>()
+        check(docstore_search(handle, text, buffer.ptr))
+        take(buffer).decodeToString()
+    }
+
+    fun variant(path: String, name: String = "default"): ByteArray = memScoped {
+        val buffer = alloc<DocstoreBuffer>()
+        check(docstore_get_variant(handle, path, name, buffer.ptr))
+        take(buffer)
+    }
+
+    private companion object {
+        fun check(status: Int) {
+            if (status != DOCSTORE_OK) {
+                throw DocStoreException(status, docstore_last_error()?.toKString() ?: "")
+            }
+        }
+
+        // Copies a buffer returned by the library, and frees it.
+        fun take(buffer: DocstoreBuffer): ByteArray {
+            val bytes = buffer.data?.reinterpret<ByteVar>()?.readBytes(buffer.len.toInt())
+            docstore_buffer_free(buffer.readValue())
+            return bytes ?: ByteArray(0)
+        }
+    }
+}
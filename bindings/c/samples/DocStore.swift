@@ -0,0 +1,67 @@
+// A minimal Swift wrapper over the C bindings. Import `docstore.h` with a
+// bridging header or a module map, and link with libdocstore_ffi.
+
+import Foundation
+
+struct DocStoreError: Error {
+    let code: Int32
+    let message: String
+}
+
+final class DocStore {
+    private let handle: OpaquePointer
+
+    init(rootDir: String) throws {
+        var handle: OpaquePointer?
+        try DocStore.check(docstore_open(rootDir, &handle))
+        self.handle = handle!
+    }
+
+    deinit {
+        docstore_close(handle)
+    }
+
+    private static func check(_ status: Int32) throws {
+        if status != DOCSTORE_OK {
+            let message = docstore_last_error().map { String(cString: $0) } ?? ""
+            throw DocStoreError(code: status, message: message)
+        }
+    }
+
+    // Copies a buffer returned by the library, and frees it.
+    private static func take(_ buffer: DocstoreBuffer) -> Data {
+        defer { docstore_buffer_free(buffer) }
+        return Data(bytes: buffer.data, count: buffer.len)
+    }
+
+    func createResource(path: String, desc: String, mimeType: String, content: Data) throws {
+        try content.withUnsafeBytes { bytes in
+            try DocStore.check(docstore_create_resource(
+                handle, path, desc, mimeType,
+                bytes.bindMemory(to: UInt8.self).baseAddress, content.count))
+        }
+    }
+
+    func deleteResource(path: String) throws {
+        try DocStore.check(docstore_delete_resource(handle, path))
+    }
+
+    /// Returns the resource metadata, decoded from json.
+    func metadata(path: String) throws -> Any {
+        var buffer = DocstoreBuffer()
+        try DocStore.check(docstore_get_metadata(handle, path, &buffer))
+        return try JSONSerialization.jsonObject(with: DocStore.take(buffer))
+    }
+
+    func search(text: String) throws -> Any {
+        var buffer = DocstoreBuffer()
+        try DocStore.check(docstore_search(handle, text, &buffer))
+        return try JSONSerialization.jsonObject(with: DocStore.take(buffer))
+    }
+
+    func variant(path: String, name: String = "default") throws -> Data {
+        var buffer = DocstoreBuffer()
+        try DocStore.check(docstore_get_variant(handle, path, name, &buffer))
+        return DocStore.take(buffer)
+    }
+}
@@ -0,0 +1,423 @@
+//! C bindings for the resource store, to embed it in any runtime able to
+//! call C functions, like Swift or Kotlin/Native. See `include/docstore.h`.
+//!
+//! Like for the Python bindings, the store lives on a dedicated thread
+//! running its own single threaded runtime, so a handle can be used from
+//! any thread. Functions return a status code, and `docstore_last_error()`
+//! describes the last failure on the calling thread. Buffers returned by the
+//! library are released with `docstore_buffer_free()`.
+
+// The pointer contracts of the functions are documented in the header.
+#![allow(clippy::missing_safety_doc)]
+
+use docstore::resource::VariantMetadata;
+use docstore::store::{ResourceStore, StoreError};
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::io::Cursor;
+use std::ptr;
+use tokio::sync::{mpsc, oneshot};
+
+pub const DOCSTORE_OK: i32 = 0;
+pub const DOCSTORE_INVALID_ARGUMENT: i32 = 1;
+pub const DOCSTORE_NO_SUCH_RESOURCE: i32 = 2;
+pub const DOCSTORE_NO_SUCH_VARIANT: i32 = 3;
+pub const DOCSTORE_RESOURCE_EXISTS: i32 = 4;
+pub const DOCSTORE_CLOSED: i32 = 5;
+pub const DOCSTORE_FAILED: i32 = 6;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+// A failure, with its status code and description.
+struct Error(i32, String);
+
+impl From<StoreError> for Error {
+    fn from(err: StoreError) -> Self {
+        let code = match err {
+            StoreError::NoSuchResource(_) => DOCSTORE_NO_SUCH_RESOURCE,
+            StoreError::NoSuchVariant(_, _) => DOCSTORE_NO_SUCH_VARIANT,
+            StoreError::ResourceExists(_) => DOCSTORE_RESOURCE_EXISTS,
+            _ => DOCSTORE_FAILED,
+        };
+        Self(code, err.to_string())
+    }
+}
+
+fn closed() -> Error {
+    Error(DOCSTORE_CLOSED, "Store is closed".into())
+}
+
+// Runs `f`, recording its error for `docstore_last_error()`, and returns the
+// status code.
+fn status(f: impl FnOnce() -> Result<(), Error>) -> i32 {
+    match f() {
+        Ok(()) => DOCSTORE_OK,
+        Err(Error(code, message)) => {
+            let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+            code
+        }
+    }
+}
+
+type Job = Box<dyn for<'a> FnOnce(&'a mut ResourceStore) -> LocalBoxFuture<'a, ()> + Send>;
+
+fn job<F>(f: F) -> Job
+where
+    F: for<'a> FnOnce(&'a mut ResourceStore) -> LocalBoxFuture<'a, ()> + Send + 'static,
+{
+    Box::new(f)
+}
+
+/// An opaque handle on an open store.
+pub struct DocstoreStore {
+    sender: mpsc::UnboundedSender<Job>,
+}
+
+impl DocstoreStore {
+    // Runs `f` on the store thread and waits for its result.
+    fn call<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut ResourceStore) -> LocalBoxFuture<'a, Result<T, StoreError>>
+            + Send
+            + 'static,
+    {
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .send(job(move |store| {
+                Box::pin(async move {
+                    // The caller waits for the reply, so it can't be gone.
+                    let _ = reply.send(f(store).await);
+                })
+            }))
+            .map_err(|_| closed())?;
+        let result = receiver.blocking_recv().map_err(|_| closed())?;
+        result.map_err(Error::from)
+    }
+}
+
+/// A byte buffer owned by the library.
+#[repr(C)]
+pub struct DocstoreBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl DocstoreBuffer {
+    fn new(bytes: Vec<u8>) -> Self {
+        let len = bytes.len();
+        let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        Self { data, len }
+    }
+}
+
+unsafe fn to_str<'a>(value: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if value.is_null() {
+        return Err(Error(
+            DOCSTORE_INVALID_ARGUMENT,
+            format!("{} is NULL", name),
+        ));
+    }
+    CStr::from_ptr(value).to_str().map_err(|_| {
+        Error(
+            DOCSTORE_INVALID_ARGUMENT,
+            format!("{} is not valid utf-8", name),
+        )
+    })
+}
+
+unsafe fn to_string(value: *const c_char, name: &str) -> Result<String, Error> {
+    to_str(value, name).map(|value| value.to_owned())
+}
+
+unsafe fn to_path(path: *const c_char) -> Result<Vec<String>, Error> {
+    Ok(to_str(path, "path")?
+        .split('/')
+        .map(|s| s.to_owned())
+        .collect())
+}
+
+unsafe fn to_bytes(data: *const u8, len: usize) -> Result<Vec<u8>, Error> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    if data.is_null() {
+        return Err(Error(DOCSTORE_INVALID_ARGUMENT, "content is NULL".into()));
+    }
+    Ok(std::slice::from_raw_parts(data, len).to_vec())
+}
+
+unsafe fn to_store<'a>(store: *const DocstoreStore) -> Result<&'a DocstoreStore, Error> {
+    store
+        .as_ref()
+        .ok_or_else(|| Error(DOCSTORE_INVALID_ARGUMENT, "store is NULL".into()))
+}
+
+unsafe fn write_buffer(out: *mut DocstoreBuffer, bytes: Vec<u8>) -> Result<(), Error> {
+    if out.is_null() {
+        return Err(Error(DOCSTORE_INVALID_ARGUMENT, "out is NULL".into()));
+    }
+    *out = DocstoreBuffer::new(bytes);
+    Ok(())
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    serde_json::to_vec(value).map_err(|err| Error(DOCSTORE_FAILED, err.to_string()))
+}
+
+/// Opens or creates the store located at `root_dir`.
+#[no_mangle]
+pub unsafe extern "C" fn docstore_open(
+    root_dir: *const c_char,
+    out: *mut *mut DocstoreStore,
+) -> i32 {
+    status(|| {
+        let root_dir = to_string(root_dir, "root_dir")?;
+        if out.is_null() {
+            return Err(Error(DOCSTORE_INVALID_ARGUMENT, "out is NULL".into()));
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+        let (ready_sender, ready_receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(Error(DOCSTORE_FAILED, err.to_string())));
+                    return;
+                }
+            };
+
+            rt.block_on(async move {
+                let mut store = match ResourceStore::new(&root_dir).await {
+                    Ok(store) => {
+                        let _ = ready_sender.send(Ok(()));
+                        store
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err.into()));
+                        return;
+                    }
+                };
+
+                while let Some(job) = receiver.recv().await {
+                    job(&mut store).await;
+                }
+            });
+        });
+
+        ready_receiver.recv().map_err(|_| closed())??;
+        *out = Box::into_raw(Box::new(DocstoreStore { sender }));
+        Ok(())
+    })
+}
+
+/// Closes the store once the pending calls are done, and frees the handle.
+#[no_mangle]
+pub unsafe extern "C" fn docstore_close(store: *mut DocstoreStore) {
+    if !store.is_null() {
+        // Dropping the sender ends the store thread.
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Frees a buffer returned by the library.
+#[no_mangle]
+pub unsafe extern "C" fn docstore_buffer_free(buffer: DocstoreBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
+
+/// Returns the description of the last error on this thread, or NULL. The
+/// string is valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn docstore_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|message| message.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn docstore_create_resource(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    desc: *const c_char,
+    mime_type: *const c_char,
+    content: *const u8,
+    len: usize,
+) -> i32 {
+    status(|| {
+        let store = to_store(store)?;
+        let (path, desc) = (to_path(path)?, to_string(desc, "desc")?);
+        let mime_type = to_string(mime_type, "mime_type")?;
+        let content = to_bytes(content, len)?;
+        store.call(move |store| {
+            Box::pin(async move {
+                let variant = VariantMetadata::new(content.len() as _, &mime_type);
+                store
+                    .create_resource_tokio(
+                        &path,
+                        &desc,
+                        &variant,
+                        Default::default(),
+                        Cursor::new(content),
+                    )
+                    .await
+            })
+        })
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn docstore_delete_resource(
+    store: *const DocstoreStore,
+    path: *const c_char,
+) -> i32 {
+    status(|| {
+        let (store, path) = (to_store(store)?, to_path(path)?);
+        store.call(move |store| Box::pin(async move { store.delete_resource(&path).await }))
+    })
+}
+
+/// Writes the resource metadata as json to `out`.
+#[no_mangle]
+pub unsafe extern "C" fn docstore_get_metadata(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    out: *mut DocstoreBuffer,
+) -> i32 {
+    status(|| {
+        let (store, path) = (to_store(store)?, to_path(path)?);
+        let metadata =
+            store.call(move |store| Box::pin(async move { store.get_metadata(&path).await }))?;
+        write_buffer(out, to_json(&metadata)?)
+    })
+}
+
+/// Writes the matching resources to `out`, as a json array of
+/// `{ "id": ..., "metadata": ... }` objects.
+#[no_mangle]
+pub unsafe extern "C" fn docstore_search(
+    store: *const DocstoreStore,
+    text: *const c_char,
+    out: *mut DocstoreBuffer,
+) -> i32 {
+    status(|| {
+        let (store, text) = (to_store(store)?, to_string(text, "text")?);
+        let results = store.call(move |store| {
+            Box::pin(async move {
+                let results = store.search(&text).await?;
+                Ok(results
+                    .into_iter()
+                    .map(|(id, metadata)| {
+                        serde_json::json!({ "id": id.to_string(), "metadata": metadata })
+                    })
+                    .collect::<Vec<_>>())
+            })
+        })?;
+        write_buffer(out, to_json(&results)?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn docstore_add_tag(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    tag: *const c_char,
+) -> i32 {
+    status(|| {
+        let (store, path, tag) = (to_store(store)?, to_path(path)?, to_string(tag, "tag")?);
+        store.call(move |store| Box::pin(async move { store.add_tag(&path, &tag).await }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn docstore_remove_tag(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    tag: *const c_char,
+) -> i32 {
+    status(|| {
+        let (store, path, tag) = (to_store(store)?, to_path(path)?, to_string(tag, "tag")?);
+        store.call(move |store| Box::pin(async move { store.remove_tag(&path, &tag).await }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn docstore_add_variant(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    variant: *const c_char,
+    mime_type: *const c_char,
+    content: *const u8,
+    len: usize,
+) -> i32 {
+    status(|| {
+        let store = to_store(store)?;
+        let (path, variant) = (to_path(path)?, to_string(variant, "variant")?);
+        let mime_type = to_string(mime_type, "mime_type")?;
+        let content = to_bytes(content, len)?;
+        store.call(move |store| {
+            Box::pin(async move {
+                let meta = VariantMetadata::new(content.len() as _, &mime_type);
+                store
+                    .add_variant_tokio(&path, &variant, &meta, Cursor::new(content))
+                    .await
+            })
+        })
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn docstore_delete_variant(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    variant: *const c_char,
+) -> i32 {
+    status(|| {
+        let store = to_store(store)?;
+        let (path, variant) = (to_path(path)?, to_string(variant, "variant")?);
+        store
+            .call(move |store| Box::pin(async move { store.delete_variant(&path, &variant).await }))
+    })
+}
+
+/// Writes the content of a variant to `out`.
+#[no_mangle]
+pub unsafe extern "C" fn docstore_get_variant(
+    store: *const DocstoreStore,
+    path: *const c_char,
+    variant: *const c_char,
+    out: *mut DocstoreBuffer,
+) -> i32 {
+    status(|| {
+        let store = to_store(store)?;
+        let (path, variant) = (to_path(path)?, to_string(variant, "variant")?);
+        let content = store.call(move |store| {
+            Box::pin(async move {
+                let mut content = vec![];
+                let mut stream = store.get_variant(&variant, &path).await?;
+                while let Some(chunk) = stream.next().await {
+                    content.extend(chunk?);
+                }
+                Ok(content)
+            })
+        })?;
+        write_buffer(out, content)
+    })
+}
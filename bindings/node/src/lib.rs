@@ -0,0 +1,429 @@
+//! Node.js bindings for the resource store.
+//!
+//! The store is not `Send`, so it lives on a dedicated thread running its own
+//! single threaded runtime. The JS facing object only holds a channel to that
+//! thread, and each call sends a command along with a oneshot reply channel.
+
+#[macro_use]
+extern crate napi_derive;
+
+use docstore::resource::VariantMetadata;
+use docstore::store::{ResourceStore, StoreError};
+use futures::StreamExt;
+use napi::bindgen_prelude::Buffer;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::Cursor;
+use tokio::sync::{mpsc, oneshot};
+
+type Reply<T> = oneshot::Sender<Result<T, StoreError>>;
+type ChunkCallback = ThreadsafeFunction<Buffer, ErrorStrategy::Fatal>;
+
+enum Command {
+    Create {
+        path: Vec<String>,
+        desc: String,
+        mime_type: String,
+        tags: Vec<String>,
+        content: Vec<u8>,
+        reply: Reply<()>,
+    },
+    Delete {
+        path: Vec<String>,
+        reply: Reply<()>,
+    },
+    Metadata {
+        path: Vec<String>,
+        reply: Reply<Value>,
+    },
+    Ls {
+        reply: Reply<Value>,
+    },
+    Search {
+        text: String,
+        reply: Reply<Value>,
+    },
+    AddTag {
+        path: Vec<String>,
+        tag: String,
+        reply: Reply<()>,
+    },
+    RemoveTag {
+        path: Vec<String>,
+        tag: String,
+        reply: Reply<()>,
+    },
+    AddVariant {
+        path: Vec<String>,
+        variant: String,
+        mime_type: String,
+        content: Vec<u8>,
+        reply: Reply<()>,
+    },
+    UpdateVariant {
+        path: Vec<String>,
+        variant: String,
+        mime_type: String,
+        content: Vec<u8>,
+        reply: Reply<()>,
+    },
+    DeleteVariant {
+        path: Vec<String>,
+        variant: String,
+        reply: Reply<()>,
+    },
+    GetVariant {
+        path: Vec<String>,
+        variant: String,
+        reply: Reply<Vec<u8>>,
+    },
+    StreamVariant {
+        path: Vec<String>,
+        variant: String,
+        on_chunk: ChunkCallback,
+        reply: Reply<()>,
+    },
+}
+
+fn to_path(path: &str) -> Vec<String> {
+    path.split('/').map(|s| s.to_owned()).collect()
+}
+
+fn to_json<T: serde::Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+async fn handle_command(store: &mut ResourceStore, command: Command) {
+    // Send errors only mean that the JS side went away, so they are ignored.
+    match command {
+        Command::Create {
+            path,
+            desc,
+            mime_type,
+            tags,
+            content,
+            reply,
+        } => {
+            let variant = VariantMetadata::new(content.len() as _, &mime_type);
+            let tags: HashSet<String> = tags.into_iter().collect();
+            let res = store
+                .create_resource_tokio(&path, &desc, &variant, tags, Cursor::new(content))
+                .await;
+            let _ = reply.send(res);
+        }
+        Command::Delete { path, reply } => {
+            let _ = reply.send(store.delete_resource(&path).await);
+        }
+        Command::Metadata { path, reply } => {
+            let _ = reply.send(store.get_metadata(&path).await.map(to_json));
+        }
+        Command::Ls { reply } => {
+            let res = match store.resources_dir().await {
+                Ok(dir) => store.ls(dir).await.map(to_json),
+                Err(err) => Err(err),
+            };
+            let _ = reply.send(res);
+        }
+        Command::Search { text, reply } => {
+            let res = store.search(&text).await.map(|results| {
+                to_json(
+                    results
+                        .into_iter()
+                        .map(|(id, meta)| (id.to_string(), meta))
+                        .collect::<Vec<_>>(),
+                )
+            });
+            let _ = reply.send(res);
+        }
+        Command::AddTag { path, tag, reply } => {
+            let _ = reply.send(store.add_tag(&path, &tag).await);
+        }
+        Command::RemoveTag { path, tag, reply } => {
+            let _ = reply.send(store.remove_tag(&path, &tag).await);
+        }
+        Command::AddVariant {
+            path,
+            variant,
+            mime_type,
+            content,
+            reply,
+        } => {
+            let meta = VariantMetadata::new(content.len() as _, &mime_type);
+            let res = store
+                .add_variant_tokio(&path, &variant, &meta, Cursor::new(content))
+                .await;
+            let _ = reply.send(res);
+        }
+        Command::UpdateVariant {
+            path,
+            variant,
+            mime_type,
+            content,
+            reply,
+        } => {
+            let meta = VariantMetadata::new(content.len() as _, &mime_type);
+            let res = store
+                .update_variant_tokio(&path, &variant, &meta, Cursor::new(content))
+                .await;
+            let _ = reply.send(res);
+        }
+        Command::DeleteVariant {
+            path,
+            variant,
+            reply,
+        } => {
+            let _ = reply.send(store.delete_variant(&path, &variant).await);
+        }
+        Command::GetVariant {
+            path,
+            variant,
+            reply,
+        } => {
+            let _ = reply.send(store.get_variant_vec(&variant, &path).await);
+        }
+        Command::StreamVariant {
+            path,
+            variant,
+            on_chunk,
+            reply,
+        } => {
+            let res = match store.get_variant(&variant, &path).await {
+                Ok(mut stream) => {
+                    let mut res = Ok(());
+                    while let Some(chunk) = stream.next().await {
+                        match chunk {
+                            Ok(chunk) => {
+                                on_chunk.call(chunk.into(), ThreadsafeFunctionCallMode::Blocking);
+                            }
+                            Err(err) => {
+                                res = Err(err);
+                                break;
+                            }
+                        }
+                    }
+                    res
+                }
+                Err(err) => Err(err),
+            };
+            let _ = reply.send(res);
+        }
+    }
+}
+
+fn to_napi_error<E: ToString>(err: E) -> napi::Error {
+    napi::Error::from_reason(err.to_string())
+}
+
+async fn send_command<T>(
+    sender: &mpsc::UnboundedSender<Command>,
+    command: impl FnOnce(Reply<T>) -> Command,
+) -> napi::Result<T> {
+    let (reply, receiver) = oneshot::channel();
+    sender
+        .send(command(reply))
+        .map_err(|_| napi::Error::from_reason("Store is closed"))?;
+    receiver
+        .await
+        .map_err(to_napi_error)?
+        .map_err(to_napi_error)
+}
+
+#[napi]
+pub struct DocStore {
+    sender: mpsc::UnboundedSender<Command>,
+}
+
+#[napi]
+impl DocStore {
+    /// Opens or creates the store located at `root_dir`.
+    #[napi(constructor)]
+    pub fn new(root_dir: String) -> napi::Result<Self> {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (ready_sender, ready_receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err.to_string()));
+                    return;
+                }
+            };
+
+            rt.block_on(async move {
+                let mut store = match ResourceStore::new(&root_dir).await {
+                    Ok(store) => {
+                        let _ = ready_sender.send(Ok(()));
+                        store
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err.to_string()));
+                        return;
+                    }
+                };
+
+                while let Some(command) = receiver.recv().await {
+                    handle_command(&mut store, command).await;
+                }
+            });
+        });
+
+        ready_receiver
+            .recv()
+            .map_err(to_napi_error)?
+            .map_err(napi::Error::from_reason)?;
+
+        Ok(Self { sender })
+    }
+
+    #[napi]
+    pub async fn create_resource(
+        &self,
+        path: String,
+        desc: String,
+        mime_type: String,
+        tags: Vec<String>,
+        content: Buffer,
+    ) -> napi::Result<()> {
+        let content = content.to_vec();
+        send_command(&self.sender, |reply| Command::Create {
+            path: to_path(&path),
+            desc,
+            mime_type,
+            tags,
+            content,
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn delete_resource(&self, path: String) -> napi::Result<()> {
+        send_command(&self.sender, |reply| Command::Delete {
+            path: to_path(&path),
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn get_metadata(&self, path: String) -> napi::Result<Value> {
+        send_command(&self.sender, |reply| Command::Metadata {
+            path: to_path(&path),
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn ls(&self) -> napi::Result<Value> {
+        send_command(&self.sender, |reply| Command::Ls { reply }).await
+    }
+
+    #[napi]
+    pub async fn search(&self, text: String) -> napi::Result<Value> {
+        send_command(&self.sender, |reply| Command::Search { text, reply }).await
+    }
+
+    #[napi]
+    pub async fn add_tag(&self, path: String, tag: String) -> napi::Result<()> {
+        send_command(&self.sender, |reply| Command::AddTag {
+            path: to_path(&path),
+            tag,
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn remove_tag(&self, path: String, tag: String) -> napi::Result<()> {
+        send_command(&self.sender, |reply| Command::RemoveTag {
+            path: to_path(&path),
+            tag,
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn add_variant(
+        &self,
+        path: String,
+        variant: String,
+        mime_type: String,
+        content: Buffer,
+    ) -> napi::Result<()> {
+        let content = content.to_vec();
+        send_command(&self.sender, |reply| Command::AddVariant {
+            path: to_path(&path),
+            variant,
+            mime_type,
+            content,
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn update_variant(
+        &self,
+        path: String,
+        variant: String,
+        mime_type: String,
+        content: Buffer,
+    ) -> napi::Result<()> {
+        let content = content.to_vec();
+        send_command(&self.sender, |reply| Command::UpdateVariant {
+            path: to_path(&path),
+            variant,
+            mime_type,
+            content,
+            reply,
+        })
+        .await
+    }
+
+    #[napi]
+    pub async fn delete_variant(&self, path: String, variant: String) -> napi::Result<()> {
+        send_command(&self.sender, |reply| Command::DeleteVariant {
+            path: to_path(&path),
+            variant,
+            reply,
+        })
+        .await
+    }
+
+    /// Returns the full content of a variant. Use `streamVariant` for large contents.
+    #[napi]
+    pub async fn get_variant(&self, path: String, variant: String) -> napi::Result<Buffer> {
+        send_command(&self.sender, |reply| Command::GetVariant {
+            path: to_path(&path),
+            variant,
+            reply,
+        })
+        .await
+        .map(|content| content.into())
+    }
+
+    /// Calls `on_chunk` for each chunk of the variant content, resolving
+    /// once the whole content was streamed.
+    #[napi(ts_args_type = "path: string, variant: string, onChunk: (chunk: Buffer) => void")]
+    pub async fn stream_variant(
+        &self,
+        path: String,
+        variant: String,
+        on_chunk: ChunkCallback,
+    ) -> napi::Result<()> {
+        send_command(&self.sender, |reply| Command::StreamVariant {
+            path: to_path(&path),
+            variant,
+            on_chunk,
+            reply,
+        })
+        .await
+    }
+}
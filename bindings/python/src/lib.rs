@@ -0,0 +1,370 @@
+//! Python bindings for the resource store.
+//!
+//! The store is not `Send`, so it lives on a dedicated thread running its own
+//! single threaded runtime. The Python object only holds a channel to that
+//! thread, and each call blocks (without holding the GIL) on a reply channel.
+
+use docstore::resource::VariantMetadata;
+use docstore::store::{ResourceStore, StoreError};
+use futures::StreamExt;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pythonize::pythonize;
+use std::collections::HashSet;
+use std::io::Cursor;
+use tokio::sync::{mpsc, oneshot};
+
+create_exception!(docstore_py, DocStoreError, PyException);
+
+// How many chunks can be buffered while streaming a variant.
+const STREAM_BUFFER_CHUNKS: usize = 4;
+
+type Reply<T> = oneshot::Sender<Result<T, StoreError>>;
+type Chunks = mpsc::Sender<Result<Vec<u8>, StoreError>>;
+
+enum Command {
+    Create {
+        path: Vec<String>,
+        desc: String,
+        mime_type: String,
+        tags: HashSet<String>,
+        content: Vec<u8>,
+        reply: Reply<()>,
+    },
+    Delete {
+        path: Vec<String>,
+        reply: Reply<()>,
+    },
+    Metadata {
+        path: Vec<String>,
+        reply: Reply<docstore::resource::ResourceMetadata>,
+    },
+    Search {
+        text: String,
+        reply: Reply<Vec<(String, docstore::resource::ResourceMetadata)>>,
+    },
+    AddTag {
+        path: Vec<String>,
+        tag: String,
+        reply: Reply<()>,
+    },
+    RemoveTag {
+        path: Vec<String>,
+        tag: String,
+        reply: Reply<()>,
+    },
+    AddVariant {
+        path: Vec<String>,
+        variant: String,
+        mime_type: String,
+        content: Vec<u8>,
+        reply: Reply<()>,
+    },
+    DeleteVariant {
+        path: Vec<String>,
+        variant: String,
+        reply: Reply<()>,
+    },
+    StreamVariant {
+        path: Vec<String>,
+        variant: String,
+        chunks: Chunks,
+    },
+}
+
+fn to_path(path: &str) -> Vec<String> {
+    path.split('/').map(|s| s.to_owned()).collect()
+}
+
+fn to_py_error(err: StoreError) -> PyErr {
+    DocStoreError::new_err(err.to_string())
+}
+
+async fn handle_command(store: &mut ResourceStore, command: Command) {
+    // Send errors only mean that the Python side went away, so they are ignored.
+    match command {
+        Command::Create {
+            path,
+            desc,
+            mime_type,
+            tags,
+            content,
+            reply,
+        } => {
+            let variant = VariantMetadata::new(content.len() as _, &mime_type);
+            let res = store
+                .create_resource_tokio(&path, &desc, &variant, tags, Cursor::new(content))
+                .await;
+            let _ = reply.send(res);
+        }
+        Command::Delete { path, reply } => {
+            let _ = reply.send(store.delete_resource(&path).await);
+        }
+        Command::Metadata { path, reply } => {
+            let _ = reply.send(store.get_metadata(&path).await);
+        }
+        Command::Search { text, reply } => {
+            let res = store.search(&text).await.map(|results| {
+                results
+                    .into_iter()
+                    .map(|(id, meta)| (id.to_string(), meta))
+                    .collect()
+            });
+            let _ = reply.send(res);
+        }
+        Command::AddTag { path, tag, reply } => {
+            let _ = reply.send(store.add_tag(&path, &tag).await);
+        }
+        Command::RemoveTag { path, tag, reply } => {
+            let _ = reply.send(store.remove_tag(&path, &tag).await);
+        }
+        Command::AddVariant {
+            path,
+            variant,
+            mime_type,
+            content,
+            reply,
+        } => {
+            let meta = VariantMetadata::new(content.len() as _, &mime_type);
+            let res = store
+                .add_variant_tokio(&path, &variant, &meta, Cursor::new(content))
+                .await;
+            let _ = reply.send(res);
+        }
+        Command::DeleteVariant {
+            path,
+            variant,
+            reply,
+        } => {
+            let _ = reply.send(store.delete_variant(&path, &variant).await);
+        }
+        Command::StreamVariant {
+            path,
+            variant,
+            chunks,
+        } => match store.get_variant(&variant, &path).await {
+            Ok(mut stream) => {
+                while let Some(chunk) = stream.next().await {
+                    let failed = chunk.is_err();
+                    if chunks.send(chunk).await.is_err() || failed {
+                        break;
+                    }
+                }
+            }
+            Err(err) => {
+                let _ = chunks.send(Err(err)).await;
+            }
+        },
+    }
+}
+
+/// Returns the bytes of `content`, which is either a bytes-like object
+/// or a file-like object with a `read()` method.
+fn content_bytes(content: &PyAny) -> PyResult<Vec<u8>> {
+    if let Ok(bytes) = content.extract::<Vec<u8>>() {
+        return Ok(bytes);
+    }
+    content.call_method0("read")?.extract::<Vec<u8>>()
+}
+
+#[pyclass]
+struct DocStore {
+    sender: mpsc::UnboundedSender<Command>,
+}
+
+impl DocStore {
+    fn send<T: Send>(
+        &self,
+        py: Python<'_>,
+        command: impl FnOnce(Reply<T>) -> Command + Send,
+    ) -> PyResult<T> {
+        py.allow_threads(|| {
+            let (reply, receiver) = oneshot::channel();
+            self.sender
+                .send(command(reply))
+                .map_err(|_| DocStoreError::new_err("Store is closed"))?;
+            receiver
+                .blocking_recv()
+                .map_err(|_| DocStoreError::new_err("Store is closed"))?
+                .map_err(to_py_error)
+        })
+    }
+}
+
+#[pymethods]
+impl DocStore {
+    /// Opens or creates the store located at `root_dir`.
+    #[new]
+    fn new(root_dir: String) -> PyResult<Self> {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (ready_sender, ready_receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err.to_string()));
+                    return;
+                }
+            };
+
+            rt.block_on(async move {
+                let mut store = match ResourceStore::new(&root_dir).await {
+                    Ok(store) => {
+                        let _ = ready_sender.send(Ok(()));
+                        store
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err.to_string()));
+                        return;
+                    }
+                };
+
+                while let Some(command) = receiver.recv().await {
+                    handle_command(&mut store, command).await;
+                }
+            });
+        });
+
+        ready_receiver
+            .recv()
+            .map_err(|err| DocStoreError::new_err(err.to_string()))?
+            .map_err(DocStoreError::new_err)?;
+
+        Ok(Self { sender })
+    }
+
+    #[pyo3(signature = (path, desc, mime_type, content, tags = None))]
+    fn create_resource(
+        &self,
+        py: Python<'_>,
+        path: &str,
+        desc: String,
+        mime_type: String,
+        content: &PyAny,
+        tags: Option<HashSet<String>>,
+    ) -> PyResult<()> {
+        let content = content_bytes(content)?;
+        self.send(py, |reply| Command::Create {
+            path: to_path(path),
+            desc,
+            mime_type,
+            tags: tags.unwrap_or_default(),
+            content,
+            reply,
+        })
+    }
+
+    fn delete_resource(&self, py: Python<'_>, path: &str) -> PyResult<()> {
+        self.send(py, |reply| Command::Delete {
+            path: to_path(path),
+            reply,
+        })
+    }
+
+    /// Returns the resource metadata as a dict.
+    fn get_metadata(&self, py: Python<'_>, path: &str) -> PyResult<PyObject> {
+        let meta = self.send(py, |reply| Command::Metadata {
+            path: to_path(path),
+            reply,
+        })?;
+        Ok(pythonize(py, &meta)?)
+    }
+
+    /// Returns a list of `(id, metadata)` tuples.
+    fn search(&self, py: Python<'_>, text: String) -> PyResult<PyObject> {
+        let results = self.send(py, |reply| Command::Search { text, reply })?;
+        Ok(pythonize(py, &results)?)
+    }
+
+    fn add_tag(&self, py: Python<'_>, path: &str, tag: String) -> PyResult<()> {
+        self.send(py, |reply| Command::AddTag {
+            path: to_path(path),
+            tag,
+            reply,
+        })
+    }
+
+    fn remove_tag(&self, py: Python<'_>, path: &str, tag: String) -> PyResult<()> {
+        self.send(py, |reply| Command::RemoveTag {
+            path: to_path(path),
+            tag,
+            reply,
+        })
+    }
+
+    fn add_variant(
+        &self,
+        py: Python<'_>,
+        path: &str,
+        variant: String,
+        mime_type: String,
+        content: &PyAny,
+    ) -> PyResult<()> {
+        let content = content_bytes(content)?;
+        self.send(py, |reply| Command::AddVariant {
+            path: to_path(path),
+            variant,
+            mime_type,
+            content,
+            reply,
+        })
+    }
+
+    fn delete_variant(&self, py: Python<'_>, path: &str, variant: String) -> PyResult<()> {
+        self.send(py, |reply| Command::DeleteVariant {
+            path: to_path(path),
+            variant,
+            reply,
+        })
+    }
+
+    /// Returns an iterator over the chunks of a variant content.
+    /// Other calls on the store wait until the iterator is exhausted or dropped.
+    #[pyo3(signature = (path, variant = "default".to_owned()))]
+    fn get_variant(&self, path: &str, variant: String) -> PyResult<VariantChunks> {
+        let (chunks, receiver) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+        self.sender
+            .send(Command::StreamVariant {
+                path: to_path(path),
+                variant,
+                chunks,
+            })
+            .map_err(|_| DocStoreError::new_err("Store is closed"))?;
+        Ok(VariantChunks { receiver })
+    }
+}
+
+#[pyclass]
+struct VariantChunks {
+    receiver: mpsc::Receiver<Result<Vec<u8>, StoreError>>,
+}
+
+#[pymethods]
+impl VariantChunks {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let receiver = &mut slf.receiver;
+        match py.allow_threads(|| receiver.blocking_recv()) {
+            Some(Ok(chunk)) => Ok(Some(PyBytes::new(py, &chunk).into())),
+            Some(Err(err)) => Err(to_py_error(err)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[pymodule]
+fn docstore_py(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<DocStore>()?;
+    m.add_class::<VariantChunks>()?;
+    m.add("DocStoreError", py.get_type::<DocStoreError>())?;
+    Ok(())
+}
@@ -0,0 +1,330 @@
+//! Authorization of the requests.
+//!
+//! Clients of the local socket are trusted with the whole API. Clients
+//! connected over TCP start unauthenticated and must first send an
+//! `authenticate` request with the secret of an access token issued by
+//! `ResourceStore::issue_access_token()`. Their requests are then limited
+//! to the scope and containers of the token, which is checked again for
+//! each request so that revoking or rotating it takes effect immediately.
+//! Until then their frames are limited to `MAX_UNAUTHENTICATED_FRAME_SIZE`.
+
+use crate::protocol::{
+    RpcError, INVALID_PARAMS, MAX_FRAME_SIZE, MAX_UNAUTHENTICATED_FRAME_SIZE, UNAUTHORIZED,
+};
+use docstore::access::{AccessToken, Scope};
+use docstore::store::ResourceStore;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize)]
+struct AuthenticateParams {
+    token: String,
+}
+
+// The scope required by each method, or None for the methods reserved to
+// local clients: importing files reads the daemon host, and reindexing is
+// an administration task.
+fn required_scope(method: &str) -> Option<Scope> {
+    match method {
+        "search" | "suggest" | "revision" => Some(Scope::SearchOnly),
+        "getMetadata" | "bestVariant" | "getVariant" | "ls" | "changesSince" | "lsByTag"
+        | "listSmartFolder" | "recent" | "suggested" | "verifyVariant" | "getProperties"
+        | "exportBookmarks" | "listShares" | "metrics" => Some(Scope::ReadOnly),
+        "createResource" | "deleteResource" | "updateDesc" | "addTag" | "removeTag"
+        | "addVariant" | "updateVariant" | "deleteVariant" | "importBookmarks"
+        | "applyTagRules" | "share" | "revokeShare" => Some(Scope::ReadWrite),
+        _ => None,
+    }
+}
+
+// Methods acting on, or revealing, all the resources, refused to the tokens
+// restricted to some containers. Suggestions come from the whole index and
+// metrics count the whole store.
+fn is_global(method: &str) -> bool {
+    matches!(
+        method,
+        "exportBookmarks" | "applyTagRules" | "listShares" | "revokeShare" | "suggest" | "metrics"
+    )
+}
+
+// Returns the resource or container targeted by a request, if any.
+fn target(params: &Value) -> Option<Vec<String>> {
+    let target = params.get("path").or_else(|| params.get("container"))?;
+    serde_json::from_value(target.clone()).ok()
+}
+
+fn unauthorized<E: ToString>(err: E) -> RpcError {
+    RpcError::new(UNAUTHORIZED, err)
+}
+
+/// Whether the resource at `path` can be returned to a client limited by
+/// `token`.
+pub fn visible(token: Option<&AccessToken>, path: &[String]) -> bool {
+    token.map(|token| token.allows_path(path)).unwrap_or(true)
+}
+
+/// Whether the directory entry at `path` can be listed to a client limited
+/// by `token`: it is in one of the token containers or leads to one.
+pub fn listable(token: Option<&AccessToken>, path: &[String]) -> bool {
+    token
+        .map(|token| {
+            token.allows_path(path)
+                || token
+                    .containers
+                    .iter()
+                    .any(|container| container.starts_with(path))
+        })
+        .unwrap_or(true)
+}
+
+/// The authorization state of a connection.
+pub struct Session {
+    remote: bool,
+    secret: Option<String>,
+}
+
+impl Session {
+    pub fn local() -> Self {
+        Self {
+            remote: false,
+            secret: None,
+        }
+    }
+
+    pub fn remote() -> Self {
+        Self {
+            remote: true,
+            secret: None,
+        }
+    }
+
+    /// The largest frame accepted from the client: remote clients are
+    /// limited to small requests until they authenticate.
+    pub fn max_frame_size(&self) -> u32 {
+        if self.remote && self.secret.is_none() {
+            MAX_UNAUTHENTICATED_FRAME_SIZE
+        } else {
+            MAX_FRAME_SIZE
+        }
+    }
+
+    /// Handles an `authenticate` request, returning the token.
+    pub async fn authenticate(
+        &mut self,
+        store: &ResourceStore,
+        params: Value,
+    ) -> Result<Value, RpcError> {
+        let p: AuthenticateParams =
+            serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err))?;
+        let token = store
+            .check_access_token(&p.token)
+            .await
+            .map_err(unauthorized)?;
+        self.secret = Some(p.token);
+        Ok(serde_json::to_value(token).unwrap_or(Value::Null))
+    }
+
+    /// Checks that the client can call `method` with `params`. Returns the
+    /// token limiting the client, or None if it has full access.
+    pub async fn authorize(
+        &self,
+        store: &ResourceStore,
+        method: &str,
+        params: &Value,
+    ) -> Result<Option<AccessToken>, RpcError> {
+        if !self.remote {
+            return Ok(None);
+        }
+        let secret = self
+            .secret
+            .as_ref()
+            .ok_or_else(|| unauthorized("Authentication required"))?;
+        let token = store
+            .check_access_token(secret)
+            .await
+            .map_err(unauthorized)?;
+
+        match required_scope(method) {
+            Some(scope) if token.allows(scope) => {}
+            Some(_) => {
+                return Err(unauthorized(format!(
+                    "{} is out of the token scope",
+                    method
+                )))
+            }
+            None => {
+                return Err(unauthorized(format!(
+                    "{} is reserved to local clients",
+                    method
+                )))
+            }
+        }
+        if !token.is_unrestricted() {
+            if is_global(method) {
+                return Err(unauthorized(format!(
+                    "{} is refused to tokens restricted to containers",
+                    method
+                )));
+            }
+            if let Some(path) = target(params) {
+                if !token.allows_path(&path) {
+                    return Err(unauthorized("Resource out of the token containers"));
+                }
+            }
+        }
+        Ok(Some(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn test_store(name: &str) -> ResourceStore {
+        let path = std::env::temp_dir().join(format!("docstored-{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        ResourceStore::new(&path.to_string_lossy())
+            .await
+            .expect("Failed to create resource store!")
+    }
+
+    async fn remote_session(store: &ResourceStore, secret: &str) -> Session {
+        let mut session = Session::remote();
+        session
+            .authenticate(store, json!({ "token": secret }))
+            .await
+            .unwrap();
+        session
+    }
+
+    fn path(path: &[&str]) -> Value {
+        json!({ "path": path })
+    }
+
+    #[tokio::test]
+    async fn local_and_unauthenticated() {
+        let store = test_store("auth-local").await;
+        let params = path(&["notes"]);
+
+        // Local clients have full access.
+        let local = Session::local();
+        assert_eq!(local.max_frame_size(), MAX_FRAME_SIZE);
+        assert!(local
+            .authorize(&store, "reindex", &params)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Remote clients must authenticate first, with a valid secret.
+        let mut remote = Session::remote();
+        assert!(remote.authorize(&store, "search", &params).await.is_err());
+        assert!(remote
+            .authenticate(&store, json!({ "token": "garbage" }))
+            .await
+            .is_err());
+        assert!(remote.authorize(&store, "search", &params).await.is_err());
+        assert_eq!(remote.max_frame_size(), MAX_UNAUTHENTICATED_FRAME_SIZE);
+    }
+
+    #[tokio::test]
+    async fn token_scope() {
+        let mut store = test_store("auth-scope").await;
+        let (_, secret) = store
+            .issue_access_token("phone", Scope::ReadOnly, &[], None)
+            .await
+            .unwrap();
+        let session = remote_session(&store, &secret).await;
+        let params = path(&["notes"]);
+        assert_eq!(session.max_frame_size(), MAX_FRAME_SIZE);
+
+        let token = session
+            .authorize(&store, "getMetadata", &params)
+            .await
+            .unwrap();
+        assert_eq!(token.unwrap().name, "phone");
+        assert!(session.authorize(&store, "search", &params).await.is_ok());
+        // Out of the scope.
+        assert!(session
+            .authorize(&store, "deleteResource", &params)
+            .await
+            .is_err());
+        // Reserved to local clients.
+        assert!(session.authorize(&store, "reindex", &params).await.is_err());
+        assert!(session
+            .authorize(&store, "issueAccessToken", &params)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn container_tokens() {
+        let mut store = test_store("auth-containers").await;
+        let photos = vec!["photos".to_owned(), "2024".to_owned()];
+        let (token, secret) = store
+            .issue_access_token("frame", Scope::ReadOnly, &[photos], None)
+            .await
+            .unwrap();
+        let session = remote_session(&store, &secret).await;
+
+        assert!(session
+            .authorize(&store, "getMetadata", &path(&["photos", "2024", "beach"]))
+            .await
+            .is_ok());
+        assert!(session
+            .authorize(&store, "getMetadata", &path(&["notes"]))
+            .await
+            .is_err());
+        assert!(session
+            .authorize(&store, "getMetadata", &path(&["photos", "2023", "beach"]))
+            .await
+            .is_err());
+
+        // Methods revealing the whole store are refused.
+        for method in ["suggest", "metrics", "exportBookmarks", "listShares"] {
+            assert!(session
+                .authorize(&store, method, &Value::Null)
+                .await
+                .is_err());
+        }
+
+        // The parents of the containers are listed, not their siblings.
+        let token = Some(&token);
+        assert!(listable(token, &["photos".to_owned()]));
+        assert!(!listable(token, &["notes".to_owned()]));
+        assert!(listable(None, &["notes".to_owned()]));
+        assert!(!visible(token, &["photos".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_tokens() {
+        let mut store = test_store("auth-revoked").await;
+        let params = path(&["notes"]);
+
+        let (token, secret) = store
+            .issue_access_token("phone", Scope::ReadWrite, &[], None)
+            .await
+            .unwrap();
+        let session = remote_session(&store, &secret).await;
+        assert!(session.authorize(&store, "addTag", &params).await.is_ok());
+        // Revoking the token takes effect on the next request.
+        assert!(store.revoke_access_token(&token.id).await.unwrap());
+        assert!(session.authorize(&store, "addTag", &params).await.is_err());
+
+        let (_, secret) = store
+            .issue_access_token(
+                "laptop",
+                Scope::ReadWrite,
+                &[],
+                Some(chrono::Duration::seconds(-1)),
+            )
+            .await
+            .unwrap();
+        let mut session = Session::remote();
+        assert!(session
+            .authenticate(&store, json!({ "token": secret }))
+            .await
+            .is_err());
+        assert!(session.authorize(&store, "addTag", &params).await.is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! `docstored` shares a single store between local clients, exposing its
+//! API as JSON-RPC over a Unix domain socket, or a named pipe on Windows.
+//!
+//! Usage: `docstored <root-dir> [socket-path]`
+//!
+//! Setting `DOCSTORED_METRICS_ADDR`, eg. to `127.0.0.1:9187`, also serves
+//! Prometheus metrics over HTTP on `/metrics` at this address.
+//!
+//! Setting `DOCSTORED_LISTEN_ADDR`, eg. to `0.0.0.0:9186`, also accepts
+//! remote clients over TCP at this address. They must authenticate with an
+//! access token, see `auth.rs`. The connection is encrypted with TLS when a
+//! certificate is configured, and non-loopback addresses are refused
+//! otherwise, see `tls.rs`.
+//!
+//! The store is not `Send`, so everything runs on a single threaded runtime
+//! with each client served by its own local task.
+
+mod auth;
+mod metrics;
+mod protocol;
+mod rpc;
+mod tls;
+
+use auth::Session;
+use docstore::store::ResourceStore;
+use log::{error, info};
+use rpc::SharedStore;
+use std::path::Path;
+use std::rc::Rc;
+use tokio::sync::Mutex;
+use tokio::task::LocalSet;
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(unix)]
+async fn listen(store: SharedStore, socket_path: String, uploads: Rc<Path>) -> std::io::Result<()> {
+    use tokio::net::UnixListener;
+
+    // Remove a stale socket left by a previous run.
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path)?;
+    info!("Listening on {}", socket_path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = store.clone();
+        let uploads = uploads.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(err) = rpc::serve(store, stream, Session::local(), uploads).await {
+                error!("Client error: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+async fn listen(store: SharedStore, pipe_name: String, uploads: Rc<Path>) -> std::io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&pipe_name)?;
+    info!("Listening on {}", pipe_name);
+
+    loop {
+        server.connect().await?;
+        // Create the next instance before serving this client, so that
+        // there is always one available for new connections.
+        let client = std::mem::replace(&mut server, ServerOptions::new().create(&pipe_name)?);
+        let store = store.clone();
+        let uploads = uploads.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(err) = rpc::serve(store, client, Session::local(), uploads).await {
+                error!("Client error: {}", err);
+            }
+        });
+    }
+}
+
+// Serves the remote clients connecting to `addr`, over TLS if `tls` is
+// set and only on loopback addresses otherwise.
+async fn listen_tcp(
+    store: SharedStore,
+    addr: String,
+    tls: Option<TlsAcceptor>,
+    uploads: Rc<Path>,
+) -> std::io::Result<()> {
+    if tls.is_none() {
+        for resolved in tokio::net::lookup_host(&addr).await? {
+            if !resolved.ip().is_loopback() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("Refusing to listen on {} without TLS", addr),
+                ));
+            }
+        }
+    }
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    info!("Listening for remote clients on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        info!("Remote client connected from {}", peer);
+        let store = store.clone();
+        let uploads = uploads.clone();
+        let tls = tls.clone();
+        tokio::task::spawn_local(async move {
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => rpc::serve(store, stream, Session::remote(), uploads).await,
+                    Err(err) => Err(err),
+                },
+                None => rpc::serve(store, stream, Session::remote(), uploads).await,
+            };
+            if let Err(err) = result {
+                error!("Remote client error: {}", err);
+            }
+        });
+    }
+}
+
+fn default_socket_path(root_dir: &str) -> String {
+    if cfg!(windows) {
+        r"\\.\pipe\docstored".into()
+    } else {
+        format!("{}/docstored.sock", root_dir)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let root_dir = match std::env::args().nth(1) {
+        Some(root_dir) => root_dir,
+        None => {
+            eprintln!("Usage: docstored <root-dir> [socket-path]");
+            std::process::exit(1);
+        }
+    };
+    let socket_path = std::env::args()
+        .nth(2)
+        .unwrap_or_else(|| default_socket_path(&root_dir));
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    LocalSet::new().block_on(&rt, async move {
+        let store = ResourceStore::new(&root_dir).await?;
+        // Uploads left by a previous run are incomplete.
+        let uploads = Path::new(&root_dir).join("uploads");
+        let _ = std::fs::remove_dir_all(&uploads);
+        std::fs::create_dir_all(&uploads)?;
+        let uploads: Rc<Path> = uploads.into();
+        if let Ok(addr) = std::env::var("DOCSTORED_METRICS_ADDR") {
+            tokio::task::spawn_local(async move {
+                if let Err(err) = metrics::serve(addr).await {
+                    error!("Metrics server error: {}", err);
+                }
+            });
+        }
+        let store = Rc::new(Mutex::new(store));
+        if let Ok(addr) = std::env::var("DOCSTORED_LISTEN_ADDR") {
+            let tls = tls::acceptor()?;
+            let store = store.clone();
+            let uploads = uploads.clone();
+            tokio::task::spawn_local(async move {
+                if let Err(err) = listen_tcp(store, addr, tls, uploads).await {
+                    error!("Remote clients server error: {}", err);
+                }
+            });
+        }
+        listen(store, socket_path, uploads).await?;
+        Ok::<(), Box<dyn std::error::Error>>(())
+    })
+}
@@ -0,0 +1,47 @@
+//! A minimal HTTP server answering `GET /metrics` with the store metrics,
+//! for Prometheus to scrape.
+
+use log::{error, info};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+async fn respond(stream: TcpStream) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    // Skip the headers, up to the empty line.
+    let mut line = String::new();
+    while stream.read_line(&mut line).await? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", docstore::metrics::render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
+
+/// Serves the metrics on `addr` until an accept error happens.
+pub async fn serve(addr: String) -> std::io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::task::spawn_local(async move {
+            if let Err(err) = respond(stream).await {
+                error!("Metrics request error: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,261 @@
+//! Wire protocol of the daemon.
+//!
+//! Every message is a frame made of a big endian u32 length followed by
+//! that many bytes. Requests and responses are JSON-RPC 2.0 objects sent
+//! in a single frame each.
+//!
+//! Variant content is streamed as raw frames terminated by an empty frame:
+//! - requests that upload content (`createResource`, `addVariant` and
+//!   `updateVariant`) are followed by the content frames, at most
+//!   `MAX_CONTENT_SIZE` bytes in total. They are written out as they
+//!   arrive, and the content of a request that is not authorized is
+//!   discarded.
+//! - a successful `getVariant` response is followed by the content frames.
+//!   If reading the content fails midway the connection is closed.
+
+use futures::io::{AsyncWrite as FuturesAsyncWrite, AsyncWriteExt as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Frames larger than this are rejected, to not let a client make us
+/// allocate arbitrary amounts of memory.
+pub const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The frame size limit until a remote client is authenticated, which is
+/// enough for any request but the content frames.
+pub const MAX_UNAUTHENTICATED_FRAME_SIZE: u32 = 64 * 1024;
+
+/// The total size of the variant content sent with a request.
+pub const MAX_CONTENT_SIZE: u64 = 256 * 1024 * 1024;
+
+// JSON-RPC error codes.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const STORE_ERROR: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new<E: ToString>(code: i64, err: E) -> Self {
+        Self {
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(value) => (Some(value), None),
+            Err(err) => (None, Some(err)),
+        };
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// Reads a frame of at most `max_size` bytes, returning `None` if the peer
+/// closed the connection.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_size: u32,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match read_frame_len(reader).await? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    if len > max_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame too large: {} bytes", len),
+        ));
+    }
+
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+async fn read_frame_len<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<u32>> {
+    match reader.read_u32().await {
+        Ok(len) => Ok(Some(len)),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &[u8],
+) -> std::io::Result<()> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+/// Writes content frames to `writer` as they arrive, up to the terminating
+/// empty frame, returning the size of the content. The outer error is a
+/// failure to read the frames, including more than `max_size` bytes of
+/// content, after which the connection can't be used anymore. The inner
+/// one is a failure of `writer`, after which the rest of the content is
+/// discarded.
+pub async fn copy_content<R: AsyncRead + Unpin, W: FuturesAsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    max_size: u64,
+) -> std::io::Result<std::io::Result<u64>> {
+    let mut size = 0;
+    loop {
+        match read_frame(reader, MAX_FRAME_SIZE).await? {
+            Some(frame) if frame.is_empty() => return Ok(Ok(size)),
+            Some(frame) => {
+                size += frame.len() as u64;
+                if size > max_size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Content larger than {} bytes", max_size),
+                    ));
+                }
+                if let Err(err) = writer.write_all(&frame).await {
+                    skip_content(reader).await?;
+                    return Ok(Err(err));
+                }
+            }
+            None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// Reads and discards content frames up to the terminating empty frame,
+/// without buffering them.
+pub async fn skip_content<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<()> {
+    loop {
+        match read_frame_len(reader).await? {
+            Some(0) => return Ok(()),
+            Some(len) => {
+                let mut frame = (&mut *reader).take(len as u64);
+                let skipped = tokio::io::copy(&mut frame, &mut tokio::io::sink()).await?;
+                if skipped < len as u64 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+) -> std::io::Result<()> {
+    let json = serde_json::to_vec(response)?;
+    write_frame(writer, &json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn content_round_trip() {
+        let mut buffer = vec![];
+        write_frame(&mut buffer, b"{}").await.unwrap();
+        write_frame(&mut buffer, b"hello ").await.unwrap();
+        write_frame(&mut buffer, b"world").await.unwrap();
+        write_frame(&mut buffer, &[]).await.unwrap();
+
+        let mut reader = buffer.as_slice();
+        assert_eq!(
+            read_frame(&mut reader, MAX_FRAME_SIZE)
+                .await
+                .unwrap()
+                .unwrap(),
+            b"{}"
+        );
+        let mut content = vec![];
+        let size = copy_content(&mut reader, &mut content, MAX_CONTENT_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(content, b"hello world");
+        // The peer closed the connection.
+        assert!(read_frame(&mut reader, MAX_FRAME_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Too much content.
+        let mut reader = &buffer[6..];
+        assert!(copy_content(&mut reader, &mut vec![], 8).await.is_err());
+        let mut reader = &buffer[6..];
+        skip_content(&mut reader).await.unwrap();
+        assert!(reader.is_empty());
+
+        // Truncated content.
+        let mut reader = &buffer[6..buffer.len() - 4];
+        assert!(copy_content(&mut reader, &mut vec![], MAX_CONTENT_SIZE)
+            .await
+            .is_err());
+
+        // The rest of the content is skipped when the writer fails.
+        let mut reader = &buffer[6..];
+        let mut space = [0; 4];
+        let mut full = futures::io::Cursor::new(&mut space[..]);
+        assert!(copy_content(&mut reader, &mut full, MAX_CONTENT_SIZE)
+            .await
+            .unwrap()
+            .is_err());
+        assert!(reader.is_empty());
+
+        // Oversized frame header.
+        let mut reader = &(MAX_FRAME_SIZE + 1).to_be_bytes()[..];
+        assert!(read_frame(&mut reader, MAX_FRAME_SIZE).await.is_err());
+
+        // Content frames are larger than the unauthenticated limit, but
+        // can still be skipped.
+        let mut buffer = vec![];
+        write_frame(&mut buffer, &[0; 100 * 1024]).await.unwrap();
+        write_frame(&mut buffer, &[]).await.unwrap();
+        let mut reader = buffer.as_slice();
+        assert!(read_frame(&mut reader, MAX_UNAUTHENTICATED_FRAME_SIZE)
+            .await
+            .is_err());
+        let mut reader = buffer.as_slice();
+        skip_content(&mut reader).await.unwrap();
+        assert!(reader.is_empty());
+    }
+}
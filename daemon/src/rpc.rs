@@ -0,0 +1,678 @@
+//! Dispatches the JSON-RPC requests of a client connection to the store.
+//!
+//! The store is shared by all the connections behind an async mutex, so
+//! each request runs to completion before the next one is processed.
+
+use crate::auth::{listable, visible, Session};
+use crate::protocol::*;
+use docstore::access::{AccessToken, Scope};
+use docstore::resource::{ResourceId, ResourceMetadata, SearchOrder, VariantMetadata};
+use docstore::store::ResourceStore;
+use docstore::sync::SyncFilter;
+use futures::{AsyncWriteExt, StreamExt};
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_util::compat::TokioAsyncWriteCompatExt;
+
+pub type SharedStore = Rc<Mutex<ResourceStore>>;
+
+type RpcResult = Result<Value, RpcError>;
+
+#[derive(Deserialize)]
+struct PathParams {
+    path: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateParams {
+    path: Vec<String>,
+    desc: String,
+    mime_type: String,
+    #[serde(default)]
+    tags: HashSet<String>,
+}
+
+#[derive(Deserialize)]
+struct TagParams {
+    path: Vec<String>,
+    tag: String,
+}
+
+#[derive(Deserialize)]
+struct VariantParams {
+    path: Vec<String>,
+    variant: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BestVariantParams {
+    path: Vec<String>,
+    #[serde(default)]
+    accepted: Vec<String>,
+    max_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SharedVariantParams {
+    token: String,
+    variant: String,
+}
+
+#[derive(Deserialize)]
+struct ShareParams {
+    path: Vec<String>,
+    /// The validity of the token, in seconds.
+    duration: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct IssueTokenParams {
+    name: String,
+    scope: Scope,
+    #[serde(default)]
+    containers: Vec<Vec<String>>,
+    /// The validity of the token in seconds, forever if missing.
+    #[serde(default)]
+    duration: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TokenIdParams {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VariantContentParams {
+    path: Vec<String>,
+    variant: String,
+    mime_type: String,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    text: String,
+    #[serde(default)]
+    variants: Option<Vec<String>>,
+    #[serde(default)]
+    order: Option<SearchOrder>,
+}
+
+#[derive(Deserialize)]
+struct TagOnlyParams {
+    tag: String,
+}
+
+#[derive(Deserialize)]
+struct DescParams {
+    path: Vec<String>,
+    desc: String,
+}
+
+#[derive(Deserialize)]
+struct NameParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SuggestParams {
+    prefix: String,
+    count: u32,
+}
+
+#[derive(Deserialize)]
+struct ChangesParams {
+    cursor: u64,
+    // The containers the pulling device doesn't sync.
+    #[serde(default)]
+    excluded: Vec<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct CountParams {
+    count: u32,
+}
+
+#[derive(Deserialize)]
+struct FileParams {
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct BookmarksParams {
+    content: String,
+    container: Vec<String>,
+}
+
+fn params<T: DeserializeOwned>(value: Value) -> Result<T, RpcError> {
+    serde_json::from_value(value).map_err(|err| RpcError::new(INVALID_PARAMS, err))
+}
+
+fn store_error<E: ToString>(err: E) -> RpcError {
+    RpcError::new(STORE_ERROR, err)
+}
+
+fn to_json<T: serde::Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+// Returns the resources visible with `token`.
+fn resources_json(
+    token: Option<&AccessToken>,
+    resources: Vec<(ResourceId, ResourceMetadata)>,
+) -> Value {
+    to_json(
+        resources
+            .into_iter()
+            .filter(|(id, _)| visible(token, &Vec::<String>::from(id.clone())))
+            .map(|(id, meta)| (id.to_string(), meta))
+            .collect::<Vec<_>>(),
+    )
+}
+
+// Methods followed by content frames.
+fn has_content(method: &str) -> bool {
+    matches!(method, "createResource" | "addVariant" | "updateVariant")
+}
+
+async fn dispatch(store: &SharedStore, session: &Session, request: Request) -> RpcResult {
+    let mut store = store.lock().await;
+    let token = session
+        .authorize(&store, &request.method, &request.params)
+        .await?;
+    let token = token.as_ref();
+    match request.method.as_str() {
+        "deleteResource" => {
+            let p: PathParams = params(request.params)?;
+            store.delete_resource(&p.path).await.map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        "updateDesc" => {
+            let p: DescParams = params(request.params)?;
+            store
+                .update_desc(&p.path, &p.desc)
+                .await
+                .map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        "getMetadata" => {
+            let p: PathParams = params(request.params)?;
+            store
+                .get_metadata(&p.path)
+                .await
+                .map(to_json)
+                .map_err(store_error)
+        }
+        "bestVariant" => {
+            let p: BestVariantParams = params(request.params)?;
+            let accepted: Vec<&str> = p.accepted.iter().map(|a| a.as_str()).collect();
+            store
+                .best_variant(&p.path, &accepted, p.max_size)
+                .await
+                .map(|best| match best {
+                    Some((name, variant)) => json!({ "name": name, "variant": variant }),
+                    None => Value::Null,
+                })
+                .map_err(store_error)
+        }
+        "ls" => {
+            let dir = store.resources_dir().await.map_err(store_error)?;
+            let resources = store.ls(dir).await.map_err(store_error)?;
+            Ok(to_json(
+                resources
+                    .into_iter()
+                    .filter(|(name, _)| listable(token, &[name.clone()]))
+                    .collect::<Vec<_>>(),
+            ))
+        }
+        "search" => {
+            let p: SearchParams = params(request.params)?;
+            match p.variants {
+                Some(variants) => {
+                    let variants: Vec<&str> = variants.iter().map(|v| v.as_str()).collect();
+                    let results = store
+                        .search_variants(&p.text, &variants)
+                        .await
+                        .map_err(store_error)?;
+                    Ok(to_json(
+                        results
+                            .into_iter()
+                            .filter(|(id, _, _)| visible(token, &Vec::<String>::from(id.clone())))
+                            .map(|(id, meta, variants)| (id.to_string(), meta, variants))
+                            .collect::<Vec<_>>(),
+                    ))
+                }
+                None => match p.order {
+                    Some(order) => store.search_ordered(&p.text, order).await,
+                    None => store.search(&p.text).await,
+                }
+                .map(|resources| resources_json(token, resources))
+                .map_err(store_error),
+            }
+        }
+        "suggest" => {
+            let p: SuggestParams = params(request.params)?;
+            store
+                .suggest(&p.prefix, p.count)
+                .map(to_json)
+                .map_err(store_error)
+        }
+        "revision" => Ok(Value::from(store.revision())),
+        "changesSince" => {
+            let p: ChangesParams = params(request.params)?;
+            let mut filter = SyncFilter::default();
+            for container in &p.excluded {
+                filter.exclude(container);
+            }
+            let changes = store
+                .changes_since_filtered(p.cursor, &filter)
+                .await
+                .map_err(store_error)?;
+            Ok(to_json(
+                changes
+                    .into_iter()
+                    .filter(|change| visible(token, &change.path))
+                    .collect::<Vec<_>>(),
+            ))
+        }
+        "lsByTag" => {
+            let p: TagOnlyParams = params(request.params)?;
+            store
+                .ls_by_tag(&p.tag)
+                .await
+                .map(|resources| resources_json(token, resources))
+                .map_err(store_error)
+        }
+        "listSmartFolder" => {
+            let p: NameParams = params(request.params)?;
+            store
+                .list_smart_folder(&p.name)
+                .await
+                .map(|resources| resources_json(token, resources))
+                .map_err(store_error)
+        }
+        "recent" => {
+            let p: CountParams = params(request.params)?;
+            store
+                .recent(p.count)
+                .await
+                .map(|resources| resources_json(token, resources))
+                .map_err(store_error)
+        }
+        "suggested" => {
+            let p: CountParams = params(request.params)?;
+            store
+                .suggested(p.count)
+                .await
+                .map(|resources| resources_json(token, resources))
+                .map_err(store_error)
+        }
+        "addTag" => {
+            let p: TagParams = params(request.params)?;
+            store.add_tag(&p.path, &p.tag).await.map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        "removeTag" => {
+            let p: TagParams = params(request.params)?;
+            store
+                .remove_tag(&p.path, &p.tag)
+                .await
+                .map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        "deleteVariant" => {
+            let p: VariantParams = params(request.params)?;
+            store
+                .delete_variant(&p.path, &p.variant)
+                .await
+                .map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        "verifyVariant" => {
+            let p: VariantParams = params(request.params)?;
+            store
+                .verify_variant(&p.path, &p.variant)
+                .await
+                .map(Value::Bool)
+                .map_err(store_error)
+        }
+        "getProperties" => {
+            let p: VariantParams = params(request.params)?;
+            store
+                .get_properties(&p.path, &p.variant)
+                .map(to_json)
+                .map_err(store_error)
+        }
+        "importFile" => {
+            let p: FileParams = params(request.params)?;
+            store.import_file(&p.file).await.map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        "importBookmarks" => {
+            let p: BookmarksParams = params(request.params)?;
+            store
+                .import_bookmarks(&p.content, &p.container)
+                .await
+                .map(to_json)
+                .map_err(store_error)
+        }
+        "exportBookmarks" => store
+            .export_bookmarks()
+            .await
+            .map(Value::String)
+            .map_err(store_error),
+        "applyTagRules" => store
+            .apply_tag_rules()
+            .await
+            .map(|count| json!(count))
+            .map_err(store_error),
+        "share" => {
+            let p: ShareParams = params(request.params)?;
+            store
+                .share(&p.path, chrono::Duration::seconds(p.duration))
+                .await
+                .map(to_json)
+                .map_err(store_error)
+        }
+        "revokeShare" => {
+            let p: TokenParams = params(request.params)?;
+            store
+                .revoke_share(&p.token)
+                .await
+                .map(|revoked| json!(revoked))
+                .map_err(store_error)
+        }
+        "listShares" => store.shares().await.map(to_json).map_err(store_error),
+        "issueAccessToken" => {
+            let p: IssueTokenParams = params(request.params)?;
+            let (token, secret) = store
+                .issue_access_token(
+                    &p.name,
+                    p.scope,
+                    &p.containers,
+                    p.duration.map(chrono::Duration::seconds),
+                )
+                .await
+                .map_err(store_error)?;
+            Ok(json!({ "token": token, "secret": secret }))
+        }
+        "rotateAccessToken" => {
+            let p: TokenIdParams = params(request.params)?;
+            store
+                .rotate_access_token(&p.id)
+                .await
+                .map(Value::String)
+                .map_err(store_error)
+        }
+        "revokeAccessToken" => {
+            let p: TokenIdParams = params(request.params)?;
+            store
+                .revoke_access_token(&p.id)
+                .await
+                .map(|revoked| json!(revoked))
+                .map_err(store_error)
+        }
+        "listAccessTokens" => store
+            .access_tokens()
+            .await
+            .map(to_json)
+            .map_err(store_error),
+        "metrics" => Ok(Value::String(docstore::metrics::render())),
+        "reindex" => {
+            store.reindex().await.map_err(store_error)?;
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method: {}", request.method),
+        )),
+    }
+}
+
+// Returns the path and variant name requested by `getVariant` or
+// `getSharedVariant`, checking the share token for the latter.
+async fn variant_target(
+    store: &ResourceStore,
+    value: Value,
+    shared: bool,
+) -> Result<(Vec<String>, String), RpcError> {
+    if shared {
+        let p: SharedVariantParams = params(value)?;
+        let token = store.check_share(&p.token).await.map_err(store_error)?;
+        Ok((token.path, p.variant))
+    } else {
+        let p: VariantParams = params(value)?;
+        Ok((p.path, p.variant))
+    }
+}
+
+// Numbers the spooled uploads.
+static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+// Adds the content frames following an authorized request to the store.
+// The content of `addVariant` is written to the store as it arrives, while
+// the content of `createResource` and `updateVariant` is spooled to
+// `uploads` first, since the store seeks it to validate and index it. The
+// store is only locked while spooling once the content is complete, but
+// stays locked while the content of `addVariant` arrives.
+async fn upload<S: AsyncRead + Unpin>(
+    store: &SharedStore,
+    stream: &mut S,
+    uploads: &Path,
+    request: Request,
+) -> std::io::Result<RpcResult> {
+    if request.method == "addVariant" {
+        let p: VariantContentParams = match params(request.params) {
+            Ok(p) => p,
+            Err(err) => {
+                skip_content(stream).await?;
+                return Ok(Err(err));
+            }
+        };
+        let mut store = store.lock().await;
+        // The size is set once the content is complete.
+        let meta = VariantMetadata::new(0, &p.mime_type);
+        let mut writer = store.add_variant_writer(&p.path, &p.variant, &meta);
+        let result = match copy_content(stream, &mut writer, MAX_CONTENT_SIZE).await? {
+            Ok(_) => writer.close().await,
+            Err(err) => Err(err),
+        };
+        return Ok(result.map(|_| Value::Null).map_err(store_error));
+    }
+
+    let spool = uploads.join(format!(
+        "{}.upload",
+        NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = spool_content(stream, &spool).await?;
+    let result = match result {
+        Ok(size) => add_spooled(store, &spool, size, request).await,
+        Err(err) => Err(store_error(err)),
+    };
+    let _ = fs::remove_file(&spool).await;
+    Ok(result)
+}
+
+// Writes the content frames to `spool`, see `copy_content()` for the errors.
+async fn spool_content<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    spool: &Path,
+) -> std::io::Result<std::io::Result<u64>> {
+    let file = match fs::File::create(spool).await {
+        Ok(file) => file,
+        Err(err) => {
+            skip_content(stream).await?;
+            return Ok(Err(err));
+        }
+    };
+    let mut file = file.compat_write();
+    let size = match copy_content(stream, &mut file, MAX_CONTENT_SIZE).await? {
+        Ok(size) => size,
+        Err(err) => return Ok(Err(err)),
+    };
+    Ok(file.flush().await.map(|_| size))
+}
+
+// Creates a resource or updates a variant with spooled content.
+async fn add_spooled(store: &SharedStore, spool: &Path, size: u64, request: Request) -> RpcResult {
+    let content = fs::File::open(spool).await.map_err(store_error)?;
+    let mut store = store.lock().await;
+    if request.method == "createResource" {
+        let p: CreateParams = params(request.params)?;
+        let variant = VariantMetadata::new(size, &p.mime_type);
+        store
+            .create_resource_tokio(&p.path, &p.desc, &variant, p.tags, content)
+            .await
+            .map_err(store_error)?;
+    } else {
+        let p: VariantContentParams = params(request.params)?;
+        let meta = VariantMetadata::new(size, &p.mime_type);
+        store
+            .update_variant_tokio(&p.path, &p.variant, &meta, content)
+            .await
+            .map_err(store_error)?;
+    }
+    Ok(Value::Null)
+}
+
+// Streams a variant content after a successful response.
+async fn get_variant<W: AsyncWrite + Unpin>(
+    store: &SharedStore,
+    session: &Session,
+    writer: &mut W,
+    id: Value,
+    params: Value,
+    shared: bool,
+) -> std::io::Result<()> {
+    let store = store.lock().await;
+    // Share tokens are checked instead.
+    if !shared {
+        if let Err(err) = session.authorize(&store, "getVariant", &params).await {
+            return write_response(writer, &Response::new(id, Err(err))).await;
+        }
+    }
+    let (path, variant) = match variant_target(&store, params, shared).await {
+        Ok(target) => target,
+        Err(err) => return write_response(writer, &Response::new(id, Err(err))).await,
+    };
+
+    let mut stream = match store.get_variant(&variant, &path).await {
+        Ok(stream) => stream,
+        Err(err) => return write_response(writer, &Response::new(id, Err(store_error(err)))).await,
+    };
+
+    write_response(writer, &Response::new(id, Ok(Value::Null))).await?;
+    while let Some(chunk) = stream.next().await {
+        let chunk =
+            chunk.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        if !chunk.is_empty() {
+            write_frame(writer, &chunk).await?;
+        }
+    }
+    write_frame(writer, &[]).await
+}
+
+/// Serves requests from a client until it disconnects. Uploaded content
+/// is spooled to the `uploads` directory.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    store: SharedStore,
+    mut stream: S,
+    mut session: Session,
+    uploads: Rc<Path>,
+) -> std::io::Result<()> {
+    while let Some(frame) = read_frame(&mut stream, session.max_frame_size()).await? {
+        let request: Request = match serde_json::from_slice(&frame) {
+            Ok(request) => request,
+            Err(err) => {
+                // We can't know if content frames follow, so give up on this client.
+                let err = RpcError::new(PARSE_ERROR, err);
+                write_response(&mut stream, &Response::new(Value::Null, Err(err))).await?;
+                return Ok(());
+            }
+        };
+        debug!("{} request", request.method);
+
+        if has_content(&request.method) {
+            // Check the request before accepting its content, so that
+            // clients can't make us store content they may not upload.
+            let authorized = session
+                .authorize(&*store.lock().await, &request.method, &request.params)
+                .await;
+            let id = request.id.clone();
+            let result = match authorized {
+                Ok(_) if request.jsonrpc == "2.0" => {
+                    upload(&store, &mut stream, &uploads, request).await?
+                }
+                Ok(_) => {
+                    skip_content(&mut stream).await?;
+                    Err(RpcError::new(
+                        INVALID_REQUEST,
+                        "Unsupported JSON-RPC version",
+                    ))
+                }
+                Err(err) => {
+                    skip_content(&mut stream).await?;
+                    Err(err)
+                }
+            };
+            if let Err(err) = &result {
+                error!("Request failed: {}", err.message);
+            }
+            write_response(&mut stream, &Response::new(id, result)).await?;
+            continue;
+        }
+
+        if request.jsonrpc != "2.0" {
+            let err = RpcError::new(INVALID_REQUEST, "Unsupported JSON-RPC version");
+            write_response(&mut stream, &Response::new(request.id, Err(err))).await?;
+            continue;
+        }
+
+        if request.method == "authenticate" {
+            let result = session
+                .authenticate(&*store.lock().await, request.params)
+                .await;
+            write_response(&mut stream, &Response::new(request.id, result)).await?;
+            continue;
+        }
+
+        if request.method == "getVariant" || request.method == "getSharedVariant" {
+            let shared = request.method == "getSharedVariant";
+            get_variant(
+                &store,
+                &session,
+                &mut stream,
+                request.id,
+                request.params,
+                shared,
+            )
+            .await?;
+            continue;
+        }
+
+        let id = request.id.clone();
+        let result = dispatch(&store, &session, request).await;
+        if let Err(err) = &result {
+            error!("Request failed: {}", err.message);
+        }
+        write_response(&mut stream, &Response::new(id, result)).await?;
+    }
+
+    Ok(())
+}
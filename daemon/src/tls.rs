@@ -0,0 +1,40 @@
+//! TLS for the remote clients.
+//!
+//! Setting `DOCSTORED_TLS_CERT` and `DOCSTORED_TLS_KEY` to the paths of a
+//! PEM certificate chain and private key makes remote clients connect over
+//! TLS. Without them, remote clients are only accepted on loopback
+//! addresses, eg. behind a TLS proxy, since access token secrets would
+//! otherwise be sent in cleartext.
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::Arc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Returns the TLS acceptor configured by the environment, if any.
+pub fn acceptor() -> std::io::Result<Option<TlsAcceptor>> {
+    let (cert_path, key_path) = match (
+        std::env::var("DOCSTORED_TLS_CERT"),
+        std::env::var("DOCSTORED_TLS_KEY"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+        (Err(_), Err(_)) => return Ok(None),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "DOCSTORED_TLS_CERT and DOCSTORED_TLS_KEY must be set together",
+            ))
+        }
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&key_path)?))?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No key in {}", key_path)))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
@@ -1,73 +1,545 @@
 use core::future;
 use docstore::{
-    resource::ResourceMetadata,
-    store::{ResourceStore, StoreError},
+    changes::ResourceDiff,
+    health::CompactOptions,
+    resource::{ResourceMetadata, ResourceSummary, VariantMetadata},
+    store::{ConflictPolicy, ResourceStore, StoreError},
 };
 use futures::TryStreamExt;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
 use std::time::Instant;
 
-fn print_resource_details(id: &str, meta: &ResourceMetadata) {
-    let mut size = 0;
-    let variants = meta.variants();
-    for variant_meta in variants.values() {
-        size += variant_meta.size();
-    }
+// Kept out of the store directory.
+const HISTORY_FILE: &str = "./.docstore_history";
+const MAX_COMPLETIONS: u32 = 1000;
+
+fn resource_size(meta: &ResourceMetadata) -> u64 {
+    meta.variants().values().map(|variant| variant.size()).sum()
+}
+
+// Variants written before stored sizes were recorded count for their size.
+fn stored_size(variant: &VariantMetadata) -> u64 {
+    variant.stored_size().unwrap_or(variant.size())
+}
+
+fn resource_stored_size(meta: &ResourceMetadata) -> u64 {
+    meta.variants().values().map(stored_size).sum()
+}
 
-    let mut out = format!("{} - {}b ", id, size);
-    for (name, variant_meta) in variants {
+fn print_resource_details(id: &str, meta: &ResourceMetadata) {
+    let mut out = format!(
+        "{} - {}b ({}b stored) ",
+        id,
+        resource_size(meta),
+        resource_stored_size(meta)
+    );
+    for (name, variant_meta) in meta.variants() {
         out.push_str(&format!(
-            "[{}: {} {}b] ",
+            "[{}: {} {}b, {}b stored] ",
             name,
             variant_meta.mime_type(),
-            variant_meta.size()
+            variant_meta.size(),
+            stored_size(variant_meta)
         ));
     }
 
     println!("{}", out);
 }
 
+/// A resource as printed with `--json`.
+#[derive(Serialize)]
+struct JsonResource<'a> {
+    id: &'a str,
+    size: u64,
+    stored_size: u64,
+    #[serde(flatten)]
+    metadata: &'a ResourceMetadata,
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("Failed to serialize the output: {}", err),
+    }
+}
+
+fn print_resources(json: bool, header: &str, resources: &[(String, ResourceMetadata)]) {
+    if json {
+        let resources: Vec<JsonResource> = resources
+            .iter()
+            .map(|(id, metadata)| JsonResource {
+                id,
+                size: resource_size(metadata),
+                stored_size: resource_stored_size(metadata),
+                metadata,
+            })
+            .collect();
+        print_json(&resources);
+    } else {
+        println!("{} {}:", resources.len(), header);
+        for (id, metadata) in resources {
+            print_resource_details(id, metadata);
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+struct Stats {
+    resources: usize,
+    variants: usize,
+    total_size: u64,
+    /// The space used in the block store by the content.
+    stored_size: u64,
+    /// The number of resources by mime type of their default variant.
+    mime_types: BTreeMap<String, usize>,
+}
+
+async fn stats(store: &ResourceStore) -> Result<Stats, StoreError> {
+    let paths: Vec<Vec<String>> = store
+        .ids_with_prefix("", u32::MAX)?
+        .into_iter()
+        .map(|id| id.into())
+        .collect();
+
+    let mut stats = Stats::default();
+    for meta in store.get_metadata_many(&paths).await? {
+        stats.resources += 1;
+        stats.variants += meta.variants().len();
+        stats.total_size += resource_size(&meta);
+        stats.stored_size += resource_stored_size(&meta);
+        if let Some(default) = meta.get_variant("default") {
+            *stats.mime_types.entry(default.mime_type()).or_default() += 1;
+        }
+    }
+    Ok(stats)
+}
+
+// Parses a revision number, `HEAD` for the current revision or `HEAD~n`
+// for the revision n changes before it.
+fn parse_revision(arg: &str, head: u64) -> Option<u64> {
+    match arg.strip_prefix("HEAD") {
+        Some("") => Some(head),
+        Some(back) => head.checked_sub(back.strip_prefix('~')?.parse().ok()?),
+        None => arg.parse().ok(),
+    }
+}
+
+fn print_diff(json: bool, diffs: &[ResourceDiff]) {
+    if json {
+        return print_json(diffs);
+    }
+    for diff in diffs {
+        let mut out = format!("{:?} {}", diff.kind, diff.path.join("/"));
+        if !diff.variants.is_empty() {
+            let variants: Vec<&str> = diff.variants.iter().map(|name| name.as_str()).collect();
+            out.push_str(&format!(" [{}]", variants.join(", ")));
+        }
+        if diff.metadata_changed {
+            out.push_str(" (metadata)");
+        }
+        println!("{}", out);
+    }
+}
+
+async fn print_variant(store: &ResourceStore, path: &[String]) -> Result<(), StoreError> {
+    let stream = store.get_variant("default", path).await?;
+    stream
+        .try_for_each(|chunk| {
+            print!("{}", String::from_utf8_lossy(&chunk));
+            future::ready(Ok(()))
+        })
+        .await?;
+
+    println!();
+    Ok(())
+}
+
+/// The state of the interactive shell, also used by rustyline to complete
+/// resource names from the index.
+struct Shell {
+    store: ResourceStore,
+    // The current container, empty for the top level.
+    cwd: Vec<String>,
+}
+
+impl Shell {
+    // The id prefix of the resources in the current container.
+    fn base(&self) -> String {
+        if self.cwd.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.cwd.join("/"))
+        }
+    }
+
+    fn resolve(&self, arg: &str) -> Vec<String> {
+        let mut path = if arg.starts_with('/') {
+            vec![]
+        } else {
+            self.cwd.clone()
+        };
+        for component in arg.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    path.pop();
+                }
+                _ => path.push(component.to_owned()),
+            }
+        }
+        path
+    }
+
+    // Returns the children of the current container whose name starts with
+    // `prefix`, which can include a relative container path. Containers end
+    // with a '/'.
+    fn children(&self, prefix: &str, count: u32) -> Result<Vec<String>, StoreError> {
+        let base = self.base();
+        let ids = self
+            .store
+            .ids_with_prefix(&format!("{}{}", base, prefix), count)?;
+
+        let mut result: Vec<String> = vec![];
+        for id in ids {
+            let id = id.to_string();
+            let relative = &id[base.len()..];
+            let child = match relative[prefix.len()..].find('/') {
+                Some(pos) => &relative[..prefix.len() + pos + 1],
+                None => relative,
+            };
+            // Ids are sorted, so the resources of a container are contiguous.
+            if result.last().map(|last| last.as_str()) != Some(child) {
+                result.push(child.to_owned());
+            }
+        }
+        Ok(result)
+    }
+
+    async fn run(&mut self, command: &str, arg: Option<&str>) -> Result<(), StoreError> {
+        match (command, arg) {
+            ("ls", _) => {
+                // Listed from the index, without loading the metadata.
+                let summaries: BTreeMap<String, ResourceSummary> = self
+                    .store
+                    .ls_summaries(&self.cwd)?
+                    .into_iter()
+                    .map(|summary| (summary.name.clone(), summary))
+                    .collect();
+                for child in self.children("", u32::MAX)? {
+                    match summaries.get(&child) {
+                        Some(summary) => {
+                            println!("{} - {} {}b", child, summary.mime_type, summary.size)
+                        }
+                        None => println!("{}", child),
+                    }
+                }
+            }
+            ("stat", Some(name)) => {
+                print_resource_details(name, &self.store.get_metadata(&self.resolve(name)).await?)
+            }
+            ("cd", arg) => {
+                let path = self.resolve(arg.unwrap_or("/"));
+                let prefix = format!("{}/", path.join("/"));
+                if path.is_empty() || !self.store.ids_with_prefix(&prefix, 1)?.is_empty() {
+                    self.cwd = path;
+                } else {
+                    println!("No such container: {}", prefix);
+                }
+            }
+            ("get", Some(name)) => print_variant(&self.store, &self.resolve(name)).await?,
+            ("put", Some(file_name)) => {
+                let file = tokio::fs::File::open(file_name).await?;
+                let size = file.metadata().await?.len();
+                let mime = mime_guess::from_path(file_name).first_or_octet_stream();
+                let name = Path::new(file_name)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| file_name.to_owned());
+                self.store
+                    .create_resource_tokio(
+                        &self.resolve(&name),
+                        file_name,
+                        &VariantMetadata::new(size, mime.as_ref()),
+                        HashSet::new(),
+                        file,
+                    )
+                    .await?;
+            }
+            ("search", Some(text)) => {
+                for (id, meta) in self.store.search(text).await? {
+                    print_resource_details(&id.to_string(), &meta);
+                }
+            }
+            ("help", _) => {
+                println!(
+                    "ls | cd <container> | stat <name> | get <name> | put <file> | search <text> | exit"
+                )
+            }
+            _ => println!("Unknown command, try `help`"),
+        }
+        Ok(())
+    }
+}
+
+impl Completer for Shell {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        // Only the argument of `cd`, `get` and `stat` is a resource name.
+        let line = &line[..pos];
+        match line.split_once(' ') {
+            Some(("cd" | "get" | "stat", arg)) => {
+                let candidates = self.children(arg, MAX_COMPLETIONS).unwrap_or_default();
+                Ok((pos - arg.len(), candidates))
+            }
+            _ => Ok((pos, vec![])),
+        }
+    }
+}
+
+impl Hinter for Shell {
+    type Hint = String;
+}
+
+impl Highlighter for Shell {}
+
+impl Validator for Shell {}
+
+impl Helper for Shell {}
+
+async fn run_shell(store: ResourceStore) -> Result<(), StoreError> {
+    let mut editor: Editor<Shell, DefaultHistory> =
+        Editor::new().map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+    editor.set_helper(Some(Shell { store, cwd: vec![] }));
+    // No history yet on the first run.
+    let _ = editor.load_history(HISTORY_FILE);
+
+    loop {
+        let prompt = match editor.helper() {
+            Some(shell) => format!("/{}> ", shell.cwd.join("/")),
+            None => break,
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                println!("Error: {}", err);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        if line == "exit" {
+            break;
+        }
+
+        let (command, arg) = match line.split_once(' ') {
+            Some((command, arg)) => (command, Some(arg.trim())),
+            None => (line, None),
+        };
+        if let Some(shell) = editor.helper_mut() {
+            if let Err(err) = shell.run(command, arg).await {
+                println!("Error: {}", err);
+            }
+        }
+    }
+
+    if let Err(err) = editor.save_history(HISTORY_FILE) {
+        println!("Failed to save the history: {}", err);
+    }
+    Ok(())
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), StoreError> {
     env_logger::init();
     let mut doc_store = ResourceStore::new("./data").await?;
 
-    if let Some(arg) = std::env::args().nth(1) {
+    // --json switches ls, search, stats, variants and diff to machine readable output.
+    let json = std::env::args().any(|arg| arg == "--json");
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| arg != "--json")
+        .collect();
+
+    if let Some(arg) = args.first() {
         let start = Instant::now();
         if arg == "put" {
-            if let Some(file_name) = std::env::args().nth(2) {
+            let args = &args[1..];
+            if args.last().map(|arg| arg.as_str()) == Some("-") {
+                // Read the content from stdin: put --name <name> [--mime <mime>] -
+                let option = |name: &str| {
+                    args.iter()
+                        .position(|arg| arg == name)
+                        .and_then(|index| args.get(index + 1))
+                        .filter(|value| *value != "-")
+                };
+                match option("--name") {
+                    Some(name) => {
+                        println!("Will store stdin as {}", name);
+                        doc_store
+                            .create_resource_from_reader(
+                                name,
+                                option("--mime").map(|mime| mime.as_str()),
+                                None,
+                                tokio::io::stdin(),
+                            )
+                            .await?;
+                        println!("File stored successfully!");
+                    }
+                    None => println!("--name is required when reading from stdin"),
+                }
+            } else if let Some(file_name) = args.first() {
                 println!("Will store {}", file_name);
-                doc_store.import_file(&file_name).await?;
+                doc_store.import_file(file_name).await?;
                 println!("File stored successfully!");
             }
         } else if arg == "ls" {
             let files = doc_store.ls(doc_store.resources_dir().await?).await?;
-            println!("{} files:", files.len());
-            for file in files {
-                print_resource_details(&file.0, &file.1);
-            }
+            print_resources(json, "files", &files);
         } else if arg == "get" {
-            if let Some(file_name) = std::env::args().nth(2) {
-                let stream = doc_store.get_variant("default", &[file_name]).await?;
-                stream
-                    .try_for_each(|chunk| {
-                        print!("{}", String::from_utf8_lossy(&chunk));
-                        future::ready(Ok(()))
-                    })
+            if let Some(file_name) = args.get(1) {
+                print_variant(&doc_store, &[file_name.clone()]).await?;
+            }
+        } else if arg == "diff" {
+            let head = doc_store.root_revision().await?;
+            let revisions = (
+                args.get(1).and_then(|arg| parse_revision(arg, head)),
+                args.get(2).and_then(|arg| parse_revision(arg, head)),
+            );
+            match revisions {
+                (Some(from), Some(to)) => print_diff(json, &doc_store.diff(from, to).await?),
+                _ => println!("Usage: diff <from> <to>, eg. diff HEAD~1 HEAD"),
+            }
+        } else if arg == "photos" {
+            if let Some(folder) = args.get(1) {
+                let container: Vec<String> = args
+                    .get(2)
+                    .map(|container| container.split('/').map(|name| name.to_owned()).collect())
+                    .unwrap_or_default();
+                let actions = doc_store
+                    .import_photo_folder(folder, &container, ConflictPolicy::SkipIfIdentical)
                     .await?;
-
-                println!();
+                for (path, action) in actions {
+                    println!("{}: {:?}", path.join("/"), action);
+                }
             }
+        } else if arg == "migrate" {
+            if let Some(dest_dir) = args.get(1) {
+                let remove_source = args.get(2).map(|arg| arg.as_str()) == Some("--remove-source");
+                doc_store.migrate(dest_dir, remove_source).await?;
+                println!("Store migrated to {}", dest_dir);
+            }
+        } else if arg == "shell" {
+            return run_shell(doc_store).await;
         } else if arg == "search" {
-            if let Some(text) = std::env::args().nth(2) {
-                let files = doc_store.search(&text).await?;
-
-                println!("{} search results:", files.len(),);
-                for file in files {
-                    print_resource_details(&file.0.to_string(), &file.1);
+            if let Some(text) = args.get(1) {
+                let files: Vec<(String, ResourceMetadata)> = doc_store
+                    .search(text)
+                    .await?
+                    .into_iter()
+                    .map(|(id, meta)| (id.to_string(), meta))
+                    .collect();
+                print_resources(json, "search results", &files);
+            }
+        } else if arg == "stats" {
+            let stats = stats(&doc_store).await?;
+            if json {
+                print_json(&stats);
+            } else {
+                println!(
+                    "{} resources, {} variants, {}b ({}b stored)",
+                    stats.resources, stats.variants, stats.total_size, stats.stored_size
+                );
+                for (mime_type, count) in &stats.mime_types {
+                    println!("{}: {}", mime_type, count);
                 }
             }
+        } else if arg == "analyze" {
+            let report = doc_store.analyze().await?;
+            if json {
+                print_json(&report);
+            } else {
+                println!(
+                    "{} blocks ({}b), {} unreachable",
+                    report.blocks,
+                    report.block_bytes,
+                    report.unreachable_blocks()
+                );
+                println!(
+                    "{} resources, {} indexed: {} missing from the index, {} stale",
+                    report.forest_resources,
+                    report.indexed_resources,
+                    report.missing_from_index.len(),
+                    report.stale_in_index.len()
+                );
+                println!(
+                    "Variants: {}b default, {}b others",
+                    report.default_variant_bytes, report.other_variant_bytes
+                );
+                println!(
+                    "Index: {}b, {}b free",
+                    report.index_bytes, report.index_free_bytes
+                );
+                println!("Largest resources:");
+                for (path, size) in &report.largest_resources {
+                    println!("{} - {}b", path.join("/"), size);
+                }
+            }
+        } else if arg == "compact" {
+            let options = CompactOptions {
+                prune_history: args.get(1).map(|arg| arg.as_str()) == Some("--prune-history"),
+            };
+            let report = doc_store.compact(options).await?;
+            if json {
+                print_json(&report);
+            } else {
+                println!(
+                    "Removed {} blocks ({}b), freed {}b of the index",
+                    report.removed_blocks, report.removed_block_bytes, report.index_bytes_freed
+                );
+            }
+        } else if arg == "variants" {
+            if let Some(name) = args.get(1) {
+                let path: Vec<String> = name.split('/').map(|s| s.to_owned()).collect();
+                let meta = doc_store.get_metadata(&path).await?;
+                if json {
+                    print_json(meta.variants());
+                } else {
+                    for (name, variant) in meta.variants() {
+                        println!(
+                            "{}: {} {}b {}",
+                            name,
+                            variant.mime_type(),
+                            variant.size(),
+                            variant.hash().unwrap_or("-")
+                        );
+                    }
+                }
+            }
+        }
+        // Keep the json output parseable.
+        if !json {
+            println!("Done in {}ms", start.elapsed().as_millis());
         }
-        println!("Done in {}ms", start.elapsed().as_millis());
     }
 
     Ok(())
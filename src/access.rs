@@ -0,0 +1,123 @@
+//! Access tokens
+//! An access token lets a remote client use the store within a scope, and
+//! optionally only under some containers. Tokens are issued with
+//! `ResourceStore::issue_access_token()`, which returns the secret to give
+//! to the client: only its blake3 hash is recorded in the settings
+//! document, so a lost secret can't be recovered, only rotated with
+//! `ResourceStore::rotate_access_token()`.
+//!
+//! Secrets are made of the token id and a random part, so that checking
+//! them doesn't require hashing against every token.
+
+use crate::settings::Settings;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What a token allows, from the least to the most powerful.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Searching, with the metadata of the results.
+    SearchOnly,
+    /// Reading resources, their metadata and the changes.
+    ReadOnly,
+    /// Reading and changing resources.
+    ReadWrite,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AccessToken {
+    pub id: String,
+    /// A name to recognize the client, eg. the device using the token.
+    pub name: String,
+    pub scope: Scope,
+    /// The containers the token gives access to, or all the resources
+    /// if empty.
+    pub containers: Vec<Vec<String>>,
+    /// The expiration time, in seconds since the Unix epoch, if any.
+    pub expires: Option<i64>,
+    // The hex encoded blake3 hash of the secret.
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub(crate) hash: String,
+}
+
+impl AccessToken {
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires.map(|expires| {
+            Utc.timestamp_opt(expires, 0)
+                .single()
+                .unwrap_or(DateTime::<Utc>::MIN_UTC)
+        })
+    }
+
+    /// Whether the token is expired at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now.timestamp())
+    }
+
+    /// Whether the token allows operations requiring `scope`.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scope >= scope
+    }
+
+    /// Whether the token gives access to all the resources.
+    pub fn is_unrestricted(&self) -> bool {
+        self.containers.is_empty()
+    }
+
+    /// Whether the token gives access to the resource or container at
+    /// `path`.
+    pub fn allows_path(&self, path: &[String]) -> bool {
+        self.is_unrestricted()
+            || self
+                .containers
+                .iter()
+                .any(|container| path.starts_with(container))
+    }
+
+    // Whether `secret` is the secret of this token.
+    pub(crate) fn matches(&self, secret: &str) -> bool {
+        // The comparison of blake3 hashes runs in constant time.
+        blake3::Hash::from_hex(&self.hash)
+            .map(|hash| hash == blake3::hash(secret.as_bytes()))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn set_secret(&mut self, secret: &str) {
+        self.hash = blake3::hash(secret.as_bytes()).to_hex().to_string();
+    }
+
+    /// Returns the token without its secret hash, to be listed.
+    pub(crate) fn public(&self) -> Self {
+        Self {
+            hash: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Returns the id of the token a secret belongs to.
+pub(crate) fn token_id(secret: &str) -> Option<&str> {
+    secret.split_once('.').map(|(id, _)| id)
+}
+
+/// The settings section holding the access tokens, by id.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct AccessTokens {
+    pub tokens: BTreeMap<String, AccessToken>,
+}
+
+impl AccessTokens {
+    /// Removes the expired tokens, returning whether some were removed.
+    pub(crate) fn purge_expired(&mut self, now: DateTime<Utc>) -> bool {
+        let count = self.tokens.len();
+        self.tokens.retain(|_, token| !token.is_expired(now));
+        count != self.tokens.len()
+    }
+}
+
+impl Settings for AccessTokens {
+    const NAME: &'static str = "docstore.access_tokens";
+    const VERSION: u32 = 1;
+}
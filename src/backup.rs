@@ -0,0 +1,239 @@
+//! Portable encrypted backups of a store.
+//! A backup is a zip archive holding the block store, the forest cid, and
+//! the access key and index database sealed with a key derived from a
+//! passphrase. Blocks don't need another layer of encryption since wnfs
+//! already encrypts private nodes and content.
+//! The archive is read and written with blocking I/O, on a blocking thread
+//! of the runtime.
+
+use argon2::Argon2;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use log::debug;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    #[error("Zip error")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("serde_cbor error")]
+    SerdeCBOR(#[from] serde_cbor::Error),
+    #[error("Key derivation error: {0}")]
+    KeyDerivation(String),
+    #[error("Invalid passphrase or corrupted backup")]
+    InvalidPassphrase,
+    #[error("Unsupported backup version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Invalid backup archive: missing {0}")]
+    MissingEntry(&'static str),
+    #[error("A store already exists in {0}")]
+    StoreExists(String),
+    #[error("Backup task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+const BACKUP_VERSION: u32 = 1;
+
+const HEADER_ENTRY: &str = "backup.header";
+const FOREST_CID_ENTRY: &str = "forest.cid";
+const ACCESS_KEY_ENTRY: &str = "access.key.sealed";
+const INDEX_ENTRY: &str = "index.sqlite.sealed";
+const BLOCKSTORE_PREFIX: &str = "blockstore/";
+
+const SALT_SIZE: usize = 16;
+
+#[derive(Deserialize, Serialize)]
+struct BackupHeader {
+    version: u32,
+    salt: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Sealed {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305, BackupError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| BackupError::KeyDerivation(e.to_string()))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+fn seal(cipher: &XChaCha20Poly1305, plaintext: &[u8]) -> Result<Vec<u8>, BackupError> {
+    let mut nonce = [0u8; 24];
+    thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| BackupError::InvalidPassphrase)?;
+    Ok(serde_cbor::to_vec(&Sealed {
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })?)
+}
+
+fn open(cipher: &XChaCha20Poly1305, sealed: &[u8]) -> Result<Vec<u8>, BackupError> {
+    let sealed: Sealed = serde_cbor::from_slice(sealed)?;
+    if sealed.nonce.len() != 24 {
+        return Err(BackupError::InvalidPassphrase);
+    }
+    cipher
+        .decrypt(
+            XNonce::from_slice(&sealed.nonce),
+            sealed.ciphertext.as_slice(),
+        )
+        .map_err(|_| BackupError::InvalidPassphrase)
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &'static str) -> Result<Vec<u8>, BackupError> {
+    let mut entry = archive.by_name(name).map_err(|e| match e {
+        zip::result::ZipError::FileNotFound => BackupError::MissingEntry(name),
+        _ => e.into(),
+    })?;
+    let mut buffer = vec![];
+    entry.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Writes a backup of the store state located in `root_dir` and of the
+/// blocks of `blockstore` to `dest`.
+pub(crate) async fn write_backup(
+    root_dir: &Path,
+    blockstore: &Path,
+    dest: &Path,
+    passphrase: &str,
+) -> Result<(), BackupError> {
+    let (root_dir, blockstore, dest, passphrase) = (
+        root_dir.to_path_buf(),
+        blockstore.to_path_buf(),
+        dest.to_path_buf(),
+        passphrase.to_owned(),
+    );
+    tokio::task::spawn_blocking(move || {
+        write_backup_blocking(&root_dir, &blockstore, &dest, &passphrase)
+    })
+    .await?
+}
+
+fn write_backup_blocking(
+    root_dir: &Path,
+    blockstore: &Path,
+    dest: &Path,
+    passphrase: &str,
+) -> Result<(), BackupError> {
+    let mut salt = vec![0u8; SALT_SIZE];
+    thread_rng().fill_bytes(&mut salt);
+    let cipher = derive_cipher(passphrase, &salt)?;
+
+    // Blocks are already encrypted and don't compress well.
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(File::create(dest)?);
+
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        salt,
+    };
+    zip.start_file(HEADER_ENTRY, options)?;
+    zip.write_all(&serde_cbor::to_vec(&header)?)?;
+
+    zip.start_file(FOREST_CID_ENTRY, options)?;
+    zip.write_all(&fs::read(root_dir.join("forest.cid"))?)?;
+
+    let access_key = fs::read(root_dir.join("access.key"))?;
+    zip.start_file(ACCESS_KEY_ENTRY, options)?;
+    zip.write_all(&seal(&cipher, &access_key)?)?;
+
+    let index = fs::read(root_dir.join("index.sqlite"))?;
+    zip.start_file(INDEX_ENTRY, options)?;
+    zip.write_all(&seal(&cipher, &index)?)?;
+
+    let mut count = 0;
+    for entry in fs::read_dir(blockstore)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        zip.start_file(format!("{}{}", BLOCKSTORE_PREFIX, name), options)?;
+        zip.write_all(&fs::read(entry.path())?)?;
+        count += 1;
+    }
+
+    zip.finish()?;
+    debug!("Backup of {} blocks written to {}", count, dest.display());
+
+    Ok(())
+}
+
+/// Restores the backup `archive` into `dest_dir`, which must not already
+/// hold a store.
+pub(crate) async fn read_backup(
+    archive: &Path,
+    passphrase: &str,
+    dest_dir: &Path,
+) -> Result<(), BackupError> {
+    let (archive, passphrase, dest_dir) = (
+        archive.to_path_buf(),
+        passphrase.to_owned(),
+        dest_dir.to_path_buf(),
+    );
+    tokio::task::spawn_blocking(move || read_backup_blocking(&archive, &passphrase, &dest_dir))
+        .await?
+}
+
+fn read_backup_blocking(
+    archive: &Path,
+    passphrase: &str,
+    dest_dir: &Path,
+) -> Result<(), BackupError> {
+    if dest_dir.join("forest.cid").exists() || dest_dir.join("access.key").exists() {
+        return Err(BackupError::StoreExists(dest_dir.display().to_string()));
+    }
+
+    let mut archive = ZipArchive::new(File::open(archive)?)?;
+
+    let header: BackupHeader = serde_cbor::from_slice(&read_entry(&mut archive, HEADER_ENTRY)?)?;
+    if header.version != BACKUP_VERSION {
+        return Err(BackupError::UnsupportedVersion(header.version));
+    }
+    let cipher = derive_cipher(passphrase, &header.salt)?;
+
+    // Check the passphrase before writing anything.
+    let access_key = open(&cipher, &read_entry(&mut archive, ACCESS_KEY_ENTRY)?)?;
+    let index = open(&cipher, &read_entry(&mut archive, INDEX_ENTRY)?)?;
+    let forest_cid = read_entry(&mut archive, FOREST_CID_ENTRY)?;
+
+    let blockstore = dest_dir.join("blockstore");
+    fs::create_dir_all(&blockstore)?;
+    for i in 0..archive.len() {
+        let (name, buffer) = {
+            let mut entry = archive.by_index(i)?;
+            // Only keep plain block files, ignoring unexpected paths.
+            let name = match entry.name().strip_prefix(BLOCKSTORE_PREFIX) {
+                Some(name) if !name.is_empty() && !name.contains(['/', '\\']) && name != ".." => {
+                    name.to_owned()
+                }
+                _ => continue,
+            };
+            let mut buffer = vec![];
+            entry.read_to_end(&mut buffer)?;
+            (name, buffer)
+        };
+        fs::write(blockstore.join(name), buffer)?;
+    }
+
+    fs::write(dest_dir.join("index.sqlite"), index)?;
+    fs::write(dest_dir.join("access.key"), access_key)?;
+    // Written last, since the store is considered initialized once it exists.
+    fs::write(dest_dir.join("forest.cid"), forest_cid)?;
+
+    Ok(())
+}
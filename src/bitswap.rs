@@ -0,0 +1,175 @@
+//! Fetching of missing blocks from peers with bitswap.
+//! `BitswapFetcher` runs a libp2p swarm speaking the bitswap protocol in a
+//! background task. It serves the blocks of the local store to the peers,
+//! and asks them for the blocks missing locally when they are first read,
+//! so that a second device materializes resources on demand instead of
+//! replicating the whole block store. Set it with
+//! `ResourceStoreBuilder::block_fetcher()`.
+//!
+//! Private blocks are encrypted, so peers only learn which blocks exist.
+
+use crate::block_fetcher::BlockFetcher;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use libipld::store::DefaultParams;
+use libipld::{Block, Cid};
+use libp2p::swarm::{SwarmBuilder, SwarmEvent};
+use libp2p::Swarm;
+use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, QueryId};
+use log::{debug, error};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+pub use libp2p::identity::Keypair;
+pub use libp2p::{Multiaddr, PeerId};
+
+type FetchRequest = (Cid, oneshot::Sender<Option<Bytes>>);
+
+fn io_error<E: ToString>(err: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+}
+
+// The blocks served to and received from the peers. Bitswap calls these
+// methods from a blocking thread, so the block files are read directly.
+struct BitswapBlocks {
+    dir: PathBuf,
+    // Blocks received for the pending requests, until they are handed to
+    // the store, which checks and writes them.
+    received: Arc<Mutex<HashMap<Cid, Vec<u8>>>>,
+}
+
+impl BitswapStore for BitswapBlocks {
+    type Params = DefaultParams;
+
+    fn contains(&mut self, cid: &Cid) -> libipld::Result<bool> {
+        Ok(self.dir.join(cid.to_string()).exists())
+    }
+
+    fn get(&mut self, cid: &Cid) -> libipld::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.dir.join(cid.to_string())) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn insert(&mut self, block: &Block<DefaultParams>) -> libipld::Result<()> {
+        self.received
+            .lock()
+            .unwrap()
+            .insert(*block.cid(), block.data().to_vec());
+        Ok(())
+    }
+
+    // Blocks are only fetched one by one with `Bitswap::get()`.
+    fn missing_blocks(&mut self, _cid: &Cid) -> libipld::Result<Vec<Cid>> {
+        Ok(vec![])
+    }
+}
+
+/// Fetches the blocks missing from a store from peers, over bitswap.
+pub struct BitswapFetcher {
+    requests: mpsc::UnboundedSender<FetchRequest>,
+    peer_id: PeerId,
+}
+
+impl BitswapFetcher {
+    /// Starts serving the blocks of the store in `root_dir` to the peers
+    /// connecting to `listen_addr`, and fetching the missing ones from
+    /// `peers`. Must be called from a tokio runtime, which runs the swarm
+    /// until the fetcher is dropped.
+    pub fn start<P: AsRef<Path>>(
+        keypair: Keypair,
+        root_dir: P,
+        listen_addr: Multiaddr,
+        peers: Vec<(PeerId, Multiaddr)>,
+    ) -> Result<Self, std::io::Error> {
+        let peer_id = PeerId::from(keypair.public());
+        let transport = libp2p::tokio_development_transport(keypair)?;
+
+        let received = Arc::new(Mutex::new(HashMap::new()));
+        let blocks = BitswapBlocks {
+            dir: root_dir.as_ref().join("blockstore"),
+            received: received.clone(),
+        };
+        let behaviour = Bitswap::new(
+            BitswapConfig::new(),
+            blocks,
+            Box::new(|task| {
+                tokio::spawn(task);
+            }),
+        );
+        let mut swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build();
+        swarm.listen_on(listen_addr).map_err(io_error)?;
+        for (peer, addr) in &peers {
+            swarm.behaviour_mut().add_address(peer, addr.clone());
+        }
+
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let peers = peers.into_iter().map(|(peer, _)| peer).collect();
+        tokio::spawn(run_swarm(swarm, peers, received, receiver));
+        Ok(Self { requests, peer_id })
+    }
+
+    /// The id of this device for its peers.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+}
+
+// Drives the swarm, starting a bitswap query for each fetch request and
+// replying once it completes.
+async fn run_swarm(
+    mut swarm: Swarm<Bitswap<DefaultParams>>,
+    peers: Vec<PeerId>,
+    received: Arc<Mutex<HashMap<Cid, Vec<u8>>>>,
+    mut requests: mpsc::UnboundedReceiver<FetchRequest>,
+) {
+    let mut pending: HashMap<QueryId, FetchRequest> = HashMap::new();
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let (cid, reply) = match request {
+                    Some(request) => request,
+                    // The fetcher was dropped.
+                    None => return,
+                };
+                let query = swarm.behaviour_mut().get(cid, peers.iter().copied());
+                pending.insert(query, (cid, reply));
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(BitswapEvent::Complete(query, result)) => {
+                    let (cid, reply) = match pending.remove(&query) {
+                        Some(request) => request,
+                        None => continue,
+                    };
+                    if let Err(err) = result {
+                        debug!("Failed to fetch block {} from the peers: {}", cid, err);
+                    }
+                    let block = received.lock().unwrap().remove(&cid);
+                    let _ = reply.send(block.map(Bytes::from));
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                    error!("Failed to connect to {:?}: {}", peer_id, error);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockFetcher for BitswapFetcher {
+    async fn fetch_block(&self, cid: &Cid) -> Result<Option<Bytes>, std::io::Error> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send((*cid, reply))
+            .map_err(|_| io_error("The bitswap swarm stopped"))?;
+        response
+            .await
+            .map_err(|_| io_error("The bitswap swarm stopped"))
+    }
+}
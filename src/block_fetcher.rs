@@ -0,0 +1,15 @@
+//! Fetching of blocks missing from the local block store.
+//! A fetcher lets a device lazily materialize resources by getting their
+//! blocks from elsewhere (eg. peers over a p2p network) when they are
+//! first read, instead of replicating the whole block store upfront.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use libipld::Cid;
+
+#[async_trait(?Send)]
+pub trait BlockFetcher {
+    /// Returns the content of the block, or `None` if it can't be found.
+    /// Fetched blocks are checked against their cid and stored locally.
+    async fn fetch_block(&self, cid: &Cid) -> Result<Option<Bytes>, std::io::Error>;
+}
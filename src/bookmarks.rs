@@ -0,0 +1,239 @@
+//! Browser bookmarks import and export.
+//! Supports the Netscape bookmarks HTML format used by browsers to export
+//! and import bookmarks, and importing OPML outlines.
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Bookmark {
+    pub url: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// The folders containing this bookmark, outermost first.
+    #[serde(skip)]
+    pub folders: Vec<String>,
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Returns the lower case name and the attributes of a tag, given the
+/// text between `<` and `>`.
+fn parse_tag(tag: &str) -> (String, Vec<(String, String)>) {
+    let tag = tag.trim();
+    let (name, mut rest) = match tag.find(char::is_whitespace) {
+        Some(pos) => (&tag[..pos], tag[pos..].trim_start()),
+        None => (tag, ""),
+    };
+
+    let mut attributes = vec![];
+    while let Some(eq) = rest.find('=') {
+        let attr_name = rest[..eq].trim().to_lowercase();
+        let value_start = rest[eq + 1..].trim_start();
+        let (value, remaining) = match value_start.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value_start[1..].find(quote) {
+                Some(end) => (&value_start[1..end + 1], &value_start[end + 2..]),
+                None => (&value_start[1..], ""),
+            },
+            _ => match value_start.find(char::is_whitespace) {
+                Some(end) => (&value_start[..end], &value_start[end..]),
+                None => (value_start, ""),
+            },
+        };
+        attributes.push((attr_name, unescape(value)));
+        rest = remaining.trim_start();
+    }
+
+    (name.to_lowercase(), attributes)
+}
+
+fn attribute(attributes: &[(String, String)], name: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|(attr_name, _)| attr_name == name)
+        .map(|(_, value)| value.clone())
+}
+
+/// Parses bookmarks in the Netscape bookmarks HTML format.
+pub fn parse_netscape(html: &str) -> Vec<Bookmark> {
+    let mut bookmarks = vec![];
+    // The folders opened by <DL> elements. The top level list has no name.
+    let mut folders: Vec<Option<String>> = vec![];
+    let mut pending_folder: Option<String> = None;
+    // The element whose text is being collected: a folder name or a link.
+    let mut current: Option<(String, Vec<(String, String)>)> = None;
+    let mut text = String::new();
+
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let (name, attributes) = parse_tag(&rest[start + 1..end]);
+        rest = &rest[end + 1..];
+
+        match name.as_str() {
+            "h3" | "a" => {
+                current = Some((name, attributes));
+                text.clear();
+            }
+            "/h3" => {
+                pending_folder = Some(unescape(text.trim()));
+                current = None;
+            }
+            "/a" => {
+                if let Some((_, attributes)) = current.take() {
+                    if let Some(url) = attribute(&attributes, "href") {
+                        bookmarks.push(Bookmark {
+                            url,
+                            title: unescape(text.trim()),
+                            icon: attribute(&attributes, "icon"),
+                            folders: folders.iter().flatten().cloned().collect(),
+                        });
+                    }
+                }
+            }
+            "dl" => folders.push(pending_folder.take()),
+            "/dl" => {
+                folders.pop();
+            }
+            _ => {}
+        }
+    }
+
+    bookmarks
+}
+
+/// Parses the outlines with an url of an OPML document. Nested outlines
+/// without url are used as folders.
+pub fn parse_opml(xml: &str) -> Result<Vec<Bookmark>, quick_xml::Error> {
+    let mut bookmarks = vec![];
+    let mut folders: Vec<Option<String>> = vec![];
+
+    let mut reader = Reader::from_str(xml);
+    loop {
+        let (element, is_empty) = match reader.read_event()? {
+            Event::Start(element) => (element, false),
+            Event::Empty(element) => (element, true),
+            Event::End(element) => {
+                if element.local_name().as_ref() == b"outline" {
+                    folders.pop();
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        if element.local_name().as_ref() != b"outline" {
+            continue;
+        }
+
+        let mut text = None;
+        let mut url = None;
+        for attr in element.attributes().flatten() {
+            let value = attr.unescape_value()?.to_string();
+            match attr.key.local_name().as_ref() {
+                b"title" => text = Some(value),
+                b"text" if text.is_none() => text = Some(value),
+                b"htmlUrl" => url = Some(value),
+                b"xmlUrl" | b"url" if url.is_none() => url = Some(value),
+                _ => {}
+            }
+        }
+
+        let is_folder = url.is_none();
+        if let Some(url) = url {
+            bookmarks.push(Bookmark {
+                title: text.clone().unwrap_or_else(|| url.clone()),
+                url,
+                icon: None,
+                folders: folders.iter().flatten().cloned().collect(),
+            });
+        }
+        // Outlines with children are closed by an End event.
+        if !is_empty {
+            folders.push(if is_folder { text } else { None });
+        }
+    }
+
+    Ok(bookmarks)
+}
+
+/// Returns whether this content looks like an OPML document.
+pub fn is_opml(content: &str) -> bool {
+    content.contains("<opml")
+}
+
+/// Exports bookmarks to the Netscape bookmarks HTML format. Bookmarks are
+/// grouped by their innermost folder.
+pub fn to_netscape(bookmarks: &[Bookmark]) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+<DL><p>
+"#,
+    );
+
+    let link = |bookmark: &Bookmark, indent: &str| {
+        let icon = bookmark
+            .icon
+            .as_ref()
+            .map(|icon| format!(r#" ICON="{}""#, escape(icon)))
+            .unwrap_or_default();
+        format!(
+            "{}<DT><A HREF=\"{}\"{}>{}</A>\n",
+            indent,
+            escape(&bookmark.url),
+            icon,
+            escape(&bookmark.title)
+        )
+    };
+
+    let mut folders: Vec<&str> = vec![];
+    for bookmark in bookmarks {
+        match bookmark.folders.last() {
+            Some(folder) => {
+                if !folders.contains(&folder.as_str()) {
+                    folders.push(folder);
+                }
+            }
+            None => html.push_str(&link(bookmark, "    ")),
+        }
+    }
+
+    for folder in folders {
+        html.push_str(&format!(
+            "    <DT><H3>{}</H3>\n    <DL><p>\n",
+            escape(folder)
+        ));
+        for bookmark in bookmarks
+            .iter()
+            .filter(|bookmark| bookmark.folders.last().map(|f| f.as_str()) == Some(folder))
+        {
+            html.push_str(&link(bookmark, "        "));
+        }
+        html.push_str("    </DL><p>\n");
+    }
+
+    html.push_str("</DL><p>\n");
+    html
+}
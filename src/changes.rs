@@ -0,0 +1,145 @@
+//! Change journal
+//! Every mutation of a resource is recorded in the private file system with
+//! a revision number increasing by one for each change. External processes
+//! keep the last revision they processed as a cursor, and catch up with
+//! `ResourceStore::changes_since()`, including after a restart.
+//! The journal is split in segments of `SEGMENT_SIZE` changes, stored in the
+//! hidden `.changes` directory, so that recording a change only rewrites the
+//! last segment.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub(crate) const CHANGES_DIR: &str = ".changes";
+pub(crate) const SEGMENT_SIZE: u64 = 1024;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ChangeOp {
+    CreateResource,
+    DeleteResource,
+    /// The variant name.
+    AddVariant(String),
+    UpdateVariant(String),
+    DeleteVariant(String),
+    UpdateDescription,
+    /// The tag.
+    AddTag(String),
+    RemoveTag(String),
+    /// The kind and target of the relation.
+    AddRelation(String, Vec<String>),
+    RemoveRelation(String, Vec<String>),
+    /// The smart folder name.
+    EnterSmartFolder(String),
+    LeaveSmartFolder(String),
+    /// The description or tags of the container at the change path.
+    UpdateContainer,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Change {
+    /// Starts at 1 and increases by one with each change.
+    pub revision: u64,
+    pub op: ChangeOp,
+    /// The path of the changed resource.
+    pub path: Vec<String>,
+    /// The time of the change, in seconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+/// Returns the segment holding this revision.
+pub(crate) fn segment_of(revision: u64) -> u64 {
+    (revision.max(1) - 1) / SEGMENT_SIZE
+}
+
+/// Segment files are named after their index, padded so that they sort
+/// in order.
+pub(crate) fn segment_name(segment: u64) -> String {
+    format!("{:016x}", segment)
+}
+
+pub(crate) fn parse_segment_name(name: &str) -> Option<u64> {
+    u64::from_str_radix(name, 16).ok()
+}
+
+/// How a resource differs between two revisions.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum DiffKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// A resource that differs between two revisions, see
+/// `ResourceStore::diff()`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResourceDiff {
+    pub path: Vec<String>,
+    pub kind: DiffKind,
+    /// The variants added, updated or deleted, by name.
+    pub variants: BTreeSet<String>,
+    /// Whether the description, the tags or the relations changed.
+    pub metadata_changed: bool,
+}
+
+/// Folds the changes made between two revisions, in order, into the net
+/// difference of each resource, sorted by path. A resource created and
+/// deleted meanwhile doesn't differ, and a resource deleted and created
+/// again has an updated default variant.
+pub(crate) fn diff(changes: &[Change]) -> Vec<ResourceDiff> {
+    // Whether each resource existed before the changes and after them.
+    let mut states: BTreeMap<&[String], (bool, bool, ResourceDiff)> = BTreeMap::new();
+    for change in changes {
+        let (_, exists, diff) = states.entry(&change.path).or_insert_with(|| {
+            let existed = change.op != ChangeOp::CreateResource;
+            let diff = ResourceDiff {
+                path: change.path.clone(),
+                kind: DiffKind::Updated,
+                variants: BTreeSet::new(),
+                metadata_changed: false,
+            };
+            (existed, existed, diff)
+        });
+        match &change.op {
+            ChangeOp::CreateResource => {
+                *exists = true;
+                diff.variants.insert("default".to_owned());
+            }
+            ChangeOp::DeleteResource => *exists = false,
+            ChangeOp::AddVariant(name)
+            | ChangeOp::UpdateVariant(name)
+            | ChangeOp::DeleteVariant(name) => {
+                diff.variants.insert(name.clone());
+            }
+            ChangeOp::UpdateDescription
+            | ChangeOp::AddTag(_)
+            | ChangeOp::RemoveTag(_)
+            | ChangeOp::AddRelation(_, _)
+            | ChangeOp::RemoveRelation(_, _) => {
+                diff.metadata_changed = true;
+            }
+            // Smart folders are computed from the resources.
+            ChangeOp::EnterSmartFolder(_) | ChangeOp::LeaveSmartFolder(_) => {}
+            // Containers are not resources.
+            ChangeOp::UpdateContainer => {}
+        }
+    }
+
+    states
+        .into_values()
+        .filter_map(|(existed, exists, mut diff)| {
+            diff.kind = match (existed, exists) {
+                (false, false) => return None,
+                (false, true) => DiffKind::Created,
+                (true, false) => DiffKind::Deleted,
+                // Only the smart folders of the resource changed.
+                (true, true) if diff.variants.is_empty() && !diff.metadata_changed => return None,
+                (true, true) => DiffKind::Updated,
+            };
+            if diff.kind == DiffKind::Deleted {
+                diff.variants.clear();
+                diff.metadata_changed = false;
+            }
+            Some(diff)
+        })
+        .collect()
+}
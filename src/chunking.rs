@@ -0,0 +1,105 @@
+//! Content-defined chunking
+//! wnfs splits private content in fixed size blocks, each encrypted with
+//! fresh keys, so a file that barely changed shares no block with its
+//! previous revision. With `ResourceStoreBuilder::content_defined_chunking()`
+//! the content of large variants is first split with FastCDC, whose chunk
+//! boundaries only depend on the bytes around them: an edit only changes
+//! the chunks it touches. Each chunk is stored as a separate
+//! `PrivateForestContent`, and their list is kept in the node metadata
+//! under the content key of the variant.
+//!
+//! The keys and nonces of a chunk are derived from its plaintext and from a
+//! secret derived from the access key of the store, so that equal chunks
+//! are encrypted to equal blocks, which the block store only keeps once.
+//! This lets anyone reading the block store tell which chunks are equal,
+//! but not what they contain, and is why chunking is off by default.
+
+use crate::store::StoreError;
+use futures::io::AsyncRead;
+use futures::StreamExt;
+use libipld::Ipld;
+use rand::{rngs::StdRng, SeedableRng};
+use wnfs::{
+    common::BlockStore,
+    nameaccumulator::Name,
+    private::{forest::hamt::HamtForest, AccessKey, PrivateForestContent},
+};
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+/// The bounds of the chunk sizes, in bytes. FastCDC supports minimum
+/// sizes from 64B to 1MB, average sizes from 256B to 4MB and maximum sizes
+/// from 1KB to 16MB, and sizes out of these ranges are clamped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkSizes {
+    pub min: u32,
+    pub avg: u32,
+    pub max: u32,
+}
+
+impl Default for ChunkSizes {
+    fn default() -> Self {
+        Self {
+            min: 256 * 1024,
+            avg: 1024 * 1024,
+            max: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkSizes {
+    fn clamped(&self) -> Self {
+        use fastcdc::v2020::*;
+
+        Self {
+            min: self.min.clamp(MINIMUM_MIN, MAXIMUM_MIN),
+            avg: self.avg.clamp(MINIMUM_AVG, MAXIMUM_AVG),
+            max: self.max.clamp(MINIMUM_MAX, MAXIMUM_MAX),
+        }
+    }
+}
+
+// Derives the secret keying the encryption of the chunks from the access
+// key of the store.
+pub(crate) fn chunk_secret(access_key: &AccessKey) -> Result<[u8; 32]> {
+    let access_key = serde_cbor::to_vec(access_key)?;
+    Ok(blake3::derive_key(
+        "docstore content-defined chunking",
+        &access_key,
+    ))
+}
+
+// Splits `content` in chunks and stores them, returning the metadata
+// values of their contents in order.
+pub(crate) async fn write_chunks(
+    secret: &[u8; 32],
+    sizes: ChunkSizes,
+    name: &Name,
+    content: impl AsyncRead + Unpin,
+    forest: &mut HamtForest,
+    store: &impl BlockStore,
+) -> Result<Vec<Ipld>> {
+    let sizes = sizes.clamped();
+    let mut chunker = fastcdc::v2020::AsyncStreamCDC::new(content, sizes.min, sizes.avg, sizes.max);
+    let mut chunks = Box::pin(chunker.as_stream());
+    let mut values = vec![];
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(std::io::Error::from)?;
+        values.push(write_chunk(secret, name, &chunk.data, forest, store).await?);
+    }
+    Ok(values)
+}
+
+// Stores a chunk encrypted with keys derived from its content, returning
+// the metadata value of its content.
+pub(crate) async fn write_chunk(
+    secret: &[u8; 32],
+    name: &Name,
+    data: &[u8],
+    forest: &mut HamtForest,
+    store: &impl BlockStore,
+) -> Result<Ipld> {
+    let mut rng = StdRng::from_seed(*blake3::keyed_hash(secret, data).as_bytes());
+    let content = PrivateForestContent::new_streaming(name, data, forest, store, &mut rng).await?;
+    Ok(content.as_metadata_value()?)
+}
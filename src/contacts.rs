@@ -0,0 +1,115 @@
+//! Full text indexing of `application/x-contact+json` values.
+//! Contacts are json objects, like:
+//! { "name": "...", "phone": ["..."], "email": ["..."] }
+//! where each member is a string or an array of strings.
+
+use serde_json::Value;
+
+pub(crate) const CONTACT_MIME_TYPE: &str = "application/x-contact+json";
+
+// The number of trailing digits of phone numbers indexed on their own, so
+// that national and international forms of a number share a term.
+const PHONE_SUFFIX_LENGTH: usize = 9;
+
+/// Which members of contacts are indexed, and how.
+#[derive(Clone, Debug)]
+pub struct ContactFields {
+    /// Names, also indexed as `^^^^` followed by their first letter to
+    /// allow "starts with" matches.
+    pub names: Vec<String>,
+    /// Phone numbers, also indexed without separators and by their
+    /// last digits.
+    pub phones: Vec<String>,
+    /// Email addresses, also indexed by their local part and domain.
+    pub emails: Vec<String>,
+    /// Other members, indexed as is.
+    pub other: Vec<String>,
+}
+
+impl Default for ContactFields {
+    fn default() -> Self {
+        Self {
+            names: vec!["name".into()],
+            phones: vec!["phone".into()],
+            emails: vec!["email".into()],
+            other: vec![],
+        }
+    }
+}
+
+/// Returns the string values of a member, which is either a string or an
+/// array of strings.
+fn member_values<'a>(contact: &'a Value, field: &str) -> Vec<&'a str> {
+    match contact.get(field) {
+        Some(Value::String(text)) => vec![text.as_str()],
+        Some(Value::Array(array)) => array.iter().filter_map(|item| item.as_str()).collect(),
+        _ => vec![],
+    }
+    .into_iter()
+    .filter(|text| !text.is_empty())
+    .collect()
+}
+
+/// Removes the separators of a phone number, keeping a leading `+`.
+fn normalize_phone(phone: &str) -> String {
+    let phone = phone.trim();
+    let digits = phone.chars().filter(|c| c.is_ascii_digit());
+    if phone.starts_with('+') {
+        std::iter::once('+').chain(digits).collect()
+    } else {
+        digits.collect()
+    }
+}
+
+fn phone_terms(phone: &str) -> Vec<String> {
+    let normalized = normalize_phone(phone);
+    let digits = normalized.trim_start_matches('+');
+    let mut terms = vec![phone.to_owned()];
+    if normalized != phone {
+        terms.push(normalized.clone());
+    }
+    if digits.len() > PHONE_SUFFIX_LENGTH {
+        terms.push(digits[digits.len() - PHONE_SUFFIX_LENGTH..].to_owned());
+    }
+    terms
+}
+
+fn email_terms(email: &str) -> Vec<String> {
+    let mut terms = vec![email.to_owned()];
+    if let Some((local, domain)) = email.rsplit_once('@') {
+        terms.push(local.to_owned());
+        terms.push(domain.to_owned());
+    }
+    terms
+}
+
+/// Returns the terms to index for this contact.
+pub(crate) fn contact_text(contact: &Value, fields: &ContactFields) -> Vec<String> {
+    let mut result = vec![];
+    for field in &fields.names {
+        for name in member_values(contact, field) {
+            result.push(name.to_owned());
+            if let Some(first) = name.chars().next() {
+                result.push(format!("^^^^{}", first));
+            }
+        }
+    }
+    for field in &fields.phones {
+        for phone in member_values(contact, field) {
+            result.extend(phone_terms(phone));
+        }
+    }
+    for field in &fields.emails {
+        for email in member_values(contact, field) {
+            result.extend(email_terms(email));
+        }
+    }
+    for field in &fields.other {
+        result.extend(
+            member_values(contact, field)
+                .into_iter()
+                .map(|v| v.to_owned()),
+        );
+    }
+    result
+}
@@ -0,0 +1,76 @@
+//! Containers
+//! The directories holding resources have no resource metadata, so their
+//! description and tags are kept by path in the hidden `.containers`
+//! private file. Containers are indexed by their name, description and
+//! tags, to be found with `ResourceStore::search_containers()`, eg. the
+//! folder named "Taxes 2023".
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+pub(crate) const CONTAINERS_FILE: &str = ".containers";
+
+/// The description and tags of a container.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ContainerMetadata {
+    desc: String,
+    tags: HashSet<String>,
+}
+
+impl ContainerMetadata {
+    pub fn desc(&self) -> String {
+        self.desc.to_owned()
+    }
+
+    pub fn set_desc(&mut self, desc: &str) {
+        self.desc = desc.to_owned();
+    }
+
+    pub fn tags(&self) -> &HashSet<String> {
+        &self.tags
+    }
+
+    /// Returns false if the container already had this tag.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        self.tags.insert(tag.to_owned())
+    }
+
+    /// Returns false if the container didn't have this tag.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        self.tags.remove(tag)
+    }
+
+    /// The text the container at `path` is searched by: its name,
+    /// description and tags.
+    pub(crate) fn text(&self, path: &[String]) -> String {
+        let mut tags: Vec<&str> = self.tags.iter().map(|tag| tag.as_str()).collect();
+        tags.sort();
+        let name = path.last().map(|name| name.as_str()).unwrap_or_default();
+        format!("{} {} {}", name, self.desc, tags.join(" "))
+    }
+}
+
+/// The metadata of the containers of a store, by path.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Containers {
+    entries: BTreeMap<Vec<String>, ContainerMetadata>,
+}
+
+impl Containers {
+    pub(crate) fn get(&self, path: &[String]) -> Option<&ContainerMetadata> {
+        self.entries.get(path)
+    }
+
+    /// Records the metadata of a container, forgetting it when empty.
+    pub(crate) fn set(&mut self, path: &[String], metadata: ContainerMetadata) {
+        if metadata == ContainerMetadata::default() {
+            self.entries.remove(path);
+        } else {
+            self.entries.insert(path.to_vec(), metadata);
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Vec<String>, &ContainerMetadata)> {
+        self.entries.iter()
+    }
+}
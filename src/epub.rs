@@ -0,0 +1,240 @@
+//! Helpers to extract metadata, text and the cover image of EPUB books.
+//! An EPUB is a zip archive where `META-INF/container.xml` points to the
+//! OPF package document, listing the book metadata, its resources (the
+//! manifest) and the reading order of the chapters (the spine).
+
+use crate::office::Archive;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::Read;
+
+pub(crate) const EPUB_MIME_TYPE: &str = "application/epub+zip";
+
+fn read_part(archive: &mut Archive, name: &str) -> Option<Vec<u8>> {
+    let mut part = archive.by_name(name).ok()?;
+    let mut buffer = vec![];
+    part.read_to_end(&mut buffer).ok()?;
+    Some(buffer)
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attr| attr.key.local_name().as_ref() == name)
+        .and_then(|attr| attr.unescape_value().ok())
+        .map(|value| value.into_owned())
+}
+
+// Resolves a path relative to the directory of the OPF document.
+fn resolve(base: &str, href: &str) -> String {
+    let mut segments: Vec<&str> = base.split('/').collect();
+    // Drop the OPF file name.
+    segments.pop();
+    for segment in href.split('#').next().unwrap_or(href).split('/') {
+        match segment {
+            "." | "" => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+struct ManifestItem {
+    path: String,
+    media_type: String,
+    properties: String,
+}
+
+/// The parsed OPF package document.
+pub(crate) struct Package {
+    title: Option<String>,
+    authors: Vec<String>,
+    // id -> item
+    manifest: HashMap<String, ManifestItem>,
+    // Manifest ids of the chapters, in reading order.
+    spine: Vec<String>,
+    // The EPUB 2 `<meta name="cover">` manifest id.
+    cover_id: Option<String>,
+}
+
+impl Package {
+    /// Returns the title and authors of the book.
+    pub(crate) fn properties(&self) -> Vec<(String, String)> {
+        let mut properties = vec![];
+        if let Some(title) = &self.title {
+            properties.push(("title".to_owned(), title.clone()));
+        }
+        for author in &self.authors {
+            properties.push(("author".to_owned(), author.clone()));
+        }
+        properties
+    }
+}
+
+fn opf_path(archive: &mut Archive) -> Option<String> {
+    let xml = read_part(archive, "META-INF/container.xml")?;
+    let mut reader = Reader::from_reader(xml.as_slice());
+    let mut buffer = vec![];
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) | Ok(Event::Empty(element))
+                if element.local_name().as_ref() == b"rootfile" =>
+            {
+                return attribute(&element, b"full-path");
+            }
+            Ok(Event::Eof) | Err(_) => return None,
+            _ => {}
+        }
+        buffer.clear();
+    }
+}
+
+/// Parses the package document of the book.
+pub(crate) fn package(archive: &mut Archive) -> Option<Package> {
+    let opf = opf_path(archive)?;
+    let xml = read_part(archive, &opf)?;
+
+    let mut package = Package {
+        title: None,
+        authors: vec![],
+        manifest: HashMap::new(),
+        spine: vec![],
+        cover_id: None,
+    };
+    let mut reader = Reader::from_reader(xml.as_slice());
+    let mut buffer = vec![];
+    let mut current: Option<&'static str> = None;
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) | Ok(Event::Empty(element)) => {
+                current = None;
+                match element.local_name().as_ref() {
+                    b"title" => current = Some("title"),
+                    b"creator" => current = Some("author"),
+                    b"item" => {
+                        if let (Some(id), Some(href)) =
+                            (attribute(&element, b"id"), attribute(&element, b"href"))
+                        {
+                            package.manifest.insert(
+                                id,
+                                ManifestItem {
+                                    path: resolve(&opf, &href),
+                                    media_type: attribute(&element, b"media-type")
+                                        .unwrap_or_default(),
+                                    properties: attribute(&element, b"properties")
+                                        .unwrap_or_default(),
+                                },
+                            );
+                        }
+                    }
+                    b"itemref" => {
+                        if let Some(id) = attribute(&element, b"idref") {
+                            package.spine.push(id);
+                        }
+                    }
+                    b"meta" => {
+                        if attribute(&element, b"name").as_deref() == Some("cover") {
+                            package.cover_id = attribute(&element, b"content");
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(content)) => {
+                if let (Some(name), Ok(value)) = (current, content.unescape()) {
+                    let value = value.trim();
+                    if !value.is_empty() {
+                        match name {
+                            "title" if package.title.is_none() => {
+                                package.title = Some(value.to_owned())
+                            }
+                            "author" => package.authors.push(value.to_owned()),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Ok(Event::End(_)) => current = None,
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    Some(package)
+}
+
+// Concatenates the text of an xhtml chapter, skipping scripts and styles.
+fn chapter_text(xhtml: &[u8], text: &mut String) {
+    let mut reader = Reader::from_reader(xhtml);
+    let mut buffer = vec![];
+    let mut skip: u32 = 0;
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) => {
+                if matches!(element.local_name().as_ref(), b"script" | b"style") {
+                    skip += 1;
+                }
+            }
+            Ok(Event::End(element)) => {
+                if matches!(element.local_name().as_ref(), b"script" | b"style") {
+                    skip = skip.saturating_sub(1);
+                } else {
+                    text.push(' ');
+                }
+            }
+            Ok(Event::Text(content)) if skip == 0 => {
+                if let Ok(value) = content.unescape() {
+                    text.push_str(&value);
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+}
+
+/// Returns the text of the chapters, in reading order.
+pub(crate) fn book_text(archive: &mut Archive, package: &Package) -> String {
+    let mut text = String::new();
+    for id in &package.spine {
+        if let Some(item) = package.manifest.get(id) {
+            if let Some(xhtml) = read_part(archive, &item.path) {
+                chapter_text(&xhtml, &mut text);
+            }
+        }
+    }
+    text
+}
+
+/// Returns the cover image and its mime type. The cover is either flagged
+/// with the EPUB 3 `cover-image` property or referenced by the EPUB 2
+/// `cover` meta element.
+pub(crate) fn cover_image(archive: &mut Archive, package: &Package) -> Option<(Vec<u8>, String)> {
+    let item = package
+        .manifest
+        .values()
+        .find(|item| {
+            item.properties
+                .split_whitespace()
+                .any(|p| p == "cover-image")
+        })
+        .or_else(|| {
+            package
+                .cover_id
+                .as_ref()
+                .and_then(|id| package.manifest.get(id))
+        })?;
+
+    if !item.media_type.starts_with("image/") {
+        return None;
+    }
+    let content = read_part(archive, &item.path)?;
+    Some((content, item.media_type.clone()))
+}
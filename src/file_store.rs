@@ -1,46 +1,353 @@
 //! A file backed store for wnfs
+//! Blocks are named after their cid, so identical blocks are only stored
+//! once. Since wnfs encrypts each block with fresh keys, this only shares
+//! the blocks of successive revisions of a file with content-defined
+//! chunking, see the `chunking` module.
+//! The blocks can also be kept in memory instead, eg. for tests.
 
+use crate::block_fetcher::BlockFetcher;
+use crate::metrics::{self, BlockSource};
+use crate::paths::long_path;
 use async_trait::async_trait;
 use bytes::Bytes;
 use libipld::Cid;
+use log::{debug, error};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
 use wnfs::common::BlockStore;
 
 type IpldError = libipld::error::Error;
 
 pub struct FileStore {
     root: PathBuf,
+    // The blocks of a store created with `in_memory()`, which has no files.
+    memory: Option<Mutex<HashMap<Cid, Bytes>>>,
+    // The maximum number of block writes running in the background.
+    // Writes are done inline when this is 1.
+    max_concurrent_writes: u32,
+    write_permits: Arc<Semaphore>,
+    // Blocks being written, so that they can be read back before the write completes.
+    pending: Arc<Mutex<HashMap<Cid, Bytes>>>,
+    // The first error of a background write, reported by `flush()`.
+    failed: Arc<Mutex<Option<std::io::Error>>>,
+    // Used to get the blocks that are not available locally.
+    fetcher: Option<Box<dyn BlockFetcher>>,
+    // The number of blocks written and synced together, 0 to write each
+    // block as it comes.
+    batch_size: usize,
+    // Blocks waiting for the next batch write. They are also in `pending`.
+    batch: Mutex<Vec<(Cid, Bytes)>>,
+    // The total size of the new blocks put in this store.
+    written: AtomicU64,
 }
 
 impl FileStore {
     pub async fn maybe_new<P: AsRef<Path>>(root: P) -> Result<Self, std::io::Error> {
+        Self::with_concurrency(root, 1).await
+    }
+
+    pub async fn with_concurrency<P: AsRef<Path>>(
+        root: P,
+        max_concurrent_writes: u32,
+    ) -> Result<Self, std::io::Error> {
         // Check if the root directory exists, or try to create it.
-        let root = root.as_ref();
+        let root = long_path(root.as_ref())?;
         if !root.exists() {
-            fs::create_dir(root).await?;
+            fs::create_dir(&root).await?;
+        }
+
+        Ok(Self::new(root, None, max_concurrent_writes))
+    }
+
+    /// Creates a store keeping its blocks in memory, which are lost when it
+    /// is dropped.
+    pub fn in_memory() -> Self {
+        Self::new(PathBuf::new(), Some(Mutex::new(HashMap::new())), 1)
+    }
+
+    fn new(
+        root: PathBuf,
+        memory: Option<Mutex<HashMap<Cid, Bytes>>>,
+        max_concurrent_writes: u32,
+    ) -> Self {
+        let max_concurrent_writes = max_concurrent_writes.max(1);
+        Self {
+            root,
+            memory,
+            max_concurrent_writes,
+            write_permits: Arc::new(Semaphore::new(max_concurrent_writes as _)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            failed: Arc::new(Mutex::new(None)),
+            fetcher: None,
+            batch_size: 0,
+            batch: Mutex::new(vec![]),
+            written: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_fetcher(&mut self, fetcher: Box<dyn BlockFetcher>) {
+        self.fetcher = Some(fetcher);
+    }
+
+    /// Batches block writes: blocks are kept in memory until `size` of them
+    /// are waiting or `flush()` is called, then written and synced to disk
+    /// together. This trades many small writes for fewer, durable ones,
+    /// which is faster on spinning disks and SD cards.
+    /// Background writes are not used when batching, and blocks kept in
+    /// memory are never batched.
+    pub fn set_write_batch_size(&mut self, size: usize) {
+        self.batch_size = size;
+    }
+
+    // Writes and syncs the batched blocks. If that fails, they stay in the
+    // batch to be written again by the next flush.
+    async fn write_batch(&self) -> Result<(), std::io::Error> {
+        let batch = std::mem::take(&mut *self.batch.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(err) = self.write_synced(&batch).await {
+            let mut current = self.batch.lock().unwrap();
+            let newer = std::mem::replace(&mut *current, batch);
+            current.extend(newer);
+            return Err(err);
+        }
+        debug!("Wrote a batch of {} blocks", batch.len());
+
+        let mut pending = self.pending.lock().unwrap();
+        for (cid, _) in batch {
+            pending.remove(&cid);
+        }
+        Ok(())
+    }
+
+    async fn write_synced(&self, blocks: &[(Cid, Bytes)]) -> Result<(), std::io::Error> {
+        let mut files = Vec::with_capacity(blocks.len());
+        for (cid, bytes) in blocks {
+            let mut file = fs::File::create(self.path_for_cid(cid)).await?;
+            file.write_all(bytes).await?;
+            files.push(file);
+        }
+        // Sync the whole group once everything is written, and the
+        // directory so that the new entries are durable too.
+        for file in files {
+            file.sync_all().await?;
+        }
+        #[cfg(unix)]
+        fs::File::open(&self.root).await?.sync_all().await?;
+        Ok(())
+    }
+
+    // Gets a missing block from the fetcher, and stores it if it is valid.
+    async fn fetch_block(&self, cid: &Cid) -> Result<Option<Bytes>, IpldError> {
+        let fetcher = match &self.fetcher {
+            Some(fetcher) => fetcher,
+            None => return Ok(None),
+        };
+
+        let bytes = match fetcher.fetch_block(cid).await? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        if self.create_cid(&bytes, cid.codec())? != *cid {
+            error!("Fetched block doesn't match its cid: {}", cid);
+            return Ok(None);
+        }
+
+        debug!("Fetched missing block {}", cid);
+        match &self.memory {
+            Some(memory) => {
+                memory.lock().unwrap().insert(*cid, bytes.clone());
+            }
+            None => fs::write(self.path_for_cid(cid), &bytes).await?,
+        }
+        Ok(Some(bytes))
+    }
+
+    pub fn has_block(&self, cid: &Cid) -> bool {
+        if let Some(memory) = &self.memory {
+            return memory.lock().unwrap().contains_key(cid);
         }
+        self.pending.lock().unwrap().contains_key(cid) || self.path_for_cid(cid).exists()
+    }
+
+    /// Returns the total size in bytes of the new blocks put in this store
+    /// since it was opened. Blocks that were already stored don't count.
+    pub fn bytes_written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
 
-        Ok(Self { root: root.into() })
+    // Counts a new block.
+    fn block_written(&self, size: usize) {
+        metrics::block_written(size);
+        self.written.fetch_add(size as _, Ordering::Relaxed);
+    }
+
+    /// Returns the number of blocks and their total size in bytes.
+    pub async fn usage(&self) -> Result<(u64, u64), std::io::Error> {
+        self.flush().await?;
+        if let Some(memory) = &self.memory {
+            let memory = memory.lock().unwrap();
+            let size = memory.values().map(|bytes| bytes.len() as u64).sum();
+            return Ok((memory.len() as u64, size));
+        }
+
+        let (mut count, mut size) = (0, 0);
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            count += 1;
+            size += entry.metadata().await?.len();
+        }
+        Ok((count, size))
+    }
+
+    /// Deletes the blocks that are not in `keep`, returning how many were removed.
+    pub async fn remove_blocks_except(&self, keep: &HashSet<Cid>) -> Result<u64, std::io::Error> {
+        self.flush().await?;
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+            let before = memory.len();
+            memory.retain(|cid, _| keep.contains(cid));
+            return Ok((before - memory.len()) as u64);
+        }
+        let keep: HashSet<String> = keep.iter().map(|cid| cid.to_string()).collect();
+
+        let mut count = 0;
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if !keep.contains(name.to_string_lossy().as_ref()) {
+                fs::remove_file(entry.path()).await?;
+                count += 1;
+            }
+        }
+        Ok(count)
     }
 
     fn path_for_cid(&self, cid: &Cid) -> PathBuf {
         let filename = cid.to_string();
         self.root.join(filename)
     }
+
+    /// Waits for all the background writes to complete, and returns
+    /// the first error that happened if any. Batched blocks are written
+    /// and synced.
+    pub async fn flush(&self) -> Result<(), std::io::Error> {
+        self.write_batch().await?;
+
+        if self.max_concurrent_writes > 1 {
+            // Once we hold all the permits, no write is in flight.
+            let _permits = self
+                .write_permits
+                .acquire_many(self.max_concurrent_writes)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+
+        match self.failed.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
 }
 
 #[async_trait(?Send)]
 impl BlockStore for FileStore {
     async fn get_block(&self, cid: &Cid) -> Result<Bytes, IpldError> {
-        let bytes = fs::read(self.path_for_cid(cid)).await?;
-        Ok(bytes.into())
+        if let Some(bytes) = self.pending.lock().unwrap().get(cid) {
+            metrics::block_read(BlockSource::Pending, bytes.len());
+            return Ok(bytes.clone());
+        }
+        if let Some(memory) = &self.memory {
+            // Counted like the pending blocks, which are in memory too.
+            let bytes = memory.lock().unwrap().get(cid).cloned();
+            if let Some(bytes) = bytes {
+                metrics::block_read(BlockSource::Pending, bytes.len());
+                return Ok(bytes);
+            }
+            return match self.fetch_block(cid).await? {
+                Some(bytes) => {
+                    metrics::block_read(BlockSource::Fetcher, bytes.len());
+                    Ok(bytes)
+                }
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+            };
+        }
+
+        match fs::read(self.path_for_cid(cid)).await {
+            Ok(bytes) => {
+                metrics::block_read(BlockSource::Disk, bytes.len());
+                Ok(bytes.into())
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                match self.fetch_block(cid).await? {
+                    Some(bytes) => {
+                        metrics::block_read(BlockSource::Fetcher, bytes.len());
+                        Ok(bytes)
+                    }
+                    None => Err(err.into()),
+                }
+            }
+            Err(err) => Err(err.into()),
+        }
     }
 
     async fn put_block(&self, bytes: impl Into<Bytes>, codec: u64) -> Result<Cid, IpldError> {
         let bytes: Bytes = bytes.into();
         let cid = self.create_cid(&bytes, codec)?;
-        fs::write(self.path_for_cid(&cid), bytes).await?;
+        // Content-defined chunking writes the same blocks again.
+        if self.has_block(&cid) {
+            return Ok(cid);
+        }
+
+        if let Some(memory) = &self.memory {
+            self.block_written(bytes.len());
+            memory.lock().unwrap().insert(cid, bytes);
+            return Ok(cid);
+        }
+
+        if self.batch_size > 0 {
+            self.block_written(bytes.len());
+            self.pending.lock().unwrap().insert(cid, bytes.clone());
+            let full = {
+                let mut batch = self.batch.lock().unwrap();
+                batch.push((cid, bytes));
+                batch.len() >= self.batch_size
+            };
+            if full {
+                self.write_batch().await?;
+            }
+            return Ok(cid);
+        }
+
+        if self.max_concurrent_writes == 1 {
+            self.block_written(bytes.len());
+            fs::write(self.path_for_cid(&cid), bytes).await?;
+            return Ok(cid);
+        }
+
+        let permit = self.write_permits.clone().acquire_owned().await?;
+        self.block_written(bytes.len());
+        self.pending.lock().unwrap().insert(cid, bytes.clone());
+
+        let path = self.path_for_cid(&cid);
+        let pending = self.pending.clone();
+        let failed = self.failed.clone();
+        tokio::spawn(async move {
+            if let Err(err) = fs::write(path, bytes).await {
+                failed.lock().unwrap().get_or_insert(err);
+            }
+            pending.lock().unwrap().remove(&cid);
+            drop(permit);
+        });
+
         Ok(cid)
     }
 }
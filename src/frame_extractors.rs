@@ -0,0 +1,145 @@
+//! Extraction of still images from videos and documents, used to create
+//! their contact sheets. No extractor is built in: register one with
+//! `register_frame_extractor()`, for instance a `CommandFrameExtractor`
+//! running `ffmpeg` for videos or `pdftoppm` for PDF documents.
+
+use crate::image_decoders::decode_with_image_crate;
+use image::DynamicImage;
+use log::error;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+pub trait FrameExtractor: Send + Sync {
+    /// Returns whether this extractor handles content with this mime type.
+    fn supports(&self, mime_type: &str) -> bool;
+
+    /// Returns up to `count` images of the content, in order: frames spread
+    /// over a video, or the first pages of a document.
+    fn extract(&self, content: &[u8], count: u32) -> Result<Vec<DynamicImage>, String>;
+}
+
+static EXTRACTORS: RwLock<Vec<Box<dyn FrameExtractor>>> = RwLock::new(Vec::new());
+
+/// Registers a frame extractor for all the stores of this process.
+pub fn register_frame_extractor(extractor: Box<dyn FrameExtractor>) {
+    EXTRACTORS.write().unwrap().push(extractor);
+}
+
+/// Returns whether a registered extractor handles this mime type.
+pub(crate) fn has_frame_extractor(mime_type: &str) -> bool {
+    EXTRACTORS
+        .read()
+        .unwrap()
+        .iter()
+        .any(|extractor| extractor.supports(mime_type))
+}
+
+/// Extracts up to `count` images with the first registered extractor
+/// supporting this mime type.
+pub(crate) fn extract_frames(
+    content: &[u8],
+    mime_type: &str,
+    count: u32,
+) -> Option<Vec<DynamicImage>> {
+    let extractors = EXTRACTORS.read().unwrap();
+    let extractor = extractors
+        .iter()
+        .find(|extractor| extractor.supports(mime_type))?;
+    match extractor.extract(content, count) {
+        Ok(frames) if !frames.is_empty() => Some(frames),
+        Ok(_) => None,
+        Err(err) => {
+            error!("Failed to extract frames of {}: {}", mime_type, err);
+            None
+        }
+    }
+}
+
+// Makes the names of the temporary directories unique in this process.
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An extractor running an external command. The content is written to a
+/// temporary file since most tools need to seek in their input, and in the
+/// arguments `{input}` is replaced by its path, `{output}` by the directory
+/// where the command writes its images and `{count}` by the number of
+/// requested images. The images are read in the order of their file names.
+/// For instance, for PDF documents:
+/// `pdftoppm -png -r 50 -l {count} {input} {output}/page`
+pub struct CommandFrameExtractor {
+    program: String,
+    args: Vec<String>,
+    mime_types: Vec<String>,
+}
+
+impl CommandFrameExtractor {
+    /// Mime types ending with `/*` match all their subtypes.
+    pub fn new(program: &str, args: &[&str], mime_types: &[&str]) -> Self {
+        Self {
+            program: program.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+            mime_types: mime_types.iter().map(|mime| (*mime).to_owned()).collect(),
+        }
+    }
+
+    fn run(&self, dir: &Path, content: &[u8], count: u32) -> Result<Vec<DynamicImage>, String> {
+        let input = dir.join("input");
+        let output = dir.join("output");
+        std::fs::create_dir_all(&output).map_err(|e| e.to_string())?;
+        std::fs::write(&input, content).map_err(|e| e.to_string())?;
+
+        let args = self.args.iter().map(|arg| {
+            arg.replace("{input}", &input.to_string_lossy())
+                .replace("{output}", &output.to_string_lossy())
+                .replace("{count}", &count.to_string())
+        });
+        let status = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| format!("Failed to run {}: {}", self.program, e))?;
+        if !status.success() {
+            return Err(format!("{} failed: {}", self.program, status));
+        }
+
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&output)
+            .map_err(|e| e.to_string())?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect();
+        files.sort();
+        files
+            .into_iter()
+            .take(count as usize)
+            .map(|file| {
+                let bytes = std::fs::read(file).map_err(|e| e.to_string())?;
+                decode_with_image_crate(bytes)
+            })
+            .collect()
+    }
+}
+
+impl FrameExtractor for CommandFrameExtractor {
+    fn supports(&self, mime_type: &str) -> bool {
+        self.mime_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => mime_type.split_once('/').map(|(value, _)| value) == Some(kind),
+                None => pattern == mime_type,
+            })
+    }
+
+    fn extract(&self, content: &[u8], count: u32) -> Result<Vec<DynamicImage>, String> {
+        let dir = std::env::temp_dir().join(format!(
+            "docstore-frames-{}-{}",
+            std::process::id(),
+            RUN_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = self.run(&dir, content, count);
+        let _ = std::fs::remove_dir_all(&dir);
+        result
+    }
+}
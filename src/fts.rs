@@ -1,8 +1,15 @@
 //! Full text indexers
 //! Indexers are registered for a given mime type.
 
+use crate::contacts::{contact_text, ContactFields, CONTACT_MIME_TYPE};
+use crate::epub;
+use crate::gpx;
+use crate::office::{core_properties, document_text};
+use crate::pdf;
+use crate::subtitles;
 use futures::{AsyncRead, AsyncReadExt};
 use serde_json::Value;
+use std::io::{Cursor, Read};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,17 +22,271 @@ pub enum IndexerError {
     IO(#[from] std::io::Error),
     #[error("serde Json error")]
     SerdeJson(#[from] serde_json::Error),
+    #[error("Zip error")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Xml error")]
+    Xml(#[from] quick_xml::Error),
+    #[error("Yaml error")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Toml error")]
+    Toml(#[from] toml::de::Error),
+    #[error("Content larger than {0} bytes")]
+    TooLarge(u64),
 }
 
-/// text/plain indexer: read all the content available.
+/// The default size above which variants are not indexed, or only the
+/// beginning of plain text ones.
+pub(crate) const DEFAULT_MAX_INDEXED_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The size of the chunks read by the indexers streaming their content.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// text/plain indexer: read the content available, up to `max_size` bytes.
+/// It is decoded chunk by chunk, so that only the text is kept in memory.
 pub async fn text_plain_indexer<C: AsyncRead + Unpin>(
     content: &mut C,
+    max_size: u64,
 ) -> Result<String, IndexerError> {
     let mut text = String::new();
-    content.read_to_string(&mut text).await?;
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    // The bytes of a character cut at the end of the previous chunk.
+    let mut pending: Vec<u8> = vec![];
+    let mut remaining = max_size;
+    while remaining > 0 {
+        let size = chunk.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let read = content.read(&mut chunk[..size]).await?;
+        if read == 0 {
+            break;
+        }
+        remaining -= read as u64;
+        pending.extend_from_slice(&chunk[..read]);
+        let valid = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(err) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err).into())
+            }
+        };
+        text.push_str(std::str::from_utf8(&pending[..valid]).unwrap_or_default());
+        pending.drain(..valid);
+    }
+    // The last character can be cut by the size limit.
     Ok(text)
 }
 
+/// Accumulates the text of the indexed values, until it reaches a size
+/// limit.
+struct TextCollector {
+    parts: Vec<String>,
+    size: usize,
+    max_size: usize,
+}
+
+impl TextCollector {
+    fn new(max_size: usize) -> Self {
+        Self {
+            parts: vec![],
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn add(&mut self, texts: Vec<String>) {
+        for text in texts {
+            if self.is_full() {
+                return;
+            }
+            self.size += text.len() + 1;
+            self.parts.push(text);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.size >= self.max_size
+    }
+
+    fn into_text(self) -> String {
+        let mut text = self.parts.join(" ");
+        if text.len() > self.max_size {
+            let mut end = self.max_size;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+        }
+        text
+    }
+}
+
+/// Splits a top-level JSON array into its elements while it is read, so
+/// that they are parsed one at a time.
+#[derive(Default)]
+struct JsonArraySplitter {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    element: Vec<u8>,
+    // Set when the current element is larger than the size limit, and
+    // skipped.
+    oversized: bool,
+}
+
+impl JsonArraySplitter {
+    /// Scans the next bytes of the array, calling `on_element` with each
+    /// element ending in them that is at most `max_size` bytes long.
+    /// Returns whether the array ended.
+    fn feed<F>(
+        &mut self,
+        bytes: &[u8],
+        max_size: usize,
+        on_element: &mut F,
+    ) -> Result<bool, IndexerError>
+    where
+        F: FnMut(&[u8]) -> Result<(), IndexerError>,
+    {
+        for &byte in bytes {
+            if self.depth == 0 {
+                // Skips to the opening bracket.
+                if byte == b'[' {
+                    self.depth = 1;
+                }
+                continue;
+            }
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+            } else {
+                match byte {
+                    b'"' => self.in_string = true,
+                    b'[' | b'{' => self.depth += 1,
+                    b']' | b'}' => self.depth -= 1,
+                    b',' if self.depth == 1 => {
+                        self.end_element(on_element)?;
+                        continue;
+                    }
+                    _ => {}
+                }
+                if self.depth == 0 {
+                    self.end_element(on_element)?;
+                    return Ok(true);
+                }
+            }
+            if self.oversized {
+                continue;
+            }
+            if self.element.len() >= max_size {
+                self.oversized = true;
+                self.element = vec![];
+            } else {
+                self.element.push(byte);
+            }
+        }
+        Ok(false)
+    }
+
+    fn end_element<F>(&mut self, on_element: &mut F) -> Result<(), IndexerError>
+    where
+        F: FnMut(&[u8]) -> Result<(), IndexerError>,
+    {
+        let element = std::mem::take(&mut self.element);
+        if std::mem::take(&mut self.oversized) {
+            return Ok(());
+        }
+        let start = element
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(element.len());
+        let end = element
+            .iter()
+            .rposition(|byte| !byte.is_ascii_whitespace())
+            .map_or(start, |end| end + 1);
+        if start < end {
+            on_element(&element[start..end])?;
+        }
+        Ok(())
+    }
+}
+
+enum JsonDocument {
+    Array(JsonArraySplitter),
+    Other(Vec<u8>),
+}
+
+/// Reads a JSON document in chunks and returns the text of its value, or
+/// of each of its elements when it is an array, up to `max_size` bytes.
+/// Arrays are parsed one element at a time, skipping the elements larger
+/// than `max_size`, so that large exports are indexed without loading them
+/// in memory. Other documents larger than `max_size` are not indexed.
+async fn json_text<C, F>(
+    content: &mut C,
+    max_size: u64,
+    mut value_text: F,
+) -> Result<String, IndexerError>
+where
+    C: AsyncRead + Unpin,
+    F: FnMut(&Value) -> Vec<String>,
+{
+    let limit = max_size.try_into().unwrap_or(usize::MAX);
+    let mut collector = TextCollector::new(limit);
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    // None until the first character of the document is read.
+    let mut document: Option<JsonDocument> = None;
+    let mut ended = false;
+
+    loop {
+        let read = content.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        let bytes = &chunk[..read];
+        if document.is_none() {
+            document = match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+                Some(b'[') => Some(JsonDocument::Array(JsonArraySplitter::default())),
+                Some(_) => Some(JsonDocument::Other(vec![])),
+                None => continue,
+            };
+        }
+        match &mut document {
+            Some(JsonDocument::Array(splitter)) => {
+                let mut on_element = |element: &[u8]| -> Result<(), IndexerError> {
+                    collector.add(value_text(&serde_json::from_slice(element)?));
+                    Ok(())
+                };
+                ended = splitter.feed(bytes, limit, &mut on_element)?;
+                if ended || collector.is_full() {
+                    break;
+                }
+            }
+            Some(JsonDocument::Other(buffer)) => {
+                if buffer.len() + bytes.len() > limit {
+                    return Err(IndexerError::TooLarge(max_size));
+                }
+                buffer.extend_from_slice(bytes);
+            }
+            None => {}
+        }
+    }
+
+    let buffer = match document {
+        Some(JsonDocument::Array(_)) if !ended && !collector.is_full() => {
+            return Err(IndexerError::IndexingFailed(
+                "Unterminated JSON array".to_owned(),
+            ))
+        }
+        Some(JsonDocument::Array(_)) => return Ok(collector.into_text()),
+        Some(JsonDocument::Other(buffer)) => buffer,
+        // Fails like other invalid documents.
+        None => vec![],
+    };
+    collector.add(value_text(&serde_json::from_slice(&buffer)?));
+    Ok(collector.into_text())
+}
+
 /// A generic indexer for flat Json data structures.
 /// Indexed properties are strings and string arrays members.
 
@@ -59,18 +320,20 @@ impl FlatJsonIndexer {
         }
     }
 
+    /// Returns the text of the json content, up to `max_size` bytes. When
+    /// it is an array, the text of each of its elements is returned.
     pub async fn get_text<C: AsyncRead + Unpin>(
         &self,
         content: &mut C,
+        max_size: u64,
     ) -> Result<String, IndexerError> {
-        let mut result: Vec<String> = vec![];
+        json_text(content, max_size, |v| self.value_text(v)).await
+    }
 
-        // 1. Read the content as json.
-        let mut buffer = vec![];
-        content.read_to_end(&mut buffer).await?;
-        let v: Value = serde_json::from_slice(&buffer)?;
+    fn value_text(&self, v: &Value) -> Vec<String> {
+        let mut result: Vec<String> = vec![];
 
-        // 2. Index each available field.
+        // Index each available field.
         for field in &self.fields {
             match v.get(field) {
                 Some(Value::String(text)) => {
@@ -87,7 +350,7 @@ impl FlatJsonIndexer {
             }
         }
 
-        Ok(result.join(" "))
+        result
     }
 }
 
@@ -98,39 +361,226 @@ pub fn new_places_indexer() -> FlatJsonIndexer {
     FlatJsonIndexer::new(&["url", "title"], None)
 }
 
-/// Indexer for the content of a "Contacts" object.
-/// This is a json value with the following format:
-/// { name: "...", phone: "[...]", email: "[...]" }
-/// If indexeing the "name" field, a value is added with
-/// the prefix "^^^^" to allow "starts with" matches.
-fn custom_contact_index(field: &str, text: &str) -> Vec<String> {
-    if text.is_empty() {
-        vec![]
-    } else if field == "name" {
-        vec![
-            text.to_owned(),
-            format!("^^^^{}", text.chars().next().unwrap()),
-        ]
-    } else {
-        vec![text.to_owned()]
+/// Json indexer: returns the text of places and contacts, or of arrays of
+/// them, up to `max_size` bytes. Fails with `IndexerError::TooLarge` for
+/// other documents larger than `max_size`.
+pub async fn json_indexer<C: AsyncRead + Unpin>(
+    content: &mut C,
+    mime: &str,
+    contact_fields: &ContactFields,
+    max_size: u64,
+) -> Result<String, IndexerError> {
+    match mime {
+        "application/x-places+json" => new_places_indexer().get_text(content, max_size).await,
+        CONTACT_MIME_TYPE => {
+            json_text(content, max_size, |contact| {
+                contact_text(contact, contact_fields)
+            })
+            .await
+        }
+        _ => Err(IndexerError::UnsupportedMime(mime.to_owned())),
     }
 }
 
-pub fn new_contacts_indexer() -> FlatJsonIndexer {
-    FlatJsonIndexer::new(
-        &["name", "phone", "email"],
-        Some(Box::new(custom_contact_index)),
-    )
+/// Maximum size of the archive members for which text content is indexed.
+const MAX_ZIP_TEXT_MEMBER_SIZE: u64 = 64 * 1024;
+
+/// application/zip indexer: indexes the names of the archive members,
+/// and the content of small text members.
+pub async fn zip_indexer<C: AsyncRead + Unpin>(content: &mut C) -> Result<String, IndexerError> {
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await?;
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer))?;
+
+    let mut result: Vec<String> = vec![];
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        result.push(file.name().to_owned());
+
+        let is_text = mime_guess::from_path(file.name())
+            .first_or_octet_stream()
+            .essence_str()
+            .starts_with("text/");
+        if file.is_file() && is_text && file.size() <= MAX_ZIP_TEXT_MEMBER_SIZE {
+            let mut text = String::new();
+            // Skip members that are not valid utf-8.
+            if file.read_to_string(&mut text).is_ok() {
+                result.push(text);
+            }
+        }
+    }
+
+    Ok(result.join(" "))
 }
 
-pub async fn json_indexer<C: AsyncRead + Unpin>(
+/// Office documents indexer: indexes the document text and its core
+/// properties (title, author, subject and keywords).
+pub async fn office_indexer<C: AsyncRead + Unpin>(
     content: &mut C,
     mime: &str,
 ) -> Result<String, IndexerError> {
-    let json_indexer = match mime {
-        "application/x-places+json" => new_places_indexer(),
-        "application/x-contact+json" => new_contacts_indexer(),
-        _ => return Err(IndexerError::UnsupportedMime(mime.to_owned())),
-    };
-    json_indexer.get_text(content).await
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await?;
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer))?;
+
+    let mut result: Vec<String> = core_properties(&mut archive, mime)
+        .into_iter()
+        .map(|(_, value)| value)
+        .collect();
+    result.push(document_text(&mut archive, mime)?);
+
+    Ok(result.join(" "))
+}
+
+/// EPUB indexer: indexes the book title, its authors and the text of
+/// its chapters.
+pub async fn epub_indexer<C: AsyncRead + Unpin>(content: &mut C) -> Result<String, IndexerError> {
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await?;
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer))?;
+
+    let package = epub::package(&mut archive)
+        .ok_or_else(|| IndexerError::IndexingFailed("Missing EPUB package document".into()))?;
+    let mut result: Vec<String> = package
+        .properties()
+        .into_iter()
+        .map(|(_, value)| value)
+        .collect();
+    result.push(epub::book_text(&mut archive, &package));
+
+    Ok(result.join(" "))
+}
+
+/// Indexes the names and descriptions of the GPX document, tracks and waypoints.
+pub async fn gpx_indexer<C: AsyncRead + Unpin>(content: &mut C) -> Result<String, IndexerError> {
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await?;
+
+    Ok(gpx::parse(&buffer).text.join(" "))
+}
+
+/// PDF indexer: indexes the title, authors and keywords of the document.
+pub async fn pdf_indexer<C: AsyncRead + Unpin>(content: &mut C) -> Result<String, IndexerError> {
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await?;
+
+    let metadata = pdf::metadata(&buffer)
+        .ok_or_else(|| IndexerError::IndexingFailed("Invalid PDF document".into()))?;
+    Ok(metadata.text())
+}
+
+/// Subtitles indexer: indexes the text of the cues of SubRip and WebVTT
+/// files, without their timings.
+pub async fn subtitles_indexer<C: AsyncRead + Unpin>(
+    content: &mut C,
+) -> Result<String, IndexerError> {
+    let mut text = String::new();
+    content.read_to_string(&mut text).await?;
+
+    Ok(subtitles::text(&text))
+}
+
+/// The mime type of `.env` files, holding `KEY=value` lines.
+pub(crate) const DOTENV_MIME_TYPE: &str = "text/x-dotenv";
+
+enum ConfigFormat {
+    Yaml,
+    Toml,
+    Dotenv,
+}
+
+fn config_format(mime: &str) -> Option<ConfigFormat> {
+    match mime {
+        "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+            Some(ConfigFormat::Yaml)
+        }
+        "application/toml" | "text/x-toml" => Some(ConfigFormat::Toml),
+        DOTENV_MIME_TYPE => Some(ConfigFormat::Dotenv),
+        _ => None,
+    }
+}
+
+pub(crate) fn is_config_file(mime: &str) -> bool {
+    config_format(mime).is_some()
+}
+
+fn yaml_text(value: &serde_yaml::Value, result: &mut Vec<String>) {
+    match value {
+        serde_yaml::Value::String(text) => result.push(text.clone()),
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                yaml_text(item, result);
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (key, item) in mapping {
+                yaml_text(key, result);
+                yaml_text(item, result);
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => yaml_text(&tagged.value, result),
+        _ => {}
+    }
+}
+
+fn toml_text(value: &toml::Value, result: &mut Vec<String>) {
+    match value {
+        toml::Value::String(text) => result.push(text.clone()),
+        toml::Value::Array(items) => {
+            for item in items {
+                toml_text(item, result);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table {
+                result.push(key.clone());
+                toml_text(item, result);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Parses `KEY=value` lines, with optional `export` prefixes and quotes.
+fn dotenv_text(text: &str, result: &mut Vec<String>) {
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        if let Some((key, value)) = line.split_once('=') {
+            result.push(key.trim().to_owned());
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            if !value.is_empty() {
+                result.push(value.to_owned());
+            }
+        }
+    }
+}
+
+/// Configuration files indexer: indexes the keys and string values of
+/// YAML (all the documents of a stream), TOML and `.env` files.
+pub async fn config_indexer<C: AsyncRead + Unpin>(
+    content: &mut C,
+    mime: &str,
+) -> Result<String, IndexerError> {
+    let format =
+        config_format(mime).ok_or_else(|| IndexerError::UnsupportedMime(mime.to_owned()))?;
+    let mut text = String::new();
+    content.read_to_string(&mut text).await?;
+
+    let mut result: Vec<String> = vec![];
+    match format {
+        ConfigFormat::Yaml => {
+            for document in serde_yaml::Deserializer::from_str(&text) {
+                let value: serde_yaml::Value = serde::Deserialize::deserialize(document)?;
+                yaml_text(&value, &mut result);
+            }
+        }
+        ConfigFormat::Toml => toml_text(&toml::from_str(&text)?, &mut result),
+        ConfigFormat::Dotenv => dotenv_text(&text, &mut result),
+    }
+
+    Ok(result.join(" "))
 }
@@ -0,0 +1,122 @@
+//! Helpers to extract the text and the extent of GPX documents.
+//! A GPX file holds waypoints (`wpt`), routes (`rte`) and tracks (`trk`),
+//! each point carrying its coordinates in `lat` and `lon` attributes.
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+pub(crate) const GPX_MIME_TYPE: &str = "application/gpx+xml";
+
+/// A bounding box, in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Bounds {
+    pub(crate) min_lat: f64,
+    pub(crate) min_lon: f64,
+    pub(crate) max_lat: f64,
+    pub(crate) max_lon: f64,
+}
+
+impl Bounds {
+    fn extend(bounds: Option<Self>, lat: f64, lon: f64) -> Self {
+        match bounds {
+            Some(bounds) => Self {
+                min_lat: bounds.min_lat.min(lat),
+                min_lon: bounds.min_lon.min(lon),
+                max_lat: bounds.max_lat.max(lat),
+                max_lon: bounds.max_lon.max(lon),
+            },
+            None => Self {
+                min_lat: lat,
+                min_lon: lon,
+                max_lat: lat,
+                max_lon: lon,
+            },
+        }
+    }
+}
+
+/// The parsed GPX document.
+pub(crate) struct Gpx {
+    // Names of the tracks and routes.
+    pub(crate) names: Vec<String>,
+    // Names, descriptions and comments of the document, tracks and waypoints.
+    pub(crate) text: Vec<String>,
+    // The extent of all the points, if there are any.
+    pub(crate) bounds: Option<Bounds>,
+}
+
+impl Gpx {
+    fn add_point(&mut self, element: &BytesStart) {
+        if !matches!(element.local_name().as_ref(), b"wpt" | b"trkpt" | b"rtept") {
+            return;
+        }
+        if let (Some(lat), Some(lon)) = (coordinate(element, b"lat"), coordinate(element, b"lon")) {
+            if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+                self.bounds = Some(Bounds::extend(self.bounds, lat, lon));
+            }
+        }
+    }
+}
+
+fn coordinate(element: &BytesStart, name: &[u8]) -> Option<f64> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attr| attr.key.local_name().as_ref() == name)
+        .and_then(|attr| attr.unescape_value().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Parses a GPX document, skipping the parts that are not well formed.
+pub(crate) fn parse(xml: &[u8]) -> Gpx {
+    let mut gpx = Gpx {
+        names: vec![],
+        text: vec![],
+        bounds: None,
+    };
+
+    let mut reader = Reader::from_reader(xml);
+    let mut buffer = vec![];
+    // The enclosing elements, to tell apart track names from point names.
+    let mut stack: Vec<Vec<u8>> = vec![];
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) => {
+                gpx.add_point(&element);
+                stack.push(element.local_name().as_ref().to_vec());
+            }
+            Ok(Event::Empty(element)) => gpx.add_point(&element),
+            Ok(Event::End(_)) => {
+                stack.pop();
+            }
+            Ok(Event::Text(content)) => {
+                let field = stack.last().map(|name| name.as_slice());
+                let parent = stack
+                    .len()
+                    .checked_sub(2)
+                    .map(|index| stack[index].as_slice());
+                // Names of track and route points are usually generated, skip them.
+                if matches!(field, Some(b"name" | b"desc" | b"cmt"))
+                    && !matches!(parent, Some(b"trkpt" | b"rtept"))
+                {
+                    if let Ok(value) = content.unescape() {
+                        let value = value.trim();
+                        if !value.is_empty() {
+                            if matches!(field, Some(b"name"))
+                                && matches!(parent, Some(b"trk" | b"rte"))
+                            {
+                                gpx.names.push(value.to_owned());
+                            }
+                            gpx.text.push(value.to_owned());
+                        }
+                    }
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    gpx
+}
@@ -0,0 +1,69 @@
+//! Store health
+//! `ResourceStore::analyze()` reports how the space of a store is used and
+//! whether the index matches the file system, and `ResourceStore::compact()`
+//! reclaims the space used by unreachable blocks, old revisions and free
+//! pages of the index.
+
+use serde::Serialize;
+
+/// The number of resources listed in `StoreReport::largest_resources`.
+pub const LARGEST_COUNT: usize = 10;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StoreReport {
+    /// The number of blocks in the blockstore, shared with the profiles.
+    pub blocks: u64,
+    /// The total size of the blocks, in bytes.
+    pub block_bytes: u64,
+    /// The number of blocks reachable from the forest of any profile. Old
+    /// revisions of the files are still reachable.
+    pub reachable_blocks: u64,
+    /// The number of resources in the file system.
+    pub forest_resources: u64,
+    /// The number of resources in the index.
+    pub indexed_resources: u64,
+    /// The resources of the file system that are not indexed.
+    pub missing_from_index: Vec<Vec<String>>,
+    /// The indexed resources that are not in the file system anymore.
+    pub stale_in_index: Vec<Vec<String>>,
+    /// The largest resources with the total size of their variants, largest
+    /// first.
+    pub largest_resources: Vec<(Vec<String>, u64)>,
+    /// The total size of the default variants, in bytes.
+    pub default_variant_bytes: u64,
+    /// The total size of the other variants, like thumbnails, in bytes.
+    pub other_variant_bytes: u64,
+    /// The size of the index database file, in bytes.
+    pub index_bytes: u64,
+    /// The size of the free pages of the index database, that a compaction
+    /// gives back.
+    pub index_free_bytes: u64,
+}
+
+impl StoreReport {
+    /// The number of blocks that a compaction removes.
+    pub fn unreachable_blocks(&self) -> u64 {
+        self.blocks.saturating_sub(self.reachable_blocks)
+    }
+
+    /// Whether the index and the file system have the same resources.
+    pub fn index_is_consistent(&self) -> bool {
+        self.missing_from_index.is_empty() && self.stale_in_index.is_empty()
+    }
+}
+
+/// Options for `ResourceStore::compact()`.
+#[derive(Clone, Debug, Default)]
+pub struct CompactOptions {
+    /// Drop the previous revisions of the files by rebuilding the forest
+    /// like `ResourceStore::reencrypt_all()`, which rewrites all the content.
+    pub prune_history: bool,
+}
+
+/// The space given back by `ResourceStore::compact()`.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CompactReport {
+    pub removed_blocks: u64,
+    pub removed_block_bytes: u64,
+    pub index_bytes_freed: u64,
+}
@@ -0,0 +1,159 @@
+//! A block store backed by a plain HTTP server, to host the block space on
+//! any storage service able to serve and accept files. Blocks are read with
+//! `GET <base>/<cid>`, checked with `HEAD <base>/<cid>` and written with
+//! `PUT <base>/<cid>`. Private blocks are encrypted before they reach the
+//! block store, so the server never sees plain content.
+
+use crate::block_fetcher::BlockFetcher;
+use crate::http_client::HttpClientError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use libipld::Cid;
+use log::debug;
+use reqwest::{Method, Response, StatusCode};
+use std::time::Duration;
+use wnfs::common::BlockStore;
+
+type IpldError = libipld::error::Error;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+pub struct HttpBlockStore {
+    client: reqwest::Client,
+    base_url: String,
+    // Sent with every request, eg. for authentication.
+    headers: Vec<(String, String)>,
+    max_retries: u32,
+}
+
+impl HttpBlockStore {
+    /// Creates a store for the blocks under `base_url`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            headers: vec![],
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Adds a header sent with every request.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Authenticates requests with a bearer token.
+    pub fn bearer_token(self, token: &str) -> Self {
+        self.header("Authorization", &format!("Bearer {}", token))
+    }
+
+    /// Sets how many times failed requests are retried, waiting twice as
+    /// long before each new attempt. Defaults to 3.
+    pub fn max_retries(mut self, count: u32) -> Self {
+        self.max_retries = count;
+        self
+    }
+
+    fn url(&self, cid: &Cid) -> String {
+        format!("{}/{}", self.base_url, cid)
+    }
+
+    // Sends a request, retrying on network errors, server errors and
+    // rate limiting.
+    async fn send(
+        &self,
+        method: Method,
+        cid: &Cid,
+        body: Option<&Bytes>,
+    ) -> Result<Response, HttpClientError> {
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.request(method.clone(), self.url(cid));
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            if let Some(body) = body {
+                request = request.body(body.clone());
+            }
+
+            let result = request.send().await;
+            let retry = match &result {
+                Ok(response) => {
+                    response.status().is_server_error()
+                        || response.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(err) => err.is_timeout() || err.is_connect(),
+            };
+            if !retry || attempt >= self.max_retries {
+                return Ok(result?);
+            }
+
+            let delay = INITIAL_BACKOFF * 2u32.pow(attempt);
+            debug!("Retrying {} {} in {:?}", method, self.url(cid), delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    fn status_error(&self, response: &Response, cid: &Cid) -> HttpClientError {
+        HttpClientError::Status(response.status().as_u16(), self.url(cid))
+    }
+
+    /// Returns whether the server has this block.
+    pub async fn has_block(&self, cid: &Cid) -> Result<bool, HttpClientError> {
+        let response = self.send(Method::HEAD, cid, None).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(self.status_error(&response, cid)),
+        }
+    }
+
+    // Returns the block content, or None if the server doesn't have it.
+    async fn get(&self, cid: &Cid) -> Result<Option<Bytes>, HttpClientError> {
+        let response = self.send(Method::GET, cid, None).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?)),
+            _ => Err(self.status_error(&response, cid)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockStore for HttpBlockStore {
+    async fn get_block(&self, cid: &Cid) -> Result<Bytes, IpldError> {
+        let bytes = self
+            .get(cid)
+            .await?
+            .ok_or_else(|| HttpClientError::Status(404, self.url(cid)))?;
+        // Don't trust the server with the block integrity.
+        if self.create_cid(&bytes, cid.codec())? != *cid {
+            return Err(HttpClientError::InvalidBlock(cid.to_string()).into());
+        }
+        Ok(bytes)
+    }
+
+    async fn put_block(&self, bytes: impl Into<Bytes>, codec: u64) -> Result<Cid, IpldError> {
+        let bytes: Bytes = bytes.into();
+        let cid = self.create_cid(&bytes, codec)?;
+
+        let response = self.send(Method::PUT, &cid, Some(&bytes)).await?;
+        if !response.status().is_success() {
+            return Err(self.status_error(&response, &cid).into());
+        }
+        Ok(cid)
+    }
+}
+
+/// Lets a local store fetch the blocks it is missing from the server.
+#[async_trait(?Send)]
+impl BlockFetcher for HttpBlockStore {
+    async fn fetch_block(&self, cid: &Cid) -> Result<Option<Bytes>, std::io::Error> {
+        self.get(cid)
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+}
@@ -0,0 +1,104 @@
+//! Resumable downloads of remote resources.
+//! The content is first streamed to a local file since the store needs
+//! seekable content to run the indexers and transformers.
+
+use futures::StreamExt;
+use log::{debug, info};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, RANGE};
+use reqwest::StatusCode;
+use std::path::Path;
+use thiserror::Error;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Error, Debug)]
+pub enum HttpClientError {
+    #[error("Request error")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    #[error("Unexpected HTTP status {0} for {1}")]
+    Status(u16, String),
+    #[error("Truncated download for {0}: expected {1}b, got {2}b")]
+    Truncated(String, u64, u64),
+    #[error("Block content doesn't match its cid {0}")]
+    InvalidBlock(String),
+}
+
+pub(crate) struct Download {
+    pub(crate) mime_type: Option<String>,
+    pub(crate) size: u64,
+}
+
+/// Downloads `url` to `dest`. If `resume` is true and `dest` already holds
+/// a partial download, only the remaining bytes are requested.
+pub(crate) async fn download(
+    url: &str,
+    dest: &Path,
+    resume: bool,
+) -> Result<Download, HttpClientError> {
+    let offset = match tokio::fs::metadata(dest).await {
+        Ok(meta) if resume => meta.len(),
+        _ => 0,
+    };
+
+    let client = reqwest::Client::new();
+    let mut request = client.get(url);
+    if offset > 0 {
+        debug!("Resuming download of {} at {}", url, offset);
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    let response = request.send().await?;
+
+    let mime_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or(value).trim().to_owned());
+
+    // The server may ignore the range request, in which case we start over.
+    let append = match response.status() {
+        StatusCode::PARTIAL_CONTENT => true,
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+            // We already have the full content.
+            return Ok(Download {
+                mime_type,
+                size: offset,
+            });
+        }
+        status if status.is_success() => false,
+        status => return Err(HttpClientError::Status(status.as_u16(), url.to_owned())),
+    };
+
+    let expected = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(|len| if append { len + offset } else { len });
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(dest)
+        .await?;
+
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+
+    let size = file.metadata().await?.len();
+    if let Some(expected) = expected {
+        if expected != size {
+            return Err(HttpClientError::Truncated(url.to_owned(), expected, size));
+        }
+    }
+
+    info!("Downloaded {} ({}b)", url, size);
+
+    Ok(Download { mime_type, size })
+}
@@ -0,0 +1,227 @@
+//! Image decoding used by the thumbnailer and blurhash transformers, and to
+//! extract image properties.
+//! The `image` crate handles the common formats. Other formats like HEIC or
+//! camera RAW files can be supported by registering decoders, for instance
+//! a `CommandDecoder` running an external conversion tool.
+//! With the `svg` feature, SVG images are rasterized by an `SvgDecoder`.
+//! Only the first frame of animated GIF and PNG images is decoded.
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::io::Reader as ImageReader;
+use image::{AnimationDecoder, DynamicImage, Frame, Frames};
+use log::error;
+use std::io::{Cursor, Write};
+use std::process::{Command, Stdio};
+use std::sync::RwLock;
+
+pub trait ImageDecoder: Send + Sync {
+    /// Returns whether this decoder handles images with this mime type.
+    fn supports(&self, mime_type: &str) -> bool;
+
+    fn decode(&self, content: &[u8]) -> Result<DynamicImage, String>;
+}
+
+static DECODERS: RwLock<Vec<Box<dyn ImageDecoder>>> = RwLock::new(Vec::new());
+
+/// Registers a decoder for all the stores of this process. Registered
+/// decoders are tried before the built-in ones.
+pub fn register_image_decoder(decoder: Box<dyn ImageDecoder>) {
+    DECODERS.write().unwrap().push(decoder);
+}
+
+/// A decoder piping the content to an external command, which writes the
+/// converted image in a format supported by the `image` crate to its
+/// standard output. For instance `magick - png:-` for ImageMagick.
+pub struct CommandDecoder {
+    program: String,
+    args: Vec<String>,
+    mime_types: Vec<String>,
+}
+
+impl CommandDecoder {
+    pub fn new(program: &str, args: &[&str], mime_types: &[&str]) -> Self {
+        Self {
+            program: program.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+            mime_types: mime_types.iter().map(|mime| (*mime).to_owned()).collect(),
+        }
+    }
+}
+
+impl ImageDecoder for CommandDecoder {
+    fn supports(&self, mime_type: &str) -> bool {
+        self.mime_types.iter().any(|mime| mime == mime_type)
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<DynamicImage, String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to run {}: {}", self.program, e))?;
+
+        // Write from another thread, since the command may fill its output
+        // pipe before reading all of its input.
+        let mut stdin = child.stdin.take().ok_or("No stdin")?;
+        let input = content.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        let _ = writer.join();
+        if !output.status.success() {
+            return Err(format!("{} failed: {}", self.program, output.status));
+        }
+
+        decode_with_image_crate(output.stdout)
+    }
+}
+
+/// Rasterizes SVG images so that they fit in a `size` pixels square, over
+/// an opaque background since thumbnails are encoded as JPEG.
+#[cfg(feature = "svg")]
+pub struct SvgDecoder {
+    size: u32,
+    background: [u8; 4],
+}
+
+#[cfg(feature = "svg")]
+impl Default for SvgDecoder {
+    fn default() -> Self {
+        Self {
+            size: 512,
+            background: [255, 255, 255, 255],
+        }
+    }
+}
+
+#[cfg(feature = "svg")]
+impl SvgDecoder {
+    /// `background` is a RGBA color.
+    pub fn new(size: u32, background: [u8; 4]) -> Self {
+        Self { size, background }
+    }
+}
+
+#[cfg(feature = "svg")]
+impl ImageDecoder for SvgDecoder {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type == "image/svg+xml"
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<DynamicImage, String> {
+        use resvg::{tiny_skia, usvg};
+
+        let tree =
+            usvg::Tree::from_data(content, &usvg::Options::default()).map_err(|e| e.to_string())?;
+        let size = tree.size();
+        let scale = self.size as f32 / size.width().max(size.height());
+        let width = (size.width() * scale).round().max(1.0) as u32;
+        let height = (size.height() * scale).round().max(1.0) as u32;
+
+        let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or("Invalid SVG size")?;
+        let [r, g, b, a] = self.background;
+        pixmap.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+        resvg::render(
+            &tree,
+            tiny_skia::Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+
+        // The pixmap uses premultiplied alpha.
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|pixel| {
+                let color = pixel.demultiply();
+                [color.red(), color.green(), color.blue(), color.alpha()]
+            })
+            .collect();
+        image::RgbaImage::from_raw(width, height, pixels)
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| "Invalid SVG pixmap".to_owned())
+    }
+}
+
+pub(crate) fn decode_with_image_crate(content: Vec<u8>) -> Result<DynamicImage, String> {
+    ImageReader::new(Cursor::new(content))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .decode()
+        .map_err(|e| e.to_string())
+}
+
+// Returns the frames of a GIF or APNG image, which are decoded lazily.
+fn frames<'a>(content: &'a [u8], mime_type: &str) -> Option<Frames<'a>> {
+    match mime_type {
+        "image/gif" => Some(GifDecoder::new(Cursor::new(content)).ok()?.into_frames()),
+        "image/png" | "image/apng" => {
+            let decoder = PngDecoder::new(Cursor::new(content)).ok()?;
+            if decoder.is_apng() {
+                Some(decoder.apng().into_frames())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether the image is an animated GIF or PNG, with several frames.
+pub(crate) fn is_animated(content: &[u8], mime_type: &str) -> bool {
+    frames(content, mime_type)
+        .map(|frames| frames.take(2).count() == 2)
+        .unwrap_or_default()
+}
+
+/// Returns the first `max` frames of an animated image, or None if it is
+/// not animated.
+pub(crate) fn animation_frames(content: &[u8], mime_type: &str, max: usize) -> Option<Vec<Frame>> {
+    if !is_animated(content, mime_type) {
+        return None;
+    }
+    frames(content, mime_type)?
+        .take(max)
+        .collect::<Result<_, _>>()
+        .ok()
+}
+
+/// Decodes an image, using the first registered decoder supporting its
+/// mime type or the built-in ones. Animated images are decoded by the
+/// built-in decoders, which stop after their first frame.
+pub(crate) fn decode_image(content: Vec<u8>, mime_type: &str) -> Option<DynamicImage> {
+    if is_animated(&content, mime_type) {
+        return match frames(&content, mime_type)?.next()? {
+            Ok(frame) => Some(DynamicImage::ImageRgba8(frame.into_buffer())),
+            Err(err) => {
+                error!(
+                    "Failed to decode the first frame of {} image: {}",
+                    mime_type, err
+                );
+                None
+            }
+        };
+    }
+
+    let result = match DECODERS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|decoder| decoder.supports(mime_type))
+    {
+        Some(decoder) => decoder.decode(&content),
+        #[cfg(feature = "svg")]
+        None if mime_type == "image/svg+xml" => SvgDecoder::default().decode(&content),
+        None => decode_with_image_crate(content),
+    };
+
+    match result {
+        Ok(img) => Some(img),
+        Err(err) => {
+            error!("Failed to decode {} image: {}", mime_type, err);
+            None
+        }
+    }
+}
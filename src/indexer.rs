@@ -3,15 +3,45 @@
 //! capabilities to the resource store:
 //! - Full Text Index of resource description and mime type specific extraction.
 //! - Tag indexing
+//! - Search suggestions, from indexed terms, tags and descriptions.
 
-use crate::fts::{json_indexer, text_plain_indexer};
-use crate::resource::{ResourceId, VariantMetadata, ContentReader};
+use crate::clock::{Clock, SystemClock};
+use crate::contacts::ContactFields;
+use crate::epub::EPUB_MIME_TYPE;
+use crate::fts::{
+    config_indexer, epub_indexer, gpx_indexer, is_config_file, json_indexer, office_indexer,
+    pdf_indexer, subtitles_indexer, text_plain_indexer, zip_indexer, IndexerError,
+    DEFAULT_MAX_INDEXED_SIZE,
+};
+use crate::gpx::{Bounds, GPX_MIME_TYPE};
+use crate::metrics::{self, QueryTimer};
+use crate::office::is_office_document;
+use crate::pdf::PDF_MIME_TYPE;
+use crate::properties::{
+    extract_properties, Properties, PropertyFilter, PropertyValue, QueryRows, CAPTURED_AT,
+    IMAGE_HASH, IMPORTED_AT, LATITUDE, LONGITUDE, MAX_LATITUDE, MAX_LONGITUDE, MIN_LATITUDE,
+    MIN_LONGITUDE, ORIGINAL_NAME, ORIGIN_PROPERTIES,
+};
+use crate::resource::{
+    ContentReader, ImportOrigin, Relation, ResourceId, SearchFacets, SearchOrder, SortKey,
+    TimelineBucket, VariantMetadata,
+};
+use crate::store::{IndexOptions, JournalMode, SearchLimits, Synchronous};
+use crate::subtitles::is_subtitles;
 use crate::timer::Timer;
+use crate::tokenizer::{normalize, suggestion_terms, Tokenization};
 use futures::io::AsyncSeekExt;
 use log::{error, info};
-use rusqlite::{Connection, OpenFlags, TransactionBehavior};
+use rusqlite::functions::FunctionFlags;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{Connection, ErrorCode, OpenFlags, OptionalExtension, TransactionBehavior};
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
 use std::io::SeekFrom;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -22,6 +52,68 @@ pub enum SqliteDbError {
     SchemaUpgrade(u32, u32),
     #[error("Indexer Error")]
     Indexer(#[from] crate::fts::IndexerError),
+    #[error("Query refused: {0}")]
+    QueryRefused(String),
+    #[error("The search returned more than {0} results")]
+    TooManyResults(usize),
+}
+
+impl SqliteDbError {
+    /// Returns whether this error means that the database file is unusable.
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            SqliteDbError::Rusqlite(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase,
+                    ..
+                },
+                _,
+            ))
+        )
+    }
+
+    /// Returns whether this error means that a search ran out of time.
+    pub fn is_interrupted(&self) -> bool {
+        matches!(
+            self,
+            SqliteDbError::Rusqlite(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: ErrorCode::OperationInterrupted,
+                    ..
+                },
+                _,
+            ))
+        )
+    }
+}
+
+// The tables that custom queries can read. The full text and suggestion
+// tables are left out since their content is extracted from the resources.
+const QUERYABLE_TABLES: [&str; 7] = [
+    "resources",
+    "containers",
+    "tags",
+    "properties",
+    "smart_folder_members",
+    "subtitles",
+    "relations",
+];
+
+// The maximum number of rows returned by a custom query.
+const MAX_QUERY_ROWS: usize = 10_000;
+
+// Allows reading the queryable tables, and nothing else.
+fn authorize_query(context: AuthContext<'_>) -> Authorization {
+    match context.action {
+        AuthAction::Select | AuthAction::Function { .. } | AuthAction::Recursive => {
+            Authorization::Allow
+        }
+        AuthAction::Read { table_name, .. } if QUERYABLE_TABLES.contains(&table_name) => {
+            Authorization::Allow
+        }
+        _ => Authorization::Deny,
+    }
 }
 
 static UPGRADE_0_1_SQL: [&str; 5] = [
@@ -40,18 +132,257 @@ static UPGRADE_0_1_SQL: [&str; 5] = [
     r#"CREATE VIRTUAL TABLE fts USING fts5(id UNINDEXED, variant UNINDEXED, content, tokenize="trigram");"#,
 ];
 
-static LATEST_VERSION: u32 = 1;
+static UPGRADE_1_2_SQL: [&str; 2] = [
+    r#"CREATE TABLE IF NOT EXISTS properties(
+        id      TEXT NOT NULL,
+        variant TEXT NOT NULL,
+        name    TEXT NOT NULL,
+        value,  -- No type affinity, to store integers, reals and text.
+        FOREIGN KEY(id) REFERENCES resources(id) ON DELETE CASCADE
+    );"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_property_name_value ON properties(name, value);"#,
+];
+
+// The locations of resources, as points or bounding boxes (eg. for GPX
+// tracks) in an R*Tree for bounding box queries.
+static UPGRADE_2_3_SQL: [&str; 1] = [r#"CREATE VIRTUAL TABLE IF NOT EXISTS locations USING rtree(
+        rid,
+        min_lat, max_lat,
+        min_lon, max_lon,
+        +id      TEXT,
+        +variant TEXT
+    );"#];
+
+// Completion candidates for search suggestions: the words of the indexed
+// text and the full resource descriptions, normalized like the fts content.
+static UPGRADE_3_4_SQL: [&str; 2] = [
+    r#"CREATE TABLE IF NOT EXISTS suggestions(
+        id      TEXT NOT NULL,
+        variant TEXT NOT NULL,
+        term    TEXT NOT NULL,
+        FOREIGN KEY(id) REFERENCES resources(id) ON DELETE CASCADE
+    );"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_suggestion_term ON suggestions(term);"#,
+];
+
+// The container of each resource, ie. its parent path, to scope searches
+// to a subtree.
+static UPGRADE_4_5_SQL: [&str; 2] = [
+    r#"ALTER TABLE resources ADD COLUMN container TEXT NOT NULL DEFAULT '';"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_resource_container ON resources(container);"#,
+];
+
+// The size of the default variant of each resource, to order search results.
+// It is NULL until set from the resource metadata by the store.
+static UPGRADE_5_6_SQL: [&str; 1] = [r#"ALTER TABLE resources ADD COLUMN size INTEGER;"#];
+
+static UPGRADE_6_7_SQL: [&str; 1] = [
+    r#"CREATE TABLE IF NOT EXISTS smart_folder_members ( folder TEXT NOT NULL, id TEXT NOT NULL, PRIMARY KEY(folder, id) );"#,
+];
+
+// A weight for each indexed text, multiplying its occurrences in relevance
+// ordered searches. fts5 tables can't be altered, so the table is rebuilt.
+static UPGRADE_7_8_SQL: [&str; 4] = [
+    r#"CREATE VIRTUAL TABLE fts_weighted USING fts5(id UNINDEXED, variant UNINDEXED, weight UNINDEXED, content, tokenize="trigram");"#,
+    r#"INSERT INTO fts_weighted (id, variant, weight, content) SELECT id, variant, 1, content FROM fts;"#,
+    r#"DROP TABLE fts;"#,
+    r#"ALTER TABLE fts_weighted RENAME TO fts;"#,
+];
+
+// The video each subtitles resource belongs to, so that searching the
+// subtitles finds the video too.
+static UPGRADE_8_9_SQL: [&str; 2] = [
+    r#"CREATE TABLE IF NOT EXISTS subtitles(
+        id    TEXT PRIMARY KEY NOT NULL,
+        video TEXT NOT NULL
+    );"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_subtitles_video ON subtitles(video);"#,
+];
+
+// When each resource was captured, eg. the EXIF date of photos, as a unix
+// timestamp. It is NULL for the resources without a capture date.
+static UPGRADE_9_10_SQL: [&str; 2] = [
+    r#"ALTER TABLE resources ADD COLUMN captured INTEGER;"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_resource_captured ON resources(captured);"#,
+];
+
+// The mime type of the default variant of each resource, to list
+// containers without loading the metadata of their resources. It is NULL
+// until set from the resource metadata by the store.
+static UPGRADE_10_11_SQL: [&str; 1] = [r#"ALTER TABLE resources ADD COLUMN mime TEXT;"#];
+
+// Settings the content of the index depends on, like the tokenization of
+// the suggestions, to rebuild it when they change.
+static UPGRADE_11_12_SQL: [&str; 1] = [r#"CREATE TABLE IF NOT EXISTS index_config(
+        key   TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );"#];
+
+// The typed links between resources, recorded in their metadata.
+static UPGRADE_12_13_SQL: [&str; 3] = [
+    r#"CREATE TABLE IF NOT EXISTS relations(
+        id     TEXT NOT NULL,
+        kind   TEXT NOT NULL,
+        target TEXT NOT NULL
+    );"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_relations_id ON relations(id, kind);"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_relations_target ON relations(target, kind);"#,
+];
+
+// The containers of the resources, searched by their name, description and
+// tags. `text` holds them normalized.
+static UPGRADE_13_14_SQL: [&str; 1] = [r#"CREATE TABLE IF NOT EXISTS containers(
+        path TEXT PRIMARY KEY NOT NULL,
+        text TEXT NOT NULL
+    );"#];
+
+// The resources of a container by id, to find the siblings of a resource
+// whose names start alike without scanning the whole container.
+static UPGRADE_14_15_SQL: [&str; 2] = [
+    r#"DROP INDEX IF EXISTS idx_resource_container;"#,
+    r#"CREATE INDEX IF NOT EXISTS idx_resource_container_id ON resources(container, id);"#,
+];
+
+static LATEST_VERSION: u32 = 15;
+
+// The capture time of resources, falling back to their modification time.
+const CAPTURE_TIME: &str = "COALESCE(captured, CAST(strftime('%s', modified) AS INTEGER))";
+
+// The ids of the resources whose text matches the `?1` pattern. Matching
+// subtitles also find the video they belong to, if it exists.
+const SEARCH_HITS: &str = r#"SELECT id FROM fts WHERE content LIKE ?1
+    UNION
+    SELECT subtitles.video FROM subtitles
+    JOIN fts ON fts.id = subtitles.id
+    JOIN resources ON resources.id = subtitles.video
+    WHERE fts.content LIKE ?1"#;
+
+/// The weight of document metadata like titles and authors in relevance
+/// ordered searches, compared to the other indexed text.
+const METADATA_WEIGHT: u32 = 4;
+
+/// Returns the path of the container of a resource, joined like the ids.
+fn container_of(id: &str) -> &str {
+    id.rsplit_once('/')
+        .map(|(container, _)| container)
+        .unwrap_or("")
+}
+
+/// Adds the container of a resource and its ancestors to the containers
+/// table, searched by their name until they get a description or tags.
+fn add_containers(conn: &Connection, id: &str) -> Result<(), rusqlite::Error> {
+    let mut container = container_of(id);
+    while !container.is_empty() {
+        let name = container.rsplit('/').next().unwrap_or_default();
+        conn.execute(
+            "INSERT OR IGNORE INTO containers (path, text) VALUES (?1, ?2)",
+            (container, normalize(name)),
+        )?;
+        container = container_of(container);
+    }
+    Ok(())
+}
+
+/// Escapes the LIKE wildcards of `prefix`, using `\` as the escape character.
+fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+// Mean Earth radius, in meters.
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// Returns the great circle distance in meters between two points.
+fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+// The number of searches kept in the cache.
+const SEARCH_CACHE_SIZE: usize = 32;
+
+// Registers the `docstore_now()` SQL function, returning the time of
+// `clock` in a format understood by the SQLite date functions.
+fn register_now(conn: &Connection, clock: Arc<dyn Clock>) -> Result<(), rusqlite::Error> {
+    let clock = AssertUnwindSafe(clock);
+    conn.create_scalar_function("docstore_now", 0, FunctionFlags::SQLITE_UTF8, move |_| {
+        Ok(clock.now().format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+    })
+}
+
+// How many virtual machine instructions SQLite runs between the checks of
+// the search deadline.
+const DEADLINE_CHECK_STEPS: i32 = 1000;
+
+// Interrupts the statements of a connection running after a deadline, until
+// dropped.
+struct SearchDeadline<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> SearchDeadline<'a> {
+    fn start(conn: &'a Connection, timeout: Option<Duration>) -> Self {
+        if let Some(timeout) = timeout {
+            let deadline = Instant::now() + timeout;
+            conn.progress_handler(
+                DEADLINE_CHECK_STEPS,
+                Some(move || Instant::now() >= deadline),
+            );
+        }
+        Self { conn }
+    }
+}
+
+impl<'a> Drop for SearchDeadline<'a> {
+    fn drop(&mut self) {
+        self.conn.progress_handler(0, None::<fn() -> bool>);
+    }
+}
 
 pub struct Indexer {
     conn: Connection,
+    // Whether the database has no file, see `in_memory()`.
+    in_memory: bool,
     should_update: bool,
+    contact_fields: ContactFields,
+    max_indexed_size: u64,
+    tokenization: Tokenization,
+    search_limits: SearchLimits,
+    clock: Arc<dyn Clock>,
+    // The results of the last searches, most recent last, keyed by the
+    // normalized text. Cleared by any change to the index.
+    search_cache: RefCell<VecDeque<(String, Vec<ResourceId>)>>,
 }
 
 impl Indexer {
-    pub fn new<P: AsRef<Path>>(root_dir: P, name: &str) -> Result<Self, SqliteDbError> {
+    pub fn new<P: AsRef<Path>>(root_dir: P, options: &IndexOptions) -> Result<Self, SqliteDbError> {
         let mut path = root_dir.as_ref().to_path_buf();
-        path.push(name);
-        let mut conn = Connection::open_with_flags(&path, OpenFlags::default())?;
+        path.push("index.sqlite");
+        let conn = Connection::open_with_flags(&path, OpenFlags::default())?;
+        Self::with_connection(conn, false, options)
+    }
+
+    /// Creates an empty index in memory, which is lost when it is dropped.
+    pub fn in_memory(options: &IndexOptions) -> Result<Self, SqliteDbError> {
+        Self::with_connection(Connection::open_in_memory()?, true, options)
+    }
+
+    fn with_connection(
+        mut conn: Connection,
+        in_memory: bool,
+        options: &IndexOptions,
+    ) -> Result<Self, SqliteDbError> {
+        conn.busy_timeout(options.busy_timeout)?;
 
         let mut version: u32 =
             conn.query_row("SELECT user_version FROM pragma_user_version", [], |r| {
@@ -71,6 +402,109 @@ impl Indexer {
                     transaction.execute(sql, [])?;
                 }
                 version = 1;
+            } else if version == 1 {
+                for sql in UPGRADE_1_2_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 2;
+            } else if version == 2 {
+                for sql in UPGRADE_2_3_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 3;
+            } else if version == 3 {
+                for sql in UPGRADE_3_4_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                // Get the suggestions of the already indexed text.
+                {
+                    let mut select = transaction.prepare("SELECT id, variant, content FROM fts")?;
+                    let mut insert = transaction.prepare(
+                        "INSERT INTO suggestions (id, variant, term) VALUES (?1, ?2, ?3)",
+                    )?;
+                    let mut rows = select.query([])?;
+                    while let Some(row) = rows.next()? {
+                        let (id, variant, content): (String, String, String) =
+                            (row.get(0)?, row.get(1)?, row.get(2)?);
+                        for term in suggestion_terms(&content, Tokenization::default()) {
+                            insert.execute((&id, &variant, term))?;
+                        }
+                    }
+                }
+                version = 4;
+            } else if version == 4 {
+                for sql in UPGRADE_4_5_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                {
+                    let mut select = transaction.prepare("SELECT id FROM resources")?;
+                    let mut update =
+                        transaction.prepare("UPDATE resources SET container = ?1 WHERE id = ?2")?;
+                    let mut rows = select.query([])?;
+                    while let Some(row) = rows.next()? {
+                        let id: String = row.get(0)?;
+                        update.execute((container_of(&id), &id))?;
+                    }
+                }
+                version = 5;
+            } else if version == 5 {
+                for sql in UPGRADE_5_6_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 6;
+            } else if version == 6 {
+                for sql in UPGRADE_6_7_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 7;
+            } else if version == 7 {
+                for sql in UPGRADE_7_8_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 8;
+            } else if version == 8 {
+                for sql in UPGRADE_8_9_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 9;
+            } else if version == 9 {
+                for sql in UPGRADE_9_10_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 10;
+            } else if version == 10 {
+                for sql in UPGRADE_10_11_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 11;
+            } else if version == 11 {
+                for sql in UPGRADE_11_12_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 12;
+            } else if version == 12 {
+                for sql in UPGRADE_12_13_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 13;
+            } else if version == 13 {
+                for sql in UPGRADE_13_14_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                {
+                    let mut select = transaction.prepare("SELECT id FROM resources")?;
+                    let mut rows = select.query([])?;
+                    while let Some(row) = rows.next()? {
+                        let id: String = row.get(0)?;
+                        add_containers(&transaction, &id)?;
+                    }
+                }
+                version = 14;
+            } else if version == 14 {
+                for sql in UPGRADE_14_15_SQL {
+                    transaction.execute(sql, [])?;
+                }
+                version = 15;
             } else {
                 error!("Unexpected version required: {}", version);
                 return Err(SqliteDbError::SchemaUpgrade(version, version));
@@ -83,24 +517,112 @@ impl Indexer {
             transaction.commit()?;
         }
 
-        conn.pragma_update(None, "journal_mode", "WAL".to_string())?;
+        let journal_mode = match options.journal_mode {
+            JournalMode::Wal => "WAL",
+            JournalMode::Delete => "DELETE",
+        };
+        conn.pragma_update(None, "journal_mode", journal_mode)?;
+        let synchronous = match options.synchronous {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+        };
+        conn.pragma_update(None, "synchronous", synchronous)?;
+        if let Some(size) = options.cache_size {
+            // Negative sizes are in KB rather than pages.
+            conn.pragma_update(None, "cache_size", -(size as i64))?;
+        }
+
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        register_now(&conn, clock.clone())?;
 
         Ok(Self {
             conn,
+            in_memory,
             should_update: false,
+            contact_fields: ContactFields::default(),
+            max_indexed_size: DEFAULT_MAX_INDEXED_SIZE,
+            tokenization: Tokenization::default(),
+            search_limits: SearchLimits::default(),
+            clock,
+            search_cache: RefCell::new(VecDeque::with_capacity(SEARCH_CACHE_SIZE)),
         })
     }
 
+    /// Sets the clock giving the modification dates, and the current time
+    /// decaying the frecency scores.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> Result<(), SqliteDbError> {
+        register_now(&self.conn, clock.clone())?;
+        self.clock = clock;
+        Ok(())
+    }
+
+    /// Sets how the words of the suggestions are found. Returns whether the
+    /// index was built with another tokenization, and must be rebuilt
+    /// before calling `record_tokenization()`.
+    pub fn set_tokenization(&mut self, tokenization: Tokenization) -> Result<bool, SqliteDbError> {
+        self.tokenization = tokenization;
+        let recorded: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM index_config WHERE key = 'tokenization'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(recorded.as_deref() != Some(tokenization.name()))
+    }
+
+    /// Records the tokenization the index was built with.
+    pub fn record_tokenization(&mut self) -> Result<(), SqliteDbError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO index_config (key, value) VALUES ('tokenization', ?)",
+            [self.tokenization.name()],
+        )?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Sets the limits of the number of results and duration of searches.
+    pub fn set_search_limits(&mut self, limits: SearchLimits) {
+        self.search_limits = limits;
+    }
+
+    // Interrupts the search queries running longer than the search timeout.
+    fn search_deadline(&self) -> SearchDeadline<'_> {
+        SearchDeadline::start(&self.conn, self.search_limits.timeout)
+    }
+
+    // Fails once a search returns more than the maximum number of results.
+    fn check_result_count(&self, count: usize) -> Result<(), SqliteDbError> {
+        match self.search_limits.max_results {
+            Some(max) if count > max => Err(SqliteDbError::TooManyResults(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets which members of contacts are indexed.
+    pub fn set_contact_fields(&mut self, fields: ContactFields) {
+        self.contact_fields = fields;
+    }
+
+    /// Sets the size above which the text of variants is not indexed, except
+    /// for the beginning of plain text ones.
+    pub fn set_max_indexed_size(&mut self, size: u64) {
+        self.max_indexed_size = size;
+    }
+
     pub fn add_resource(&mut self, id: &ResourceId) -> Result<(), SqliteDbError> {
         let _timer = Timer::start(&format!("Indexer add resource {}", id.to_string()));
-        let now = chrono::Utc::now();
+        let now = self.clock.now();
         self.conn
             .execute(
-                "INSERT INTO resources (id, frecency, modified) VALUES (?1, ?2, ?3)",
-                (id, 0, now),
+                "INSERT INTO resources (id, frecency, modified, container) VALUES (?1, ?2, ?3, ?4)",
+                (id, 0, now, container_of(&id.to_string())),
             )
             .map(|_| ())?;
-        self.should_update = true;
+        add_containers(&self.conn, &id.to_string())?;
+        self.set_changed();
         Ok(())
     }
 
@@ -112,7 +634,144 @@ impl Indexer {
         self.conn
             .execute("DELETE FROM fts  WHERE id = ?", [id])
             .map(|_| ())?;
-        self.should_update = true;
+        self.conn
+            .execute("DELETE FROM properties WHERE id = ?", [id])
+            .map(|_| ())?;
+        self.conn
+            .execute("DELETE FROM locations WHERE id = ?", [id])
+            .map(|_| ())?;
+        self.conn
+            .execute("DELETE FROM suggestions WHERE id = ?", [id])
+            .map(|_| ())?;
+        // Links to a deleted video are kept, in case it is created again.
+        self.conn
+            .execute("DELETE FROM subtitles WHERE id = ?", [id])
+            .map(|_| ())?;
+        // Relations to a deleted resource are kept, in case it is created
+        // again.
+        self.conn
+            .execute("DELETE FROM relations WHERE id = ?", [id])
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Replaces the relations of a resource.
+    pub fn set_relations(
+        &mut self,
+        id: &ResourceId,
+        relations: &[Relation],
+    ) -> Result<(), SqliteDbError> {
+        let transaction = self.conn.transaction()?;
+        transaction.execute("DELETE FROM relations WHERE id = ?", [id])?;
+        for relation in relations {
+            let target: ResourceId = relation.target.as_slice().into();
+            transaction.execute(
+                "INSERT INTO relations (id, kind, target) VALUES (?1, ?2, ?3)",
+                (id, &relation.kind, &target),
+            )?;
+        }
+        transaction.commit()?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Returns the existing resources linked to `id` by relations of this
+    /// kind, or of any kind if None, in both directions.
+    pub fn related(
+        &self,
+        id: &ResourceId,
+        kind: Option<&str>,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!(
+            "Indexer related to {} by {:?}",
+            id.to_string(),
+            kind
+        ));
+
+        let mut stmt = self.conn.prepare(
+            r#"SELECT target FROM relations
+               WHERE id = ?1 AND (?2 IS NULL OR kind = ?2)
+               AND target IN (SELECT id FROM resources)
+               UNION
+               SELECT id FROM relations WHERE target = ?1 AND (?2 IS NULL OR kind = ?2)
+               ORDER BY 1"#,
+        )?;
+        let mut rows = stmt.query((id, kind))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Sets the text a container is searched by, see
+    /// `ContainerMetadata::text()`. `path` is joined like the resource ids.
+    pub fn set_container(&mut self, path: &str, text: &str) -> Result<(), SqliteDbError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO containers (path, text) VALUES (?1, ?2)",
+            (path, normalize(text)),
+        )?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Returns the paths of the containers whose name, description or tags
+    /// contain `text`, joined like the resource ids.
+    pub fn search_containers(&self, text: &str) -> Result<Vec<String>, SqliteDbError> {
+        let text = normalize(text);
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!("Indexer search containers {}", text));
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path FROM containers WHERE text LIKE ? ORDER BY path")?;
+        let mut rows = stmt.query([format!("%{}%", text)])?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+            self.check_result_count(result.len())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Removes all the resources from the index, before reindexing them.
+    /// Every table of the schema is emptied, except `index_config` and the
+    /// internal tables of SQLite and of the virtual tables.
+    pub fn clear(&mut self) -> Result<(), SqliteDbError> {
+        let transaction = self.conn.transaction()?;
+        let tables: Vec<(String, String)> = {
+            let mut stmt =
+                transaction.prepare("SELECT name, sql FROM sqlite_master WHERE type = 'table'")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                ))
+            })?;
+            rows.collect::<Result<_, _>>()?
+        };
+        let virtual_tables: Vec<&String> = tables
+            .iter()
+            .filter(|(_, sql)| sql.starts_with("CREATE VIRTUAL TABLE"))
+            .map(|(name, _)| name)
+            .collect();
+        for (table, _) in &tables {
+            let internal = table.starts_with("sqlite_")
+                || virtual_tables
+                    .iter()
+                    .any(|name| table.starts_with(&format!("{}_", name)));
+            if internal || table == "index_config" {
+                continue;
+            }
+            transaction.execute(&format!("DELETE FROM \"{}\"", table), [])?;
+        }
+        transaction.commit()?;
+        self.set_changed();
         Ok(())
     }
 
@@ -128,121 +787,1290 @@ impl Indexer {
                 (id, variant),
             )
             .map(|_| ())?;
-        self.should_update = true;
-        Ok(())
-    }
-
-    pub fn add_tag(&mut self, id: &ResourceId, tag: &str) -> Result<(), SqliteDbError> {
-        let _timer = Timer::start(&format!("Indexer add tag {} to {}", tag, id.to_string()));
         self.conn
-            .execute("INSERT INTO tags (id, tag) VALUES (?1, ?2)", (id, tag))
+            .execute(
+                "DELETE FROM properties WHERE id = ?1 AND variant = ?2",
+                (id, variant),
+            )
             .map(|_| ())?;
-        self.should_update = true;
+        self.conn
+            .execute(
+                "DELETE FROM locations WHERE id = ?1 AND variant = ?2",
+                (id, variant),
+            )
+            .map(|_| ())?;
+        self.conn
+            .execute(
+                "DELETE FROM suggestions WHERE id = ?1 AND variant = ?2",
+                (id, variant),
+            )
+            .map(|_| ())?;
+        if variant == "default" {
+            self.set_captured(id, None)?;
+        }
+        self.set_changed();
         Ok(())
     }
 
-    pub fn remove_tag(&mut self, id: &ResourceId, tag: &str) -> Result<(), SqliteDbError> {
-        let _timer = Timer::start(&format!("Indexer remove tag {} to {}", tag, id.to_string()));
+    /// Records the video that the subtitles `id` belong to, or removes the
+    /// link.
+    pub fn set_subtitled_video(
+        &mut self,
+        id: &ResourceId,
+        video: Option<&ResourceId>,
+    ) -> Result<(), SqliteDbError> {
         self.conn
-            .execute("DELETE FROM tags WHERE id=?1 and tag=?2", (id, tag))
+            .execute("DELETE FROM subtitles WHERE id = ?", [id])
             .map(|_| ())?;
-        self.should_update = true;
+        if let Some(video) = video {
+            self.conn
+                .execute(
+                    "INSERT INTO subtitles (id, video) VALUES (?1, ?2)",
+                    (id, video),
+                )
+                .map(|_| ())?;
+        }
+        self.set_changed();
         Ok(())
     }
 
-    pub fn add_text(
+    /// Records when a resource was captured, from its default variant.
+    fn set_captured(
         &mut self,
         id: &ResourceId,
-        variant_name: &str,
-        text: &str,
+        captured: Option<i64>,
     ) -> Result<(), SqliteDbError> {
-        let _timer = Timer::start(&format!(
-            "Indexer add text to {} [{}]",
-            id.to_string(),
-            variant_name
-        ));
-
-        // Remove diacritics since the trigram tokenizer of SQlite doesn't have this option.
-        let content = secular::lower_lay_string(text);
         self.conn
             .execute(
-                "INSERT INTO fts (id, variant, content) VALUES (?1, ?2, ?3)",
-                (id, variant_name, &content),
+                "UPDATE resources SET captured = ?1 WHERE id = ?2",
+                (captured, id),
             )
             .map(|_| ())?;
-        self.should_update = true;
+        self.set_changed();
         Ok(())
     }
 
-    pub async fn add_variant<C: ContentReader>(
-        &mut self,
-        id: &ResourceId,
-        variant_name: &str,
-        variant: &VariantMetadata,
-        content: &mut C,
-    ) -> Result<(), SqliteDbError> {
-        let _timer = Timer::start(&format!(
-            "Indexer add content to {} [{}]",
-            id.to_string(),
-            variant_name
-        ));
+    /// Returns the resources grouped by capture date, or modification date
+    /// for those without one, in `bucket`s from the most recent, along with
+    /// the bucket names like `2024-05-17`, `2024-05` or `2024`.
+    pub fn timeline(
+        &self,
+        bucket: TimelineBucket,
+    ) -> Result<Vec<(String, Vec<ResourceId>)>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer timeline by {:?}", bucket));
 
-        let mime = variant.mime_type().to_owned();
-        let text = if mime.ends_with("json") {
-            Some(json_indexer(content, &mime).await?)
-        } else {
-            match mime.as_str() {
-                "text/plain" => Some(text_plain_indexer(content).await?),
-                _ => None,
-            }
+        let format = match bucket {
+            TimelineBucket::Day => "%Y-%m-%d",
+            TimelineBucket::Month => "%Y-%m",
+            TimelineBucket::Year => "%Y",
         };
-
-        if let Some(text) = text {
-            {
-                self.add_text(id, variant_name, &text)?;
+        let mut stmt = self.conn.prepare(&format!(
+            r#"SELECT strftime(?1, {0}, 'unixepoch'), id FROM resources
+               ORDER BY {0} DESC, id"#,
+            CAPTURE_TIME
+        ))?;
+        let mut rows = stmt.query([format])?;
+        let mut result: Vec<(String, Vec<ResourceId>)> = vec![];
+        while let Some(row) = rows.next()? {
+            let (name, id): (String, ResourceId) = (row.get(0)?, row.get(1)?);
+            match result.last_mut() {
+                Some((last, ids)) if *last == name => ids.push(id),
+                _ => result.push((name, vec![id])),
             }
         }
 
-        content
-            .seek(SeekFrom::Start(0))
-            .await
-            .expect("Failed to seek!!");
+        Ok(result)
+    }
 
+    /// Records the size of the default variant of a resource.
+    pub fn set_size(&mut self, id: &ResourceId, size: u64) -> Result<(), SqliteDbError> {
+        self.conn
+            .execute("UPDATE resources SET size = ?1 WHERE id = ?2", (size, id))
+            .map(|_| ())?;
+        self.set_changed();
         Ok(())
     }
 
-    pub async fn update_variant<C: ContentReader>(
-        &mut self,
-        id: &ResourceId,
-        variant_name: &str,
-        variant: &VariantMetadata,
-        content: &mut C,
+    /// Records the mime type of the default variant of a resource.
+    pub fn set_mime_type(&mut self, id: &ResourceId, mime: &str) -> Result<(), SqliteDbError> {
+        self.conn
+            .execute("UPDATE resources SET mime = ?1 WHERE id = ?2", (mime, id))
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Returns the resources without a recorded size or mime type, which
+    /// were indexed before they were.
+    pub fn missing_summaries(&self) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id FROM resources WHERE size IS NULL OR mime IS NULL")?;
+        let mut rows = stmt.query([])?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Updates the modification date of a resource.
+    pub fn touch(&mut self, id: &ResourceId) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!("Indexer touch {}", id.to_string()));
+        let now = self.clock.now();
+        self.conn
+            .execute(
+                "UPDATE resources SET modified = ?1 WHERE id = ?2",
+                (now, id),
+            )
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Bumps the frecency score of a resource when its content is accessed.
+    /// This doesn't flag the index as needing an update since that would
+    /// turn every read into a write: the new score is persisted with the
+    /// next mutation.
+    pub fn visit(&self, id: &ResourceId) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!("Indexer visit {}", id.to_string()));
+        self.conn
+            .execute(
+                "UPDATE resources SET frecency = frecency + 1 WHERE id = ?",
+                [id],
+            )
+            .map(|_| ())?;
+        Ok(())
+    }
+
+    pub fn add_tag(&mut self, id: &ResourceId, tag: &str) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!("Indexer add tag {} to {}", tag, id.to_string()));
+        self.conn
+            .execute("INSERT INTO tags (id, tag) VALUES (?1, ?2)", (id, tag))
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    pub fn remove_tag(&mut self, id: &ResourceId, tag: &str) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!("Indexer remove tag {} to {}", tag, id.to_string()));
+        self.conn
+            .execute("DELETE FROM tags WHERE id=?1 and tag=?2", (id, tag))
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    pub fn add_text(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        text: &str,
+    ) -> Result<(), SqliteDbError> {
+        self.add_weighted_text(id, variant_name, text, 1)
+    }
+
+    /// Like `add_text()`, counting each occurrence `weight` times when
+    /// ordering by relevance.
+    pub fn add_weighted_text(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        text: &str,
+        weight: u32,
+    ) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!(
+            "Indexer add text to {} [{}]",
+            id.to_string(),
+            variant_name
+        ));
+
+        // Remove diacritics since the trigram tokenizer of SQlite doesn't have this option.
+        let content = normalize(text);
+        self.conn
+            .execute(
+                "INSERT INTO fts (id, variant, weight, content) VALUES (?1, ?2, ?3, ?4)",
+                (id, variant_name, weight, &content),
+            )
+            .map(|_| ())?;
+        for term in suggestion_terms(&content, self.tokenization) {
+            self.add_suggestion(id, variant_name, term)?;
+        }
+        self.set_changed();
+        Ok(())
+    }
+
+    fn add_suggestion(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        term: &str,
+    ) -> Result<(), SqliteDbError> {
+        self.conn
+            .execute(
+                "INSERT INTO suggestions (id, variant, term) VALUES (?1, ?2, ?3)",
+                (id, variant_name, term),
+            )
+            .map(|_| ())?;
+        Ok(())
+    }
+
+    /// Indexes the description of a resource, which is also suggested as
+    /// a whole.
+    pub fn add_description(&mut self, id: &ResourceId, desc: &str) -> Result<(), SqliteDbError> {
+        self.add_text(id, "default", desc)?;
+        let desc = normalize(desc.trim());
+        // Single word descriptions are already suggested as terms.
+        if !desc.is_empty() && !suggestion_terms(&desc, self.tokenization).contains(desc.as_str()) {
+            self.add_suggestion(id, "default", &desc)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a description indexed by `add_description()`, leaving the
+    /// text and suggestions of the default variant content in place.
+    pub fn remove_description(&mut self, id: &ResourceId, desc: &str) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!("Indexer remove description of {}", id.to_string()));
+
+        let content = normalize(desc);
+        self.conn.execute(
+            r#"DELETE FROM fts WHERE rowid = (SELECT rowid FROM fts
+               WHERE id = ?1 AND variant = 'default' AND content = ?2 LIMIT 1)"#,
+            (id, &content),
+        )?;
+        let whole = normalize(desc.trim());
+        let mut terms: Vec<&str> = suggestion_terms(&content, self.tokenization)
+            .into_iter()
+            .collect();
+        if !whole.is_empty() && !terms.contains(&whole.as_str()) {
+            terms.push(&whole);
+        }
+        // The same terms may come from the content, only remove one of each.
+        for term in terms {
+            self.conn.execute(
+                r#"DELETE FROM suggestions WHERE rowid = (SELECT rowid FROM suggestions
+                   WHERE id = ?1 AND variant = 'default' AND term = ?2 LIMIT 1)"#,
+                (id, term),
+            )?;
+        }
+        self.set_changed();
+        Ok(())
+    }
+
+    pub fn add_property(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        name: &str,
+        value: &PropertyValue,
+    ) -> Result<(), SqliteDbError> {
+        self.conn
+            .execute(
+                "INSERT INTO properties (id, variant, name, value) VALUES (?1, ?2, ?3, ?4)",
+                (id, variant_name, name, value),
+            )
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    /// Indexes the origin of an imported resource as properties. They are
+    /// kept apart from the ones of the default variant, since they don't
+    /// change with its content. The source path is left out.
+    pub fn add_origin(
+        &mut self,
+        id: &ResourceId,
+        origin: &ImportOrigin,
+    ) -> Result<(), SqliteDbError> {
+        self.add_property(
+            id,
+            ORIGIN_PROPERTIES,
+            ORIGINAL_NAME,
+            &origin.file_name.as_str().into(),
+        )?;
+        self.add_property(
+            id,
+            ORIGIN_PROPERTIES,
+            IMPORTED_AT,
+            &origin.imported_at.into(),
+        )
+    }
+
+    pub fn add_location(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        latitude: f64,
+        longitude: f64,
+    ) -> Result<(), SqliteDbError> {
+        self.add_bounds(id, variant_name, latitude, longitude, latitude, longitude)
+    }
+
+    pub fn add_bounds(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Result<(), SqliteDbError> {
+        self.conn
+            .execute(
+                "INSERT INTO locations (min_lat, max_lat, min_lon, max_lon, id, variant) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                (min_lat, max_lat, min_lon, max_lon, id, variant_name),
+            )
+            .map(|_| ())?;
+        self.set_changed();
+        Ok(())
+    }
+
+    // Returns the (id, bounds) of resources overlapping this bounding box.
+    fn locations_in(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Result<Vec<(ResourceId, Bounds)>, SqliteDbError> {
+        let mut stmt = self.conn.prepare(
+            r#"SELECT id, min_lat, min_lon, max_lat, max_lon FROM locations
+               WHERE max_lat >= ?1 AND min_lat <= ?3 AND max_lon >= ?2 AND min_lon <= ?4"#,
+        )?;
+        let mut rows = stmt.query((min_lat, min_lon, max_lat, max_lon))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push((
+                row.get(0)?,
+                Bounds {
+                    min_lat: row.get(1)?,
+                    min_lon: row.get(2)?,
+                    max_lat: row.get(3)?,
+                    max_lon: row.get(4)?,
+                },
+            ));
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the resources located in this bounding box, or with an extent
+    /// overlapping it.
+    pub fn within_bounds(
+        &self,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!(
+            "Indexer within bounds ({}, {}) ({}, {})",
+            min_lat, min_lon, max_lat, max_lon
+        ));
+
+        let mut result: Vec<ResourceId> = vec![];
+        for (id, _) in self.locations_in(min_lat, min_lon, max_lat, max_lon)? {
+            if !result.contains(&id) {
+                result.push(id);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the resources located less than `radius` meters away from
+    /// this point, closest first.
+    pub fn near(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!(
+            "Indexer near ({}, {}) {}m",
+            latitude, longitude, radius
+        ));
+
+        // Pre-filter with a bounding box containing the circle, which is not
+        // accurate close to the poles and the antimeridian.
+        let delta_lat = (radius / EARTH_RADIUS).to_degrees();
+        let delta_lon = delta_lat / latitude.to_radians().cos().max(f64::EPSILON);
+
+        let mut candidates: Vec<(ResourceId, f64)> = vec![];
+        for (id, bounds) in self.locations_in(
+            latitude - delta_lat,
+            longitude - delta_lon,
+            latitude + delta_lat,
+            longitude + delta_lon,
+        )? {
+            // The distance to the closest point of the extent.
+            let distance = haversine_distance(
+                latitude,
+                longitude,
+                latitude.clamp(bounds.min_lat, bounds.max_lat),
+                longitude.clamp(bounds.min_lon, bounds.max_lon),
+            );
+            if distance > radius {
+                continue;
+            }
+            // Keep the closest location when several variants have one.
+            match candidates.iter_mut().find(|(item, _)| *item == id) {
+                Some(candidate) => candidate.1 = candidate.1.min(distance),
+                None => candidates.push((id, distance)),
+            }
+        }
+
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+        Ok(candidates.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Returns the resources with a default variant image hash at most
+    /// `max_distance` bits away from `hash`, closest first.
+    pub fn similar_images(
+        &self,
+        hash: i64,
+        max_distance: u32,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!(
+            "Indexer similar images {:016x} {}",
+            hash, max_distance
+        ));
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, value FROM properties WHERE name = ?1 AND variant = 'default'")?;
+        let mut rows = stmt.query([IMAGE_HASH])?;
+        let mut candidates: Vec<(ResourceId, u32)> = vec![];
+        while let Some(row) = rows.next()? {
+            let value: i64 = row.get(1)?;
+            let distance = (value ^ hash).count_ones();
+            if distance <= max_distance {
+                candidates.push((row.get(0)?, distance));
+            }
+        }
+
+        candidates.sort_by_key(|(_, distance)| *distance);
+        Ok(candidates.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Returns the properties of a resource variant.
+    pub fn properties(
+        &self,
+        id: &ResourceId,
+        variant_name: &str,
+    ) -> Result<Properties, SqliteDbError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, value FROM properties WHERE id = ?1 AND variant = ?2")?;
+        let mut rows = stmt.query((id, variant_name))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push((row.get(0)?, row.get(1)?));
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the resources matching all the filters.
+    pub fn query_properties(
+        &self,
+        filters: &[PropertyFilter],
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer query properties {:?}", filters));
+
+        if filters.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut queries = vec![];
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![];
+        for filter in filters {
+            let (name, condition, values) = filter.condition();
+            queries.push(format!(
+                "SELECT DISTINCT id FROM properties WHERE name = ? AND {}",
+                condition
+            ));
+            params.push(name);
+            for value in values {
+                params.push(value);
+            }
+        }
+
+        let mut stmt = self.conn.prepare(&queries.join(" INTERSECT "))?;
+        let mut rows = stmt.query(params.as_slice())?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns whether the content of variants with this mime type is
+    /// used for full text search or to extract properties.
+    pub fn indexes_content(mime: &str) -> bool {
+        mime.ends_with("json")
+            || is_office_document(mime)
+            || mime.starts_with("image/")
+            || mime == "text/plain"
+            || mime == "application/zip"
+            || mime == EPUB_MIME_TYPE
+            || mime == GPX_MIME_TYPE
+            || mime == PDF_MIME_TYPE
+            || is_subtitles(mime)
+            || is_config_file(mime)
+    }
+
+    pub async fn add_variant<C: ContentReader>(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: &mut C,
+    ) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start(&format!(
+            "Indexer add content to {} [{}]",
+            id.to_string(),
+            variant_name
+        ));
+
+        let mime = variant.mime_type().to_owned();
+        if variant_name == "default" {
+            self.set_mime_type(id, &mime)?;
+        }
+        // Plain text and json are streamed, but the other structured formats
+        // need their whole content to be parsed, so they are only indexed
+        // when small enough to be loaded in memory.
+        let too_large = variant.size() > self.max_indexed_size;
+        let text = if mime == "text/plain" {
+            Some(text_plain_indexer(content, self.max_indexed_size).await?)
+        } else if mime.ends_with("json") {
+            match json_indexer(content, &mime, &self.contact_fields, self.max_indexed_size).await {
+                Err(IndexerError::TooLarge(_)) => {
+                    info!(
+                        "Not indexing the {} variant of {}: {} bytes",
+                        variant_name,
+                        id.to_string(),
+                        variant.size()
+                    );
+                    None
+                }
+                result => Some(result?),
+            }
+        } else if too_large {
+            info!(
+                "Not indexing the {} variant of {}: {} bytes",
+                variant_name,
+                id.to_string(),
+                variant.size()
+            );
+            None
+        } else if is_office_document(&mime) {
+            Some(office_indexer(content, &mime).await?)
+        } else if is_config_file(&mime) {
+            Some(config_indexer(content, &mime).await?)
+        } else if is_subtitles(&mime) {
+            Some(subtitles_indexer(content).await?)
+        } else {
+            match mime.as_str() {
+                "application/zip" => Some(zip_indexer(content).await?),
+                EPUB_MIME_TYPE => Some(epub_indexer(content).await?),
+                GPX_MIME_TYPE => Some(gpx_indexer(content).await?),
+                PDF_MIME_TYPE => Some(pdf_indexer(content).await?),
+                _ => None,
+            }
+        };
+
+        if let Some(text) = text {
+            // Only the metadata of PDF documents is indexed.
+            let weight = if mime == PDF_MIME_TYPE {
+                METADATA_WEIGHT
+            } else {
+                1
+            };
+            self.add_weighted_text(id, variant_name, &text, weight)?;
+        }
+
+        content
+            .seek(SeekFrom::Start(0))
+            .await
+            .expect("Failed to seek!!");
+
+        let (mut latitude, mut longitude) = (None, None);
+        let mut extent = [None; 4];
+        for (name, value) in extract_properties(content, &mime).await {
+            match (name.as_str(), &value) {
+                (CAPTURED_AT, PropertyValue::Integer(v)) if variant_name == "default" => {
+                    self.set_captured(id, Some(*v))?
+                }
+                (LATITUDE, PropertyValue::Real(v)) => latitude = Some(*v),
+                (LONGITUDE, PropertyValue::Real(v)) => longitude = Some(*v),
+                (MIN_LATITUDE, PropertyValue::Real(v)) => extent[0] = Some(*v),
+                (MIN_LONGITUDE, PropertyValue::Real(v)) => extent[1] = Some(*v),
+                (MAX_LATITUDE, PropertyValue::Real(v)) => extent[2] = Some(*v),
+                (MAX_LONGITUDE, PropertyValue::Real(v)) => extent[3] = Some(*v),
+                _ => {}
+            }
+            self.add_property(id, variant_name, &name, &value)?;
+        }
+        if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+            self.add_location(id, variant_name, latitude, longitude)?;
+        } else if let [Some(min_lat), Some(min_lon), Some(max_lat), Some(max_lon)] = extent {
+            self.add_bounds(id, variant_name, min_lat, min_lon, max_lat, max_lon)?;
+        }
+
+        content
+            .seek(SeekFrom::Start(0))
+            .await
+            .expect("Failed to seek!!");
+
+        Ok(())
+    }
+
+    pub async fn update_variant<C: ContentReader>(
+        &mut self,
+        id: &ResourceId,
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: &mut C,
     ) -> Result<(), SqliteDbError> {
         self.delete_variant(id, variant_name)?;
         self.add_variant(id, variant_name, variant, content).await
     }
 
+    /// Returns whether the indexed text of a resource contains `text`.
+    pub fn has_text(&self, id: &ResourceId, text: &str) -> Result<bool, SqliteDbError> {
+        let search = format!("%{}%", normalize(text));
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT 1 FROM fts WHERE id = ?1 AND content LIKE ?2 LIMIT 1")?;
+        Ok(stmt.exists((id, search))?)
+    }
+
     pub fn search(&self, text: &str) -> Result<Vec<ResourceId>, SqliteDbError> {
+        // Interactive searches often repeat, eg. when deleting typed text.
+        let text = normalize(text);
+        if let Some((_, result)) = self
+            .search_cache
+            .borrow()
+            .iter()
+            .find(|(cached, _)| *cached == text)
+        {
+            metrics::search_cache_hit();
+            return Ok(result.clone());
+        }
+
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
         let _timer = Timer::start(&format!("Indexer search {}", text));
 
-        let search = format!("%{}%", secular::lower_lay_string(text));
+        let search = format!("%{}%", text);
+
+        let mut stmt = self.conn.prepare(SEARCH_HITS)?;
+        let mut rows = stmt.query([search])?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0).unwrap());
+            self.check_result_count(result.len())?;
+        }
+
+        let mut cache = self.search_cache.borrow_mut();
+        if cache.len() == SEARCH_CACHE_SIZE {
+            cache.pop_front();
+        }
+        cache.push_back((text, result.clone()));
+
+        Ok(result)
+    }
+
+    /// Like `search()`, with the results in this order.
+    pub fn search_ordered(
+        &self,
+        text: &str,
+        order: SearchOrder,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!("Indexer search {} by {:?}", text, order));
+
+        let text = normalize(text);
+        let key = match order.key {
+            // The weighted number of occurrences in the description and variants.
+            SortKey::Relevance => {
+                "SUM(fts.weight * (length(fts.content) - length(replace(fts.content, ?2, ''))) / length(?2))"
+            }
+            SortKey::Modified => "resources.modified",
+            SortKey::Size => "COALESCE(resources.size, 0)",
+            SortKey::Frecency => {
+                "resources.frecency / (1.0 + julianday(docstore_now()) - julianday(resources.modified))"
+            }
+        };
+        let direction = if order.descending { "DESC" } else { "ASC" };
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"SELECT fts.id FROM fts JOIN resources ON resources.id = fts.id
+               WHERE fts.content LIKE ?1
+               GROUP BY fts.id
+               ORDER BY {} {}, fts.id"#,
+            key, direction
+        ))?;
+        let mut params = vec![format!("%{}%", text)];
+        if order.key == SortKey::Relevance {
+            params.push(text);
+        }
+        let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+            self.check_result_count(result.len())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the resources with this tag, and containing `text` if set,
+    /// along with their size. Without text, the relevance order falls back
+    /// to the resource ids.
+    pub fn tagged_ordered(
+        &self,
+        tag: &str,
+        text: Option<&str>,
+        order: SearchOrder,
+    ) -> Result<Vec<(ResourceId, u64)>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!(
+            "Indexer tagged {} with {:?} by {:?}",
+            tag, text, order
+        ));
+
+        let text = text.map(normalize);
+        let key = match order.key {
+            SortKey::Relevance if text.is_some() => {
+                "SUM(fts.weight * (length(fts.content) - length(replace(fts.content, ?3, ''))) / length(?3))"
+            }
+            SortKey::Relevance => "0",
+            SortKey::Modified => "resources.modified",
+            SortKey::Size => "COALESCE(resources.size, 0)",
+            SortKey::Frecency => {
+                "resources.frecency / (1.0 + julianday(docstore_now()) - julianday(resources.modified))"
+            }
+        };
+        let direction = if order.descending { "DESC" } else { "ASC" };
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"SELECT resources.id, COALESCE(resources.size, 0) FROM resources
+               JOIN tags ON tags.id = resources.id
+               LEFT JOIN fts ON fts.id = resources.id
+               WHERE tags.tag = ?1 AND (?2 IS NULL OR fts.content LIKE ?2)
+               GROUP BY resources.id
+               ORDER BY {} {}, resources.id"#,
+            key, direction
+        ))?;
+        let mut params = vec![
+            Some(tag.to_owned()),
+            text.as_ref().map(|text| format!("%{}%", text)),
+        ];
+        if order.key == SortKey::Relevance && text.is_some() {
+            params.push(text);
+        }
+        let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push((row.get(0)?, row.get(1)?));
+            self.check_result_count(result.len())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the last recorded members of a smart folder.
+    pub fn smart_folder_members(&self, folder: &str) -> Result<HashSet<ResourceId>, SqliteDbError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id FROM smart_folder_members WHERE folder = ?")?;
+        let mut rows = stmt.query([folder])?;
+        let mut result = HashSet::new();
+        while let Some(row) = rows.next()? {
+            result.insert(row.get(0)?);
+        }
+        Ok(result)
+    }
+
+    /// Records the members of a smart folder, replacing the previous ones.
+    pub fn set_smart_folder_members(
+        &mut self,
+        folder: &str,
+        members: &HashSet<ResourceId>,
+    ) -> Result<(), SqliteDbError> {
+        let transaction = self.conn.transaction()?;
+        transaction.execute(
+            "DELETE FROM smart_folder_members WHERE folder = ?",
+            [folder],
+        )?;
+        for id in members {
+            transaction.execute(
+                "INSERT INTO smart_folder_members (folder, id) VALUES (?1, ?2)",
+                (folder, id),
+            )?;
+        }
+        transaction.commit()?;
+        self.set_changed();
+        Ok(())
+    }
 
+    /// Forgets the members of the smart folders that are not in `folders`.
+    pub fn retain_smart_folders(&mut self, folders: &[String]) -> Result<(), SqliteDbError> {
         let mut stmt = self
             .conn
-            .prepare("SELECT DISTINCT id from fts WHERE content LIKE ?")?;
+            .prepare("SELECT DISTINCT folder FROM smart_folder_members")?;
+        let mut rows = stmt.query([])?;
+        let mut stale = vec![];
+        while let Some(row) = rows.next()? {
+            let folder: String = row.get(0)?;
+            if !folders.contains(&folder) {
+                stale.push(folder);
+            }
+        }
+        drop(rows);
+        drop(stmt);
+        for folder in stale {
+            self.conn.execute(
+                "DELETE FROM smart_folder_members WHERE folder = ?",
+                [folder],
+            )?;
+            self.set_changed();
+        }
+        Ok(())
+    }
+
+    /// Like `search()`, only returning the resources of the `container`
+    /// subtree. `container` is a path joined like the resource ids.
+    pub fn search_in(&self, text: &str, container: &str) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!("Indexer search {} in {}", text, container));
+
+        let search = format!("%{}%", normalize(text));
+
+        let mut stmt = self.conn.prepare(
+            r#"SELECT DISTINCT fts.id FROM fts JOIN resources ON resources.id = fts.id
+               WHERE fts.content LIKE ?1
+               AND (resources.container = ?2 OR substr(resources.container, 1, length(?3)) = ?3)"#,
+        )?;
+        let mut rows = stmt.query((search, container, format!("{}/", container)))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+            self.check_result_count(result.len())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Counts the resources matching `search()` by tag, top-level mime type
+    /// and year of capture, most frequent values first.
+    pub fn search_facets(&self, text: &str) -> Result<SearchFacets, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!("Indexer search facets {}", text));
+
+        let search = format!("%{}%", normalize(text));
+        let facet = |value: &str, join: &str| -> Result<Vec<(String, usize)>, SqliteDbError> {
+            let mut stmt = self.conn.prepare(&format!(
+                r#"WITH hits AS ({})
+                   SELECT {} AS value, COUNT(DISTINCT hits.id) AS count FROM hits {}
+                   WHERE value IS NOT NULL AND value != ''
+                   GROUP BY value
+                   ORDER BY count DESC, value"#,
+                SEARCH_HITS, value, join
+            ))?;
+            let mut rows = stmt.query([&search])?;
+            let mut result = vec![];
+            while let Some(row) = rows.next()? {
+                result.push((row.get(0)?, row.get(1)?));
+            }
+            Ok(result)
+        };
+
+        Ok(SearchFacets {
+            tags: facet("tags.tag", "JOIN tags ON tags.id = hits.id")?,
+            mime_categories: facet(
+                "CASE WHEN instr(resources.mime, '/') > 0
+                 THEN substr(resources.mime, 1, instr(resources.mime, '/') - 1)
+                 ELSE resources.mime END",
+                "JOIN resources ON resources.id = hits.id",
+            )?,
+            years: facet(
+                &format!("strftime('%Y', {}, 'unixepoch')", CAPTURE_TIME),
+                "JOIN resources ON resources.id = hits.id",
+            )?,
+        })
+    }
+
+    /// Like `search()`, also returning the tags of each resource.
+    pub fn search_with_tags(
+        &self,
+        text: &str,
+    ) -> Result<Vec<(ResourceId, Vec<String>)>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!("Indexer search with tags {}", text));
+
+        let search = format!("%{}%", normalize(text));
+
+        let mut stmt = self.conn.prepare(
+            r#"SELECT hits.id, tags.tag
+               FROM (SELECT DISTINCT id FROM fts WHERE content LIKE ?) AS hits
+               LEFT JOIN tags ON tags.id = hits.id
+               ORDER BY hits.id, tags.tag"#,
+        )?;
         let mut rows = stmt.query([search])?;
+        let mut result: Vec<(ResourceId, Vec<String>)> = vec![];
+        while let Some(row) = rows.next()? {
+            let id: ResourceId = row.get(0)?;
+            let tag: Option<String> = row.get(1)?;
+            if result.last().map(|(last, _)| *last != id).unwrap_or(true) {
+                result.push((id, vec![]));
+            }
+            if let (Some(tag), Some((_, tags))) = (tag, result.last_mut()) {
+                tags.push(tag);
+            }
+        }
+        self.check_result_count(result.len())?;
+
+        Ok(result)
+    }
+
+    /// Searches the text indexed for these variants, or for all of them if
+    /// `variants` is empty. Returns the matching variant names for each
+    /// resource.
+    pub fn search_variants(
+        &self,
+        text: &str,
+        variants: &[&str],
+    ) -> Result<Vec<(ResourceId, Vec<String>)>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _deadline = self.search_deadline();
+        let _timer = Timer::start(&format!("Indexer search {} in {:?}", text, variants));
+
+        let search = format!("%{}%", normalize(text));
+
+        let mut sql = "SELECT DISTINCT id, variant FROM fts WHERE content LIKE ?".to_owned();
+        if !variants.is_empty() {
+            sql.push_str(&format!(
+                " AND variant IN ({})",
+                vec!["?"; variants.len()].join(", ")
+            ));
+        }
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&search];
+        for variant in variants {
+            params.push(variant);
+        }
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut rows = stmt.query(params.as_slice())?;
+        let mut result: Vec<(ResourceId, Vec<String>)> = vec![];
+        while let Some(row) = rows.next()? {
+            let id: ResourceId = row.get(0)?;
+            let variant: String = row.get(1)?;
+            match result.iter_mut().find(|(item, _)| *item == id) {
+                Some((_, variants)) => variants.push(variant),
+                None => result.push((id, vec![variant])),
+            }
+        }
+        self.check_result_count(result.len())?;
+
+        Ok(result)
+    }
+
+    fn query_ids(&self, sql: &str, limit: u32) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let mut rows = stmt.query([limit])?;
         let mut result = vec![];
         while let Some(row) = rows.next()? {
-            result.push(row.get(0).unwrap());
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns up to `limit` completions for `prefix`, drawn from the
+    /// indexed words, the tags and the resource descriptions. The ones
+    /// used by the most resources come first.
+    pub fn suggest(&self, prefix: &str, limit: u32) -> Result<Vec<String>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer suggest {}", prefix));
+
+        let pattern = like_prefix(&normalize(prefix));
+        let mut stmt = self.conn.prepare(
+            r#"SELECT term, COUNT(DISTINCT id) AS count FROM (
+                 SELECT term, id FROM suggestions WHERE term LIKE ?1 ESCAPE '\'
+                 UNION ALL
+                 SELECT tag AS term, id FROM tags
+                 WHERE tag LIKE ?1 ESCAPE '\' AND id IN (SELECT id FROM resources)
+               )
+               GROUP BY term ORDER BY count DESC, term LIMIT ?2"#,
+        )?;
+        let mut rows = stmt.query((pattern, limit))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns up to `limit` resource ids starting with `prefix`, in
+    /// lexicographic order. Unlike `suggest()` the match is case sensitive,
+    /// to complete resource paths.
+    pub fn ids_with_prefix(
+        &self,
+        prefix: &str,
+        limit: u32,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer ids with prefix {}", prefix));
+
+        // No UTF-8 string contains 0xff, so this range holds exactly the ids
+        // starting with the prefix, and is looked up in the primary key.
+        let mut stmt = self.conn.prepare(
+            "SELECT id FROM resources WHERE id >= ?1 AND id < ?1 || x'ff' ORDER BY id LIMIT ?2",
+        )?;
+        let mut rows = stmt.query((prefix, limit))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Like `ids_with_prefix()`, only returning the resources directly in
+    /// `container`.
+    pub fn container_ids_with_prefix(
+        &self,
+        container: &str,
+        prefix: &str,
+        limit: u32,
+    ) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!(
+            "Indexer ids with prefix {} in {}",
+            prefix, container
+        ));
+
+        let mut stmt = self.conn.prepare(
+            r#"SELECT id FROM resources WHERE container = ?1 AND id >= ?2 AND id < ?2 || x'ff'
+               ORDER BY id LIMIT ?3"#,
+        )?;
+        let mut rows = stmt.query((container, prefix, limit))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the id, default variant mime type, size and modification
+    /// date of the resources directly in `container`, by id.
+    pub fn container_summaries(
+        &self,
+        container: &str,
+    ) -> Result<
+        Vec<(
+            ResourceId,
+            Option<String>,
+            Option<u64>,
+            chrono::DateTime<chrono::Utc>,
+        )>,
+        SqliteDbError,
+    > {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer container summaries {}", container));
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, mime, size, modified FROM resources WHERE container = ? ORDER BY id",
+        )?;
+        let mut rows = stmt.query([container])?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?));
         }
 
         Ok(result)
     }
 
+    /// Runs a custom read-only query, eg. to count resources by tag or mime
+    /// type. Only the resources, containers, tags, properties,
+    /// smart_folder_members, subtitles and relations tables can be read, and
+    /// blob values are not supported.
+    pub fn query_rows(
+        &self,
+        sql: &str,
+        params: &[PropertyValue],
+    ) -> Result<QueryRows, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer query rows {}", sql));
+
+        // The authorizer is only needed while the statement is compiled.
+        self.conn.authorizer(Some(authorize_query));
+        let stmt = self.conn.prepare(sql);
+        self.conn
+            .authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+        let mut stmt = stmt.map_err(|err| match err {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: ErrorCode::AuthorizationForStatementDenied,
+                    ..
+                },
+                _,
+            ) => SqliteDbError::QueryRefused(format!(
+                "only the {} tables can be read",
+                QUERYABLE_TABLES.join(", ")
+            )),
+            err => err.into(),
+        })?;
+        if !stmt.readonly() {
+            return Err(SqliteDbError::QueryRefused(
+                "only read-only statements are allowed".into(),
+            ));
+        }
+
+        let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            if result.len() == MAX_QUERY_ROWS {
+                return Err(SqliteDbError::QueryRefused(format!(
+                    "more than {} rows returned",
+                    MAX_QUERY_ROWS
+                )));
+            }
+            result.push(
+                (0..columns.len())
+                    .map(|index| row.get(index))
+                    .collect::<Result<Vec<Option<PropertyValue>>, _>>()?,
+            );
+        }
+
+        Ok(QueryRows {
+            columns,
+            rows: result,
+        })
+    }
+
+    /// Returns the resources having this tag.
+    pub fn by_tag(&self, tag: &str) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer by tag {}", tag));
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT id FROM tags WHERE tag = ?")?;
+        let mut rows = stmt.query([tag])?;
+        let mut result = vec![];
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the most recently modified resources.
+    pub fn recent(&self, limit: u32) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer recent {}", limit));
+
+        self.query_ids(
+            "SELECT id FROM resources ORDER BY modified DESC LIMIT ?",
+            limit,
+        )
+    }
+
+    /// Returns the resources with the best frecency score, decayed by the
+    /// number of days since their last modification.
+    pub fn suggested(&self, limit: u32) -> Result<Vec<ResourceId>, SqliteDbError> {
+        let _query = QueryTimer::start();
+        let _timer = Timer::start(&format!("Indexer suggested {}", limit));
+
+        self.query_ids(
+            r#"SELECT id FROM resources
+               ORDER BY frecency / (1.0 + julianday(docstore_now()) - julianday(modified)) DESC, modified DESC
+               LIMIT ?"#,
+            limit,
+        )
+    }
+
+    /// Moves the content of the write-ahead log to the database file, so that
+    /// the file can be copied on its own.
+    pub fn checkpoint(&self) -> Result<(), SqliteDbError> {
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn is_in_memory(&self) -> bool {
+        self.in_memory
+    }
+
+    /// Writes a copy of the database to the `dest` file, which must not
+    /// exist, eg. to save an index kept in memory.
+    pub fn write_to(&self, dest: &Path) -> Result<(), SqliteDbError> {
+        self.conn
+            .execute("VACUUM INTO ?1", [dest.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// Returns the size of the database file and of its free pages, in bytes.
+    pub fn file_usage(&self) -> Result<(u64, u64), SqliteDbError> {
+        let pragma = |name: &str| -> Result<u64, SqliteDbError> {
+            Ok(self
+                .conn
+                .query_row(&format!("PRAGMA {}", name), [], |r| r.get(0))?)
+        };
+        let page_size = pragma("page_size")?;
+        Ok((
+            pragma("page_count")? * page_size,
+            pragma("freelist_count")? * page_size,
+        ))
+    }
+
+    /// Merges the segments of the full text index and refreshes the query
+    /// planner statistics. The indexed content doesn't change.
+    pub fn optimize(&self) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start("Indexer optimize");
+        self.conn
+            .execute_batch("INSERT INTO fts(fts) VALUES('optimize'); PRAGMA optimize;")?;
+        Ok(())
+    }
+
+    /// Rebuilds the database file to give back its free pages.
+    pub fn vacuum(&self) -> Result<(), SqliteDbError> {
+        let _timer = Timer::start("Indexer vacuum");
+        self.conn.execute_batch("VACUUM")?;
+        self.checkpoint()
+    }
+
     pub fn set_updated(&mut self) {
         self.should_update = false;
     }
 
+    // Records a change to the index, which makes cached searches outdated.
+    fn set_changed(&mut self) {
+        self.should_update = true;
+        self.search_cache.get_mut().clear();
+    }
+
     #[inline(always)]
     pub fn should_update(&self) -> bool {
         self.should_update
@@ -1,7 +1,50 @@
+pub mod access;
+pub mod backup;
+#[cfg(feature = "bitswap")]
+pub mod bitswap;
+pub mod block_fetcher;
+pub mod bookmarks;
+pub mod changes;
+pub mod chunking;
+pub mod clock;
+pub mod contacts;
+pub mod containers;
+mod epub;
 mod file_store;
+pub mod frame_extractors;
 pub(crate) mod fts;
+mod gpx;
+pub mod health;
+#[cfg(feature = "http-client")]
+pub mod http_block_store;
+#[cfg(feature = "http-client")]
+pub mod http_client;
+pub mod image_decoders;
 mod indexer;
+pub mod locks;
+pub mod maintenance;
+pub mod metrics;
+mod office;
+mod paths;
+mod pdf;
+pub mod photos;
+pub mod properties;
+#[cfg(feature = "age")]
+pub mod recovery;
+pub mod recovery_shares;
 pub mod resource;
+pub mod rules;
+pub mod settings;
+pub mod sharing;
+pub mod smart_folders;
 pub mod store;
+mod subtitles;
+pub mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod text_recognizers;
 pub(crate) mod timer;
+pub mod tokenizer;
+pub mod tombstones;
 pub mod transformers;
+pub mod validators;
@@ -0,0 +1,158 @@
+//! Resource locks
+//! Mutations lock the resources they change until they are committed or
+//! rolled back. Applications can also lock a resource or a container with
+//! `ResourceStore::try_lock_resource()`, for instance while a document is
+//! edited: the store then refuses to change it with
+//! `StoreError::ResourceLocked` until the returned `ResourceLock` is
+//! dropped. The holder of the lock changes the resources through
+//! `ResourceLock::allow_changes()`.
+//!
+//! Locking a container locks all the resources under it. `ResourceLocks`
+//! handles are shared with the store, so that a user interface can tell
+//! whether a resource is being modified without waiting for the store.
+//!
+//! Variants pushed with `ResourceStore::add_variant_writer()` lock their
+//! resource while the content is streamed, until the writer is closed or
+//! dropped.
+//!
+//! Running mutations on different resources concurrently is out of scope:
+//! the store isn't `Send`, and every change writes a new revision of the
+//! shared resources directory, which would need merging the revisions
+//! written concurrently from the same one. Mutations all need
+//! `&mut ResourceStore`, and these locks are what lets a user interface
+//! tell that a document is being modified instead of blocking on it.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Whether one path is the same as, or contains, the other.
+fn overlaps(a: &[String], b: &[String]) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+struct HeldLock {
+    id: u64,
+    path: Vec<String>,
+    // Whether the holder currently lets changes through.
+    allowed: bool,
+}
+
+#[derive(Default)]
+struct LockState {
+    // The locks held by `ResourceLock`s.
+    held: Vec<HeldLock>,
+    // The paths changed by the ongoing mutation.
+    mutating: Vec<Vec<String>>,
+    next_id: u64,
+}
+
+impl LockState {
+    fn held_mut(&mut self, id: u64) -> Option<&mut HeldLock> {
+        self.held.iter_mut().find(|held| held.id == id)
+    }
+}
+
+/// A handle on the resource locks of a store.
+#[derive(Clone, Default)]
+pub struct ResourceLocks {
+    state: Rc<RefCell<LockState>>,
+}
+
+impl ResourceLocks {
+    /// Whether changes to `path` would be refused, or are ongoing.
+    pub fn is_locked(&self, path: &[String]) -> bool {
+        let state = self.state.borrow();
+        state
+            .held
+            .iter()
+            .map(|held| &held.path)
+            .chain(state.mutating.iter())
+            .any(|locked| overlaps(locked, path))
+    }
+
+    /// Locks `path`, or returns None if it is already locked or being
+    /// modified.
+    pub fn try_lock(&self, path: &[String]) -> Option<ResourceLock> {
+        if self.is_locked(path) {
+            return None;
+        }
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.held.push(HeldLock {
+            id,
+            path: path.to_vec(),
+            allowed: false,
+        });
+        Some(ResourceLock {
+            id,
+            path: path.to_vec(),
+            state: self.state.clone(),
+        })
+    }
+
+    // Whether a `ResourceLock` prevents changes to `path`.
+    pub(crate) fn is_held(&self, path: &[String]) -> bool {
+        self.state
+            .borrow()
+            .held
+            .iter()
+            .any(|held| !held.allowed && overlaps(&held.path, path))
+    }
+
+    // Records that the ongoing mutation changes `path`.
+    pub(crate) fn begin_change(&self, path: &[String]) {
+        self.state.borrow_mut().mutating.push(path.to_vec());
+    }
+
+    // Releases the paths of the mutation once it is over.
+    pub(crate) fn end_changes(&self) {
+        self.state.borrow_mut().mutating.clear();
+    }
+}
+
+/// A lock on a resource or a container, released when dropped.
+pub struct ResourceLock {
+    id: u64,
+    path: Vec<String>,
+    state: Rc<RefCell<LockState>>,
+}
+
+impl ResourceLock {
+    pub fn path(&self) -> &[String] {
+        &self.path
+    }
+
+    /// Lets the store change the locked resources while the returned guard
+    /// is alive, for the holder of the lock to save its changes. They are
+    /// still reported as locked, and can't be locked again, meanwhile.
+    pub fn allow_changes(&self) -> AllowedChanges<'_> {
+        if let Some(held) = self.state.borrow_mut().held_mut(self.id) {
+            held.allowed = true;
+        }
+        AllowedChanges { lock: self }
+    }
+}
+
+impl Drop for ResourceLock {
+    fn drop(&mut self) {
+        self.state
+            .borrow_mut()
+            .held
+            .retain(|held| held.id != self.id);
+    }
+}
+
+/// Changes to the resources of a `ResourceLock` allowed by its holder,
+/// refused again when dropped.
+pub struct AllowedChanges<'a> {
+    lock: &'a ResourceLock,
+}
+
+impl Drop for AllowedChanges<'_> {
+    fn drop(&mut self) {
+        if let Some(held) = self.lock.state.borrow_mut().held_mut(self.lock.id) {
+            held.allowed = false;
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Background maintenance
+//! `ResourceStore::start_maintenance()` runs the housekeeping tasks of a
+//! store on their own schedule: removing unreachable blocks, optimizing the
+//! index, backfilling lazy thumbnails and pruning the history of the files.
+//!
+//! The store is not `Send`, so the maintenance runs as a local task sharing
+//! the store with the application, and must be started from a `LocalSet`.
+//! Tasks only run when nobody else holds the store, and thumbnails are
+//! created in small batches, releasing the store in between, so that the
+//! maintenance doesn't delay the application.
+
+use crate::health::CompactOptions;
+use crate::store::{ResourceStore, StoreError};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+
+// How long to wait before trying again when the store is in use.
+const BUSY_RETRY: Duration = Duration::from_secs(1);
+
+/// How often each maintenance task runs, or None to never run it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MaintenancePolicy {
+    /// Removes the blocks that are not reachable anymore and gives back the
+    /// free pages of the index, see `ResourceStore::compact()`.
+    pub compact: Option<Duration>,
+    /// See `ResourceStore::optimize_index()`.
+    pub optimize_index: Option<Duration>,
+    /// Creates up to `thumbnail_batch` missing thumbnails.
+    pub backfill_thumbnails: Option<Duration>,
+    pub thumbnail_batch: usize,
+    /// Compacts the store and drops the previous revisions of the files,
+    /// which rewrites all the content.
+    pub prune_history: Option<Duration>,
+}
+
+impl Default for MaintenancePolicy {
+    /// Compacts daily and optimizes the index every 6 hours, backfills a few
+    /// thumbnails every minute and keeps the history.
+    fn default() -> Self {
+        Self {
+            compact: Some(Duration::from_secs(24 * 3600)),
+            optimize_index: Some(Duration::from_secs(6 * 3600)),
+            backfill_thumbnails: Some(Duration::from_secs(60)),
+            thumbnail_batch: 4,
+            prune_history: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Task {
+    Compact,
+    OptimizeIndex,
+    BackfillThumbnails,
+    PruneHistory,
+}
+
+impl Task {
+    fn period(&self, policy: &MaintenancePolicy) -> Option<Duration> {
+        match self {
+            Self::Compact => policy.compact,
+            Self::OptimizeIndex => policy.optimize_index,
+            Self::BackfillThumbnails => policy.backfill_thumbnails,
+            Self::PruneHistory => policy.prune_history,
+        }
+    }
+
+    async fn run(
+        &self,
+        store: &mut ResourceStore,
+        policy: &MaintenancePolicy,
+    ) -> Result<(), StoreError> {
+        match self {
+            Self::Compact => {
+                let report = store.compact(CompactOptions::default()).await?;
+                debug!("Maintenance compaction: {:?}", report);
+            }
+            Self::OptimizeIndex => store.optimize_index()?,
+            Self::BackfillThumbnails => {
+                let count = store.backfill_thumbnails(policy.thumbnail_batch).await?;
+                debug!("Maintenance created {} thumbnails", count);
+            }
+            Self::PruneHistory => {
+                let report = store
+                    .compact(CompactOptions {
+                        prune_history: true,
+                    })
+                    .await?;
+                debug!("Maintenance history pruning: {:?}", report);
+            }
+        }
+        Ok(())
+    }
+}
+
+const TASKS: [Task; 4] = [
+    Task::Compact,
+    Task::OptimizeIndex,
+    Task::BackfillThumbnails,
+    Task::PruneHistory,
+];
+
+/// The running maintenance task, see `ResourceStore::start_maintenance()`.
+/// Dropping the handle also stops the maintenance.
+pub struct MaintenanceHandle {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl MaintenanceHandle {
+    /// Stops the maintenance, waiting for the running task to complete.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+pub(crate) fn spawn(
+    store: Rc<Mutex<ResourceStore>>,
+    policy: MaintenancePolicy,
+) -> MaintenanceHandle {
+    let (stop, mut stopped) = oneshot::channel();
+
+    let task = tokio::task::spawn_local(async move {
+        let start = Instant::now();
+        let mut next_runs: Vec<(Task, Instant)> = TASKS
+            .iter()
+            .filter_map(|task| Some((*task, start + task.period(&policy)?)))
+            .collect();
+
+        loop {
+            let (index, due) = match next_runs
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (_, due))| *due)
+            {
+                Some((index, (_, due))) => (index, *due),
+                None => return,
+            };
+            tokio::select! {
+                _ = &mut stopped => return,
+                _ = sleep_until(due) => {}
+            }
+
+            let task = next_runs[index].0;
+            let mut store = match store.try_lock() {
+                Ok(store) => store,
+                Err(_) => {
+                    next_runs[index].1 = Instant::now() + BUSY_RETRY;
+                    continue;
+                }
+            };
+            if let Err(err) = task.run(&mut store, &policy).await {
+                error!("Maintenance task {:?} failed: {}", task, err);
+            }
+            drop(store);
+
+            if let Some(period) = task.period(&policy) {
+                next_runs[index].1 = Instant::now() + period;
+            }
+        }
+    });
+
+    MaintenanceHandle { stop, task }
+}
@@ -0,0 +1,209 @@
+//! Process wide metrics, rendered in the Prometheus text format by
+//! `render()` so that servers embedding the store can expose them.
+//!
+//! Block reads are counted by source: `pending` for blocks read back while
+//! their write is in flight (the block cache), `disk` and `fetcher`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bounds of the index query latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+pub(crate) enum BlockSource {
+    Pending,
+    Disk,
+    Fetcher,
+}
+
+struct Histogram {
+    // Cumulative counts, one per bucket.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    // The sum of the observed values, in microseconds.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            buckets: [ZERO; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add((seconds * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+}
+
+struct Metrics {
+    operations: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    pending_reads: AtomicU64,
+    disk_reads: AtomicU64,
+    fetcher_reads: AtomicU64,
+    search_cache_hits: AtomicU64,
+    query_latency: Histogram,
+}
+
+static METRICS: Metrics = Metrics {
+    operations: Mutex::new(BTreeMap::new()),
+    bytes_read: AtomicU64::new(0),
+    bytes_written: AtomicU64::new(0),
+    pending_reads: AtomicU64::new(0),
+    disk_reads: AtomicU64::new(0),
+    fetcher_reads: AtomicU64::new(0),
+    search_cache_hits: AtomicU64::new(0),
+    query_latency: Histogram::new(),
+};
+
+/// Counts a store operation of this type.
+pub(crate) fn count_operation(name: &'static str) {
+    *METRICS.operations.lock().unwrap().entry(name).or_default() += 1;
+}
+
+pub(crate) fn block_read(source: BlockSource, size: usize) {
+    let counter = match source {
+        BlockSource::Pending => &METRICS.pending_reads,
+        BlockSource::Disk => &METRICS.disk_reads,
+        BlockSource::Fetcher => &METRICS.fetcher_reads,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    METRICS.bytes_read.fetch_add(size as _, Ordering::Relaxed);
+}
+
+pub(crate) fn block_written(size: usize) {
+    METRICS
+        .bytes_written
+        .fetch_add(size as _, Ordering::Relaxed);
+}
+
+pub(crate) fn search_cache_hit() {
+    METRICS.search_cache_hits.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the latency of an index query when dropped.
+pub(crate) struct QueryTimer {
+    start: Instant,
+}
+
+impl QueryTimer {
+    pub(crate) fn start() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        METRICS
+            .query_latency
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Returns the current metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP docstore_operations_total Store operations, by type."
+    );
+    let _ = writeln!(out, "# TYPE docstore_operations_total counter");
+    for (name, count) in METRICS.operations.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "docstore_operations_total{{operation=\"{}\"}} {}",
+            name, count
+        );
+    }
+
+    counter(
+        &mut out,
+        "docstore_block_bytes_read_total",
+        "Bytes of blocks read.",
+        METRICS.bytes_read.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "docstore_block_bytes_written_total",
+        "Bytes of blocks written.",
+        METRICS.bytes_written.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP docstore_block_reads_total Block reads, by source. Reads from `pending` are block cache hits."
+    );
+    let _ = writeln!(out, "# TYPE docstore_block_reads_total counter");
+    for (source, counter) in [
+        ("pending", &METRICS.pending_reads),
+        ("disk", &METRICS.disk_reads),
+        ("fetcher", &METRICS.fetcher_reads),
+    ] {
+        let _ = writeln!(
+            out,
+            "docstore_block_reads_total{{source=\"{}\"}} {}",
+            source,
+            counter.load(Ordering::Relaxed)
+        );
+    }
+
+    counter(
+        &mut out,
+        "docstore_search_cache_hits_total",
+        "Searches answered from the index cache.",
+        METRICS.search_cache_hits.load(Ordering::Relaxed),
+    );
+
+    let histogram = &METRICS.query_latency;
+    let _ = writeln!(
+        out,
+        "# HELP docstore_index_query_seconds Latency of index queries."
+    );
+    let _ = writeln!(out, "# TYPE docstore_index_query_seconds histogram");
+    for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+        let _ = writeln!(
+            out,
+            "docstore_index_query_seconds_bucket{{le=\"{}\"}} {}",
+            bound,
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let _ = writeln!(
+        out,
+        "docstore_index_query_seconds_bucket{{le=\"+Inf\"}} {}",
+        count
+    );
+    let _ = writeln!(
+        out,
+        "docstore_index_query_seconds_sum {}",
+        histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "docstore_index_query_seconds_count {}", count);
+
+    out
+}
@@ -0,0 +1,132 @@
+//! Helpers to extract text and core properties from office documents.
+//! Both OOXML (docx, xlsx, pptx) and ODF (odt, ods, odp) documents are
+//! zip archives holding xml parts.
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+pub(crate) type Archive = ZipArchive<Cursor<Vec<u8>>>;
+
+pub(crate) fn is_ooxml(mime: &str) -> bool {
+    mime.starts_with("application/vnd.openxmlformats-officedocument.")
+}
+
+pub(crate) fn is_odf(mime: &str) -> bool {
+    mime.starts_with("application/vnd.oasis.opendocument.")
+}
+
+pub(crate) fn is_office_document(mime: &str) -> bool {
+    is_ooxml(mime) || is_odf(mime)
+}
+
+fn read_part(archive: &mut Archive, name: &str) -> Option<String> {
+    let mut part = archive.by_name(name).ok()?;
+    let mut xml = String::new();
+    part.read_to_string(&mut xml).ok()?;
+    Some(xml)
+}
+
+/// Returns the names of the parts holding the document text.
+fn text_parts(archive: &Archive, mime: &str) -> Vec<String> {
+    if is_odf(mime) {
+        return vec!["content.xml".to_owned()];
+    }
+
+    let mut parts: Vec<String> = archive
+        .file_names()
+        .filter(|name| {
+            *name == "word/document.xml"
+                || *name == "xl/sharedStrings.xml"
+                || (name.starts_with("ppt/slides/slide") && name.ends_with(".xml"))
+        })
+        .map(|name| name.to_owned())
+        .collect();
+    // Keep slides in a stable order.
+    parts.sort();
+    parts
+}
+
+/// Concatenates the text nodes of an xml document, separating paragraphs.
+fn xml_text(xml: &str, text: &mut String) -> Result<(), quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event()? {
+            Event::Text(content) => text.push_str(&content.unescape()?),
+            Event::End(element) => {
+                // End of paragraphs (w:p, a:p, text:p) and headings (text:h).
+                if matches!(element.local_name().as_ref(), b"p" | b"h") {
+                    text.push(' ');
+                }
+            }
+            Event::Empty(element) => {
+                // Tabs and explicit spaces.
+                if matches!(element.local_name().as_ref(), b"tab" | b"s") {
+                    text.push(' ');
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the text content of the document.
+pub(crate) fn document_text(archive: &mut Archive, mime: &str) -> Result<String, quick_xml::Error> {
+    let mut text = String::new();
+    for part in text_parts(archive, mime) {
+        if let Some(xml) = read_part(archive, &part) {
+            xml_text(&xml, &mut text)?;
+        }
+    }
+    Ok(text)
+}
+
+/// Returns the core properties of the document: title, author, subject and keywords.
+pub(crate) fn core_properties(archive: &mut Archive, mime: &str) -> Vec<(String, String)> {
+    let part = if is_odf(mime) {
+        "meta.xml"
+    } else {
+        "docProps/core.xml"
+    };
+    let xml = match read_part(archive, part) {
+        Some(xml) => xml,
+        None => return vec![],
+    };
+
+    let mut properties = vec![];
+    let mut reader = Reader::from_str(&xml);
+    let mut current: Option<&'static str> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                current = match element.local_name().as_ref() {
+                    b"title" => Some("title"),
+                    b"creator" | b"initial-creator" => Some("author"),
+                    b"subject" => Some("subject"),
+                    b"keywords" | b"keyword" => Some("keywords"),
+                    _ => None,
+                };
+            }
+            Ok(Event::Text(content)) => {
+                if let (Some(name), Ok(value)) = (current, content.unescape()) {
+                    let value = value.trim();
+                    // Only keep the first author, ODF documents have both
+                    // an initial and a current creator.
+                    let known = properties
+                        .iter()
+                        .any(|(n, _)| n == name && name == "author");
+                    if !value.is_empty() && !known {
+                        properties.push((name.to_owned(), value.to_owned()));
+                    }
+                }
+            }
+            Ok(Event::End(_)) => current = None,
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    properties
+}
@@ -0,0 +1,65 @@
+//! Paths of the files of the store.
+//! On Windows, paths longer than MAX_PATH (260 characters) are only usable
+//! with the `\\?\` prefix, and deep roots easily go over this limit since
+//! the block files are named after their cid. Prefixed paths are not
+//! normalized by the system, so they must be absolute and only made of
+//! normal components.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Returns the absolute form of `path`, resolving its `.` and `..`
+/// components lexically.
+pub(crate) fn absolute(path: &Path) -> std::io::Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            other => result.push(other.as_os_str()),
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the absolute form of `path`, that is not subject to the MAX_PATH
+/// limit on Windows.
+#[cfg(windows)]
+pub(crate) fn long_path(path: &Path) -> std::io::Result<PathBuf> {
+    use std::path::Prefix;
+
+    let path = absolute(path)?;
+    let mut components = path.components();
+    let mut result = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(disk) => PathBuf::from(format!(r"\\?\{}:\", disk as char)),
+            Prefix::UNC(server, share) => {
+                let mut result = PathBuf::from(r"\\?\UNC\");
+                result.push(server);
+                result.push(share);
+                result
+            }
+            // Already verbatim, or a device path.
+            _ => return Ok(path),
+        },
+        _ => return Ok(path),
+    };
+    for component in components {
+        if let Component::Normal(name) = component {
+            result.push(name);
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: &Path) -> std::io::Result<PathBuf> {
+    absolute(path)
+}
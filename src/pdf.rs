@@ -0,0 +1,189 @@
+//! Helpers to extract the metadata of PDF documents.
+//! The document information dictionary referenced by the trailer holds the
+//! title, author and keywords as PDF text strings, and the XMP packet of the
+//! catalog `/Metadata` stream may hold more recent values. XMP values win
+//! when both are present.
+
+use lopdf::{Document, Object};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+pub(crate) const PDF_MIME_TYPE: &str = "application/pdf";
+
+/// The metadata of a PDF document.
+#[derive(Debug, Default)]
+pub(crate) struct Metadata {
+    pub(crate) title: Option<String>,
+    pub(crate) authors: Vec<String>,
+    pub(crate) keywords: Vec<String>,
+    pub(crate) page_count: Option<u32>,
+}
+
+impl Metadata {
+    /// Returns the title, authors and keywords, to be indexed.
+    pub(crate) fn text(&self) -> String {
+        let mut text: Vec<&str> = vec![];
+        text.extend(self.title.as_deref());
+        text.extend(self.authors.iter().map(|author| author.as_str()));
+        text.extend(self.keywords.iter().map(|keyword| keyword.as_str()));
+        text.join(" ")
+    }
+}
+
+// Follows an indirect reference, if any.
+fn resolve<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Object> {
+    match object {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        _ => Some(object),
+    }
+}
+
+// Decodes a PDF text string: UTF-16BE or UTF-8 with a byte order mark, or
+// PDFDocEncoding which matches Latin-1 for the printable characters.
+fn decode_text(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        let units: Vec<u16> = utf16
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else if let Some(utf8) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        String::from_utf8_lossy(utf8).into_owned()
+    } else {
+        bytes.iter().map(|byte| *byte as char).collect()
+    }
+}
+
+fn split_keywords(keywords: &str) -> Vec<String> {
+    keywords
+        .split([',', ';'])
+        .map(|keyword| keyword.trim())
+        .filter(|keyword| !keyword.is_empty())
+        .map(|keyword| keyword.to_owned())
+        .collect()
+}
+
+// Reads the document information dictionary.
+fn info_metadata(doc: &Document) -> Metadata {
+    let mut metadata = Metadata::default();
+    let info = match doc
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|info| resolve(doc, info))
+        .and_then(|info| info.as_dict().ok())
+    {
+        Some(info) => info,
+        None => return metadata,
+    };
+
+    let text = |key: &[u8]| {
+        info.get(key)
+            .ok()
+            .and_then(|value| resolve(doc, value))
+            .and_then(|value| value.as_str().ok())
+            .map(|value| decode_text(value).trim().to_owned())
+            .filter(|value| !value.is_empty())
+    };
+    metadata.title = text(b"Title");
+    metadata.authors = text(b"Author").into_iter().collect();
+    metadata.keywords = text(b"Keywords")
+        .map(|keywords| split_keywords(&keywords))
+        .unwrap_or_default();
+    metadata
+}
+
+// Returns the content of the catalog XMP metadata stream.
+fn xmp_packet(doc: &Document) -> Option<Vec<u8>> {
+    let root = resolve(doc, doc.trailer.get(b"Root").ok()?)?
+        .as_dict()
+        .ok()?;
+    let stream = resolve(doc, root.get(b"Metadata").ok()?)?
+        .as_stream()
+        .ok()?;
+    // Metadata streams are usually left uncompressed for other tools.
+    Some(
+        stream
+            .decompressed_content()
+            .unwrap_or_else(|_| stream.content.clone()),
+    )
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum XmpField {
+    Title,
+    Creator,
+    Subject,
+    Keywords,
+}
+
+// Reads the Dublin Core title, creators and subjects, and the PDF keywords
+// of an XMP packet. Values of the rdf:Alt, rdf:Seq and rdf:Bag containers
+// are in rdf:li elements.
+fn xmp_metadata(xmp: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    let mut reader = Reader::from_reader(xmp);
+    let mut buffer = vec![];
+    let mut current: Option<XmpField> = None;
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) => match element.name().as_ref() {
+                b"dc:title" => current = Some(XmpField::Title),
+                b"dc:creator" => current = Some(XmpField::Creator),
+                b"dc:subject" => current = Some(XmpField::Subject),
+                b"pdf:Keywords" => current = Some(XmpField::Keywords),
+                _ => {}
+            },
+            Ok(Event::Text(content)) => {
+                let value = content
+                    .unescape()
+                    .map(|value| value.trim().to_owned())
+                    .unwrap_or_default();
+                match current {
+                    _ if value.is_empty() => {}
+                    Some(XmpField::Title) if metadata.title.is_none() => {
+                        metadata.title = Some(value)
+                    }
+                    Some(XmpField::Creator) => metadata.authors.push(value),
+                    Some(XmpField::Subject) => metadata.keywords.push(value),
+                    Some(XmpField::Keywords) => metadata.keywords.extend(split_keywords(&value)),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(element)) => {
+                if matches!(
+                    element.name().as_ref(),
+                    b"dc:title" | b"dc:creator" | b"dc:subject" | b"pdf:Keywords"
+                ) {
+                    current = None;
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+    metadata
+}
+
+/// Parses the metadata of a PDF document, or returns None if the document
+/// can't be loaded.
+pub(crate) fn metadata(buffer: &[u8]) -> Option<Metadata> {
+    let doc = Document::load_mem(buffer).ok()?;
+
+    let mut metadata = info_metadata(&doc);
+    if let Some(xmp) = xmp_packet(&doc).map(|xmp| xmp_metadata(&xmp)) {
+        if xmp.title.is_some() {
+            metadata.title = xmp.title;
+        }
+        if !xmp.authors.is_empty() {
+            metadata.authors = xmp.authors;
+        }
+        if !xmp.keywords.is_empty() {
+            metadata.keywords = xmp.keywords;
+        }
+    }
+    metadata.page_count = Some(doc.get_pages().len() as u32);
+
+    Some(metadata)
+}
@@ -0,0 +1,134 @@
+//! Photo folders
+//! Cameras and phones store the files of a capture side by side in DCIM
+//! folders: the still and the video of a live photo (`IMG_0001.HEIC` and
+//! `IMG_0001.MOV`), a JPEG and its raw version (`DSC_0001.JPG` and
+//! `DSC_0001.NEF`), or the frames of a burst
+//! (`IMG_20240101_120000_BURST001_COVER.jpg`, `..._BURST002.jpg`).
+//! `ResourceStore::import_photo_folder()` imports each capture as a single
+//! resource: its main image is the default variant, and the other files
+//! are added as variants named after their role.
+
+use std::path::{Path, PathBuf};
+
+/// The variant holding the video of a live or motion photo.
+pub const MOTION_VARIANT: &str = "motion";
+/// The variant holding the raw version of a photo.
+pub const RAW_VARIANT: &str = "raw";
+/// The variants holding the other frames of a burst, as `burst`,
+/// `burst-2`, etc.
+pub const BURST_VARIANT: &str = "burst";
+
+// The extensions of the raw formats of common cameras.
+const RAW_EXTENSIONS: [&str; 10] = [
+    "dng", "cr2", "cr3", "nef", "arw", "raf", "orf", "rw2", "pef", "srw",
+];
+
+fn is_raw(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| {
+            RAW_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())
+        })
+        .unwrap_or_default()
+}
+
+fn lowercase_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+// The files of a capture share their name without extension, up to the
+// burst frame suffix.
+fn capture_key(path: &Path) -> String {
+    let mut key = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if let Some(burst) = key.find("_burst") {
+        key.truncate(burst);
+    }
+    key
+}
+
+// Returns `base` for the first variant of a role, and `base-<n>` for the
+// following ones.
+fn variant_name(base: &str, index: usize) -> String {
+    if index == 0 {
+        base.to_owned()
+    } else {
+        format!("{}-{}", base, index + 1)
+    }
+}
+
+/// The files of a capture.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Capture {
+    /// The main image, or the only file of the capture.
+    pub primary: PathBuf,
+    /// The other files, with the name of their variant.
+    pub variants: Vec<(String, PathBuf)>,
+}
+
+/// Groups the image and video files of a folder, with their mime types,
+/// by capture. Files are sorted by name, and so are the captures.
+pub(crate) fn group_captures(mut files: Vec<(PathBuf, String)>) -> Vec<Capture> {
+    files.sort_by_key(|(path, _)| lowercase_name(path));
+
+    let mut groups: Vec<(String, Vec<(PathBuf, String)>)> = vec![];
+    for (path, mime) in files {
+        let key = capture_key(&path);
+        // Captures of different folders are not grouped.
+        match groups
+            .iter_mut()
+            .find(|(other, members)| *other == key && members[0].0.parent() == path.parent())
+        {
+            Some((_, members)) => members.push((path, mime)),
+            None => groups.push((key, vec![(path, mime)])),
+        }
+    }
+
+    let mut captures = vec![];
+    for (_, members) in groups {
+        let is_image =
+            |(path, mime): &&(PathBuf, String)| mime.starts_with("image/") && !is_raw(path);
+        // Burst covers are the frame chosen by the camera.
+        let primary = members
+            .iter()
+            .filter(is_image)
+            .find(|(path, _)| lowercase_name(path).contains("_cover"))
+            .or_else(|| members.iter().find(is_image))
+            .or_else(|| members.iter().find(|(path, _)| is_raw(path)));
+        let primary = match primary {
+            Some((primary, _)) => primary.clone(),
+            None => {
+                // Without a photo, the files are not a capture.
+                captures.extend(members.into_iter().map(|(path, _)| Capture {
+                    primary: path,
+                    variants: vec![],
+                }));
+                continue;
+            }
+        };
+
+        let (mut bursts, mut raws, mut motions) = (0, 0, 0);
+        let mut variants = vec![];
+        for (path, mime) in members {
+            if path == primary {
+                continue;
+            }
+            let name = if is_raw(&path) {
+                raws += 1;
+                variant_name(RAW_VARIANT, raws - 1)
+            } else if mime.starts_with("video/") {
+                motions += 1;
+                variant_name(MOTION_VARIANT, motions - 1)
+            } else {
+                bursts += 1;
+                variant_name(BURST_VARIANT, bursts - 1)
+            };
+            variants.push((name, path));
+        }
+        captures.push(Capture { primary, variants });
+    }
+    captures
+}
@@ -0,0 +1,423 @@
+//! Typed resource properties
+//! Properties are extracted from variant content by mime type specific
+//! extractors, and stored in the index to allow equality and range queries.
+
+use crate::epub::{self, EPUB_MIME_TYPE};
+use crate::gpx::{self, GPX_MIME_TYPE};
+use crate::image_decoders::{decode_image, is_animated};
+use crate::office::{core_properties, is_office_document};
+use crate::pdf::{self, PDF_MIME_TYPE};
+use exif::{Exif, In, Tag, Value};
+use futures::{AsyncRead, AsyncReadExt};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::ToSql;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PropertyValue {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+/// The result of a custom query of the index, with the values of each row
+/// in the order of the columns. NULL values are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryRows {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<PropertyValue>>>,
+}
+
+impl From<i64> for PropertyValue {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<u32> for PropertyValue {
+    fn from(value: u32) -> Self {
+        Self::Integer(value as _)
+    }
+}
+
+impl From<f64> for PropertyValue {
+    fn from(value: f64) -> Self {
+        Self::Real(value)
+    }
+}
+
+impl From<&str> for PropertyValue {
+    fn from(value: &str) -> Self {
+        Self::Text(value.to_owned())
+    }
+}
+
+impl ToSql for PropertyValue {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, rusqlite::Error> {
+        match self {
+            Self::Integer(value) => value.to_sql(),
+            Self::Real(value) => value.to_sql(),
+            Self::Text(value) => value.to_sql(),
+        }
+    }
+}
+
+impl FromSql for PropertyValue {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Integer(value) => Ok(Self::Integer(value)),
+            ValueRef::Real(value) => Ok(Self::Real(value)),
+            ValueRef::Text(text) => Ok(Self::Text(String::from_utf8_lossy(text).into())),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A filter on a named property. Range bounds are inclusive.
+#[derive(Clone, Debug)]
+pub enum PropertyFilter {
+    Equal(String, PropertyValue),
+    GreaterThan(String, PropertyValue),
+    LessThan(String, PropertyValue),
+    Range(String, PropertyValue, PropertyValue),
+}
+
+impl PropertyFilter {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Equal(name, _)
+            | Self::GreaterThan(name, _)
+            | Self::LessThan(name, _)
+            | Self::Range(name, _, _) => name,
+        }
+    }
+
+    /// Returns the property name, the sql condition on the `value` column
+    /// for this filter and the values to bind.
+    pub(crate) fn condition(&self) -> (&String, &'static str, Vec<&PropertyValue>) {
+        match self {
+            Self::Equal(name, value) => (name, "value = ?", vec![value]),
+            Self::GreaterThan(name, value) => (name, "value > ?", vec![value]),
+            Self::LessThan(name, value) => (name, "value < ?", vec![value]),
+            Self::Range(name, low, high) => (name, "value BETWEEN ? AND ?", vec![low, high]),
+        }
+    }
+}
+
+pub type Properties = Vec<(String, PropertyValue)>;
+
+// The size of the downscaled image used to compute the palette.
+const PALETTE_SAMPLE_SIZE: u32 = 32;
+// The number of colors kept in the palette.
+const PALETTE_SIZE: usize = 4;
+
+/// Returns the dominant colors of an image as `#rrggbb` strings, most
+/// frequent first. Colors are bucketed with 4 bits per channel, and each
+/// bucket is represented by the average color of its pixels.
+fn palette(img: &DynamicImage) -> Vec<String> {
+    let sample = img
+        .thumbnail(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE)
+        .to_rgba8();
+
+    // bucket -> (pixel count, sum of r, g, b)
+    let mut buckets: HashMap<u16, (u32, u32, u32, u32)> = HashMap::new();
+    for pixel in sample.pixels() {
+        let [r, g, b, a] = pixel.0;
+        // Skip mostly transparent pixels.
+        if a < 128 {
+            continue;
+        }
+        let key = ((r as u16 >> 4) << 8) | ((g as u16 >> 4) << 4) | (b as u16 >> 4);
+        let entry = buckets.entry(key).or_insert((0, 0, 0, 0));
+        entry.0 += 1;
+        entry.1 += r as u32;
+        entry.2 += g as u32;
+        entry.3 += b as u32;
+    }
+
+    let mut buckets: Vec<_> = buckets.into_values().collect();
+    buckets.sort_by(|a, b| b.0.cmp(&a.0));
+    buckets
+        .iter()
+        .take(PALETTE_SIZE)
+        .map(|(count, r, g, b)| format!("#{:02x}{:02x}{:02x}", r / count, g / count, b / count))
+        .collect()
+}
+
+/// Name of the property holding the perceptual hash of an image.
+pub const IMAGE_HASH: &str = "dhash";
+
+/// Returns the difference hash of an image: the image is reduced to 9x8
+/// grayscale pixels, and each bit tells whether a pixel is brighter than its
+/// right neighbour. Resized or recompressed copies of an image get hashes
+/// that differ by a few bits only.
+fn dhash(img: &DynamicImage) -> u64 {
+    let sample = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if sample.get_pixel(x, y).0[0] > sample.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Names of the properties holding the location of a resource, in degrees.
+pub const LATITUDE: &str = "latitude";
+pub const LONGITUDE: &str = "longitude";
+// The extent of GPX tracks, routes and waypoints.
+pub const MIN_LATITUDE: &str = "min_latitude";
+pub const MIN_LONGITUDE: &str = "min_longitude";
+pub const MAX_LATITUDE: &str = "max_latitude";
+pub const MAX_LONGITUDE: &str = "max_longitude";
+
+// Converts an EXIF GPS coordinate (degrees, minutes, seconds) to degrees,
+// negative for the southern and western hemispheres.
+fn exif_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: u8) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(dms) if dms.len() >= 3 => {
+            dms[0].to_f64() + dms[1].to_f64() / 60.0 + dms[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+
+    let negative = match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|value| value.first() == Some(&negative_ref))
+            .unwrap_or(false),
+        _ => false,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// Returns the latitude and longitude found in the EXIF data of an image.
+fn exif_location(exif: &Exif) -> Option<(f64, f64)> {
+    let latitude = exif_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?;
+    let longitude = exif_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?;
+    Some((latitude, longitude))
+}
+
+/// Name of the property holding when a photo was taken, as a unix
+/// timestamp. EXIF dates don't always have a time zone, so they are
+/// recorded in the local time of the camera, as if it was UTC.
+pub const CAPTURED_AT: &str = "captured_at";
+
+/// Returns the EXIF DateTimeOriginal of an image, as a unix timestamp.
+fn exif_captured_at(exif: &Exif) -> Option<i64> {
+    let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?;
+    let date = match &field.value {
+        Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+    let captured_at =
+        chrono::NaiveDate::from_ymd_opt(date.year as i32, date.month as u32, date.day as u32)?
+            .and_hms_opt(date.hour as u32, date.minute as u32, date.second as u32)?;
+    Some(captured_at.and_utc().timestamp())
+}
+
+fn location_properties(latitude: f64, longitude: f64) -> Properties {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return vec![];
+    }
+
+    vec![
+        (LATITUDE.to_owned(), latitude.into()),
+        (LONGITUDE.to_owned(), longitude.into()),
+    ]
+}
+
+/// Name of the property set to 1 for animated GIF and PNG images, whose
+/// other properties describe their first frame.
+pub const ANIMATED: &str = "animated";
+
+/// image/* extractor: records the image dimensions, its dominant colors,
+/// its perceptual hash, whether it is animated and the location and date
+/// where the photo was taken.
+async fn image_properties<C: AsyncRead + Unpin>(content: &mut C, mime: &str) -> Properties {
+    let mut buffer = vec![];
+    if content.read_to_end(&mut buffer).await.is_err() {
+        return vec![];
+    }
+
+    // EXIF data is also available for formats that can't be decoded.
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&buffer))
+        .ok();
+    let location = exif.as_ref().and_then(exif_location);
+    let captured_at = exif.as_ref().and_then(exif_captured_at);
+
+    let mut properties: Properties = vec![];
+    if is_animated(&buffer, mime) {
+        properties.push((ANIMATED.to_owned(), PropertyValue::Integer(1)));
+    }
+    if let Some(img) = decode_image(buffer, mime) {
+        properties.push(("width".to_owned(), img.width().into()));
+        properties.push(("height".to_owned(), img.height().into()));
+
+        let colors = palette(&img);
+        if let Some(color) = colors.first() {
+            properties.push(("dominant_color".to_owned(), color.as_str().into()));
+            properties.push(("palette".to_owned(), colors.join(",").as_str().into()));
+        }
+        // Stored with the bits of the u64 hash.
+        properties.push((IMAGE_HASH.to_owned(), (dhash(&img) as i64).into()));
+    }
+
+    if let Some((latitude, longitude)) = location {
+        properties.extend(location_properties(latitude, longitude));
+    }
+    if let Some(captured_at) = captured_at {
+        properties.push((CAPTURED_AT.to_owned(), captured_at.into()));
+    }
+
+    properties
+}
+
+/// application/x-places+json extractor: records the optional location of
+/// the place, from its `latitude` and `longitude` members.
+async fn places_properties<C: AsyncRead + Unpin>(content: &mut C) -> Properties {
+    let mut buffer = vec![];
+    if content.read_to_end(&mut buffer).await.is_err() {
+        return vec![];
+    }
+
+    let place: serde_json::Value = match serde_json::from_slice(&buffer) {
+        Ok(place) => place,
+        Err(_) => return vec![],
+    };
+
+    match (
+        place.get(LATITUDE).and_then(|v| v.as_f64()),
+        place.get(LONGITUDE).and_then(|v| v.as_f64()),
+    ) {
+        (Some(latitude), Some(longitude)) => location_properties(latitude, longitude),
+        _ => vec![],
+    }
+}
+
+/// Office documents extractor: records the core properties of the document.
+async fn office_properties<C: AsyncRead + Unpin>(content: &mut C, mime: &str) -> Properties {
+    let mut buffer = vec![];
+    if content.read_to_end(&mut buffer).await.is_err() {
+        return vec![];
+    }
+
+    match zip::ZipArchive::new(Cursor::new(buffer)) {
+        Ok(mut archive) => core_properties(&mut archive, mime)
+            .into_iter()
+            .map(|(name, value)| (name, PropertyValue::Text(value)))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// EPUB extractor: records the title and authors of the book.
+async fn epub_properties<C: AsyncRead + Unpin>(content: &mut C) -> Properties {
+    let mut buffer = vec![];
+    if content.read_to_end(&mut buffer).await.is_err() {
+        return vec![];
+    }
+
+    match zip::ZipArchive::new(Cursor::new(buffer))
+        .ok()
+        .and_then(|mut archive| epub::package(&mut archive))
+    {
+        Some(package) => package
+            .properties()
+            .into_iter()
+            .map(|(name, value)| (name, PropertyValue::Text(value)))
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// GPX extractor: records the name of the first track or route and the
+/// bounding box of all the points.
+async fn gpx_properties<C: AsyncRead + Unpin>(content: &mut C) -> Properties {
+    let mut buffer = vec![];
+    if content.read_to_end(&mut buffer).await.is_err() {
+        return vec![];
+    }
+
+    let gpx = gpx::parse(&buffer);
+    let mut properties = vec![];
+    if let Some(name) = gpx.names.into_iter().next() {
+        properties.push(("title".to_owned(), PropertyValue::Text(name)));
+    }
+    if let Some(bounds) = gpx.bounds {
+        properties.push((MIN_LATITUDE.to_owned(), bounds.min_lat.into()));
+        properties.push((MIN_LONGITUDE.to_owned(), bounds.min_lon.into()));
+        properties.push((MAX_LATITUDE.to_owned(), bounds.max_lat.into()));
+        properties.push((MAX_LONGITUDE.to_owned(), bounds.max_lon.into()));
+    }
+    properties
+}
+
+/// Names of the properties holding the origin of imported resources, see
+/// `ResourceMetadata::origin()`. They are attached to the `origin` pseudo
+/// variant, eg. for `ResourceStore::get_properties()`.
+pub const ORIGIN_PROPERTIES: &str = "origin";
+pub const ORIGINAL_NAME: &str = "original_name";
+pub const IMPORTED_AT: &str = "imported_at";
+
+/// Name of the property holding the number of pages of a document.
+pub const PAGE_COUNT: &str = "page_count";
+
+/// PDF extractor: records the title, authors, keywords and number of pages
+/// of the document.
+async fn pdf_properties<C: AsyncRead + Unpin>(content: &mut C) -> Properties {
+    let mut buffer = vec![];
+    if content.read_to_end(&mut buffer).await.is_err() {
+        return vec![];
+    }
+
+    let metadata = match pdf::metadata(&buffer) {
+        Some(metadata) => metadata,
+        None => return vec![],
+    };
+    let mut properties: Properties = vec![];
+    if let Some(title) = metadata.title {
+        properties.push(("title".to_owned(), PropertyValue::Text(title)));
+    }
+    for author in metadata.authors {
+        properties.push(("author".to_owned(), PropertyValue::Text(author)));
+    }
+    if !metadata.keywords.is_empty() {
+        properties.push((
+            "keywords".to_owned(),
+            PropertyValue::Text(metadata.keywords.join(", ")),
+        ));
+    }
+    if let Some(count) = metadata.page_count {
+        properties.push((PAGE_COUNT.to_owned(), count.into()));
+    }
+    properties
+}
+
+/// Returns the properties for this content, based on its mime type.
+pub async fn extract_properties<C: AsyncRead + Unpin>(content: &mut C, mime: &str) -> Properties {
+    if mime.starts_with("image/") {
+        image_properties(content, mime).await
+    } else if is_office_document(mime) {
+        office_properties(content, mime).await
+    } else if mime == EPUB_MIME_TYPE {
+        epub_properties(content).await
+    } else if mime == "application/x-places+json" {
+        places_properties(content).await
+    } else if mime == GPX_MIME_TYPE {
+        gpx_properties(content).await
+    } else if mime == PDF_MIME_TYPE {
+        pdf_properties(content).await
+    } else {
+        vec![]
+    }
+}
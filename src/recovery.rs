@@ -0,0 +1,85 @@
+//! Recovery of the access key with age or SSH keys.
+//! The access key can be wrapped to the public keys of several recipients,
+//! for instance a hardware-backed key or one held in escrow, so that the
+//! matching private key can restore `access.key` if it is lost.
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use std::io::{BufReader, Read, Write};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RecoveryError {
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    #[error("Invalid recipient: {0}")]
+    InvalidRecipient(String),
+    #[error("Invalid or encrypted identity")]
+    InvalidIdentity,
+    #[error("Encryption error")]
+    Encrypt(#[from] age::EncryptError),
+    #[error("Decryption error")]
+    Decrypt(#[from] age::DecryptError),
+    #[error("At least one recipient is needed")]
+    NoRecipients,
+}
+
+pub(crate) const WRAPPED_ACCESS_KEY: &str = "access.key.age";
+// The public keys the access key is wrapped to, one per line. They are kept
+// to wrap the new access key after a reencryption.
+pub(crate) const RECIPIENTS: &str = "access.key.recipients";
+
+/// Parses an age (`age1...`) or SSH (`ssh-ed25519 ...`, `ssh-rsa ...`)
+/// public key.
+fn parse_recipient(recipient: &str) -> Result<Box<dyn age::Recipient + Send>, RecoveryError> {
+    if let Ok(recipient) = age::x25519::Recipient::from_str(recipient) {
+        return Ok(Box::new(recipient));
+    }
+    age::ssh::Recipient::from_str(recipient)
+        .map(|recipient| Box::new(recipient) as _)
+        .map_err(|_| RecoveryError::InvalidRecipient(recipient.to_owned()))
+}
+
+/// Parses an age secret key, or an unencrypted SSH private key.
+fn parse_identity(identity: &str) -> Result<Box<dyn age::Identity>, RecoveryError> {
+    if let Ok(identity) = age::x25519::Identity::from_str(identity.trim()) {
+        return Ok(Box::new(identity));
+    }
+    match age::ssh::Identity::from_buffer(BufReader::new(identity.as_bytes()), None) {
+        Ok(identity @ age::ssh::Identity::Unencrypted(_)) => Ok(Box::new(identity)),
+        _ => Err(RecoveryError::InvalidIdentity),
+    }
+}
+
+/// Encrypts `plaintext` to all the recipients, as an armored age file.
+pub(crate) fn wrap(plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>, RecoveryError> {
+    let recipients = recipients
+        .iter()
+        .map(|recipient| parse_recipient(recipient))
+        .collect::<Result<Vec<_>, _>>()?;
+    let encryptor =
+        age::Encryptor::with_recipients(recipients).ok_or(RecoveryError::NoRecipients)?;
+
+    let mut wrapped = vec![];
+    let armor = ArmoredWriter::wrap_output(&mut wrapped, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?.finish()?;
+    Ok(wrapped)
+}
+
+/// Decrypts a file created by `wrap()` with the private key of one of the
+/// recipients.
+pub(crate) fn unwrap(wrapped: &[u8], identity: &str) -> Result<Vec<u8>, RecoveryError> {
+    let identity = parse_identity(identity)?;
+    let decryptor = match age::Decryptor::new(ArmoredReader::new(wrapped))? {
+        age::Decryptor::Recipients(decryptor) => decryptor,
+        _ => return Err(RecoveryError::Decrypt(age::DecryptError::DecryptionFailed)),
+    };
+
+    let mut plaintext = vec![];
+    decryptor
+        .decrypt(std::iter::once(identity.as_ref()))?
+        .read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
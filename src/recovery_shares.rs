@@ -0,0 +1,227 @@
+//! Recovery shares of the access key.
+//! The access key can be split with Shamir's secret sharing into `n`
+//! printable shares, any `k` of them being enough to restore it while fewer
+//! reveal nothing about it. Each byte of the key is the constant term of a
+//! random polynomial of degree `k - 1` over GF(256), and a share holds the
+//! values of these polynomials at its own non zero `x`.
+//!
+//! Shares look like `docstore-share-<set>-<k>-<x>-<values>-<checksum>`, all
+//! in hex. The set identifies the shares created together, and the checksum
+//! catches typos when they are typed back.
+
+use rand::Rng;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ShareError {
+    #[error("Invalid threshold: {0} of {1} shares")]
+    InvalidThreshold(u8, u8),
+    #[error("Invalid share: {0}")]
+    InvalidShare(String),
+    #[error("The shares don't belong to the same set")]
+    MixedSets,
+    #[error("{0} shares are needed, got {1}")]
+    NotEnoughShares(u8, usize),
+}
+
+const PREFIX: &str = "docstore-share";
+
+// Multiplies in GF(256), with the polynomial of AES.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+// The multiplicative inverse of a non zero element is a^254.
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = mul(result, a);
+    }
+    result
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// The first bytes of the blake3 hash, as hex.
+fn short_hash(bytes: &[u8]) -> String {
+    to_hex(&blake3::hash(bytes).as_bytes()[..4])
+}
+
+struct Share {
+    set: String,
+    threshold: u8,
+    x: u8,
+    values: Vec<u8>,
+}
+
+impl Share {
+    fn body(&self) -> String {
+        format!(
+            "{}-{}-{:02x}-{:02x}-{}",
+            PREFIX,
+            self.set,
+            self.threshold,
+            self.x,
+            to_hex(&self.values)
+        )
+    }
+
+    fn parse(share: &str) -> Result<Self, ShareError> {
+        let invalid = || ShareError::InvalidShare(share.to_owned());
+        // Shares may be typed back with spaces, line breaks or in uppercase.
+        let share = share.split_whitespace().collect::<String>().to_lowercase();
+        let (body, checksum) = share.rsplit_once('-').ok_or_else(invalid)?;
+        if short_hash(body.as_bytes()) != checksum {
+            return Err(invalid());
+        }
+
+        let fields: Vec<&str> = body
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .ok_or_else(invalid)?
+            .split('-')
+            .collect();
+        match fields.as_slice() {
+            [set, threshold, x, values] => Ok(Self {
+                set: set.to_string(),
+                threshold: u8::from_str_radix(threshold, 16).map_err(|_| invalid())?,
+                x: u8::from_str_radix(x, 16).map_err(|_| invalid())?,
+                values: from_hex(values).ok_or_else(invalid)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Share {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let body = self.body();
+        write!(f, "{}-{}", body, short_hash(body.as_bytes()))
+    }
+}
+
+/// Splits `secret` into `count` shares, any `threshold` of them restoring
+/// it with `combine()`.
+pub(crate) fn split(
+    secret: &[u8],
+    count: u8,
+    threshold: u8,
+    rng: &mut impl Rng,
+) -> Result<Vec<String>, ShareError> {
+    if threshold < 2 || threshold > count {
+        return Err(ShareError::InvalidThreshold(threshold, count));
+    }
+
+    // The coefficients of the polynomial of each byte, constant term first.
+    let polynomials: Vec<Vec<u8>> = secret
+        .iter()
+        .map(|byte| {
+            let mut coefficients = vec![*byte];
+            coefficients.extend((1..threshold).map(|_| rng.gen::<u8>()));
+            coefficients
+        })
+        .collect();
+
+    let set = short_hash(secret);
+    Ok((1..=count)
+        .map(|x| {
+            let values = polynomials
+                .iter()
+                .map(|coefficients| {
+                    // Horner's method.
+                    coefficients
+                        .iter()
+                        .rev()
+                        .fold(0, |value, coefficient| mul(value, x) ^ coefficient)
+                })
+                .collect();
+            Share {
+                set: set.clone(),
+                threshold,
+                x,
+                values,
+            }
+            .to_string()
+        })
+        .collect())
+}
+
+/// Restores the secret from shares created by `split()`.
+pub(crate) fn combine(shares: &[String]) -> Result<Vec<u8>, ShareError> {
+    let mut parsed: Vec<Share> = vec![];
+    for share in shares {
+        let share = Share::parse(share)?;
+        // The same share given twice doesn't count.
+        if !parsed.iter().any(|other| other.x == share.x) {
+            parsed.push(share);
+        }
+    }
+
+    let first = parsed.first().ok_or(ShareError::NotEnoughShares(2, 0))?;
+    if parsed.iter().any(|share| {
+        share.set != first.set
+            || share.threshold != first.threshold
+            || share.values.len() != first.values.len()
+            || share.x == 0
+    }) {
+        return Err(ShareError::MixedSets);
+    }
+    if parsed.len() < first.threshold as usize {
+        return Err(ShareError::NotEnoughShares(first.threshold, parsed.len()));
+    }
+    let parsed = &parsed[..first.threshold as usize];
+
+    // Lagrange interpolation at x = 0, where subtracting is xoring.
+    let weights: Vec<u8> = parsed
+        .iter()
+        .map(|share| {
+            parsed
+                .iter()
+                .filter(|other| other.x != share.x)
+                .fold(1, |weight, other| {
+                    mul(weight, mul(other.x, inv(other.x ^ share.x)))
+                })
+        })
+        .collect();
+    let secret: Vec<u8> = (0..first.values.len())
+        .map(|i| {
+            parsed
+                .iter()
+                .zip(&weights)
+                .fold(0, |byte, (share, weight)| {
+                    byte ^ mul(share.values[i], *weight)
+                })
+        })
+        .collect();
+
+    // Shares with valid checksums but from different splits of secrets
+    // sharing the set id would restore garbage.
+    if short_hash(&secret) != first.set {
+        return Err(ShareError::MixedSets);
+    }
+    Ok(secret)
+}
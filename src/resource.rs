@@ -1,22 +1,21 @@
 //! Resource representation
 
+use chrono::{DateTime, Utc};
 use futures::io::AsyncSeek;
 use futures::AsyncRead;
 use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput, ValueRef};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use tokio_util::compat::Compat;
 
+/// The content of a variant, readable with the `futures` io traits.
+/// Tokio readers can be used with the `ResourceStore::*_tokio()` methods.
 pub trait ContentReader: AsyncRead + AsyncSeek + Unpin {}
 
-// Default implementations for types used internally
-impl<T: AsRef<[u8]> + Unpin> ContentReader for Compat<std::io::Cursor<T>> {}
-impl <T: ContentReader> ContentReader for Box<T> {}
-impl ContentReader for Compat<tokio::fs::File> {}
+impl<T: AsyncRead + AsyncSeek + Unpin + ?Sized> ContentReader for T {}
 
 /// Type used to represent a unique id for a resource.
 /// Currently using the resource path.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ResourceId(String);
 
 impl From<&[String]> for ResourceId {
@@ -53,13 +52,104 @@ impl ToString for ResourceId {
     }
 }
 
+/// A search result, with the tags of the resource read from the index
+/// instead of its metadata.
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub id: ResourceId,
+    pub tags: Vec<String>,
+}
+
+/// How many search results have each tag, top-level mime type (eg.
+/// `image`) and year of capture, for filter sidebars. Values are ordered
+/// from the most to the least frequent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchFacets {
+    pub tags: Vec<(String, usize)>,
+    pub mime_categories: Vec<(String, usize)>,
+    /// Photos are dated by their capture, other resources by their last
+    /// modification.
+    pub years: Vec<(String, usize)>,
+}
+
+/// The key used to order search results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SortKey {
+    /// The number of occurrences of the searched text.
+    #[default]
+    Relevance,
+    /// The last modification date.
+    Modified,
+    /// The size of the default variant.
+    Size,
+    /// The frecency score, decayed by the time since the last modification.
+    Frecency,
+}
+
+/// How search results are ordered, see `ResourceStore::search_ordered()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SearchOrder {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Default for SearchOrder {
+    /// Most relevant first.
+    fn default() -> Self {
+        Self {
+            key: SortKey::Relevance,
+            descending: true,
+        }
+    }
+}
+
+/// The time span grouping resources in `ResourceStore::timeline()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TimelineBucket {
+    Day,
+    Month,
+    Year,
+}
+
+/// The resources captured during a time span, most recent first.
+#[derive(Clone, Debug)]
+pub struct TimelineEntry {
+    /// The span, like `2024-05-17`, `2024-05` or `2024`.
+    pub bucket: String,
+    pub count: usize,
+    pub ids: Vec<ResourceId>,
+}
+
+/// A resource listed by `ResourceStore::ls_summaries()`, from the index.
+#[derive(Clone, Debug)]
+pub struct ResourceSummary {
+    pub name: String,
+    pub id: ResourceId,
+    /// The mime type of the default variant.
+    pub mime_type: String,
+    /// The size of the default variant, in bytes.
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct VariantMetadata {
-    /// The variant size in bytes.
+    /// The variant size in bytes. The store replaces the size given by
+    /// callers with the size of the content actually written.
     size: u64,
     /// The variant mime type.
     /// TODO: Consider using a mime specific type.
     mime_type: String,
+    /// The hex encoded blake3 hash of the content, computed by the store
+    /// when the variant is written. Missing for variants written before
+    /// hashes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// The size in bytes of the blocks holding the content, including the
+    /// encryption overhead. Missing for variants written before stored
+    /// sizes were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stored_size: Option<u64>,
 }
 
 impl VariantMetadata {
@@ -67,6 +157,8 @@ impl VariantMetadata {
         Self {
             size,
             mime_type: mime_type.to_owned(),
+            hash: None,
+            stored_size: None,
         }
     }
 
@@ -85,6 +177,106 @@ impl VariantMetadata {
     pub fn set_mime_type(&mut self, mime_type: &str) {
         self.mime_type = mime_type.to_owned();
     }
+
+    /// Returns the content hash, usable for deduplication or as an ETag.
+    pub fn hash(&self) -> Option<&str> {
+        self.hash.as_deref()
+    }
+
+    pub(crate) fn set_hash(&mut self, hash: String) {
+        self.hash = Some(hash);
+    }
+
+    /// Returns the space used by the content in the block store.
+    pub fn stored_size(&self) -> Option<u64> {
+        self.stored_size
+    }
+
+    pub(crate) fn set_stored_size(&mut self, stored_size: u64) {
+        self.stored_size = Some(stored_size);
+    }
+}
+
+/// The resource is an attachment of the target, eg. a note.
+pub const ATTACHMENT_OF: &str = "attachment_of";
+/// The resource was made from the target, eg. an edited photo.
+pub const DERIVED_FROM: &str = "derived_from";
+/// The resource belongs to the target album.
+pub const ALBUM_MEMBER: &str = "album_member";
+
+/// A typed link from a resource to another one, see `ATTACHMENT_OF`,
+/// `DERIVED_FROM` and `ALBUM_MEMBER` for common kinds. Applications can use
+/// their own kinds.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Relation {
+    pub kind: String,
+    /// The path of the linked resource.
+    pub target: Vec<String>,
+}
+
+impl Relation {
+    pub fn new(kind: &str, target: &[String]) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            target: target.to_vec(),
+        }
+    }
+}
+
+/// Where an imported resource comes from.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ImportOrigin {
+    /// The name of the imported file, indexed as the `original_name`
+    /// property.
+    pub file_name: String,
+    /// The absolute path of the imported file on the importing host, if
+    /// any. It is never indexed.
+    pub source_path: Option<String>,
+    /// When the resource was imported, as a unix timestamp. It is indexed as
+    /// the `imported_at` property.
+    pub imported_at: i64,
+}
+
+// A mime type accepted by a client, eg. `image/webp;q=0.8`, `image/*` or
+// `*/*`, with its weight.
+struct AcceptedMime {
+    main: String,
+    sub: String,
+    q: f32,
+}
+
+impl AcceptedMime {
+    fn parse(accepted: &str) -> Option<Self> {
+        let mut parts = accepted.split(';');
+        let (main, sub) = parts.next()?.trim().split_once('/')?;
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        Some(Self {
+            main: main.to_ascii_lowercase(),
+            sub: sub.to_ascii_lowercase(),
+            q,
+        })
+    }
+
+    // How specifically this matches `mime`: 2 for the same type, 1 for
+    // `type/*` and 0 for `*/*`, or None if it doesn't match.
+    fn specificity(&self, mime: &str) -> Option<u8> {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        let (main, sub) = essence.split_once('/')?;
+        if self.main == "*" {
+            Some(0)
+        } else if !self.main.eq_ignore_ascii_case(main) {
+            None
+        } else if self.sub == "*" {
+            Some(1)
+        } else if self.sub.eq_ignore_ascii_case(sub) {
+            Some(2)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -97,6 +289,16 @@ pub struct ResourceMetadata {
     variants: HashMap<String, VariantMetadata>,
     /// The set of tags for this resource.
     tags: HashSet<String>,
+    /// Set for the resources created by importing files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<ImportOrigin>,
+    /// For subtitles, the path of the video they belong to when it is not
+    /// found by name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subtitled_video: Option<Vec<String>>,
+    /// The typed links from this resource to others.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    relations: Vec<Relation>,
 }
 
 impl ResourceMetadata {
@@ -108,6 +310,9 @@ impl ResourceMetadata {
             desc: desc.to_owned(),
             variants,
             tags,
+            origin: None,
+            subtitled_video: None,
+            relations: vec![],
         }
     }
 
@@ -119,10 +324,127 @@ impl ResourceMetadata {
         self.desc.to_owned()
     }
 
+    pub fn origin(&self) -> Option<&ImportOrigin> {
+        self.origin.as_ref()
+    }
+
+    pub(crate) fn set_origin(&mut self, origin: Option<ImportOrigin>) {
+        self.origin = origin;
+    }
+
+    pub fn subtitled_video(&self) -> Option<&[String]> {
+        self.subtitled_video.as_deref()
+    }
+
+    /// Links subtitles to a video, or back to the video with the same name
+    /// if None. Use `ResourceStore::update_metadata()` to persist it.
+    pub fn set_subtitled_video(&mut self, video: Option<&[String]>) {
+        self.subtitled_video = video.map(|video| video.to_vec());
+    }
+
+    pub fn relations(&self) -> &[Relation] {
+        &self.relations
+    }
+
+    /// Links this resource to `target`, returning false if they were already
+    /// linked by this kind of relation. Use `ResourceStore::update_metadata()`
+    /// or `ResourceStore::add_relation()` to persist it.
+    pub fn add_relation(&mut self, kind: &str, target: &[String]) -> bool {
+        let relation = Relation::new(kind, target);
+        if self.relations.contains(&relation) {
+            return false;
+        }
+        self.relations.push(relation);
+        true
+    }
+
+    /// Removes a link to `target`, returning whether it existed.
+    pub fn remove_relation(&mut self, kind: &str, target: &[String]) -> bool {
+        let count = self.relations.len();
+        self.relations
+            .retain(|relation| relation.kind != kind || relation.target != target);
+        count != self.relations.len()
+    }
+
+    /// Restores from `previous` the fields that are changed by
+    /// `ResourceStore::update_metadata()`, keeping the variants.
+    pub(crate) fn restore_fields(&mut self, previous: &ResourceMetadata) {
+        self.desc = previous.desc.clone();
+        self.tags = previous.tags.clone();
+        self.origin = previous.origin.clone();
+        self.subtitled_video = previous.subtitled_video.clone();
+        self.relations = previous.relations.clone();
+    }
+
     pub fn get_variant(&self, name: &str) -> Option<&VariantMetadata> {
         self.variants.get(name)
     }
 
+    /// Returns the variant that best suits a client accepting these mime
+    /// types, in the HTTP `Accept` syntax, eg. `image/webp`,
+    /// `image/*;q=0.8` or `*/*`, and any type when empty. The weight of a
+    /// variant comes from the most specific type matching it, and variants
+    /// with a 0 weight are not acceptable.
+    ///
+    /// The variants of at most `max_size` bytes are preferred, eg. the
+    /// thumbnail for small displays, or the smallest one when none fits.
+    /// Among them, the highest weight wins, then the most specific and
+    /// earliest accepted type, and then the largest variant.
+    pub fn best_variant(
+        &self,
+        accepted: &[&str],
+        max_size: Option<u64>,
+    ) -> Option<(&str, &VariantMetadata)> {
+        let accepted: Vec<AcceptedMime> = if accepted.is_empty() {
+            AcceptedMime::parse("*/*").into_iter().collect()
+        } else {
+            accepted
+                .iter()
+                .filter_map(|accepted| AcceptedMime::parse(accepted))
+                .collect()
+        };
+
+        // The weight, specificity and index of the accepted type of each
+        // acceptable variant.
+        let mut candidates: Vec<(f32, u8, usize, &str, &VariantMetadata)> = vec![];
+        for (name, variant) in &self.variants {
+            let mime = variant.mime_type();
+            let matched = accepted
+                .iter()
+                .enumerate()
+                .filter_map(|(index, accepted)| {
+                    accepted
+                        .specificity(&mime)
+                        .map(|specificity| (accepted.q, specificity, index))
+                })
+                .min_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+            if let Some((q, specificity, index)) = matched {
+                if q > 0.0 {
+                    candidates.push((q, specificity, index, name.as_str(), variant));
+                }
+            }
+        }
+
+        let fits = |variant: &VariantMetadata| max_size.map_or(true, |max| variant.size() <= max);
+        if !candidates.iter().any(|candidate| fits(candidate.4)) {
+            return candidates
+                .into_iter()
+                .min_by(|a, b| a.4.size().cmp(&b.4.size()).then(a.3.cmp(b.3)))
+                .map(|candidate| (candidate.3, candidate.4));
+        }
+        candidates
+            .into_iter()
+            .filter(|candidate| fits(candidate.4))
+            .min_by(|a, b| {
+                b.0.total_cmp(&a.0)
+                    .then(b.1.cmp(&a.1))
+                    .then(a.2.cmp(&b.2))
+                    .then(b.4.size().cmp(&a.4.size()))
+                    .then(a.3.cmp(b.3))
+            })
+            .map(|candidate| (candidate.3, candidate.4))
+    }
+
     pub fn has_variant(&self, name: &str) -> bool {
         self.variants.contains_key(name)
     }
@@ -0,0 +1,128 @@
+//! Automatic tagging and routing rules
+//! Rules are stored in the settings document and applied when resources
+//! are created. Tagging rules can also be re-run over existing resources
+//! with `ResourceStore::apply_tag_rules()`, while routing rules only decide
+//! the container of imported files.
+
+use crate::settings::Settings;
+use serde::{Deserialize, Serialize};
+
+/// A rule adding `tag` to resources matching all of its conditions.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TagRule {
+    pub tag: String,
+    /// The mime type of the default variant, either exact or with a
+    /// wildcard subtype like `image/*`.
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    /// Some text that the indexed content of the resource must contain.
+    #[serde(default)]
+    pub contains: Option<String>,
+}
+
+impl TagRule {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_owned(),
+            mime_type: None,
+            contains: None,
+        }
+    }
+
+    pub fn with_mime_type(mut self, mime_type: &str) -> Self {
+        self.mime_type = Some(mime_type.to_owned());
+        self
+    }
+
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.contains = Some(text.to_owned());
+        self
+    }
+
+    pub(crate) fn matches_mime_type(&self, mime_type: &str) -> bool {
+        match &self.mime_type {
+            None => true,
+            Some(pattern) => mime_type_matches(pattern, mime_type),
+        }
+    }
+}
+
+// Whether a mime type matches a pattern, either exact or with a wildcard
+// subtype like `image/*`.
+fn mime_type_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => mime_type
+            .split_once('/')
+            .map(|(value, _)| value == kind)
+            .unwrap_or(false),
+        None => pattern == "*" || pattern == mime_type,
+    }
+}
+
+/// The settings section holding the tagging rules.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TagRules {
+    pub rules: Vec<TagRule>,
+}
+
+impl Settings for TagRules {
+    const NAME: &'static str = "docstore.tag_rules";
+    const VERSION: u32 = 1;
+}
+
+/// A rule routing the imported files of a mime type, either exact or with a
+/// wildcard subtype like `image/*`, to a container.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RouteRule {
+    pub mime_type: String,
+    pub container: Vec<String>,
+}
+
+impl RouteRule {
+    pub fn new(mime_type: &str, container: &[&str]) -> Self {
+        Self {
+            mime_type: mime_type.to_owned(),
+            container: container.iter().map(|name| (*name).to_owned()).collect(),
+        }
+    }
+}
+
+/// The settings section holding the routing rules. Files imported with
+/// `ResourceStore::import_file()`, `ResourceStore::create_resource_from_reader()`
+/// or `ResourceStore::import_url()` go to the container of the first rule
+/// matching their mime type, and to the root container otherwise.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RouteRules {
+    pub rules: Vec<RouteRule>,
+}
+
+impl RouteRules {
+    /// The default categories: `photos`, `videos`, `audio` and
+    /// `documents`.
+    pub fn categories() -> Self {
+        Self {
+            rules: vec![
+                RouteRule::new("image/*", &["photos"]),
+                RouteRule::new("video/*", &["videos"]),
+                RouteRule::new("audio/*", &["audio"]),
+                RouteRule::new("application/pdf", &["documents"]),
+                RouteRule::new("application/epub+zip", &["documents"]),
+                RouteRule::new("text/*", &["documents"]),
+            ],
+        }
+    }
+
+    /// Returns the container of the files of this mime type.
+    pub fn container_for(&self, mime_type: &str) -> &[String] {
+        self.rules
+            .iter()
+            .find(|rule| mime_type_matches(&rule.mime_type, mime_type))
+            .map(|rule| rule.container.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+impl Settings for RouteRules {
+    const NAME: &'static str = "docstore.route_rules";
+    const VERSION: u32 = 1;
+}
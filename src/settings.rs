@@ -0,0 +1,30 @@
+//! Settings documents
+//! Settings are typed sections stored together in the hidden `.settings`
+//! private file. Each section is tagged with the version of its format.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub trait Settings: Serialize + DeserializeOwned {
+    /// The name of the settings section. Names starting with `docstore.`
+    /// are reserved for the store itself.
+    const NAME: &'static str;
+    /// The version of the settings format.
+    const VERSION: u32;
+
+    /// Called when the stored section has a different version. Returning
+    /// `None` makes `get_settings()` fail with a version mismatch error.
+    fn migrate(_version: u32, _value: serde_cbor::Value) -> Option<Self> {
+        None
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct SettingsEntry {
+    pub(crate) version: u32,
+    pub(crate) value: serde_cbor::Value,
+}
+
+pub(crate) type SettingsDocument = BTreeMap<String, SettingsEntry>;
+
+pub(crate) const SETTINGS_FILE: &str = ".settings";
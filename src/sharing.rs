@@ -0,0 +1,52 @@
+//! Share tokens
+//! A share token gives access to a resource until it expires or is
+//! revoked. Tokens are unguessable random ids, recorded in the settings
+//! document so that the serving side can check them with
+//! `ResourceStore::check_share()`.
+
+use crate::settings::Settings;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ShareToken {
+    /// The token itself, also used to revoke it.
+    pub id: String,
+    pub path: Vec<String>,
+    /// The expiration time, in seconds since the Unix epoch.
+    pub expires: i64,
+}
+
+impl ShareToken {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.expires, 0)
+            .single()
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+
+    /// Whether the token is expired at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires <= now.timestamp()
+    }
+}
+
+/// The settings section holding the active share tokens, by id.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Shares {
+    pub tokens: BTreeMap<String, ShareToken>,
+}
+
+impl Shares {
+    /// Removes the expired tokens, returning whether some were removed.
+    pub(crate) fn purge_expired(&mut self, now: DateTime<Utc>) -> bool {
+        let count = self.tokens.len();
+        self.tokens.retain(|_, token| !token.is_expired(now));
+        count != self.tokens.len()
+    }
+}
+
+impl Settings for Shares {
+    const NAME: &'static str = "docstore.shares";
+    const VERSION: u32 = 1;
+}
@@ -0,0 +1,102 @@
+//! Smart folders
+//! A smart folder is a saved search over the resources with a given tag,
+//! capped to a number of resources and to a total size, for instance to
+//! pick the photos that fit on a device. Their definitions are stored in the
+//! settings document, and their membership is re-evaluated after each
+//! mutation, recording `EnterSmartFolder` and `LeaveSmartFolder` changes.
+
+use crate::resource::{ResourceId, SearchOrder};
+use crate::settings::Settings;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SmartFolder {
+    pub name: String,
+    /// The tag of the resources to consider.
+    pub tag: String,
+    /// Some text that the indexed content of the resources must contain.
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// The order in which resources are added to the folder until one of
+    /// the limits is reached.
+    #[serde(default)]
+    pub order: SearchOrder,
+    /// The maximum number of resources.
+    #[serde(default)]
+    pub max_count: Option<usize>,
+    /// The maximum total size of the default variants, in bytes.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+impl SmartFolder {
+    pub fn new(name: &str, tag: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            tag: tag.to_owned(),
+            contains: None,
+            order: SearchOrder::default(),
+            max_count: None,
+            max_size: None,
+        }
+    }
+
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.contains = Some(text.to_owned());
+        self
+    }
+
+    pub fn with_order(mut self, order: SearchOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_max_count(mut self, count: usize) -> Self {
+        self.max_count = Some(count);
+        self
+    }
+
+    pub fn with_max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Keeps the ordered candidates while they fit in the limits. The first
+    /// resource that doesn't fit ends the folder, to respect the order.
+    pub(crate) fn select(&self, candidates: Vec<(ResourceId, u64)>) -> Vec<ResourceId> {
+        let mut total_size = 0;
+        let mut result = vec![];
+        for (id, size) in candidates {
+            if self
+                .max_count
+                .map(|max| result.len() >= max)
+                .unwrap_or(false)
+            {
+                break;
+            }
+            total_size += size;
+            if self.max_size.map(|max| total_size > max).unwrap_or(false) {
+                break;
+            }
+            result.push(id);
+        }
+        result
+    }
+}
+
+/// The settings section holding the smart folder definitions.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SmartFolders {
+    pub folders: Vec<SmartFolder>,
+}
+
+impl SmartFolders {
+    pub fn get(&self, name: &str) -> Option<&SmartFolder> {
+        self.folders.iter().find(|folder| folder.name == name)
+    }
+}
+
+impl Settings for SmartFolders {
+    const NAME: &'static str = "docstore.smart_folders";
+    const VERSION: u32 = 1;
+}
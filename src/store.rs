@@ -1,26 +1,81 @@
 //! Private resources store api
 
+use crate::access::{token_id, AccessToken, AccessTokens, Scope};
+use crate::backup::{read_backup, write_backup, BackupError};
+use crate::block_fetcher::BlockFetcher;
+use crate::bookmarks::{is_opml, parse_netscape, parse_opml, to_netscape, Bookmark};
+use crate::changes::{
+    diff, parse_segment_name, segment_name, segment_of, Change, ChangeOp, ResourceDiff, CHANGES_DIR,
+};
+use crate::chunking::{self, ChunkSizes};
+use crate::clock::{Clock, SystemClock};
+use crate::contacts::ContactFields;
+use crate::containers::{ContainerMetadata, Containers, CONTAINERS_FILE};
+use crate::fts::{DEFAULT_MAX_INDEXED_SIZE, DOTENV_MIME_TYPE};
+use crate::health::{CompactOptions, CompactReport, StoreReport, LARGEST_COUNT};
 use crate::indexer::{Indexer, SqliteDbError};
-use crate::resource::{ContentReader, ResourceId, VariantMetadata};
-use crate::transformers::{run_transformers, TransformerResult, VariantChange};
+use crate::locks::{ResourceLock, ResourceLocks};
+use crate::maintenance::{self, MaintenanceHandle, MaintenancePolicy};
+use crate::metrics;
+use crate::paths::long_path;
+use crate::photos::group_captures;
+use crate::properties::{Properties, PropertyFilter, PropertyValue, QueryRows, IMAGE_HASH};
+use crate::recovery_shares::{self, ShareError};
+use crate::resource::{
+    ContentReader, ImportOrigin, ResourceId, ResourceSummary, SearchFacets, SearchHit, SearchOrder,
+    TimelineBucket, TimelineEntry, VariantMetadata,
+};
+use crate::rules::{RouteRules, TagRules};
+use crate::settings::{Settings, SettingsDocument, SettingsEntry, SETTINGS_FILE};
+use crate::sharing::{ShareToken, Shares};
+use crate::smart_folders::{SmartFolder, SmartFolders};
+use crate::subtitles::{belongs_to, is_subtitles};
+use crate::sync::{SyncFilter, SYNC_FILTER};
+use crate::tokenizer::Tokenization;
+use crate::tombstones::{Tombstone, TombstoneSettings, Tombstones, TOMBSTONES_FILE};
+use crate::transformers::contact_sheet::ContactSheetSettings;
+use crate::transformers::queue::TransformQueue;
+use crate::transformers::thumbnailer::{ThumbnailSettings, Thumbnailer, THUMBNAIL_VARIANT};
+use crate::transformers::{
+    run_transformers, TransformerConfig, TransformerResult, VariantChange, VariantTransformer,
+    TRANSFORMERS,
+};
+use crate::validators::{self, Validator};
 use crate::{file_store::FileStore, resource::ResourceMetadata};
 use async_stream::stream;
-use chrono::Utc;
-use futures::stream::LocalBoxStream;
-use libipld::Cid;
-use log::debug;
-use rand::{rngs::ThreadRng, thread_rng};
-use serde::{de::DeserializeOwned, Serialize};
-use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use futures::io::{AsyncRead, AsyncReadExt as _, AsyncSeek, AsyncWrite, BufReader};
+use futures::ready;
+use futures::stream::{LocalBoxStream, Stream};
+use futures::{StreamExt, TryStreamExt};
+use libipld::cbor::DagCborCodec;
+use libipld::codec::Codec;
+use libipld::{Cid, Ipld};
+use log::{debug, error, info};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
+use std::future::Future;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::fs;
+use tokio::io::{
+    AsyncRead as TokioAsyncRead, AsyncReadExt, AsyncSeek as TokioAsyncSeek, AsyncSeekExt,
+    AsyncWriteExt, DuplexStream,
+};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 use wnfs::{
     common::BlockStore,
-    nameaccumulator::AccumulatorSetup,
+    nameaccumulator::{AccumulatorSetup, Name},
     private::{
         forest::{hamt::HamtForest, traits::PrivateForest},
         AccessKey, PrivateDirectory, PrivateFile, PrivateForestContent, PrivateNode,
@@ -32,6 +87,8 @@ use wnfs::{
 pub enum StoreError {
     #[error("No such resource in store: {0:?}")]
     NoSuchResource(Vec<String>),
+    #[error("No such container in store: {0:?}")]
+    NoSuchContainer(Vec<String>),
     #[error("Invalid variant name: {0}")]
     InvalidVariant(String),
     #[error("No variant '{0}' for this resource: {1:?}")]
@@ -40,6 +97,16 @@ pub enum StoreError {
     NoVariantContent(String, Vec<String>),
     #[error("No metadata found for this resource: {0:?}")]
     NoResourceMetadata(Vec<String>),
+    #[error("No content hash recorded for the '{0}' variant of {1:?}")]
+    NoVariantHash(String, Vec<String>),
+    #[error("No such smart folder: {0}")]
+    NoSuchSmartFolder(String),
+    #[error("A resource already exists at {0:?}")]
+    ResourceExists(Vec<String>),
+    #[error("No such transformer: {0}")]
+    NoSuchTransformer(String),
+    #[error("Resource is locked: {0:?}")]
+    ResourceLocked(Vec<String>),
     #[error("I/O error")]
     IO(#[from] std::io::Error),
     #[error("serde_cbor error")]
@@ -47,7 +114,184 @@ pub enum StoreError {
     #[error("IPLD error")]
     IPLD(#[from] libipld::error::Error),
     #[error("SQlite error")]
-    Sqlite(#[from] SqliteDbError),
+    Sqlite(SqliteDbError),
+    #[error("Query refused: {0}")]
+    QueryRefused(String),
+    #[error("The search returned more than {0} results")]
+    TooManyResults(usize),
+    #[error("The search timed out")]
+    SearchTimeout,
+    #[error("Zip error")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Settings '{0}' have version {1}, expected version {2}")]
+    SettingsVersion(String, u32, u32),
+    #[error("Backup error")]
+    Backup(#[from] BackupError),
+    #[error("serde_json error")]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Block {0} doesn't match its content")]
+    BlockVerification(String),
+    #[error("Xml error")]
+    Xml(#[from] quick_xml::Error),
+    #[error("Invalid, expired or revoked share token")]
+    InvalidShareToken,
+    #[error("Invalid, expired or revoked access token")]
+    InvalidAccessToken,
+    #[error("Content rejected: {0}")]
+    Rejected(String),
+    #[error("Invalid profile name: {0}")]
+    InvalidProfileName(String),
+    #[error("No such profile: {0}")]
+    NoSuchProfile(String),
+    #[error("Profile already exists: {0}")]
+    ProfileExists(String),
+    #[error("Invalid revisions: {0} to {1}")]
+    InvalidRevisions(u64, u64),
+    #[error("No such snapshot: {0}")]
+    NoSuchSnapshot(String),
+    #[error("Snapshot already exists: {0}")]
+    SnapshotExists(String),
+    #[error("A reencryption is in progress")]
+    ReencryptionInProgress,
+    #[error("Recovery shares error")]
+    Shares(#[from] ShareError),
+    #[cfg(feature = "http-client")]
+    #[error("HTTP client error")]
+    HttpClient(#[from] crate::http_client::HttpClientError),
+    #[cfg(feature = "age")]
+    #[error("Access key recovery error")]
+    Recovery(#[from] crate::recovery::RecoveryError),
+    #[error("{context}: {}", root_message(.source))]
+    Context {
+        context: ErrorContext,
+        source: Box<StoreError>,
+    },
+}
+
+// The message of the innermost cause of an error, eg. "No space left on
+// device" rather than "I/O error".
+fn root_message(err: &dyn std::error::Error) -> String {
+    let mut err = err;
+    while let Some(source) = err.source() {
+        err = source;
+    }
+    err.to_string()
+}
+
+impl StoreError {
+    /// Returns the error without the context of the operation.
+    pub fn root(&self) -> &StoreError {
+        match self {
+            StoreError::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    /// Returns the operation which failed and what it was working on, if
+    /// known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            StoreError::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    // Whether the error comes from a lower layer and doesn't tell what the
+    // store was doing.
+    fn is_opaque(&self) -> bool {
+        matches!(
+            self,
+            StoreError::IO(_)
+                | StoreError::SerdeCBOR(_)
+                | StoreError::IPLD(_)
+                | StoreError::Sqlite(_)
+                | StoreError::Zip(_)
+                | StoreError::SerdeJson(_)
+                | StoreError::Xml(_)
+        )
+    }
+}
+
+/// The operation during which an error happened, and the resource,
+/// variant or local file it was working on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub operation: String,
+    pub path: Option<Vec<String>>,
+    pub variant: Option<String>,
+    pub file: Option<PathBuf>,
+}
+
+impl ErrorContext {
+    fn new(operation: &str) -> Self {
+        Self {
+            operation: operation.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn path(mut self, path: &[String]) -> Self {
+        self.path = Some(path.to_vec());
+        self
+    }
+
+    fn variant(mut self, variant: &str) -> Self {
+        self.variant = Some(variant.to_owned());
+        self
+    }
+
+    fn file(mut self, file: &Path) -> Self {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(variant) = &self.variant {
+            write!(f, " '{}'", variant)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " on '{}'", path.join("/"))?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " ({})", file.display())?;
+        }
+        Ok(())
+    }
+}
+
+// Adds the context of an operation to the opaque errors. Errors which
+// already have one keep the innermost, most precise context.
+trait WithContext<T> {
+    fn with_context(self, context: impl FnOnce() -> ErrorContext) -> Result<T>;
+}
+
+impl<T> WithContext<T> for Result<T> {
+    fn with_context(self, context: impl FnOnce() -> ErrorContext) -> Result<T> {
+        self.map_err(|err| {
+            if err.is_opaque() {
+                StoreError::Context {
+                    context: context(),
+                    source: Box::new(err),
+                }
+            } else {
+                err
+            }
+        })
+    }
+}
+
+impl From<SqliteDbError> for StoreError {
+    fn from(err: SqliteDbError) -> Self {
+        match err {
+            SqliteDbError::QueryRefused(reason) => StoreError::QueryRefused(reason),
+            SqliteDbError::TooManyResults(max) => StoreError::TooManyResults(max),
+            err if err.is_interrupted() => StoreError::SearchTimeout,
+            err => StoreError::Sqlite(err),
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, StoreError>;
@@ -67,6 +311,18 @@ where
     }
 }
 
+// Like `from_cbor()`, but returns None if the file doesn't exist.
+async fn maybe_from_cbor<T, P: AsRef<Path>>(path: P) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+{
+    match from_cbor(path).await {
+        Ok(value) => Ok(Some(value)),
+        Err(StoreError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 // Serialize an object as cbor to a file
 async fn to_cbor<T, P: AsRef<Path>>(path: P, value: T) -> Result<()>
 where
@@ -76,183 +332,2104 @@ where
     Ok(())
 }
 
-fn subpath<P: AsRef<Path>>(root: P, leaf: &str) -> PathBuf {
-    let mut path: PathBuf = root.as_ref().into();
-    path.push(leaf);
-    path
+// Returns the cids of the blocks reachable from `root`.
+async fn reachable_blocks(from: &impl BlockStore, root: &Cid) -> Result<HashSet<Cid>> {
+    let mut visited = HashSet::new();
+    let mut pending = vec![*root];
+    while let Some(cid) = pending.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+
+        // Encrypted private blocks are raw leaves, but the forest is made
+        // of DAG-CBOR blocks linking to other blocks.
+        if cid.codec() == DAG_CBOR_CODEC {
+            let bytes = from.get_block(&cid).await?;
+            let ipld: Ipld = DagCborCodec.decode(&bytes)?;
+            ipld.references(&mut pending);
+        }
+    }
+    Ok(visited)
 }
 
-pub struct ResourceStore {
-    forest: HamtForest,
-    block_store: FileStore,
-    access_key: AccessKey,
-    rng: ThreadRng,
-    root_dir: PathBuf,
-    indexer: Indexer,
+// Copies the blocks reachable from `root` that are missing in the
+// destination, checking that each copy gets the same cid.
+// Returns the number of copied blocks.
+async fn copy_reachable_blocks(from: &impl BlockStore, to: &FileStore, root: &Cid) -> Result<u64> {
+    let mut count = 0;
+    for cid in reachable_blocks(from, root).await? {
+        if !to.has_block(&cid) {
+            let bytes = from.get_block(&cid).await?;
+            let copy = to.put_block(bytes, cid.codec()).await?;
+            if copy != cid {
+                return Err(StoreError::BlockVerification(cid.to_string()));
+            }
+            count += 1;
+        }
+    }
+
+    to.flush().await?;
+    Ok(count)
 }
 
-impl ResourceStore {
-    async fn init_forest<P: AsRef<Path>>(
-        root_dir: P,
-        store: &impl BlockStore,
-        rng: &mut impl CryptoRngCore,
-    ) -> Result<(Cid, AccessKey)> {
-        debug!("Initializing a new forest");
-        let setup = AccumulatorSetup::trusted(rng);
-        let forest = &mut Rc::new(HamtForest::new(setup));
-        let dir = &mut Rc::new(PrivateDirectory::new(&forest.empty_name(), Utc::now(), rng));
-        let access_key = dir.as_node().store(forest, store, rng).await?;
-        let forest_cid = forest.store(store).await?;
+// The directory holding the state of the profiles, which share the
+// blockstore of the root directory.
+const PROFILES_DIR: &str = "profiles";
 
-        // Save the initial access key.
-        to_cbor(subpath(&root_dir, "access.key"), &access_key).await?;
+fn profile_dir(base_dir: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(StoreError::InvalidProfileName(name.to_owned()));
+    }
+    Ok(subpath(subpath(base_dir, PROFILES_DIR), name))
+}
 
-        Ok((forest_cid, access_key))
+// Returns the directories holding the state of a forest using the blockstore
+// of `base_dir`: the default profile and the named ones.
+async fn state_dirs(base_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![base_dir.to_path_buf()];
+    let profiles = subpath(base_dir, PROFILES_DIR);
+    if profiles.exists() {
+        let mut entries = fs::read_dir(&profiles).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
     }
+    Ok(dirs)
+}
 
-    /// Create a new store, with all the data stored under the root dir.
-    /// The root directory and required sub directories will be created
-    /// if they don't already exist.
-    pub async fn new<P: AsRef<Path>>(root_dir: P) -> Result<Self> {
-        if !root_dir.as_ref().exists() {
-            fs::create_dir(&root_dir).await?;
+// Returns the blocks reachable from any of the forests, from their
+// snapshots, or from the new forest of an ongoing reencryption.
+// Fails if one of these roots can't be read, since the blocks it pins
+// would otherwise be considered unreachable.
+async fn reachable_from_forests(base_dir: &Path, block_store: &FileStore) -> Result<HashSet<Cid>> {
+    let mut reachable = HashSet::new();
+    for dir in state_dirs(base_dir).await? {
+        let mut roots: Vec<Cid> = vec![];
+        if let Some(cid) = maybe_from_cbor(subpath(&dir, "forest.cid")).await? {
+            roots.push(cid);
         }
+        if let Some(journal) =
+            maybe_from_cbor::<ReencryptJournal, _>(subpath(&dir, REENCRYPT_JOURNAL)).await?
+        {
+            roots.push(journal.forest_cid);
+        }
+        if let Some(snapshots) =
+            maybe_from_cbor::<BTreeMap<String, PinnedSnapshot>, _>(subpath(&dir, SNAPSHOTS)).await?
+        {
+            roots.extend(snapshots.values().map(|pinned| pinned.snapshot.forest_cid));
+        }
+        if let Some(log) = maybe_from_cbor::<Vec<PinnedUndo>, _>(subpath(&dir, UNDO_LOG)).await? {
+            roots.extend(log.iter().map(|pinned| pinned.entry.forest_cid));
+        }
+        for root in roots {
+            reachable.extend(reachable_blocks(block_store, &root).await?);
+        }
+    }
+    Ok(reachable)
+}
 
-        let block_store = FileStore::maybe_new(subpath(&root_dir, "blockstore")).await?;
+// Removes the blocks that are not reachable from any of the forests.
+// Returns the number of removed blocks.
+async fn remove_unreachable_blocks(base_dir: &Path, block_store: &FileStore) -> Result<u64> {
+    let reachable = reachable_from_forests(base_dir, block_store).await?;
+    Ok(block_store.remove_blocks_except(&reachable).await?)
+}
 
-        let mut rng = thread_rng();
-        // Initialize the forest and access key from serialized ones if possible.
-        let (forest_cid, access_key) = match (
-            from_cbor(subpath(&root_dir, "forest.cid")).await,
-            from_cbor(subpath(&root_dir, "access.key")).await,
-        ) {
-            (Ok(cid), Ok(access_key)) => {
-                debug!("Using existing access key");
-                (cid, access_key)
+// Streams content block by block, fetching up to `read_ahead` of the following
+// blocks while the current one is consumed. `block` returns the content
+// stream starting at a block index, which is empty past the last block.
+fn read_ahead<'a, S>(
+    read_ahead: usize,
+    block: impl Fn(u64) -> S + 'a,
+) -> impl Stream<Item = Result<Vec<u8>>> + 'a
+where
+    S: Stream<Item = IpldResult<Vec<u8>>> + 'a,
+{
+    let mut blocks = futures::stream::iter(0..)
+        .map(move |index| {
+            let content = block(index);
+            async move { Box::pin(content).next().await }
+        })
+        .buffered(read_ahead + 1);
+
+    stream! {
+        while let Some(Some(chunk)) = blocks.next().await {
+            let failed = chunk.is_err();
+            yield chunk.map_err(|e| e.into());
+            if failed {
+                break;
             }
-            _ => ResourceStore::init_forest(&root_dir, &block_store, &mut rng).await?,
-        };
+        }
+    }
+}
 
-        let forest = HamtForest::load(&forest_cid, &block_store).await?;
+// Streams the content of a variant of `file` from `forest`, whether it is
+// kept in the node metadata, split in chunks or stored as a whole, fetching
+// up to `ahead` blocks ahead.
+fn variant_stream<'a>(
+    forest: &'a HamtForest,
+    block_store: &'a FileStore,
+    ahead: usize,
+    file: &PrivateFile,
+    variant_name: &str,
+    path: &[String],
+) -> Result<LocalBoxStream<'a, Result<Vec<u8>>>> {
+    let file_metadata = file.get_metadata();
+    if variant_name != "default" {
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file_metadata.get_deserializable("res_meta");
+        match maybe_resource_metadata {
+            Some(Ok(resource_metadata)) if resource_metadata.has_variant(variant_name) => {}
+            Some(Ok(_)) => {
+                return Err(StoreError::NoSuchVariant(
+                    variant_name.to_owned(),
+                    path.to_vec(),
+                ))
+            }
+            _ => return Err(StoreError::NoResourceMetadata(path.to_vec())),
+        }
+    }
 
-        let indexer = Indexer::new(root_dir.as_ref().to_path_buf(), "index.sqlite")?;
+    match file_metadata.get(&format!("{}_variant", variant_name)) {
+        Some(Ipld::Bytes(bytes)) => Ok(Box::pin(futures::stream::iter([Ok(bytes.clone())]))),
+        Some(Ipld::List(chunks)) => {
+            let chunks = chunks
+                .iter()
+                .map(PrivateForestContent::from_metadata_value)
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(Box::pin(futures::stream::iter(chunks).flat_map(
+                move |chunk| content_stream(forest, block_store, ahead, Rc::new(chunk)),
+            )))
+        }
+        Some(variant_ipld) => {
+            let content = PrivateForestContent::from_metadata_value(variant_ipld)?;
+            Ok(Box::pin(content_stream(
+                forest,
+                block_store,
+                ahead,
+                Rc::new(content),
+            )))
+        }
+        None if variant_name == "default" => {
+            // The "main" file content.
+            let file = Rc::new(file.clone());
+            Ok(Box::pin(read_ahead(ahead, move |index| {
+                let file = file.clone();
+                stream! {
+                    for await value in file.stream_content(index, forest, block_store) {
+                        yield value;
+                    }
+                }
+            })))
+        }
+        None => Err(StoreError::NoVariantContent(
+            variant_name.to_owned(),
+            path.to_vec(),
+        )),
+    }
+}
 
-        let mut store = Self {
-            forest,
-            block_store,
-            access_key,
-            rng,
-            root_dir: root_dir.as_ref().into(),
-            indexer,
-        };
+// Streams `content` from `forest`, fetching up to `ahead` blocks ahead.
+fn content_stream<'a>(
+    forest: &'a HamtForest,
+    block_store: &'a FileStore,
+    ahead: usize,
+    content: Rc<PrivateForestContent>,
+) -> impl Stream<Item = Result<Vec<u8>>> + 'a {
+    read_ahead(ahead, move |index| {
+        let content = content.clone();
+        stream! {
+            for await value in content.stream(index, forest, block_store) {
+                yield value;
+            }
+        }
+    })
+}
 
-        store.mkdir(&[".resources".to_owned()]).await?;
-        store.mkdir(&[".index".to_owned()]).await?;
+// Computes the blake3 hash and the size of the content read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    read: u64,
+}
 
-        Ok(store)
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            read: 0,
+        }
     }
 
-    /// Get a handle to the root of the file system.
-    pub async fn root(&self) -> Result<Rc<PrivateDirectory>> {
-        let root = PrivateNode::load(&self.access_key, &self.forest, &self.block_store, None)
-            .await?
-            .search_latest(&self.forest, &self.block_store)
-            .await?;
-
-        Ok(root.as_dir()?)
+    // Returns the hex encoded hash of the content read so far.
+    fn hash(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
     }
 
-    /// Get a handle to a sub directory in the file system.
-    async fn subdir(&self, path: &[String]) -> Result<Rc<PrivateDirectory>> {
-        match self
-            .root()
-            .await?
-            .get_node(path, true, &self.forest, &self.block_store)
-            .await?
-        {
-            Some(PrivateNode::Dir(dir)) => Ok(dir),
-            _ => Err(StoreError::NoSuchResource(path.to_vec())),
-        }
+    // Records the hash and size of the content written through this reader,
+    // and the size of the blocks written since `stored_before`.
+    fn update_variant(
+        &self,
+        variant: &mut VariantMetadata,
+        block_store: &FileStore,
+        stored_before: u64,
+    ) {
+        variant.set_hash(self.hash());
+        variant.set_size(self.read);
+        variant.set_stored_size(block_store.bytes_written() - stored_before);
     }
+}
 
-    /// Get a handle to the resources subdirectory of the file system.
-    pub async fn resources_dir(&self) -> Result<Rc<PrivateDirectory>> {
-        self.subdir(&[".resources".to_owned()]).await
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.hasher.update(&buf[..read]);
+        this.read += read as u64;
+        Poll::Ready(Ok(read))
     }
+}
 
-    async fn index_dir(&self) -> Result<Rc<PrivateDirectory>> {
-        self.subdir(&[".index".to_owned()]).await
+// A seekable reader over the content of a variant, which is only fetched
+// as it is read: indexers stop reading at `max_indexed_size`, so large
+// variants are never loaded in memory. Seeking backwards streams the
+// content again from its start.
+struct VariantReader<'a> {
+    open: Box<dyn Fn() -> Result<LocalBoxStream<'a, Result<Vec<u8>>>> + 'a>,
+    stream: LocalBoxStream<'a, Result<Vec<u8>>>,
+    // The last chunk read from the stream, which ends at `stream_position`.
+    chunk: Vec<u8>,
+    stream_position: u64,
+    position: u64,
+    size: u64,
+}
+
+impl<'a> VariantReader<'a> {
+    fn new(
+        open: impl Fn() -> Result<LocalBoxStream<'a, Result<Vec<u8>>>> + 'a,
+        size: u64,
+    ) -> Result<Self> {
+        Ok(Self {
+            stream: open()?,
+            open: Box::new(open),
+            chunk: vec![],
+            stream_position: 0,
+            position: 0,
+            size,
+        })
     }
+}
 
-    /// Create a new directory, starting the path from the root.
-    pub async fn mkdir(&mut self, path: &[String]) -> Result<()> {
-        let mut root = PrivateNode::load(&self.access_key, &self.forest, &self.block_store, None)
-            .await?
-            .search_latest(&self.forest, &self.block_store)
-            .await?;
+impl AsyncRead for VariantReader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            let chunk_start = this.stream_position - this.chunk.len() as u64;
+            if this.position >= chunk_start && this.position < this.stream_position {
+                let offset = (this.position - chunk_start) as usize;
+                let read = buf.len().min(this.chunk.len() - offset);
+                buf[..read].copy_from_slice(&this.chunk[offset..offset + read]);
+                this.position += read as u64;
+                return Poll::Ready(Ok(read));
+            }
+            match ready!(this.stream.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => {
+                    this.stream_position += chunk.len() as u64;
+                    this.chunk = chunk;
+                }
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(&err))),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
 
-        let root = root.as_dir_mut()?;
+impl AsyncSeek for VariantReader<'_> {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
 
-        root.mkdir(
-            path,
-            true,
-            Utc::now(),
-            &self.forest,
-            &self.block_store,
-            &mut self.rng,
-        )
-        .await?;
+        if position < this.stream_position - this.chunk.len() as u64 {
+            this.stream = (this.open)().map_err(|err| to_io_error(&err))?;
+            this.chunk.clear();
+            this.stream_position = 0;
+        }
+        this.position = position;
+        Poll::Ready(Ok(position))
+    }
+}
 
-        root.as_node()
-            .store(&mut self.forest, &self.block_store, &mut self.rng)
-            .await?;
+// Adapts a stream of content chunks to an `AsyncRead`.
+fn stream_reader<'a>(
+    stream: impl Stream<Item = IpldResult<Vec<u8>>> + 'a,
+) -> impl AsyncRead + Unpin + 'a {
+    Box::pin(stream.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))
+        .into_async_read()
+}
 
-        self.save_state().await
-    }
+fn subpath<P: AsRef<Path>>(root: P, leaf: &str) -> PathBuf {
+    let mut path: PathBuf = root.as_ref().into();
+    path.push(leaf);
+    path
+}
 
-    async fn save_state(&mut self) -> Result<()> {
-        if self.indexer.should_update() {
-            // Update <root_dir>/index.sqlite to .index/index.sqlite
-            let mut dir = self.index_dir().await?;
-            let dir_name = dir.header.get_name().clone();
-            let now = Utc::now();
-            let file = dir
-                .open_file_mut(
-                    &["index.sqlite".to_owned()],
-                    true,
-                    now,
-                    &mut self.forest,
-                    &self.block_store,
-                    &mut self.rng,
-                )
-                .await?;
-            let mut full_path = self.root_dir.clone();
-            full_path.push("index.sqlite");
-            let reader = fs::File::open(full_path).await?;
-            let source = PrivateFile::with_content_streaming(
-                &dir_name,
-                now,
-                reader.compat(),
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+/// Options for `ResourceStore::import_url()`.
+#[cfg(feature = "http-client")]
+#[derive(Default)]
+pub struct ImportUrlOptions {
+    /// The resource name, defaulting to the last segment of the url path.
+    pub name: Option<String>,
+    /// The resource description, defaulting to the url.
+    pub desc: Option<String>,
+    pub tags: HashSet<String>,
+    /// Resume a previously interrupted download of the same url.
+    pub resume: bool,
+}
 
-            file.copy_content_from(&source, now);
+#[cfg(feature = "http-client")]
+fn url_file_name(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.path_segments()
+                .and_then(|segments| segments.filter(|s| !s.is_empty()).last())
+                .map(|s| s.to_owned())
+        })
+        .unwrap_or_else(|| "noname".to_owned())
+}
 
-            dir.as_node()
-                .store(&mut self.forest, &self.block_store, &mut self.rng)
-                .await?;
+/// What to do when creating a resource at the path of an existing one, see
+/// `ResourceStore::create_resource_with_policy()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Fail with `StoreError::ResourceExists`.
+    #[default]
+    Fail,
+    /// Replace the existing resource and its variants.
+    Overwrite,
+    /// Create the resource next to the existing one, with a " (n)" suffix
+    /// added to its name.
+    KeepBoth,
+    /// Do nothing if the default variant of the existing resource has the
+    /// same content hash, and fail like `Fail` otherwise.
+    SkipIfIdentical,
+}
 
-            self.indexer.set_updated();
-        }
+/// The action taken by `ResourceStore::create_resource_with_policy()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImportAction {
+    /// No resource existed at this path.
+    Created,
+    /// The existing resource was replaced.
+    Overwritten,
+    /// The resource was created at this other path.
+    Renamed(Vec<String>),
+    /// The existing resource already had this content.
+    Skipped,
+}
 
-        to_cbor(
-            subpath(&self.root_dir, "forest.cid"),
-            self.forest.store(&self.block_store).await?,
-        )
-        .await
+// Adds a " (n)" suffix to the leaf name, before its extension.
+fn suffixed_path(path: &[String], n: usize) -> Vec<String> {
+    let mut result = path.to_vec();
+    if let Some(leaf) = result.last_mut() {
+        *leaf = match leaf.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => {
+                format!("{} ({}).{}", stem, n, extension)
+            }
+            _ => format!("{} ({})", leaf, n),
+        };
+    }
+    result
+}
+
+/// Options for `ResourceStore::clone_to()`.
+#[derive(Default)]
+pub struct CloneOptions {
+    /// Rebuild the copy with new keys, without the history of the store.
+    pub new_access_key: bool,
+}
+
+/// Options for `ResourceStore::extract_archive()`.
+#[derive(Default)]
+pub struct ExtractOptions {
+    /// The container where the archive members are created. Defaults to
+    /// the archive path without its extension.
+    pub container: Option<Vec<String>>,
+    /// Tags added to every extracted resource.
+    pub tags: HashSet<String>,
+    /// Delete the archive resource once extracted.
+    pub remove_archive: bool,
+}
+
+/// A writer adding a variant to a resource, returned by
+/// `ResourceStore::add_variant_writer()`.
+/// Content is streamed to the store as it is written, and the variant is
+/// only added once the writer is closed. Dropping the writer before closing
+/// it discards the variant and leaves the store unchanged, the blocks
+/// already written being removed by the next `ResourceStore::compact()`.
+pub struct VariantWriter<'a> {
+    pipe: Option<DuplexStream>,
+    task: LocalBoxFuture<'a, Result<()>>,
+    result: Option<Result<()>>,
+}
+
+fn to_io_error(err: &StoreError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+}
+
+impl<'a> VariantWriter<'a> {
+    // Makes progress on the store side, which consumes the written content.
+    fn poll_task(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.result.is_none() {
+            match self.task.as_mut().poll(cx) {
+                Poll::Ready(result) => self.result = Some(result),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        match &self.result {
+            Some(Err(err)) => Poll::Ready(Err(to_io_error(err))),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<'a> AsyncWrite for VariantWriter<'a> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        // The store only stops reading early on failure.
+        if let Poll::Ready(res) = this.poll_task(cx) {
+            return Poll::Ready(res.and(Err(std::io::ErrorKind::BrokenPipe.into())));
+        }
+
+        let pipe = match this.pipe.as_mut() {
+            Some(pipe) => pipe,
+            None => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        };
+        tokio::io::AsyncWrite::poll_write(Pin::new(pipe), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_task(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(pipe) = this.pipe.as_mut() {
+            ready!(tokio::io::AsyncWrite::poll_shutdown(Pin::new(pipe), cx))?;
+            this.pipe = None;
+        }
+        this.poll_task(cx)
+    }
+}
+
+const DEFAULT_READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+const DEFAULT_READ_AHEAD: usize = 4;
+
+const DEFAULT_INLINE_THRESHOLD: usize = 4 * 1024;
+
+const DAG_CBOR_CODEC: u64 = 0x71;
+
+const PLACES_MIME_TYPE: &str = "application/x-places+json";
+
+// How many resources named alike are considered when linking subtitles and
+// videos.
+const MAX_SIBLINGS: u32 = 64;
+
+// How many bytes are read to sniff the mime type of imported files.
+const SNIFF_SIZE: u64 = 8192;
+
+/// Returns the mime type detected from magic bytes at the start of the content.
+fn sniff_mime_type(header: &[u8]) -> Option<&'static str> {
+    infer::get(header).map(|kind| kind.mime_type())
+}
+
+/// How the mime type of imported files is decided.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MimePolicy {
+    /// Only use the file extension.
+    Extension,
+    /// Sniff the content if the extension doesn't map to a known mime type.
+    #[default]
+    SniffIfUnknown,
+    /// Prefer the sniffed mime type over the one from the file extension.
+    PreferSniffed,
+}
+
+impl MimePolicy {
+    fn mime_type(&self, path: &Path, header: &[u8]) -> String {
+        // `.env` files are not known to mime_guess.
+        if path.file_name() == Some(OsStr::new(".env"))
+            || path.extension() == Some(OsStr::new("env"))
+        {
+            return DOTENV_MIME_TYPE.to_owned();
+        }
+
+        let guessed = mime_guess::from_path(path).first();
+        let sniffed = match (self, &guessed) {
+            (MimePolicy::Extension, _) | (MimePolicy::SniffIfUnknown, Some(_)) => None,
+            _ => sniff_mime_type(header),
+        };
+
+        match (sniffed, guessed) {
+            (Some(sniffed), _) => sniffed.to_owned(),
+            (None, Some(guessed)) => guessed.to_string(),
+            (None, None) => "application/octet-stream".to_owned(),
+        }
+    }
+}
+
+/// How the index database journals its transactions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JournalMode {
+    /// A write-ahead log, letting readers run while writing.
+    #[default]
+    Wal,
+    /// A rollback journal deleted after each transaction, for file systems
+    /// without shared memory support.
+    Delete,
+}
+
+/// When the index database waits for its writes to reach the disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Synchronous {
+    /// Never: the index may be corrupted by a power loss, and is then
+    /// recovered from its last snapshot.
+    Off,
+    /// At the critical moments. With a write-ahead log, the last
+    /// transactions may be lost on power loss but the index stays
+    /// consistent.
+    Normal,
+    /// After each transaction.
+    #[default]
+    Full,
+}
+
+/// The durability and performance settings of the index database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexOptions {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// How long to retry when the database is locked by another connection.
+    pub busy_timeout: Duration,
+    /// The size of the page cache in KB, or None for the SQLite default.
+    pub cache_size: Option<u32>,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            busy_timeout: Duration::from_secs(5),
+            cache_size: None,
+        }
+    }
+}
+
+/// Guards against searches returning most of the store, eg. for a single
+/// character, or taking too long. Searches going over them fail with
+/// `StoreError::TooManyResults` or `StoreError::SearchTimeout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchLimits {
+    /// The maximum number of results, or None for no limit.
+    pub max_results: Option<usize>,
+    /// The maximum duration of the index query, or None for no limit.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        Self {
+            max_results: Some(10_000),
+            timeout: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// Query results with the store revision they were computed at, see
+/// `ResourceStore::stamp()`.
+#[derive(Clone, Debug)]
+pub struct Stamped<T> {
+    pub revision: u64,
+    pub results: T,
+}
+
+/// Progress of `ResourceStore::reencrypt_all()`, in number of files.
+#[derive(Clone, Copy, Debug)]
+pub struct ReencryptProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+// The state of an ongoing reencryption, persisted in `reencrypt.journal`
+// after each copied file so that an interrupted one can be resumed.
+#[derive(Deserialize, Serialize)]
+struct ReencryptJournal {
+    forest_cid: Cid,
+    access_key: AccessKey,
+    // The paths of the files already copied to the new forest.
+    done: HashSet<Vec<String>>,
+    // Set once the copy completed, while switching to the new forest.
+    switching: bool,
+}
+
+const REENCRYPT_JOURNAL: &str = "reencrypt.journal";
+
+// The resources touched by an ongoing mutation, persisted in
+// `mutation.journal` until the new forest is saved. If the mutation fails or
+// the process dies before that, the forest is reverted to `forest_cid` and the
+// index entries of these resources are rebuilt from it.
+#[derive(Clone, Deserialize, Serialize)]
+struct MutationJournal {
+    forest_cid: Cid,
+    paths: Vec<Vec<String>>,
+}
+
+const MUTATION_JOURNAL: &str = "mutation.journal";
+
+/// A named state of the store, created by `ResourceStore::tag_snapshot()`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// The forest of the snapshot, whose blocks are kept by compaction.
+    pub forest_cid: Cid,
+    /// The revision of the last change recorded in the snapshot.
+    pub revision: u64,
+    /// When the snapshot was created, in seconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+// A snapshot with the access key of its root directory, which changes when
+// the store is reencrypted.
+#[derive(Clone, Deserialize, Serialize)]
+struct PinnedSnapshot {
+    snapshot: Snapshot,
+    access_key: AccessKey,
+}
+
+// The snapshots of a profile by name, outside of the forest since restoring
+// one replaces it.
+const SNAPSHOTS: &str = "snapshots.cbor";
+
+/// The snapshot of the state replaced by the last
+/// `ResourceStore::restore_snapshot()`.
+pub const BEFORE_RESTORE_SNAPSHOT: &str = "before-restore";
+
+/// An operation reverted by `ResourceStore::undo()`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum UndoOp {
+    DeleteResource,
+    /// The tag.
+    AddTag(String),
+    RemoveTag(String),
+    /// Any change made with `ResourceStore::update_metadata()`.
+    UpdateMetadata,
+    /// The variant name.
+    DeleteVariant(String),
+}
+
+/// A recent operation that can be undone.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UndoEntry {
+    pub op: UndoOp,
+    pub path: Vec<String>,
+    /// The forest before the operation, whose blocks are kept by compaction.
+    pub forest_cid: Cid,
+    /// When the operation was made, in seconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+// An undo entry with the access key of the root directory of its forest,
+// which changes when the store is reencrypted.
+#[derive(Deserialize, Serialize)]
+struct PinnedUndo {
+    entry: UndoEntry,
+    access_key: AccessKey,
+}
+
+// The undo log of a profile, oldest first, outside of the forest like the
+// snapshots.
+const UNDO_LOG: &str = "undo.cbor";
+
+/// The default number of operations kept in the undo log.
+pub const DEFAULT_UNDO_DEPTH: usize = 20;
+
+pub struct ResourceStore {
+    forest: HamtForest,
+    block_store: FileStore,
+    access_key: AccessKey,
+    rng: StdRng,
+    clock: Arc<dyn Clock>,
+    // Holds the blockstore shared by all the profiles.
+    base_dir: PathBuf,
+    // Holds the state of this profile: access key, forest cid and index.
+    root_dir: PathBuf,
+    indexer: Indexer,
+    read_buffer_size: usize,
+    read_ahead: usize,
+    inline_threshold: usize,
+    chunk_sizes: Option<ChunkSizes>,
+    mime_policy: MimePolicy,
+    validators: Vec<Box<dyn Validator>>,
+    // Cached directory handles, see `invalidate_cache()`.
+    root_cache: RefCell<Option<Rc<PrivateDirectory>>>,
+    resources_cache: RefCell<Option<Rc<PrivateDirectory>>>,
+    sync_filter: SyncFilter,
+    // The journal of the ongoing mutation, and how many nested mutations
+    // (eg. variants created by transformers) are running.
+    mutation: Option<MutationJournal>,
+    mutation_depth: usize,
+    // The resources waiting for lazily created variants.
+    transform_queue: TransformQueue,
+    // Held by applications, and for the resources of the ongoing mutation.
+    locks: ResourceLocks,
+    // Increased by each change, see `revision()`.
+    revision: u64,
+    undo_depth: usize,
+}
+
+/// Configures and opens a `ResourceStore`.
+pub struct ResourceStoreBuilder {
+    root_dir: PathBuf,
+    profile: Option<String>,
+    max_concurrent_writes: u32,
+    write_batch_size: usize,
+    in_memory: bool,
+    read_buffer_size: usize,
+    read_ahead: usize,
+    inline_threshold: usize,
+    chunk_sizes: Option<ChunkSizes>,
+    mime_policy: MimePolicy,
+    block_fetcher: Option<Box<dyn BlockFetcher>>,
+    validators: Vec<Box<dyn Validator>>,
+    contact_fields: ContactFields,
+    max_indexed_size: u64,
+    index_options: IndexOptions,
+    search_limits: SearchLimits,
+    tokenization: Tokenization,
+    undo_depth: usize,
+    rng_seed: Option<u64>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ResourceStoreBuilder {
+    pub fn new<P: AsRef<Path>>(root_dir: P) -> Self {
+        Self {
+            root_dir: root_dir.as_ref().into(),
+            profile: None,
+            max_concurrent_writes: 1,
+            write_batch_size: 0,
+            in_memory: false,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            read_ahead: DEFAULT_READ_AHEAD,
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+            chunk_sizes: None,
+            mime_policy: MimePolicy::default(),
+            block_fetcher: None,
+            validators: vec![],
+            contact_fields: ContactFields::default(),
+            max_indexed_size: DEFAULT_MAX_INDEXED_SIZE,
+            index_options: IndexOptions::default(),
+            search_limits: SearchLimits::default(),
+            tokenization: Tokenization::default(),
+            undo_depth: DEFAULT_UNDO_DEPTH,
+            rng_seed: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Opens the `name` profile instead of the default one. Profiles have
+    /// their own access key, forest and index but share the blockstore of
+    /// the root directory.
+    pub fn profile(mut self, name: &str) -> Self {
+        self.profile = Some(name.to_owned());
+        self
+    }
+
+    /// Sets how many blocks can be written in parallel when ingesting content.
+    /// Defaults to 1, meaning that blocks are written sequentially.
+    pub fn max_concurrent_writes(mut self, count: u32) -> Self {
+        self.max_concurrent_writes = count;
+        self
+    }
+
+    /// Writes blocks in batches of `blocks`, synced to disk together, instead
+    /// of one by one. Pending blocks are always written when the store state
+    /// is saved, so completed operations are durable. Defaults to 0, which
+    /// disables batching.
+    pub fn write_batch_size(mut self, blocks: usize) -> Self {
+        self.write_batch_size = blocks;
+        self
+    }
+
+    /// Keeps the blocks and the index in memory instead of files, eg. for
+    /// fast tests. They are lost when the store is dropped, so the root
+    /// directory, which still holds the small files of the store state like
+    /// its forest cid and access key, should be a new one. Such a store
+    /// can't be backed up, and its index is not snapshotted in the private
+    /// file system, but it can be cloned or migrated to a store in files.
+    pub fn in_memory(mut self) -> Self {
+        self.in_memory = true;
+        self
+    }
+
+    /// Sets the size of the buffer used to read content when ingesting it,
+    /// reduced to the size of the content when it is known to be smaller.
+    /// The size of the blocks themselves is decided by wnfs, but the size of
+    /// the chunks content is split in before is set with
+    /// `content_defined_chunking()`.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Sets how many blocks are fetched ahead of the one being read when
+    /// streaming variants with `get_variant()`. This hides the latency of
+    /// slow or remote blockstores for sequential reads. Defaults to 4, and
+    /// 0 fetches blocks one by one.
+    pub fn read_ahead(mut self, blocks: usize) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
+    /// Sets the size in bytes up to which the content of variants is kept
+    /// in the metadata of their resource instead of separate blocks. This
+    /// speeds up storing and reading many tiny resources like contacts or
+    /// bookmarks. Defaults to 4KB, and 0 disables it.
+    pub fn inline_threshold(mut self, size: usize) -> Self {
+        self.inline_threshold = size;
+        self
+    }
+
+    /// Splits the content of variants larger than the inline threshold in
+    /// chunks of `sizes` with content-defined boundaries, so that the
+    /// revisions of a large file share the blocks of their unchanged parts.
+    /// See the `chunking` module for what this reveals. Disabled by default.
+    pub fn content_defined_chunking(mut self, sizes: ChunkSizes) -> Self {
+        self.chunk_sizes = Some(sizes);
+        self
+    }
+
+    /// Sets how the mime type of imported files is decided.
+    pub fn mime_policy(mut self, policy: MimePolicy) -> Self {
+        self.mime_policy = policy;
+        self
+    }
+
+    /// Sets a fetcher used to get blocks missing from the local block store.
+    pub fn block_fetcher(mut self, fetcher: impl BlockFetcher + 'static) -> Self {
+        self.block_fetcher = Some(Box::new(fetcher));
+        self
+    }
+
+    /// Adds a validator checking content before it is stored. Validators
+    /// run in the order they were added.
+    pub fn validator(mut self, validator: impl Validator + 'static) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Sets which members of `application/x-contact+json` resources are
+    /// indexed, and how. Changes apply to resources indexed afterwards.
+    pub fn contact_fields(mut self, fields: ContactFields) -> Self {
+        self.contact_fields = fields;
+        self
+    }
+
+    /// Sets the size in bytes above which the text of variants is not
+    /// indexed, to bound the memory used while importing. Only the first
+    /// `size` bytes of the text of larger plain text and json variants are
+    /// indexed, and the elements of json arrays larger than `size` are
+    /// skipped. Defaults to 8MB.
+    pub fn max_indexed_size(mut self, size: u64) -> Self {
+        self.max_indexed_size = size;
+        self
+    }
+
+    /// Sets the journal mode, synchronous mode, busy timeout and cache size
+    /// of the index database, to trade durability for speed. Defaults to a
+    /// write-ahead log synced after each transaction, which is the safest.
+    pub fn index_options(mut self, options: IndexOptions) -> Self {
+        self.index_options = options;
+        self
+    }
+
+    /// Sets the maximum number of results and duration of searches.
+    /// Defaults to 10000 results and 5 seconds.
+    pub fn search_limits(mut self, limits: SearchLimits) -> Self {
+        self.search_limits = limits;
+        self
+    }
+
+    /// Sets how many of the last operations `ResourceStore::undo()` can
+    /// revert, 0 disabling it. Defaults to `DEFAULT_UNDO_DEPTH`.
+    pub fn undo_depth(mut self, depth: usize) -> Self {
+        self.undo_depth = depth;
+        self
+    }
+
+    /// Sets how the words suggested by `suggest()` are found in the indexed
+    /// text. Changing it rebuilds the index when the store is opened.
+    pub fn tokenization(mut self, tokenization: Tokenization) -> Self {
+        self.tokenization = tokenization;
+        self
+    }
+
+    /// Sets the clock giving the time of modifications, changes and token
+    /// expirations. Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Seeds the random generator used for keys and names, so that the
+    /// store is reproducible. Only meant for tests.
+    #[cfg(feature = "testing")]
+    pub(crate) fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Opens the store, creating the root directory and required sub
+    /// directories if they don't already exist.
+    pub async fn build(self) -> Result<ResourceStore> {
+        let base_dir = long_path(&self.root_dir)?;
+        let root_dir = match &self.profile {
+            Some(name) => profile_dir(&base_dir, name)?,
+            None => base_dir.clone(),
+        };
+        if !root_dir.exists() {
+            fs::create_dir_all(&root_dir).await?;
+        }
+
+        let mut block_store = if self.in_memory {
+            FileStore::in_memory()
+        } else {
+            FileStore::with_concurrency(
+                subpath(&base_dir, "blockstore"),
+                self.max_concurrent_writes,
+            )
+            .await?
+        };
+        if let Some(fetcher) = self.block_fetcher {
+            block_store.set_fetcher(fetcher);
+        }
+        block_store.set_write_batch_size(self.write_batch_size);
+
+        ResourceStore::finish_reencryption(&base_dir, &root_dir, &block_store).await?;
+        let interrupted_mutation = ResourceStore::revert_forest(&root_dir).await?;
+
+        let mut rng = match self.rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        // Initialize the forest and access key from serialized ones if possible.
+        let (forest_cid, access_key) = match (
+            from_cbor(subpath(&root_dir, "forest.cid")).await,
+            from_cbor(subpath(&root_dir, "access.key")).await,
+        ) {
+            (Ok(cid), Ok(access_key)) => {
+                debug!("Using existing access key");
+                (cid, access_key)
+            }
+            _ => {
+                ResourceStore::init_forest(&root_dir, &block_store, &mut rng, self.clock.now())
+                    .await?
+            }
+        };
+
+        let forest = HamtForest::load(&forest_cid, &block_store).await?;
+
+        let index_options = self.index_options;
+        let opened = if self.in_memory {
+            Indexer::in_memory(&index_options)
+        } else {
+            Indexer::new(&root_dir, &index_options)
+        };
+        let (mut indexer, needs_reindex) = match opened {
+            Ok(indexer) => (indexer, false),
+            Err(err) if err.is_corruption() => {
+                error!("The index database is corrupted, trying to recover.");
+                ResourceStore::recover_index(
+                    &root_dir,
+                    &index_options,
+                    &access_key,
+                    &forest,
+                    &block_store,
+                )
+                .await?
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        indexer.set_clock(self.clock.clone())?;
+        indexer.set_contact_fields(self.contact_fields);
+        indexer.set_max_indexed_size(self.max_indexed_size);
+        indexer.set_search_limits(self.search_limits);
+        let retokenize = indexer.set_tokenization(self.tokenization)?;
+
+        let sync_filter = from_cbor(subpath(&root_dir, SYNC_FILTER))
+            .await
+            .unwrap_or_default();
+
+        let mut store = ResourceStore {
+            forest,
+            block_store,
+            access_key,
+            rng,
+            clock: self.clock,
+            base_dir,
+            root_dir,
+            indexer,
+            read_buffer_size: self.read_buffer_size,
+            read_ahead: self.read_ahead,
+            inline_threshold: self.inline_threshold,
+            chunk_sizes: self.chunk_sizes,
+            mime_policy: self.mime_policy,
+            validators: self.validators,
+            root_cache: RefCell::new(None),
+            resources_cache: RefCell::new(None),
+            sync_filter,
+            mutation: None,
+            mutation_depth: 0,
+            transform_queue: TransformQueue::default(),
+            locks: ResourceLocks::default(),
+            revision: 0,
+            undo_depth: self.undo_depth,
+        };
+
+        store.mkdir(&[".resources".to_owned()]).await?;
+        store.mkdir(&[".index".to_owned()]).await?;
+        store.mkdir(&[CHANGES_DIR.to_owned()]).await?;
+        store.revision = store.root_revision().await?;
+
+        if needs_reindex {
+            store.reindex().await?;
+            // The reindexed forest is already the reverted one.
+            let _ = fs::remove_file(subpath(&store.root_dir, MUTATION_JOURNAL)).await;
+        } else if let Some(journal) = interrupted_mutation {
+            info!("Rolling back an interrupted mutation.");
+            store.rollback_mutation(journal).await?;
+        }
+        if retokenize {
+            if !needs_reindex {
+                info!("Rebuilding the index for the new tokenization.");
+                store.indexer.clear()?;
+                store.reindex().await?;
+            }
+            store.indexer.record_tokenization()?;
+            store.save_state().await?;
+        }
+
+        // Record the sizes and mime types that were not indexed before.
+        let missing_summaries = store.indexer.missing_summaries()?;
+        if !missing_summaries.is_empty() {
+            for id in missing_summaries {
+                let path: Vec<String> = id.clone().into();
+                let default_variant = match store.get_metadata(&path).await {
+                    Ok(metadata) => metadata.get_variant("default").cloned(),
+                    Err(_) => None,
+                };
+                let (size, mime) = default_variant
+                    .map(|variant| (variant.size(), variant.mime_type()))
+                    .unwrap_or_default();
+                store.indexer.set_size(&id, size)?;
+                store.indexer.set_mime_type(&id, &mime)?;
+            }
+            store.save_state().await?;
+        }
+
+        Ok(store)
+    }
+}
+
+impl ResourceStore {
+    async fn init_forest<P: AsRef<Path>>(
+        root_dir: P,
+        store: &impl BlockStore,
+        rng: &mut impl CryptoRngCore,
+        now: DateTime<Utc>,
+    ) -> Result<(Cid, AccessKey)> {
+        debug!("Initializing a new forest");
+        let setup = AccumulatorSetup::trusted(rng);
+        let forest = &mut Rc::new(HamtForest::new(setup));
+        let dir = &mut Rc::new(PrivateDirectory::new(&forest.empty_name(), now, rng));
+        let access_key = dir.as_node().store(forest, store, rng).await?;
+        let forest_cid = forest.store(store).await?;
+
+        // Save the initial access key.
+        to_cbor(subpath(&root_dir, "access.key"), &access_key).await?;
+
+        Ok((forest_cid, access_key))
+    }
+
+    // Moves the index database and its journal files aside.
+    async fn move_index_aside(root_dir: &Path) -> Result<()> {
+        let suffix = Utc::now().format("%Y%m%d%H%M%S");
+        let path = subpath(root_dir, "index.sqlite");
+        fs::rename(
+            &path,
+            subpath(root_dir, &format!("index.sqlite.corrupt-{}", suffix)),
+        )
+        .await?;
+        for journal in [
+            "index.sqlite-wal",
+            "index.sqlite-shm",
+            "index.sqlite-journal",
+        ] {
+            let _ = fs::remove_file(subpath(root_dir, journal)).await;
+        }
+        Ok(())
+    }
+
+    /// Replaces a corrupted index database by the last snapshot saved in
+    /// the private file system, or by an empty one if the snapshot is
+    /// missing or unusable. Returns the new indexer, and whether the
+    /// resources need to be reindexed.
+    async fn recover_index(
+        root_dir: &Path,
+        options: &IndexOptions,
+        access_key: &AccessKey,
+        forest: &HamtForest,
+        block_store: &FileStore,
+    ) -> Result<(Indexer, bool)> {
+        Self::move_index_aside(root_dir).await?;
+
+        let root = PrivateNode::load(access_key, forest, block_store, None)
+            .await?
+            .search_latest(forest, block_store)
+            .await?
+            .as_dir()?;
+        let snapshot = root
+            .get_node(
+                &[".index".to_owned(), "index.sqlite".to_owned()],
+                true,
+                forest,
+                block_store,
+            )
+            .await?;
+        if let Some(PrivateNode::File(file)) = snapshot {
+            let bytes = file.get_content(forest, block_store).await?;
+            fs::write(subpath(root_dir, "index.sqlite"), bytes).await?;
+            match Indexer::new(root_dir, options) {
+                Ok(indexer) => {
+                    info!("Index restored from the last snapshot.");
+                    return Ok((indexer, false));
+                }
+                Err(err) if err.is_corruption() => {
+                    error!("The index snapshot is corrupted too.");
+                    Self::move_index_aside(root_dir).await?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        info!("Rebuilding the index from scratch.");
+        Ok((Indexer::new(root_dir, options)?, true))
+    }
+
+    /// Returns the path, file and metadata of all the resources, walking
+    /// the resources directory recursively.
+    async fn all_resources(&self) -> Result<Vec<(Vec<String>, Rc<PrivateFile>, ResourceMetadata)>> {
+        let mut result = vec![];
+        let mut dirs: Vec<Vec<String>> = vec![vec![]];
+        while let Some(dir_path) = dirs.pop() {
+            let dir = if dir_path.is_empty() {
+                self.resources_dir().await?
+            } else {
+                self.resources_dir()
+                    .await?
+                    .get_node(&dir_path, true, &self.forest, &self.block_store)
+                    .await?
+                    .ok_or_else(|| StoreError::NoSuchResource(dir_path.clone()))?
+                    .as_dir()?
+            };
+
+            for (name, _) in dir.ls(&[], true, &self.forest, &self.block_store).await? {
+                let mut path = dir_path.clone();
+                path.push(name);
+
+                let file = match dir
+                    .get_node(
+                        &path[dir_path.len()..],
+                        true,
+                        &self.forest,
+                        &self.block_store,
+                    )
+                    .await?
+                {
+                    Some(PrivateNode::Dir(_)) => {
+                        dirs.push(path);
+                        continue;
+                    }
+                    Some(PrivateNode::File(file)) => file,
+                    None => continue,
+                };
+
+                let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+                    file.get_metadata().get_deserializable("res_meta");
+                if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
+                    result.push((path, file, resource_metadata));
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Returns the tags of the rules matching this resource.
+    fn matching_tags(
+        &self,
+        rules: &TagRules,
+        id: &ResourceId,
+        default_variant: &VariantMetadata,
+    ) -> Result<Vec<String>> {
+        let mut tags = vec![];
+        for rule in &rules.rules {
+            if !rule.matches_mime_type(&default_variant.mime_type()) {
+                continue;
+            }
+            if let Some(text) = &rule.contains {
+                if !self.indexer.has_text(id, text)? {
+                    continue;
+                }
+            }
+            tags.push(rule.tag.clone());
+        }
+        Ok(tags)
+    }
+
+    /// Applies the tagging rules to all the existing resources, for instance
+    /// after the rules changed. Returns the number of tags added.
+    pub async fn apply_tag_rules(&mut self) -> Result<u32> {
+        let rules = match self.get_settings::<TagRules>().await? {
+            Some(rules) if !rules.rules.is_empty() => rules,
+            _ => return Ok(0),
+        };
+
+        let mut count = 0;
+        for (path, _, resource_metadata) in self.all_resources().await? {
+            let default_variant = match resource_metadata.get_variant("default") {
+                Some(variant) => variant,
+                None => continue,
+            };
+            let id = path.as_slice().into();
+            for tag in self.matching_tags(&rules, &id, default_variant)? {
+                if !resource_metadata.tags().contains(&tag) {
+                    self.add_tag(&path, &tag).await?;
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Rebuilds the index from the resources stored in the private file
+    /// system, which are the authoritative data. The index must be empty.
+    pub async fn reindex(&mut self) -> Result<()> {
+        for (path, file, resource_metadata) in self.all_resources().await? {
+            debug!("Reindexing {:?}", path);
+            self.index_resource(&path, &file, &resource_metadata)
+                .await?;
+        }
+        for (path, metadata) in self.read_containers().await?.iter() {
+            self.indexer
+                .set_container(&path.join("/"), &metadata.text(path))?;
+        }
+
+        self.save_state().await
+    }
+
+    // Adds a resource stored in the private file system to the index.
+    async fn index_resource(
+        &mut self,
+        path: &[String],
+        file: &PrivateFile,
+        resource_metadata: &ResourceMetadata,
+    ) -> Result<()> {
+        let id: ResourceId = path.into();
+        self.indexer.add_resource(&id)?;
+        if let Some(variant) = resource_metadata.get_variant("default") {
+            self.indexer.set_size(&id, variant.size())?;
+        }
+        for tag in resource_metadata.tags() {
+            self.indexer.add_tag(&id, tag)?;
+        }
+        self.indexer
+            .add_description(&id, &resource_metadata.desc())?;
+        if let Some(origin) = resource_metadata.origin() {
+            self.indexer.add_origin(&id, origin)?;
+        }
+        if !resource_metadata.relations().is_empty() {
+            self.indexer
+                .set_relations(&id, resource_metadata.relations())?;
+        }
+
+        for (variant_name, variant) in resource_metadata.variants() {
+            if !Indexer::indexes_content(&variant.mime_type()) {
+                continue;
+            }
+            let (forest, block_store, ahead) = (&self.forest, &self.block_store, self.read_ahead);
+            let mut content = VariantReader::new(
+                || variant_stream(forest, block_store, ahead, file, variant_name, path),
+                variant.size(),
+            )?;
+            self.indexer
+                .add_variant(&id, variant_name, variant, &mut content)
+                .await?;
+        }
+        self.link_resource(path, resource_metadata).await
+    }
+
+    // Returns up to `MAX_SIBLINGS` resources of the container of `path`
+    // whose names start like `prefix`, with their metadata.
+    async fn siblings_with_prefix(
+        &self,
+        path: &[String],
+        prefix: &str,
+    ) -> Result<Vec<(Vec<String>, ResourceMetadata)>> {
+        let mut siblings = vec![];
+        let mut prefix_path = path.to_vec();
+        prefix_path.pop();
+        let container = prefix_path.join("/");
+        prefix_path.push(prefix.to_owned());
+        let prefix: ResourceId = prefix_path.as_slice().into();
+        for id in self.indexer.container_ids_with_prefix(
+            &container,
+            &prefix.to_string(),
+            MAX_SIBLINGS + 1,
+        )? {
+            let sibling: Vec<String> = id.into();
+            if sibling == path {
+                continue;
+            }
+            // The index can be ahead of the forest while rolling back.
+            if let Ok(metadata) = self.get_metadata(&sibling).await {
+                siblings.push((sibling, metadata));
+            }
+        }
+        siblings.truncate(MAX_SIBLINGS as usize);
+        Ok(siblings)
+    }
+
+    // Links subtitles to the video they belong to, and a video to the
+    // subtitles named like it, so that searching subtitles finds the video.
+    async fn link_resource(&mut self, path: &[String], metadata: &ResourceMetadata) -> Result<()> {
+        let (mime, name) = match (metadata.get_variant("default"), path.last()) {
+            (Some(variant), Some(name)) => (variant.mime_type(), name),
+            _ => return Ok(()),
+        };
+        let id = path.into();
+
+        if is_subtitles(&mime) {
+            if let Some(video) = metadata.subtitled_video() {
+                return Ok(self.indexer.set_subtitled_video(&id, Some(&video.into()))?);
+            }
+            let stem = name.split('.').next().unwrap_or_default();
+            let video = self
+                .siblings_with_prefix(path, stem)
+                .await?
+                .into_iter()
+                .find(|(video, video_metadata)| {
+                    belongs_to(name, video.last().unwrap())
+                        && video_metadata
+                            .get_variant("default")
+                            .map(|variant| variant.mime_type().starts_with("video/"))
+                            .unwrap_or_default()
+                });
+            let video: Option<ResourceId> = video.map(|(video, _)| video.as_slice().into());
+            return Ok(self.indexer.set_subtitled_video(&id, video.as_ref())?);
+        }
+
+        // The default variant may not be subtitles anymore.
+        self.indexer.set_subtitled_video(&id, None)?;
+        if mime.starts_with("video/") {
+            let stem = name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(name);
+            for (subtitles, subtitles_metadata) in self.siblings_with_prefix(path, stem).await? {
+                let is_named_subtitles = belongs_to(subtitles.last().unwrap(), name)
+                    && subtitles_metadata
+                        .get_variant("default")
+                        .map(|variant| is_subtitles(&variant.mime_type()))
+                        .unwrap_or_default();
+                if is_named_subtitles && subtitles_metadata.subtitled_video().is_none() {
+                    self.indexer
+                        .set_subtitled_video(&subtitles.as_slice().into(), Some(&id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a new store, with all the data stored under the root dir.
+    /// The root directory and required sub directories will be created
+    /// if they don't already exist.
+    pub async fn new<P: AsRef<Path>>(root_dir: P) -> Result<Self> {
+        ResourceStoreBuilder::new(root_dir).build().await
+    }
+
+    /// Returns a builder to configure the store before opening it.
+    pub fn builder<P: AsRef<Path>>(root_dir: P) -> ResourceStoreBuilder {
+        ResourceStoreBuilder::new(root_dir)
+    }
+
+    /// Opens an existing profile of the store located at `root_dir`.
+    pub async fn open_profile<P: AsRef<Path>>(root_dir: P, name: &str) -> Result<Self> {
+        if !profile_dir(root_dir.as_ref(), name)?.exists() {
+            return Err(StoreError::NoSuchProfile(name.to_owned()));
+        }
+        ResourceStoreBuilder::new(root_dir)
+            .profile(name)
+            .build()
+            .await
+    }
+
+    /// Creates a new profile in the store located at `root_dir`, and opens it.
+    pub async fn create_profile<P: AsRef<Path>>(root_dir: P, name: &str) -> Result<Self> {
+        if profile_dir(root_dir.as_ref(), name)?.exists() {
+            return Err(StoreError::ProfileExists(name.to_owned()));
+        }
+        ResourceStoreBuilder::new(root_dir)
+            .profile(name)
+            .build()
+            .await
+    }
+
+    /// Returns the names of the profiles of the store located at `root_dir`,
+    /// besides the default one.
+    pub async fn list_profiles<P: AsRef<Path>>(root_dir: P) -> Result<Vec<String>> {
+        let root_dir = root_dir.as_ref();
+        let mut names: Vec<String> = state_dirs(root_dir)
+            .await?
+            .into_iter()
+            .filter(|dir| dir != root_dir)
+            .filter_map(|dir| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Deletes a profile of the store located at `root_dir`, and the blocks
+    /// that no other profile uses. No profile should be opened meanwhile,
+    /// since the blocks they didn't commit yet would be removed too.
+    pub async fn delete_profile<P: AsRef<Path>>(root_dir: P, name: &str) -> Result<()> {
+        let root_dir = root_dir.as_ref();
+        let dir = profile_dir(root_dir, name)?;
+        if !dir.exists() {
+            return Err(StoreError::NoSuchProfile(name.to_owned()));
+        }
+        fs::remove_dir_all(&dir).await?;
+
+        let block_store = FileStore::maybe_new(subpath(root_dir, "blockstore")).await?;
+        let count = remove_unreachable_blocks(root_dir, &block_store).await?;
+        debug!("Removed {} blocks of the {} profile", count, name);
+        Ok(())
+    }
+
+    /// Returns the block store shared by the profiles, for tools working on
+    /// the raw blocks like debuggers or replicators. Blocks written by an
+    /// ongoing mutation may not be flushed yet.
+    pub fn block_store(&self) -> &impl BlockStore {
+        &self.block_store
+    }
+
+    /// Returns the cid of the last saved forest, from which all the blocks
+    /// of this profile are reachable.
+    pub async fn forest_cid(&self) -> Result<Cid> {
+        from_cbor(subpath(&self.root_dir, "forest.cid")).await
+    }
+
+    /// Returns the revision of the last change recorded in the journal, or
+    /// 0 for a new store. It identifies the state of the root directory,
+    /// see `changes_since()`.
+    pub async fn root_revision(&self) -> Result<u64> {
+        let dir = self.subdir(&[CHANGES_DIR.to_owned()]).await?;
+        let revision = match self.change_segments(&dir).await?.last() {
+            Some(segment) => self
+                .read_change_segment(&dir, *segment)
+                .await?
+                .last()
+                .map(|change| change.revision),
+            None => None,
+        };
+        Ok(revision.unwrap_or(0))
+    }
+
+    /// Get a handle to the root of the file system.
+    /// The handle is cached until the next mutation.
+    pub async fn root(&self) -> Result<Rc<PrivateDirectory>> {
+        if let Some(root) = self.root_cache.borrow().as_ref() {
+            return Ok(root.clone());
+        }
+
+        let root = PrivateNode::load(&self.access_key, &self.forest, &self.block_store, None)
+            .await?
+            .search_latest(&self.forest, &self.block_store)
+            .await?
+            .as_dir()?;
+
+        *self.root_cache.borrow_mut() = Some(root.clone());
+        Ok(root)
+    }
+
+    /// Drops the cached directory handles, which is needed once the
+    /// forest was updated.
+    fn invalidate_cache(&self) {
+        self.root_cache.borrow_mut().take();
+        self.resources_cache.borrow_mut().take();
+    }
+
+    /// Get a handle to a sub directory in the file system.
+    async fn subdir(&self, path: &[String]) -> Result<Rc<PrivateDirectory>> {
+        match self
+            .root()
+            .await?
+            .get_node(path, true, &self.forest, &self.block_store)
+            .await?
+        {
+            Some(PrivateNode::Dir(dir)) => Ok(dir),
+            _ => Err(StoreError::NoSuchResource(path.to_vec())),
+        }
+    }
+
+    /// Get a handle to the resources subdirectory of the file system.
+    /// The handle is cached until the next mutation.
+    pub async fn resources_dir(&self) -> Result<Rc<PrivateDirectory>> {
+        if let Some(dir) = self.resources_cache.borrow().as_ref() {
+            return Ok(dir.clone());
+        }
+
+        let dir = self.subdir(&[".resources".to_owned()]).await?;
+        *self.resources_cache.borrow_mut() = Some(dir.clone());
+        Ok(dir)
+    }
+
+    async fn index_dir(&self) -> Result<Rc<PrivateDirectory>> {
+        self.subdir(&[".index".to_owned()]).await
+    }
+
+    /// Create a new directory, starting the path from the root.
+    pub async fn mkdir(&mut self, path: &[String]) -> Result<()> {
+        let mut root = PrivateNode::load(&self.access_key, &self.forest, &self.block_store, None)
+            .await?
+            .search_latest(&self.forest, &self.block_store)
+            .await?;
+
+        let root = root.as_dir_mut()?;
+
+        root.mkdir(
+            path,
+            true,
+            self.clock.now(),
+            &self.forest,
+            &self.block_store,
+            &mut self.rng,
+        )
+        .await?;
+
+        root.as_node()
+            .store(&mut self.forest, &self.block_store, &mut self.rng)
+            .await?;
+        // The directory may be created under the resources directory.
+        self.invalidate_cache();
+
+        self.save_state().await
+    }
+
+    async fn save_state(&mut self) -> Result<()> {
+        // Only the root handle is outdated: mutations keep the resources
+        // directory handle current with `store_resources_dir()`.
+        self.root_cache.borrow_mut().take();
+
+        // An index in memory is lost with the store, so there is no need
+        // to recover it from a snapshot.
+        if self.indexer.should_update() && !self.indexer.is_in_memory() {
+            // Update <root_dir>/index.sqlite to .index/index.sqlite
+            let mut dir = self.index_dir().await?;
+            let dir_name = dir.header.get_name().clone();
+            let now = self.clock.now();
+            let file = dir
+                .open_file_mut(
+                    &["index.sqlite".to_owned()],
+                    true,
+                    now,
+                    &mut self.forest,
+                    &self.block_store,
+                    &mut self.rng,
+                )
+                .await?;
+            // Include the pending changes of the write-ahead log in the snapshot.
+            self.indexer.checkpoint()?;
+            let mut full_path = self.root_dir.clone();
+            full_path.push("index.sqlite");
+            let reader = fs::File::open(full_path).await?;
+            let source = PrivateFile::with_content_streaming(
+                &dir_name,
+                now,
+                reader.compat(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+            file.copy_content_from(&source, now);
+
+            dir.as_node()
+                .store(&mut self.forest, &self.block_store, &mut self.rng)
+                .await?;
+
+            self.indexer.set_updated();
+        }
+
+        let forest_cid = self.forest.store(&self.block_store).await?;
+
+        // Make sure all the blocks are written before persisting the forest cid.
+        self.block_store.flush().await?;
+
+        // Switch to the new forest atomically, a torn forest.cid would make
+        // rolling back mutations impossible.
+        let pending = subpath(&self.root_dir, "forest.cid.pending");
+        to_cbor(&pending, forest_cid).await?;
+        fs::rename(&pending, subpath(&self.root_dir, "forest.cid")).await?;
+        Ok(())
+    }
+
+    // Records that `path` is about to be mutated. Nested mutations add their
+    // path to the journal of the outer one.
+    async fn begin_mutation(&mut self, path: &[String]) -> Result<()> {
+        if self.locks.is_held(path) {
+            return Err(StoreError::ResourceLocked(path.to_vec()));
+        }
+        // The journal is only kept once written, so that failing here
+        // doesn't leave a mutation in progress.
+        let mut journal = match &self.mutation {
+            Some(journal) => journal.clone(),
+            None => MutationJournal {
+                forest_cid: from_cbor(subpath(&self.root_dir, "forest.cid")).await?,
+                paths: vec![],
+            },
+        };
+        if !journal.paths.iter().any(|journaled| journaled == path) {
+            journal.paths.push(path.to_vec());
+            let journal_path = subpath(&self.root_dir, MUTATION_JOURNAL);
+            if let Err(err) = to_cbor(&journal_path, &journal).await {
+                if self.mutation.is_none() {
+                    // Don't leave a partial journal to be recovered.
+                    let _ = fs::remove_file(&journal_path).await;
+                }
+                return Err(err);
+            }
+        }
+        self.mutation = Some(journal);
+        self.locks.begin_change(path);
+        self.mutation_depth += 1;
+        Ok(())
+    }
+
+    // Commits the mutation if it succeeded, or rolls it back. Only the
+    // outermost mutation does either, since nested ones may fail without
+    // failing the outer one.
+    async fn end_mutation(&mut self, result: Result<()>) -> Result<()> {
+        self.mutation_depth -= 1;
+        if self.mutation_depth > 0 {
+            return result;
+        }
+        self.locks.end_changes();
+        let journal = match self.mutation.take() {
+            Some(journal) => journal,
+            None => return result,
+        };
+        let result = match result {
+            Ok(()) => self.update_smart_folders().await,
+            err => err,
+        };
+
+        match result {
+            Ok(()) => {
+                fs::remove_file(subpath(&self.root_dir, MUTATION_JOURNAL)).await?;
+                Ok(())
+            }
+            Err(err) => {
+                error!("Mutation failed, rolling back: {}", err);
+                if let Err(rollback_err) = self.rollback_mutation(journal).await {
+                    error!("Failed to roll back the mutation: {}", rollback_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Reverts the forest to the state before the mutation, and makes the
+    // index of the mutated resources match it again.
+    async fn rollback_mutation(&mut self, journal: MutationJournal) -> Result<()> {
+        self.forest = HamtForest::load(&journal.forest_cid, &self.block_store).await?;
+        self.invalidate_cache();
+
+        for path in &journal.paths {
+            self.indexer.delete_resource(&path.as_slice().into())?;
+            let file = match self.maybe_file(path).await {
+                Ok(file) => file,
+                // The resource didn't exist before the mutation.
+                Err(_) => continue,
+            };
+            let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+                file.get_metadata().get_deserializable("res_meta");
+            if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
+                self.index_resource(path, &file, &resource_metadata).await?;
+            }
+        }
+
+        self.save_state().await?;
+        fs::remove_file(subpath(&self.root_dir, MUTATION_JOURNAL)).await?;
+        Ok(())
+    }
+
+    // Points forest.cid back to the forest before a mutation that was
+    // interrupted, returning its journal to finish the rollback once the
+    // store is opened.
+    async fn revert_forest(root_dir: &Path) -> Result<Option<MutationJournal>> {
+        let journal: MutationJournal = match from_cbor(subpath(root_dir, MUTATION_JOURNAL)).await {
+            Ok(journal) => journal,
+            Err(_) => return Ok(None),
+        };
+        let pending = subpath(root_dir, "forest.cid.pending");
+        to_cbor(&pending, journal.forest_cid).await?;
+        fs::rename(&pending, subpath(root_dir, "forest.cid")).await?;
+        Ok(Some(journal))
+    }
+
+    // Returns the content of a hidden file at the root of the private file
+    // system, like the settings document.
+    async fn read_root_file(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self
+            .root()
+            .await?
+            .get_node(&[name.to_owned()], true, &self.forest, &self.block_store)
+            .await?
+        {
+            Some(PrivateNode::File(file)) => Ok(Some(
+                file.get_content(&self.forest, &self.block_store).await?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    // Replaces the content of a hidden file at the root of the private file
+    // system. The caller is responsible for saving the state afterwards.
+    async fn write_root_file(&mut self, name: &str, bytes: Vec<u8>) -> Result<()> {
+        let mut root = PrivateNode::load(&self.access_key, &self.forest, &self.block_store, None)
+            .await?
+            .search_latest(&self.forest, &self.block_store)
+            .await?;
+        let root = root.as_dir_mut()?;
+        let root_name = root.header.get_name().clone();
+        let now = self.clock.now();
+
+        let file = root
+            .open_file_mut(
+                &[name.to_owned()],
+                true,
+                now,
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+        let source = PrivateFile::with_content_streaming(
+            &root_name,
+            now,
+            std::io::Cursor::new(bytes).compat(),
+            &mut self.forest,
+            &self.block_store,
+            &mut self.rng,
+        )
+        .await?;
+        file.copy_content_from(&source, now);
+
+        root.as_node()
+            .store(&mut self.forest, &self.block_store, &mut self.rng)
+            .await?;
+        // The cached directory handles are outdated.
+        self.invalidate_cache();
+        Ok(())
+    }
+
+    async fn settings_document(&self) -> Result<SettingsDocument> {
+        match self.read_root_file(SETTINGS_FILE).await? {
+            Some(bytes) => Ok(serde_cbor::from_slice(&bytes)?),
+            None => Ok(SettingsDocument::new()),
+        }
+    }
+
+    async fn save_settings_document(&mut self, document: &SettingsDocument) -> Result<()> {
+        let bytes = serde_cbor::to_vec(document)?;
+        self.write_root_file(SETTINGS_FILE, bytes).await?;
+        self.save_state().await
+    }
+
+    /// Returns the settings section of type `T`, or `None` if they were never set.
+    pub async fn get_settings<T: Settings>(&self) -> Result<Option<T>> {
+        let mut document = self.settings_document().await?;
+        match document.remove(T::NAME) {
+            Some(entry) if entry.version == T::VERSION => {
+                Ok(Some(serde_cbor::value::from_value(entry.value)?))
+            }
+            Some(entry) => match T::migrate(entry.version, entry.value) {
+                Some(settings) => Ok(Some(settings)),
+                None => Err(StoreError::SettingsVersion(
+                    T::NAME.to_owned(),
+                    entry.version,
+                    T::VERSION,
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Persists the settings section of type `T`, replacing the current one.
+    pub async fn set_settings<T: Settings>(&mut self, settings: &T) -> Result<()> {
+        let mut document = self.settings_document().await?;
+        document.insert(
+            T::NAME.to_owned(),
+            SettingsEntry {
+                version: T::VERSION,
+                value: serde_cbor::value::to_value(settings)?,
+            },
+        );
+        self.save_settings_document(&document).await
+    }
+
+    // Returns the indexes of the change journal segments, in order.
+    async fn change_segments(&self, dir: &Rc<PrivateDirectory>) -> Result<Vec<u64>> {
+        let mut segments: Vec<u64> = dir
+            .ls(&[], true, &self.forest, &self.block_store)
+            .await?
+            .into_iter()
+            .filter_map(|(name, _)| parse_segment_name(&name))
+            .collect();
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    async fn read_change_segment(
+        &self,
+        dir: &Rc<PrivateDirectory>,
+        segment: u64,
+    ) -> Result<Vec<Change>> {
+        match dir
+            .get_node(
+                &[segment_name(segment)],
+                true,
+                &self.forest,
+                &self.block_store,
+            )
+            .await?
+        {
+            Some(PrivateNode::File(file)) => {
+                let bytes = file.get_content(&self.forest, &self.block_store).await?;
+                Ok(serde_cbor::from_slice(&bytes)?)
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Appends a change to the journal, returning its revision. The caller is
+    // responsible for saving the state afterwards.
+    async fn record_change(&mut self, op: ChangeOp, path: &[String]) -> Result<u64> {
+        let mut dir = self.subdir(&[CHANGES_DIR.to_owned()]).await?;
+        let last_segment = self.change_segments(&dir).await?.last().copied();
+        let mut changes = match last_segment {
+            Some(segment) => self.read_change_segment(&dir, segment).await?,
+            None => vec![],
+        };
+
+        let now = self.clock.now();
+        let revision = changes
+            .last()
+            .map(|change| change.revision + 1)
+            .unwrap_or(1);
+        let segment = segment_of(revision);
+        if Some(segment) != last_segment {
+            // The last segment is full.
+            changes.clear();
+        }
+        changes.push(Change {
+            revision,
+            op,
+            path: path.to_vec(),
+            timestamp: now.timestamp(),
+        });
+
+        let bytes = serde_cbor::to_vec(&changes)?;
+        let dir_name = dir.header.get_name().clone();
+        let file = dir
+            .open_file_mut(
+                &[segment_name(segment)],
+                true,
+                now,
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+        let source = PrivateFile::with_content_streaming(
+            &dir_name,
+            now,
+            std::io::Cursor::new(bytes).compat(),
+            &mut self.forest,
+            &self.block_store,
+            &mut self.rng,
+        )
+        .await?;
+        file.copy_content_from(&source, now);
+
+        dir.as_node()
+            .store(&mut self.forest, &self.block_store, &mut self.rng)
+            .await?;
+        self.revision += 1;
+        Ok(revision)
+    }
+
+    /// Returns the revision of the store, which increases with each change
+    /// while it is open. It starts at `root_revision()`, but unlike it never
+    /// goes back, eg. when restoring a snapshot or rolling back a failed
+    /// mutation.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Whether nothing changed since `revision`, ie. whether results
+    /// computed at that revision are still up to date. UIs keeping result
+    /// lists use it to know when to refresh them.
+    pub fn is_current(&self, revision: u64) -> bool {
+        revision == self.revision
+    }
+
+    /// Stamps query results with the current revision, eg.
+    /// `store.stamp(store.search(text).await?)`, to check them later with
+    /// `is_current()`.
+    pub fn stamp<T>(&self, results: T) -> Stamped<T> {
+        Stamped {
+            revision: self.revision,
+            results,
+        }
+    }
+
+    /// Returns the changes made after the `cursor` revision, oldest first.
+    /// Use 0 to get all the changes, and then the revision of the last
+    /// change received as the next cursor.
+    pub async fn changes_since(&self, cursor: u64) -> Result<Vec<Change>> {
+        let dir = self.subdir(&[CHANGES_DIR.to_owned()]).await?;
+        let mut result = vec![];
+        for segment in self.change_segments(&dir).await? {
+            if segment < segment_of(cursor + 1) {
+                continue;
+            }
+            let changes = self.read_change_segment(&dir, segment).await?;
+            result.extend(
+                changes
+                    .into_iter()
+                    .filter(|change| change.revision > cursor),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Returns the changes made after the `cursor` revision to the
+    /// resources synced by `filter`. This is what a sync process pulls from
+    /// a peer, with the `sync_filter()` of the pulling device.
+    pub async fn changes_since_filtered(
+        &self,
+        cursor: u64,
+        filter: &SyncFilter,
+    ) -> Result<Vec<Change>> {
+        let mut changes = self.changes_since(cursor).await?;
+        changes.retain(|change| filter.is_synced(&change.path));
+        Ok(changes)
+    }
+
+    /// Returns the resources that differ between the `from` and `to`
+    /// revisions, with their changed variants, from the change journal.
+    /// `root_revision()` is the current revision, so the last change is
+    /// `diff(root - 1, root)`.
+    pub async fn diff(&self, from: u64, to: u64) -> Result<Vec<ResourceDiff>> {
+        metrics::count_operation("diff");
+        if from > to || to > self.root_revision().await? {
+            return Err(StoreError::InvalidRevisions(from, to));
+        }
+        let mut changes = self.changes_since(from).await?;
+        changes.retain(|change| change.revision <= to);
+        Ok(diff(&changes))
+    }
+
+    /// Returns which containers are synced to this device.
+    pub fn sync_filter(&self) -> &SyncFilter {
+        &self.sync_filter
+    }
+
+    /// Marks a container as synced or not to this device. Resources of
+    /// containers that are not synced are left out of
+    /// `changes_since_filtered()` with this filter.
+    pub async fn set_container_synced(&mut self, container: &[String], synced: bool) -> Result<()> {
+        let changed = if synced {
+            self.sync_filter.include(container)
+        } else {
+            self.sync_filter.exclude(container)
+        };
+        if changed {
+            to_cbor(subpath(&self.root_dir, SYNC_FILTER), &self.sync_filter).await?;
+        }
+        Ok(())
+    }
+
+    /// Persists a new revision of the resources directory.
+    async fn store_resources_dir(&mut self, dir: &Rc<PrivateDirectory>) -> Result<()> {
+        dir.as_node()
+            .store(&mut self.forest, &self.block_store, &mut self.rng)
+            .await?;
+        *self.resources_cache.borrow_mut() = Some(dir.clone());
+        Ok(())
     }
 
     /// Returns the private file at this path if it exists.
@@ -260,542 +2437,3631 @@ impl ResourceStore {
         match self
             .resources_dir()
             .await?
-            .get_node(path, true, &self.forest, &self.block_store)
+            .get_node(path, true, &self.forest, &self.block_store)
+            .await?
+        {
+            Some(PrivateNode::File(file)) => Ok(file),
+            _ => Err(StoreError::NoSuchResource(path.to_vec())),
+        }
+    }
+
+    /// Apply the output of variant transformers for this resource.
+    // Returns the size of the buffer used to read the content of `variant`,
+    // which doesn't need to be larger than the content itself. Callers
+    // leave the size to 0 when they don't know it.
+    fn read_buffer_capacity(&self, variant: &VariantMetadata) -> usize {
+        match variant.size() {
+            0 => self.read_buffer_size,
+            size => size.min(self.read_buffer_size as u64) as usize,
+        }
+    }
+
+    async fn validate<C: ContentReader>(
+        &self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: &mut C,
+    ) -> Result<()> {
+        validators::validate(&self.validators, path, variant_name, variant, content)
+            .await
+            .map_err(StoreError::Rejected)
+    }
+
+    pub async fn apply_variant_transforms(
+        &mut self,
+        path: &[String],
+        transforms: Vec<TransformerResult>,
+    ) -> Result<()> {
+        for transform in transforms {
+            match transform {
+                TransformerResult::Delete(variant_name) => {
+                    match self.delete_variant(path, &variant_name).await {
+                        // Lazily created variants may not exist yet.
+                        Err(StoreError::NoSuchVariant(_, _)) => {}
+                        result => result?,
+                    }
+                }
+                TransformerResult::Create(variant) => {
+                    if variant.name != "default" {
+                        self.add_variant(path, &variant.name, &variant.meta, variant.content)
+                            .await?
+                    }
+                }
+                TransformerResult::Update(variant) => {
+                    if variant.name != "default" {
+                        self.update_variant(path, &variant.name, &variant.meta, variant.content)
+                            .await?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration of the built-in transformers, read from
+    /// their settings sections.
+    pub async fn transformer_config(&self) -> Result<TransformerConfig> {
+        Ok(TransformerConfig {
+            transformers: self.get_settings().await?.unwrap_or_default(),
+            thumbnails: self
+                .get_settings::<ThumbnailSettings>()
+                .await?
+                .unwrap_or_default(),
+            contact_sheets: self
+                .get_settings::<ContactSheetSettings>()
+                .await?
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Enables or disables a built-in transformer, named after the variant
+    /// it creates, eg. `THUMBNAIL_VARIANT`. Existing variants are kept.
+    pub async fn set_transformer_enabled(&mut self, name: &str, enabled: bool) -> Result<()> {
+        if !TRANSFORMERS.contains(&name) {
+            return Err(StoreError::NoSuchTransformer(name.to_owned()));
+        }
+        let mut settings = self.transformer_config().await?.transformers;
+        if enabled {
+            settings.disabled.remove(name);
+        } else {
+            settings.disabled.insert(name.to_owned());
+        }
+        self.set_settings(&settings).await
+    }
+
+    // Creates the thumbnail of an image resource if it doesn't have one yet,
+    // returning whether it was created.
+    async fn create_missing_thumbnail(&mut self, path: &[String]) -> Result<bool> {
+        let file = self.maybe_file(path).await?;
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file.get_metadata().get_deserializable("res_meta");
+        let metadata = match maybe_resource_metadata {
+            Some(Ok(metadata)) => metadata,
+            _ => return Err(StoreError::NoResourceMetadata(path.to_vec())),
+        };
+        let default_variant = match metadata.get_variant("default") {
+            Some(variant) if !metadata.has_variant(THUMBNAIL_VARIANT) => variant.clone(),
+            _ => return Ok(false),
+        };
+        let config = self.transformer_config().await?;
+        if !default_variant.mime_type().starts_with("image/")
+            || !config.is_enabled(THUMBNAIL_VARIANT)
+        {
+            return Ok(false);
+        }
+
+        let bytes = self.file_variant_vec(&file, "default", path).await?;
+        let mut variant_change = VariantChange::Created(default_variant);
+        let settings = ThumbnailSettings {
+            lazy: false,
+            ..config.thumbnails
+        };
+        let results = Thumbnailer::new(&settings)
+            .transform_variant(
+                &mut variant_change,
+                &mut std::io::Cursor::new(bytes).compat(),
+            )
+            .await;
+        if results.is_empty() {
+            // The image could not be decoded.
+            return Ok(false);
+        }
+        self.apply_variant_transforms(path, results).await?;
+        Ok(true)
+    }
+
+    /// Returns the thumbnail of an image resource. With lazy thumbnails,
+    /// it is created and persisted on the first call.
+    pub async fn get_thumbnail<'a>(
+        &'a mut self,
+        path: &[String],
+    ) -> Result<LocalBoxStream<'a, Result<Vec<u8>>>> {
+        self.create_missing_thumbnail(path).await?;
+        self.get_variant(THUMBNAIL_VARIANT, path).await
+    }
+
+    /// Creates up to `count` of the missing image thumbnails, returning how
+    /// many were created. With lazy thumbnails, calling it repeatedly when
+    /// the application is idle backfills them in the background.
+    /// The images created or updated since the store was opened come first,
+    /// in the order of the transform queue, see `prioritize_transforms()`.
+    pub async fn backfill_thumbnails(&mut self, count: usize) -> Result<usize> {
+        let mut created = 0;
+        while created < count {
+            let path = match self.transform_queue.pop() {
+                Some(path) => path,
+                None => break,
+            };
+            match self.create_missing_thumbnail(&path).await {
+                Ok(true) => created += 1,
+                Ok(false) => {}
+                // Deleted since it was queued.
+                Err(StoreError::NoSuchResource(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        for (path, _, metadata) in self.all_resources().await? {
+            if created == count {
+                break;
+            }
+            if metadata.has_variant(THUMBNAIL_VARIANT) {
+                continue;
+            }
+            if self.create_missing_thumbnail(&path).await? {
+                created += 1;
+            }
+        }
+        Ok(created)
+    }
+
+    /// Moves the resource at `path` to the front of the transform queue, so
+    /// that its lazily created variants are the next ones created by
+    /// `backfill_thumbnails()`. Call it for the resources on screen, for
+    /// instance while importing a folder of photos.
+    pub async fn prioritize_transforms(&mut self, path: &[String]) -> Result<()> {
+        // Fail early for unknown resources.
+        let _ = self.maybe_file(path).await?;
+        self.transform_queue.prioritize(path);
+        Ok(())
+    }
+
+    /// Locks a resource or a container, so that the store refuses to change
+    /// them until the returned lock is dropped, except through
+    /// `ResourceLock::allow_changes()`. Returns None if they are already
+    /// locked or being modified.
+    pub fn try_lock_resource(&self, path: &[String]) -> Option<ResourceLock> {
+        self.locks.try_lock(path)
+    }
+
+    /// Returns a handle on the resource locks, to check whether resources
+    /// are being modified while the store is in use, see `locks`.
+    pub fn resource_locks(&self) -> ResourceLocks {
+        self.locks.clone()
+    }
+
+    /// Add a resource with a default variant content.
+    pub async fn create_resource(
+        &mut self,
+        path: &[String],
+        desc: &str,
+        default_variant: &VariantMetadata,
+        tags: HashSet<String>,
+        content: impl ContentReader,
+    ) -> Result<()> {
+        metrics::count_operation("create_resource");
+        self.create_resource_from(path, desc, default_variant, tags, content, None)
+            .await
+    }
+
+    // Creates a resource, recording its origin if it is imported.
+    async fn create_resource_from(
+        &mut self,
+        path: &[String],
+        desc: &str,
+        default_variant: &VariantMetadata,
+        tags: HashSet<String>,
+        content: impl ContentReader,
+        origin: Option<ImportOrigin>,
+    ) -> Result<()> {
+        self.begin_mutation(path).await?;
+        let result = self
+            .do_create_resource(path, desc, default_variant, tags, content, origin)
+            .await;
+        self.end_mutation(result)
+            .await
+            .with_context(|| ErrorContext::new("create_resource").path(path))
+    }
+
+    /// Add a resource like `create_resource()`, resolving a conflict with
+    /// an existing resource at `path` with `policy`. Returns the action taken.
+    pub async fn create_resource_with_policy(
+        &mut self,
+        path: &[String],
+        desc: &str,
+        default_variant: &VariantMetadata,
+        tags: HashSet<String>,
+        content: impl ContentReader,
+        policy: ConflictPolicy,
+    ) -> Result<ImportAction> {
+        metrics::count_operation("create_resource_with_policy");
+        self.create_with_policy(path, desc, default_variant, tags, content, policy, None)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_with_policy(
+        &mut self,
+        path: &[String],
+        desc: &str,
+        default_variant: &VariantMetadata,
+        tags: HashSet<String>,
+        content: impl ContentReader,
+        policy: ConflictPolicy,
+        origin: Option<ImportOrigin>,
+    ) -> Result<ImportAction> {
+        let existing = match self.get_metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(StoreError::NoSuchResource(_)) => {
+                self.create_resource_from(path, desc, default_variant, tags, content, origin)
+                    .await?;
+                return Ok(ImportAction::Created);
+            }
+            Err(err) => return Err(err),
+        };
+
+        match policy {
+            ConflictPolicy::Fail => Err(StoreError::ResourceExists(path.to_vec())),
+            ConflictPolicy::Overwrite => {
+                self.begin_mutation(path).await?;
+                let result = async {
+                    self.do_delete_resource(path, self.clock.now().timestamp())
+                        .await?;
+                    self.do_create_resource(path, desc, default_variant, tags, content, origin)
+                        .await
+                }
+                .await;
+                self.end_mutation(result).await?;
+                Ok(ImportAction::Overwritten)
+            }
+            ConflictPolicy::KeepBoth => {
+                let mut n = 1;
+                let free_path = loop {
+                    let candidate = suffixed_path(path, n);
+                    if self
+                        .resources_dir()
+                        .await?
+                        .get_node(&candidate, true, &self.forest, &self.block_store)
+                        .await?
+                        .is_none()
+                    {
+                        break candidate;
+                    }
+                    n += 1;
+                };
+                self.create_resource_from(&free_path, desc, default_variant, tags, content, origin)
+                    .await?;
+                Ok(ImportAction::Renamed(free_path))
+            }
+            ConflictPolicy::SkipIfIdentical => {
+                let mut content = HashingReader::new(content);
+                futures::io::copy(&mut content, &mut futures::io::sink()).await?;
+                let existing_hash = existing
+                    .get_variant("default")
+                    .and_then(|variant| variant.hash());
+                if existing_hash == Some(content.hash().as_str()) {
+                    Ok(ImportAction::Skipped)
+                } else {
+                    Err(StoreError::ResourceExists(path.to_vec()))
+                }
+            }
+        }
+    }
+
+    async fn do_create_resource(
+        &mut self,
+        path: &[String],
+        desc: &str,
+        default_variant: &VariantMetadata,
+        tags: HashSet<String>,
+        content: impl ContentReader,
+        origin: Option<ImportOrigin>,
+    ) -> Result<()> {
+        let mut content =
+            BufReader::with_capacity(self.read_buffer_capacity(default_variant), content);
+        self.validate(path, "default", default_variant, &mut content)
+            .await?;
+        let mut dir = self.resources_dir().await?;
+        let now = self.clock.now();
+
+        let id = path.into();
+        self.indexer.add_resource(&id)?;
+        self.indexer.add_description(&id, desc)?;
+        if let Some(origin) = &origin {
+            self.indexer.add_origin(&id, origin)?;
+        }
+        self.indexer
+            .add_variant(&id, "default", default_variant, &mut content)
+            .await?;
+
+        // Add the tags of the matching rules, once the content is indexed.
+        let mut tags = tags;
+        let rules = self.get_settings::<TagRules>().await?.unwrap_or_default();
+        tags.extend(self.matching_tags(&rules, &id, default_variant)?);
+        for tag in &tags {
+            self.indexer.add_tag(&id, tag)?;
+        }
+
+        // Create the resource metadata.
+        let mut resource_metadata = ResourceMetadata::new(desc, default_variant, tags);
+        resource_metadata.set_origin(origin);
+
+        // Collect the results from the variant transformers.
+        let config = self.transformer_config().await?;
+        let mut variant_change = VariantChange::Created(default_variant.clone());
+        let transformer_results =
+            run_transformers(&mut variant_change, &mut content, &config).await;
+        if config.lazy_thumbnails() && default_variant.mime_type().starts_with("image/") {
+            self.transform_queue.push(path);
+        }
+
+        let dir_name = dir.header.get_name().clone();
+        let file = dir
+            .open_file_mut(
+                path,
+                false,
+                now,
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let stored_before = self.block_store.bytes_written();
+        let mut content = HashingReader::new(content);
+        self.write_variant_content(file, &dir_name, "default", &mut content, now)
+            .await?;
+
+        // Set the resource metadata
+        let mut default_variant = default_variant.clone();
+        content.update_variant(&mut default_variant, &self.block_store, stored_before);
+        self.indexer.set_size(&id, default_variant.size())?;
+        resource_metadata.add_variant("default", &default_variant);
+        let node_metadata = file.get_metadata_mut();
+        node_metadata.put_serializable("res_meta", resource_metadata.clone())?;
+
+        self.store_resources_dir(&dir).await?;
+        self.link_resource(path, &resource_metadata).await?;
+
+        self.record_change(ChangeOp::CreateResource, path).await?;
+        let mut tombstones = self.read_tombstones().await?;
+        if tombstones.remove(path) {
+            self.write_tombstones(tombstones).await?;
+        }
+
+        // Apply the variant transformers. This needs to be done after the
+        // resource is fully created.
+        self.apply_variant_transforms(path, transformer_results)
+            .await?;
+
+        self.save_state().await
+    }
+
+    /// Add a variant to an existing resource.
+    pub async fn add_variant(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: impl ContentReader,
+    ) -> Result<()> {
+        metrics::count_operation("add_variant");
+        self.begin_mutation(path).await?;
+        let result = self
+            .do_add_variant(path, variant_name, variant, content)
+            .await;
+        self.end_mutation(result).await.with_context(|| {
+            ErrorContext::new("add_variant")
+                .path(path)
+                .variant(variant_name)
+        })
+    }
+
+    async fn do_add_variant(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: impl ContentReader,
+    ) -> Result<()> {
+        if variant_name == "default" {
+            return Err(StoreError::InvalidVariant(variant_name.to_owned()));
+        }
+
+        let mut content = BufReader::with_capacity(self.read_buffer_capacity(variant), content);
+        self.validate(path, variant_name, variant, &mut content)
+            .await?;
+
+        let mut dir = self.resources_dir().await?;
+        let file = dir
+            .open_file_mut(
+                path,
+                true,
+                self.clock.now(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let file_name = file.header.get_name().clone();
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file.get_metadata().get_deserializable("res_meta");
+        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
+            let id = path.into();
+            self.indexer
+                .add_variant(&id, variant_name, variant, &mut content)
+                .await?;
+            self.indexer.touch(&id)?;
+
+            let stored_before = self.block_store.bytes_written();
+            let mut content = HashingReader::new(content);
+            self.write_variant_content(
+                file,
+                &file_name,
+                variant_name,
+                &mut content,
+                self.clock.now(),
+            )
+            .await?;
+
+            let mut variant = variant.clone();
+            content.update_variant(&mut variant, &self.block_store, stored_before);
+            resource_metadata.add_variant(variant_name, &variant);
+            file.get_metadata_mut()
+                .put_serializable("res_meta", resource_metadata)?;
+
+            self.store_resources_dir(&dir).await?;
+
+            self.record_change(ChangeOp::AddVariant(variant_name.to_owned()), path)
+                .await?;
+
+            self.save_state().await
+        } else {
+            Err(StoreError::NoResourceMetadata(path.to_vec()))
+        }
+    }
+
+    /// Returns a writer adding a variant to an existing resource, for
+    /// content that is pushed rather than read by the store.
+    /// The size of the variant is set to the number of bytes written once
+    /// the writer is closed.
+    /// Validators are not run, since the content is not available upfront.
+    pub fn add_variant_writer(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+    ) -> VariantWriter<'_> {
+        let (pipe, reader) = tokio::io::duplex(self.read_buffer_size);
+        let task = self.add_variant_streaming(
+            path.to_vec(),
+            variant_name.to_owned(),
+            variant.clone(),
+            reader.compat(),
+        );
+
+        VariantWriter {
+            pipe: Some(pipe),
+            task: Box::pin(task),
+            result: None,
+        }
+    }
+
+    async fn add_variant_streaming(
+        &mut self,
+        path: Vec<String>,
+        variant_name: String,
+        mut variant: VariantMetadata,
+        content: impl AsyncRead + Unpin,
+    ) -> Result<()> {
+        if variant_name == "default" {
+            return Err(StoreError::InvalidVariant(variant_name));
+        }
+        // Locked resources are refused before their content is streamed,
+        // and the resource is reported as locked meanwhile. The lock is
+        // already taken when the caller changes it through its own lock.
+        if self.locks.is_held(&path) {
+            return Err(StoreError::ResourceLocked(path));
+        }
+        let lock = self.locks.try_lock(&path);
+
+        // The content goes to a copy of the forest, which replaces the one
+        // of the store once the writer is closed: dropping the writer
+        // before leaves the store unchanged, and its blocks unreachable
+        // until `compact()` removes them.
+        let mut forest = self.forest.clone();
+        let mut dir = self.resources_dir().await?;
+        let file = dir
+            .open_file_mut(
+                &path,
+                true,
+                self.clock.now(),
+                &mut forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let file_name = file.header.get_name().clone();
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file.get_metadata().get_deserializable("res_meta");
+        let mut resource_metadata = match maybe_resource_metadata {
+            Some(Ok(resource_metadata)) => resource_metadata,
+            _ => return Err(StoreError::NoResourceMetadata(path)),
+        };
+
+        let stored_before = self.block_store.bytes_written();
+        let mut content = HashingReader::new(content);
+        self.write_variant_content_to(
+            &mut forest,
+            file,
+            &file_name,
+            &variant_name,
+            &mut content,
+            self.clock.now(),
+        )
+        .await?;
+
+        // The content is complete once the writer is closed.
+        content.update_variant(&mut variant, &self.block_store, stored_before);
+        resource_metadata.add_variant(&variant_name, &variant);
+        file.get_metadata_mut()
+            .put_serializable("res_meta", resource_metadata)?;
+        // Kept to index the content once the directory is stored.
+        let file = file.clone();
+
+        // Only committing the variant changes the store, so that is what the
+        // mutation journal covers.
+        let _allowed = lock.as_ref().map(|lock| lock.allow_changes());
+        self.begin_mutation(&path).await?;
+        let result = async {
+            self.forest = forest;
+            self.store_resources_dir(&dir).await?;
+
+            // The content was not seekable while streaming, so read it back
+            // from the store when it needs to be indexed.
+            let id = path.as_slice().into();
+            if Indexer::indexes_content(&variant.mime_type()) {
+                let (forest, block_store, ahead) =
+                    (&self.forest, &self.block_store, self.read_ahead);
+                let mut content = VariantReader::new(
+                    || variant_stream(forest, block_store, ahead, &file, &variant_name, &path),
+                    variant.size(),
+                )?;
+                self.indexer
+                    .add_variant(&id, &variant_name, &variant, &mut content)
+                    .await?;
+            }
+            self.indexer.touch(&id)?;
+
+            self.record_change(ChangeOp::AddVariant(variant_name.clone()), &path)
+                .await?;
+
+            self.save_state().await
+        }
+        .await;
+        self.end_mutation(result).await.with_context(|| {
+            ErrorContext::new("add_variant_writer")
+                .path(&path)
+                .variant(&variant_name)
+        })
+    }
+
+    /// Update a variant of an existing resource.
+    pub async fn update_variant(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: impl ContentReader,
+    ) -> Result<()> {
+        metrics::count_operation("update_variant");
+        self.begin_mutation(path).await?;
+        let result = self
+            .do_update_variant(path, variant_name, variant, content)
+            .await;
+        self.end_mutation(result).await.with_context(|| {
+            ErrorContext::new("update_variant")
+                .path(path)
+                .variant(variant_name)
+        })
+    }
+
+    async fn do_update_variant(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: impl ContentReader,
+    ) -> Result<()> {
+        let mut content = BufReader::with_capacity(self.read_buffer_capacity(variant), content);
+        self.validate(path, variant_name, variant, &mut content)
+            .await?;
+        let config = self.transformer_config().await?;
+        let mut dir = self.resources_dir().await?;
+        let dir_name = dir.header.get_name().clone();
+        let file = dir
+            .open_file_mut(
+                path,
+                true,
+                self.clock.now(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        if variant_name == "default" {
+            let now = self.clock.now();
+
+            let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+                file.get_metadata().get_deserializable("res_meta");
+            let mut resource_metadata = match maybe_resource_metadata {
+                Some(Ok(resource_metadata)) => resource_metadata,
+                _ => return Err(StoreError::NoResourceMetadata(path.to_vec())),
+            };
+
+            let id = path.into();
+            self.indexer
+                .update_variant(&id, variant_name, variant, &mut content)
+                .await?;
+            self.indexer.touch(&id)?;
+
+            // Collect the results from the variant transformers.
+            let mut variant_change = VariantChange::Updated(variant.clone());
+            let transformer_results =
+                run_transformers(&mut variant_change, &mut content, &config).await;
+            if config.lazy_thumbnails() && variant.mime_type().starts_with("image/") {
+                self.transform_queue.push(path);
+            }
+
+            // Special case for the default variant, updating the main file content.
+            let stored_before = self.block_store.bytes_written();
+            let mut content = HashingReader::new(content);
+            self.write_variant_content(file, &dir_name, variant_name, &mut content, now)
+                .await?;
+
+            // Keep the default variant metadata in sync with the new content.
+            let mut variant = variant.clone();
+            content.update_variant(&mut variant, &self.block_store, stored_before);
+            self.indexer.set_size(&id, variant.size())?;
+            resource_metadata.add_variant(variant_name, &variant);
+            file.get_metadata_mut()
+                .put_serializable("res_meta", resource_metadata.clone())?;
+
+            self.store_resources_dir(&dir).await?;
+            // The mime type may have changed.
+            self.link_resource(path, &resource_metadata).await?;
+
+            self.record_change(ChangeOp::UpdateVariant(variant_name.to_owned()), path)
+                .await?;
+
+            self.apply_variant_transforms(path, transformer_results)
+                .await?;
+
+            return self.save_state().await;
+        }
+
+        let file_name = file.header.get_name().clone();
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file.get_metadata().get_deserializable("res_meta");
+        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
+            let id = path.into();
+            self.indexer
+                .update_variant(&id, variant_name, variant, &mut content)
+                .await?;
+            self.indexer.touch(&id)?;
+
+            let stored_before = self.block_store.bytes_written();
+            let mut content = HashingReader::new(content);
+            self.write_variant_content(
+                file,
+                &file_name,
+                variant_name,
+                &mut content,
+                self.clock.now(),
+            )
+            .await?;
+
+            let mut variant = variant.clone();
+            content.update_variant(&mut variant, &self.block_store, stored_before);
+            resource_metadata.add_variant(variant_name, &variant);
+            file.get_metadata_mut()
+                .put_serializable("res_meta", resource_metadata)?;
+
+            self.store_resources_dir(&dir).await?;
+
+            self.record_change(ChangeOp::UpdateVariant(variant_name.to_owned()), path)
+                .await?;
+
+            self.save_state().await
+        } else {
+            Err(StoreError::NoResourceMetadata(path.to_vec()))
+        }
+    }
+
+    /// Like `create_resource()`, for content read with the tokio io traits.
+    pub async fn create_resource_tokio<R: TokioAsyncRead + TokioAsyncSeek + Unpin>(
+        &mut self,
+        path: &[String],
+        desc: &str,
+        default_variant: &VariantMetadata,
+        tags: HashSet<String>,
+        content: R,
+    ) -> Result<()> {
+        self.create_resource(path, desc, default_variant, tags, content.compat())
+            .await
+    }
+
+    /// Like `add_variant()`, for content read with the tokio io traits.
+    pub async fn add_variant_tokio<R: TokioAsyncRead + TokioAsyncSeek + Unpin>(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: R,
+    ) -> Result<()> {
+        self.add_variant(path, variant_name, variant, content.compat())
+            .await
+    }
+
+    /// Like `update_variant()`, for content read with the tokio io traits.
+    pub async fn update_variant_tokio<R: TokioAsyncRead + TokioAsyncSeek + Unpin>(
+        &mut self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: R,
+    ) -> Result<()> {
+        self.update_variant(path, variant_name, variant, content.compat())
+            .await
+    }
+
+    /// Deletes a single variant from an existing resource.
+    pub async fn delete_variant(&mut self, path: &[String], variant_name: &str) -> Result<()> {
+        metrics::count_operation("delete_variant");
+        let before = self.undo_point().await?;
+        self.begin_mutation(path).await?;
+        let result = self.do_delete_variant(path, variant_name).await;
+        self.end_mutation(result).await.with_context(|| {
+            ErrorContext::new("delete_variant")
+                .path(path)
+                .variant(variant_name)
+        })?;
+        self.push_undo(UndoOp::DeleteVariant(variant_name.to_owned()), path, before)
+            .await;
+        Ok(())
+    }
+
+    async fn do_delete_variant(&mut self, path: &[String], variant_name: &str) -> Result<()> {
+        // Deleting the default variant is not allowed.
+        if variant_name == "default" {
+            return Err(StoreError::InvalidVariant(variant_name.to_owned()));
+        }
+
+        let mut dir = self.resources_dir().await?;
+
+        let file = dir
+            .open_file_mut(
+                path,
+                true,
+                self.clock.now(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let file_metadata = file.get_metadata_mut();
+
+        // Get the private forest content for that resource.
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file_metadata.get_deserializable("res_meta");
+        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
+            if !resource_metadata.has_variant(variant_name) {
+                return Err(StoreError::NoSuchVariant(
+                    variant_name.to_owned(),
+                    path.to_vec(),
+                ));
+            }
+
+            resource_metadata.remove_variant(variant_name);
+            file_metadata.put_serializable("res_meta", resource_metadata)?;
+
+            let _ = file_metadata.delete(&format!("{}_variant", variant_name));
+        } else {
+            return Err(StoreError::NoResourceMetadata(path.to_vec()));
+        }
+
+        self.store_resources_dir(&dir).await?;
+
+        let id = path.into();
+        self.indexer.delete_variant(&id, variant_name)?;
+        self.indexer.touch(&id)?;
+
+        self.record_change(ChangeOp::DeleteVariant(variant_name.to_owned()), path)
+            .await?;
+
+        self.save_state().await
+    }
+
+    /// Removes a resource and all its variants from the store.
+    pub async fn delete_resource(&mut self, path: &[String]) -> Result<()> {
+        metrics::count_operation("delete_resource");
+        let before = self.undo_point().await?;
+        self.begin_mutation(path).await?;
+        let result = self
+            .do_delete_resource(path, self.clock.now().timestamp())
+            .await;
+        self.end_mutation(result)
+            .await
+            .with_context(|| ErrorContext::new("delete_resource").path(path))?;
+        self.push_undo(UndoOp::DeleteResource, path, before).await;
+        Ok(())
+    }
+
+    async fn do_delete_resource(&mut self, path: &[String], deleted_at: i64) -> Result<()> {
+        let mut dir = self.resources_dir().await?;
+
+        dir.rm(path, true, &self.forest, &self.block_store).await?;
+        self.store_resources_dir(&dir).await?;
+
+        self.indexer.delete_resource(&path.into())?;
+        self.transform_queue.remove(path);
+
+        let revision = self.record_change(ChangeOp::DeleteResource, path).await?;
+        let mut tombstones = self.read_tombstones().await?;
+        tombstones.record(Tombstone {
+            path: path.to_vec(),
+            revision,
+            deleted_at,
+        });
+        self.write_tombstones(tombstones).await?;
+
+        self.save_state().await
+    }
+
+    async fn read_containers(&self) -> Result<Containers> {
+        match self.read_root_file(CONTAINERS_FILE).await? {
+            Some(bytes) => Ok(serde_cbor::from_slice(&bytes)?),
+            None => Ok(Containers::default()),
+        }
+    }
+
+    // Fails if there is no container at `path` in the resources directory.
+    async fn check_container(&self, path: &[String]) -> Result<()> {
+        match self
+            .resources_dir()
+            .await?
+            .get_node(path, true, &self.forest, &self.block_store)
+            .await?
+        {
+            Some(PrivateNode::Dir(_)) => Ok(()),
+            _ => Err(StoreError::NoSuchContainer(path.to_vec())),
+        }
+    }
+
+    /// Returns the description and tags of the container at `path`, empty
+    /// if they were never set.
+    pub async fn get_container_metadata(&self, path: &[String]) -> Result<ContainerMetadata> {
+        metrics::count_operation("get_container_metadata");
+        self.check_container(path).await?;
+        Ok(self
+            .read_containers()
+            .await?
+            .get(path)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Updates the description and tags of the container at `path`, which
+    /// are searched along with its name by `search_containers()`.
+    pub async fn update_container_metadata(
+        &mut self,
+        path: &[String],
+        update: impl FnOnce(&mut ContainerMetadata),
+    ) -> Result<()> {
+        metrics::count_operation("update_container_metadata");
+        self.check_container(path).await?;
+        let mut containers = self.read_containers().await?;
+        let previous = containers.get(path).cloned().unwrap_or_default();
+        let mut metadata = previous.clone();
+        update(&mut metadata);
+        if metadata == previous {
+            return Ok(());
+        }
+
+        self.indexer
+            .set_container(&path.join("/"), &metadata.text(path))?;
+        containers.set(path, metadata);
+        self.write_root_file(CONTAINERS_FILE, serde_cbor::to_vec(&containers)?)
+            .await?;
+        self.record_change(ChangeOp::UpdateContainer, path).await?;
+        self.save_state().await
+    }
+
+    /// Returns the containers whose name, description or tags contain
+    /// `text`, with their metadata. Containers are found once they hold a
+    /// resource, or when their metadata was set.
+    pub async fn search_containers(
+        &self,
+        text: &str,
+    ) -> Result<Vec<(Vec<String>, ContainerMetadata)>> {
+        metrics::count_operation("search_containers");
+        let paths = self.indexer.search_containers(text)?;
+        let containers = self.read_containers().await?;
+        Ok(paths
+            .into_iter()
+            .map(|path| {
+                let path: Vec<String> = path.split('/').map(|s| s.to_owned()).collect();
+                let metadata = containers.get(&path).cloned().unwrap_or_default();
+                (path, metadata)
+            })
+            .collect())
+    }
+
+    async fn read_tombstones(&self) -> Result<Tombstones> {
+        match self.read_root_file(TOMBSTONES_FILE).await? {
+            Some(bytes) => Ok(serde_cbor::from_slice(&bytes)?),
+            None => Ok(Tombstones::default()),
+        }
+    }
+
+    // Writes the tombstones, dropping the ones past the retention window.
+    // The caller is responsible for saving the state afterwards.
+    async fn write_tombstones(&mut self, mut tombstones: Tombstones) -> Result<()> {
+        let settings = self
+            .get_settings::<TombstoneSettings>()
+            .await?
+            .unwrap_or_default();
+        tombstones.prune(self.clock.now().timestamp(), &settings);
+        let bytes = serde_cbor::to_vec(&tombstones)?;
+        self.write_root_file(TOMBSTONES_FILE, bytes).await
+    }
+
+    /// Returns the tombstones of the deleted resources, for a sync process
+    /// to send them to the other replicas.
+    pub async fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        Ok(self.read_tombstones().await?.into_vec())
+    }
+
+    /// Returns the tombstone of the resource at `path`, if it was deleted.
+    /// A sync process should not create a resource from another replica if
+    /// it was deleted after the remote change.
+    pub async fn tombstone(&self, path: &[String]) -> Result<Option<Tombstone>> {
+        Ok(self.read_tombstones().await?.get(path).cloned())
+    }
+
+    /// Applies the tombstones of another replica: the resources deleted
+    /// there are deleted here too, unless they were modified after their
+    /// deletion. The tombstones are kept to be propagated to the next
+    /// replicas. Returns the paths of the deleted resources.
+    pub async fn apply_tombstones(&mut self, tombstones: &[Tombstone]) -> Result<Vec<Vec<String>>> {
+        metrics::count_operation("apply_tombstones");
+        let mut deleted = vec![];
+        let mut missing = vec![];
+        for tombstone in tombstones {
+            let path = &tombstone.path;
+            match self.maybe_file(path).await {
+                Ok(file) => {
+                    let modified = file
+                        .get_metadata()
+                        .get_modified()
+                        .map(|modified| modified.timestamp())
+                        .unwrap_or_default();
+                    if modified > tombstone.deleted_at {
+                        continue;
+                    }
+                    self.begin_mutation(path).await?;
+                    let result = self.do_delete_resource(path, tombstone.deleted_at).await;
+                    self.end_mutation(result).await?;
+                    deleted.push(path.clone());
+                }
+                Err(StoreError::NoSuchResource(_)) => missing.push(tombstone),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(deleted);
+        }
+        let revision = self.root_revision().await?;
+        let mut known = self.read_tombstones().await?;
+        let mut changed = false;
+        for tombstone in missing {
+            changed |= known.record(Tombstone {
+                revision,
+                ..tombstone.clone()
+            });
+        }
+        if changed {
+            self.write_tombstones(known).await?;
+            self.save_state().await?;
+        }
+        Ok(deleted)
+    }
+
+    /// Add a tag to this resource.
+    pub async fn add_tag(&mut self, path: &[String], tag: &str) -> Result<()> {
+        metrics::count_operation("add_tag");
+        let before = self.undo_point().await?;
+        self.begin_mutation(path).await?;
+        let result = self.do_add_tag(path, tag).await;
+        self.end_mutation(result)
+            .await
+            .with_context(|| ErrorContext::new("add_tag").path(path))?;
+        self.push_undo(UndoOp::AddTag(tag.to_owned()), path, before)
+            .await;
+        Ok(())
+    }
+
+    async fn do_add_tag(&mut self, path: &[String], tag: &str) -> Result<()> {
+        let mut dir = self.resources_dir().await?;
+
+        let file = dir
+            .open_file_mut(
+                path,
+                true,
+                self.clock.now(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let file_metadata = file.get_metadata_mut();
+
+        // Get the private forest content for that resource.
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file_metadata.get_deserializable("res_meta");
+        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
+            resource_metadata.add_tag(tag);
+            file_metadata.put_serializable("res_meta", resource_metadata)?;
+        } else {
+            return Err(StoreError::NoResourceMetadata(path.to_vec()));
+        }
+
+        self.store_resources_dir(&dir).await?;
+
+        let id = path.into();
+        self.indexer.add_tag(&id, tag)?;
+        self.indexer.touch(&id)?;
+
+        self.record_change(ChangeOp::AddTag(tag.to_owned()), path)
+            .await?;
+
+        self.save_state().await
+    }
+
+    /// Changes the description of a resource.
+    pub async fn update_desc(&mut self, path: &[String], desc: &str) -> Result<()> {
+        self.update_metadata(path, |metadata| metadata.set_desc(desc))
+            .await
+    }
+
+    /// Changes the metadata of a resource with `update`, persisting it and
+    /// reindexing its description and tags. Changes to the variants are
+    /// ignored, since their metadata describes the stored content.
+    pub async fn update_metadata(
+        &mut self,
+        path: &[String],
+        update: impl FnOnce(&mut ResourceMetadata),
+    ) -> Result<()> {
+        metrics::count_operation("update_metadata");
+        let before = self.undo_point().await?;
+        self.begin_mutation(path).await?;
+        let result = self.do_update_metadata(path, update).await;
+        self.end_mutation(result)
+            .await
+            .with_context(|| ErrorContext::new("update_metadata").path(path))?;
+        self.push_undo(UndoOp::UpdateMetadata, path, before).await;
+        Ok(())
+    }
+
+    /// Links the resource at `path` to `target` by a relation of this
+    /// kind, eg. `resource::ATTACHMENT_OF`. The relation is recorded in the
+    /// metadata of the resource, and both must exist.
+    pub async fn add_relation(
+        &mut self,
+        path: &[String],
+        kind: &str,
+        target: &[String],
+    ) -> Result<()> {
+        metrics::count_operation("add_relation");
+        let _ = self.maybe_file(target).await?;
+        self.update_metadata(path, |metadata| {
+            metadata.add_relation(kind, target);
+        })
+        .await
+    }
+
+    /// Removes a relation added by `add_relation()`.
+    pub async fn remove_relation(
+        &mut self,
+        path: &[String],
+        kind: &str,
+        target: &[String],
+    ) -> Result<()> {
+        metrics::count_operation("remove_relation");
+        self.update_metadata(path, |metadata| {
+            metadata.remove_relation(kind, target);
+        })
+        .await
+    }
+
+    /// Returns the resources linked to the one at `path` by relations of
+    /// this kind, or of any kind if None, in both directions: the targets
+    /// of its relations and the resources having it as target. For
+    /// instance the attachments of a note, or the note of an attachment.
+    pub async fn get_related(
+        &self,
+        path: &[String],
+        kind: Option<&str>,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("get_related");
+        let ids = self.indexer.related(&path.into(), kind)?;
+        self.with_metadata(ids).await
+    }
+
+    async fn do_update_metadata(
+        &mut self,
+        path: &[String],
+        update: impl FnOnce(&mut ResourceMetadata),
+    ) -> Result<()> {
+        let mut dir = self.resources_dir().await?;
+
+        let file = dir
+            .open_file_mut(
+                path,
+                true,
+                self.clock.now(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let file_metadata = file.get_metadata_mut();
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file_metadata.get_deserializable("res_meta");
+        let previous = match maybe_resource_metadata {
+            Some(Ok(resource_metadata)) => resource_metadata,
+            _ => return Err(StoreError::NoResourceMetadata(path.to_vec())),
+        };
+
+        let mut resource_metadata = previous.clone();
+        update(&mut resource_metadata);
+        let names: Vec<String> = resource_metadata.variants().keys().cloned().collect();
+        for name in names {
+            resource_metadata.remove_variant(&name);
+        }
+        for (name, variant) in previous.variants() {
+            resource_metadata.add_variant(name, variant);
+        }
+        file_metadata.put_serializable("res_meta", resource_metadata.clone())?;
+
+        self.store_resources_dir(&dir).await?;
+
+        let id = path.into();
+        if resource_metadata.subtitled_video() != previous.subtitled_video() {
+            self.link_resource(path, &resource_metadata).await?;
+        }
+        if resource_metadata.desc() != previous.desc() {
+            self.indexer.remove_description(&id, &previous.desc())?;
+            self.indexer
+                .add_description(&id, &resource_metadata.desc())?;
+            self.record_change(ChangeOp::UpdateDescription, path)
+                .await?;
+        }
+
+        let mut added: Vec<&String> = resource_metadata
+            .tags()
+            .difference(previous.tags())
+            .collect();
+        let mut removed: Vec<&String> = previous
+            .tags()
+            .difference(resource_metadata.tags())
+            .collect();
+        added.sort();
+        removed.sort();
+        for tag in added {
+            self.indexer.add_tag(&id, tag)?;
+            self.record_change(ChangeOp::AddTag(tag.clone()), path)
+                .await?;
+        }
+        for tag in removed {
+            self.indexer.remove_tag(&id, tag)?;
+            self.record_change(ChangeOp::RemoveTag(tag.clone()), path)
+                .await?;
+        }
+
+        if resource_metadata.relations() != previous.relations() {
+            self.indexer
+                .set_relations(&id, resource_metadata.relations())?;
+            for relation in resource_metadata.relations() {
+                if !previous.relations().contains(relation) {
+                    let op = ChangeOp::AddRelation(relation.kind.clone(), relation.target.clone());
+                    self.record_change(op, path).await?;
+                }
+            }
+            for relation in previous.relations() {
+                if !resource_metadata.relations().contains(relation) {
+                    let op =
+                        ChangeOp::RemoveRelation(relation.kind.clone(), relation.target.clone());
+                    self.record_change(op, path).await?;
+                }
+            }
+        }
+        self.indexer.touch(&id)?;
+
+        self.save_state().await
+    }
+
+    /// Remove a tag from this resource.
+    pub async fn remove_tag(&mut self, path: &[String], tag: &str) -> Result<()> {
+        metrics::count_operation("remove_tag");
+        let before = self.undo_point().await?;
+        self.begin_mutation(path).await?;
+        let result = self.do_remove_tag(path, tag).await;
+        self.end_mutation(result)
+            .await
+            .with_context(|| ErrorContext::new("remove_tag").path(path))?;
+        self.push_undo(UndoOp::RemoveTag(tag.to_owned()), path, before)
+            .await;
+        Ok(())
+    }
+
+    async fn do_remove_tag(&mut self, path: &[String], tag: &str) -> Result<()> {
+        let mut dir = self.resources_dir().await?;
+
+        let file = dir
+            .open_file_mut(
+                path,
+                true,
+                self.clock.now(),
+                &mut self.forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+
+        let file_metadata = file.get_metadata_mut();
+
+        // Get the private forest content for that resource.
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file_metadata.get_deserializable("res_meta");
+        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
+            resource_metadata.remove_tag(tag);
+            file_metadata.put_serializable("res_meta", resource_metadata)?;
+        } else {
+            return Err(StoreError::NoResourceMetadata(path.to_vec()));
+        }
+
+        self.store_resources_dir(&dir).await?;
+
+        let id = path.into();
+        self.indexer.remove_tag(&id, tag)?;
+        self.indexer.touch(&id)?;
+
+        self.record_change(ChangeOp::RemoveTag(tag.to_owned()), path)
+            .await?;
+
+        self.save_state().await
+    }
+
+    /// Retrieves the content for this path and variant as a bytes vector.
+    /// Should only be used for small variant sizes.
+    pub async fn get_variant_vec(&self, variant_name: &str, path: &[String]) -> Result<Vec<u8>> {
+        metrics::count_operation("get_variant_vec");
+        let result = async {
+            let file = self.maybe_file(path).await?;
+            self.indexer.visit(&path.into())?;
+
+            self.file_variant_vec(&file, variant_name, path).await
+        }
+        .await;
+        result.with_context(|| {
+            ErrorContext::new("get_variant")
+                .path(path)
+                .variant(variant_name)
+        })
+    }
+
+    async fn file_variant_vec(
+        &self,
+        file: &PrivateFile,
+        variant_name: &str,
+        path: &[String],
+    ) -> Result<Vec<u8>> {
+        variant_stream(
+            &self.forest,
+            &self.block_store,
+            self.read_ahead,
+            file,
+            variant_name,
+            path,
+        )?
+        .try_concat()
+        .await
+    }
+
+    /// Checks that the content of a variant still matches the hash recorded
+    /// when it was written, returning false if it was corrupted. Variants
+    /// written before hashes were recorded can't be verified.
+    pub async fn verify_variant(&self, path: &[String], variant_name: &str) -> Result<bool> {
+        metrics::count_operation("verify_variant");
+        let metadata = self.get_metadata(path).await?;
+        let variant = metadata
+            .get_variant(variant_name)
+            .ok_or_else(|| StoreError::NoSuchVariant(variant_name.to_owned(), path.to_vec()))?;
+        let expected = variant
+            .hash()
+            .ok_or_else(|| StoreError::NoVariantHash(variant_name.to_owned(), path.to_vec()))?;
+
+        let mut hasher = blake3::Hasher::new();
+        let mut stream = self.get_variant(variant_name, path).await?;
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+        Ok(hasher.finalize().to_hex().as_str() == expected)
+    }
+
+    /// Retrieves the content for this path and variant as a stream of byte chunks.
+    pub async fn get_variant<'a>(
+        &'a self,
+        variant_name: &str,
+        path: &[String],
+    ) -> Result<LocalBoxStream<'a, Result<Vec<u8>>>> {
+        metrics::count_operation("get_variant");
+        let file = self.maybe_file(path).await?;
+        self.indexer.visit(&path.into())?;
+
+        variant_stream(
+            &self.forest,
+            &self.block_store,
+            self.read_ahead,
+            &file,
+            variant_name,
+            path,
+        )
+    }
+
+    // Returns the path of an imported file, in the container given by the
+    // routing rules for its mime type.
+    async fn import_path(&self, name: &str, mime: &str) -> Result<Vec<String>> {
+        let rules = self.get_settings::<RouteRules>().await?.unwrap_or_default();
+        let mut path = rules.container_for(mime).to_vec();
+        path.push(name.to_owned());
+        Ok(path)
+    }
+
+    /// Imports a local file to the private store, in the container given by
+    /// the `RouteRules` settings section for its mime type if any.
+    pub async fn import_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.import_file_with_policy(path, ConflictPolicy::Fail)
+            .await
+            .map(|_| ())
+    }
+
+    /// Imports a file like `import_file()`, resolving a conflict with an
+    /// existing resource of the same name with `policy`.
+    pub async fn import_file_with_policy<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        policy: ConflictPolicy,
+    ) -> Result<ImportAction> {
+        metrics::count_operation("import_file");
+        let full_path = path.as_ref();
+        self.do_import_file(full_path, policy)
+            .await
+            .with_context(|| ErrorContext::new("import_file").file(full_path))
+    }
+
+    async fn do_import_file(
+        &mut self,
+        full_path: &Path,
+        policy: ConflictPolicy,
+    ) -> Result<ImportAction> {
+        let file_name = full_path
+            .file_name()
+            .unwrap_or(OsStr::new("noname.txt"))
+            .to_string_lossy();
+
+        let mut reader = fs::File::open(full_path).await?;
+        let reader_meta = reader.metadata().await?;
+
+        // Read the beginning of the file to sniff its mime type.
+        let mut header = vec![];
+        if self.mime_policy != MimePolicy::Extension {
+            (&mut reader)
+                .take(SNIFF_SIZE)
+                .read_to_end(&mut header)
+                .await?;
+            reader.seek(SeekFrom::Start(0)).await?;
+        }
+        let mime = self.mime_policy.mime_type(full_path, &header);
+
+        debug!("Mime type for {} is {}", full_path.display(), mime);
+        let variant = VariantMetadata::new(reader_meta.len(), &mime);
+
+        // The local path is kept out of the description, which is indexed.
+        let source_path = fs::canonicalize(full_path)
+            .await
+            .unwrap_or_else(|_| full_path.to_path_buf());
+        let origin = ImportOrigin {
+            file_name: file_name.to_string(),
+            source_path: Some(source_path.display().to_string()),
+            imported_at: self.clock.now().timestamp(),
+        };
+        let path = self.import_path(&file_name, &mime).await?;
+        self.create_with_policy(
+            &path,
+            &file_name,
+            &variant,
+            HashSet::new(),
+            reader.compat(),
+            policy,
+            Some(origin),
+        )
+        .await
+    }
+
+    /// Imports the photos and videos of a camera or phone folder, like
+    /// `DCIM`, and of its sub-folders under `container`. The files of a
+    /// capture, like the still and video of a live photo, a photo and its
+    /// raw version, or the frames of a burst, are grouped as the variants of
+    /// a single resource named after its main image, see the `photos`
+    /// module. Other files are ignored. Returns the action taken for each
+    /// resource.
+    pub async fn import_photo_folder<P: AsRef<Path>>(
+        &mut self,
+        folder: P,
+        container: &[String],
+        policy: ConflictPolicy,
+    ) -> Result<Vec<(Vec<String>, ImportAction)>> {
+        metrics::count_operation("import_photo_folder");
+        let mut files = vec![];
+        let mut pending = vec![folder.as_ref().to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let mime = self.file_mime_type(&path).await?;
+                if mime.starts_with("image/") || mime.starts_with("video/") {
+                    files.push((path, mime));
+                }
+            }
+        }
+
+        let mut actions = vec![];
+        for capture in group_captures(files) {
+            let file_name = capture
+                .primary
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let mut path = container.to_vec();
+            path.push(file_name.clone());
+            debug!("Importing {:?} as {:?}", capture, path);
+
+            let mime = self.file_mime_type(&capture.primary).await?;
+            let file = fs::File::open(&capture.primary).await?;
+            let variant = VariantMetadata::new(file.metadata().await?.len(), &mime);
+            let origin = ImportOrigin {
+                file_name: file_name.clone(),
+                source_path: Some(
+                    fs::canonicalize(&capture.primary)
+                        .await
+                        .unwrap_or_else(|_| capture.primary.clone())
+                        .display()
+                        .to_string(),
+                ),
+                imported_at: self.clock.now().timestamp(),
+            };
+            let action = self
+                .create_with_policy(
+                    &path,
+                    &file_name,
+                    &variant,
+                    HashSet::new(),
+                    file.compat(),
+                    policy,
+                    Some(origin),
+                )
+                .await?;
+
+            let created = match &action {
+                ImportAction::Skipped => {
+                    actions.push((path, action));
+                    continue;
+                }
+                ImportAction::Renamed(new_path) => new_path.clone(),
+                _ => path.clone(),
+            };
+            for (name, source) in &capture.variants {
+                let mime = self.file_mime_type(source).await?;
+                let file = fs::File::open(source).await?;
+                let variant = VariantMetadata::new(file.metadata().await?.len(), &mime);
+                self.add_variant(&created, name, &variant, file.compat())
+                    .await?;
+            }
+            actions.push((path, action));
+        }
+        Ok(actions)
+    }
+
+    // Returns the mime type of a local file, according to the mime policy.
+    async fn file_mime_type(&self, path: &Path) -> Result<String> {
+        let mut header = vec![];
+        if self.mime_policy != MimePolicy::Extension {
+            fs::File::open(path)
+                .await?
+                .take(SNIFF_SIZE)
+                .read_to_end(&mut header)
+                .await?;
+        }
+        Ok(self.mime_policy.mime_type(path, &header))
+    }
+
+    /// Imports content from any reader, like stdin, as the `name` resource.
+    /// The content is spooled under `<root_dir>/downloads` first, since it
+    /// is read several times. Without a `mime` type, it is decided from the
+    /// name and the content like for `import_file()`, which also routes it
+    /// to a container. When `size` is given, content of another size is
+    /// rejected to detect truncated input.
+    pub async fn create_resource_from_reader<R: TokioAsyncRead + Unpin>(
+        &mut self,
+        name: &str,
+        mime: Option<&str>,
+        size: Option<u64>,
+        mut reader: R,
+    ) -> Result<()> {
+        metrics::count_operation("create_resource_from_reader");
+        let downloads = subpath(&self.root_dir, "downloads");
+        if !downloads.exists() {
+            fs::create_dir(&downloads).await?;
+        }
+        let spool = subpath(&downloads, &format!("{:016x}.spool", self.rng.gen::<u64>()));
+
+        let result: Result<()> = async {
+            let mut file = fs::File::create(&spool).await?;
+            let copied = tokio::io::copy(&mut reader, &mut file).await?;
+            file.flush().await?;
+            if let Some(size) = size {
+                if size != copied {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("Expected {} bytes but got {}", size, copied),
+                    )
+                    .into());
+                }
+            }
+
+            let mut file = fs::File::open(&spool).await?;
+            let mime = match mime {
+                Some(mime) => mime.to_owned(),
+                None => {
+                    let mut header = vec![];
+                    (&mut file)
+                        .take(SNIFF_SIZE)
+                        .read_to_end(&mut header)
+                        .await?;
+                    file.seek(SeekFrom::Start(0)).await?;
+                    self.mime_policy.mime_type(Path::new(name), &header)
+                }
+            };
+            debug!("Mime type for {} is {}", name, mime);
+
+            let variant = VariantMetadata::new(copied, &mime);
+            let origin = ImportOrigin {
+                file_name: name.to_owned(),
+                source_path: None,
+                imported_at: self.clock.now().timestamp(),
+            };
+            let path = self.import_path(name, &mime).await?;
+            self.create_resource_from(
+                &path,
+                name,
+                &variant,
+                HashSet::new(),
+                file.compat(),
+                Some(origin),
+            )
+            .await
+        }
+        .await;
+
+        let _ = fs::remove_file(&spool).await;
+        result
+    }
+
+    /// Sniffs the default variant content of a resource, and updates its
+    /// mime type if it differs from the current one. The indexers and
+    /// transformers run again in that case.
+    /// Returns the new mime type if it was changed.
+    pub async fn refresh_mime_type(&mut self, path: &[String]) -> Result<Option<String>> {
+        let current = self
+            .get_metadata(path)
+            .await?
+            .get_variant("default")
+            .map(|variant| variant.mime_type())
+            .unwrap_or_default();
+
+        let content = self.get_variant_vec("default", path).await?;
+        match sniff_mime_type(&content) {
+            Some(mime) if mime != current => {
+                debug!("Mime type for {:?} changed to {}", path, mime);
+                let variant = VariantMetadata::new(content.len() as _, mime);
+                self.update_variant(
+                    path,
+                    "default",
+                    &variant,
+                    std::io::Cursor::new(content).compat(),
+                )
+                .await?;
+                Ok(Some(mime.to_owned()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Imports a remote resource to the private store.
+    /// The content is downloaded under `<root_dir>/downloads` first, and the
+    /// Content-Type of the response is used as the mime type if available.
+    #[cfg(feature = "http-client")]
+    pub async fn import_url(&mut self, url: &str, options: ImportUrlOptions) -> Result<()> {
+        let downloads = subpath(&self.root_dir, "downloads");
+        if !downloads.exists() {
+            fs::create_dir(&downloads).await?;
+        }
+
+        // Named after the url, with a hash that stays the same across
+        // releases, to find the download again when resuming it.
+        let partial = subpath(
+            &downloads,
+            &format!("{}.part", blake3::hash(url.as_bytes()).to_hex()),
+        );
+
+        let download = crate::http_client::download(url, &partial, options.resume).await?;
+
+        let name = options.name.unwrap_or_else(|| url_file_name(url));
+        let mime = download.mime_type.unwrap_or_else(|| {
+            mime_guess::from_path(&name)
+                .first_or_octet_stream()
+                .to_string()
+        });
+        debug!("Mime type for {} is {}", url, mime);
+        let variant = VariantMetadata::new(download.size, &mime);
+
+        let reader = fs::File::open(&partial).await?;
+        let path = self.import_path(&name, &mime).await?;
+        self.create_resource(
+            &path,
+            &options.desc.unwrap_or_else(|| url.to_owned()),
+            &variant,
+            options.tags,
+            reader.compat(),
+        )
+        .await?;
+
+        fs::remove_file(&partial).await?;
+        Ok(())
+    }
+
+    /// Explodes a zip archive resource into individual resources.
+    /// Returns the paths of the created resources.
+    pub async fn extract_archive(
+        &mut self,
+        path: &[String],
+        options: ExtractOptions,
+    ) -> Result<Vec<Vec<String>>> {
+        let container = options.container.unwrap_or_else(|| {
+            let mut container = path.to_vec();
+            if let Some(leaf) = container.last_mut() {
+                if let Some(stem) = Path::new(leaf.as_str()).file_stem() {
+                    *leaf = stem.to_string_lossy().to_string();
+                }
+            }
+            container
+        });
+
+        let content = self.get_variant_vec("default", path).await?;
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(content))?;
+
+        let mut created = vec![];
+        for i in 0..archive.len() {
+            let (member_path, buffer) = {
+                let mut file = archive.by_index(i)?;
+                // Skip directories and members with unsafe paths.
+                let name = match file.enclosed_name() {
+                    Some(name) if file.is_file() => name.to_path_buf(),
+                    _ => continue,
+                };
+                let mut buffer = vec![];
+                std::io::Read::read_to_end(&mut file, &mut buffer)?;
+                (name, buffer)
+            };
+
+            let mut resource_path = container.clone();
+            for component in member_path.iter() {
+                resource_path.push(component.to_string_lossy().to_string());
+            }
+
+            let mime = mime_guess::from_path(&member_path).first_or_octet_stream();
+            let variant = VariantMetadata::new(buffer.len() as _, mime.as_ref());
+            self.create_resource(
+                &resource_path,
+                &member_path.display().to_string(),
+                &variant,
+                options.tags.clone(),
+                std::io::Cursor::new(buffer).compat(),
+            )
+            .await?;
+            created.push(resource_path);
+        }
+
+        if options.remove_archive {
+            self.delete_resource(path).await?;
+        }
+
+        Ok(created)
+    }
+
+    pub async fn ls(&self, dir: Rc<PrivateDirectory>) -> Result<Vec<(String, ResourceMetadata)>> {
+        metrics::count_operation("ls");
+        let children = dir.ls(&[], true, &self.forest, &self.block_store).await?;
+
+        let mut results = vec![];
+        for (path, metadata) in children {
+            let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+                metadata.get_deserializable("res_meta");
+            match maybe_resource_metadata {
+                Some(Ok(resource_metadata)) => results.push((path, resource_metadata)),
+                // Containers don't have resource metadata.
+                None => continue,
+                Some(Err(_)) => return Err(StoreError::NoResourceMetadata(vec![path])),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Lists the resources of `container` from the index, with the basic
+    /// information of their default variant. Unlike `ls()`, the metadata of
+    /// each resource is not loaded: use `get_metadata()` when opening one.
+    pub fn ls_summaries(&self, container: &[String]) -> Result<Vec<ResourceSummary>> {
+        metrics::count_operation("ls_summaries");
+        Ok(self
+            .indexer
+            .container_summaries(&container.join("/"))?
+            .into_iter()
+            .map(|(id, mime_type, size, modified)| {
+                let path: Vec<String> = id.clone().into();
+                ResourceSummary {
+                    name: path.last().cloned().unwrap_or_default(),
+                    id,
+                    mime_type: mime_type.unwrap_or_default(),
+                    size: size.unwrap_or_default(),
+                    modified,
+                }
+            })
+            .collect())
+    }
+
+    pub async fn get_metadata(&self, path: &[String]) -> Result<ResourceMetadata> {
+        metrics::count_operation("get_metadata");
+        let file = self.maybe_file(path).await?;
+
+        let file_metadata = file.get_metadata();
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file_metadata.get_deserializable("res_meta");
+        if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
+            Ok(resource_metadata)
+        } else {
+            Err(StoreError::NoResourceMetadata(path.to_vec()))
+        }
+    }
+
+    /// Retrieves the metadata of several resources, in the same order as
+    /// `paths`. The resources directory is resolved once, and each parent
+    /// container only once for resources sharing it.
+    pub async fn get_metadata_many(&self, paths: &[Vec<String>]) -> Result<Vec<ResourceMetadata>> {
+        let resources = self.resources_dir().await?;
+
+        // Group the leaf names by parent container.
+        let mut by_parent: BTreeMap<&[String], Vec<usize>> = BTreeMap::new();
+        for (index, path) in paths.iter().enumerate() {
+            if path.is_empty() {
+                return Err(StoreError::NoSuchResource(path.clone()));
+            }
+            by_parent
+                .entry(&path[..path.len() - 1])
+                .or_default()
+                .push(index);
+        }
+
+        let mut results: Vec<Option<ResourceMetadata>> = vec![None; paths.len()];
+        for (parent, indexes) in by_parent {
+            let dir = if parent.is_empty() {
+                resources.clone()
+            } else {
+                match resources
+                    .get_node(parent, true, &self.forest, &self.block_store)
+                    .await?
+                {
+                    Some(PrivateNode::Dir(dir)) => dir,
+                    _ => return Err(StoreError::NoSuchResource(paths[indexes[0]].clone())),
+                }
+            };
+
+            for index in indexes {
+                let path = &paths[index];
+                let leaf = &path[path.len() - 1..];
+                match dir
+                    .get_node(leaf, true, &self.forest, &self.block_store)
+                    .await?
+                {
+                    Some(PrivateNode::File(file)) => {
+                        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+                            file.get_metadata().get_deserializable("res_meta");
+                        match maybe_resource_metadata {
+                            Some(Ok(resource_metadata)) => results[index] = Some(resource_metadata),
+                            _ => return Err(StoreError::NoResourceMetadata(path.clone())),
+                        }
+                    }
+                    _ => return Err(StoreError::NoSuchResource(path.clone())),
+                }
+            }
+        }
+
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    async fn with_metadata(
+        &self,
+        ids: Vec<ResourceId>,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        let paths: Vec<Vec<String>> = ids.iter().map(|id| id.clone().into()).collect();
+        let metadata = self.get_metadata_many(&paths).await?;
+        Ok(ids.into_iter().zip(metadata).collect())
+    }
+
+    pub async fn search(&self, text: &str) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("search");
+        let ids = self.indexer.search(text)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Like `search()`, with the results sorted by the index in this order.
+    pub async fn search_ordered(
+        &self,
+        text: &str,
+        order: SearchOrder,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("search");
+        let ids = self.indexer.search_ordered(text, order)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Like `search()`, limited to the resources under `path_prefix`, eg.
+    /// `["projects", "alpha"]`, including the ones in its sub-containers.
+    pub async fn search_in(
+        &self,
+        text: &str,
+        path_prefix: &[String],
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("search_in");
+        let ids = if path_prefix.is_empty() {
+            self.indexer.search(text)?
+        } else {
+            self.indexer.search_in(text, &path_prefix.join("/"))?
+        };
+        self.with_metadata(ids).await
+    }
+
+    /// Like `search()`, also counting the results by tag, top-level mime
+    /// type and year to render filters.
+    pub async fn search_with_facets(
+        &self,
+        text: &str,
+    ) -> Result<(Vec<(ResourceId, ResourceMetadata)>, SearchFacets)> {
+        metrics::count_operation("search_with_facets");
+        let ids = self.indexer.search(text)?;
+        let facets = self.indexer.search_facets(text)?;
+        Ok((self.with_metadata(ids).await?, facets))
+    }
+
+    /// Like `search()`, returning the tags of the matching resources instead
+    /// of their metadata. This only uses the index, which makes it cheaper
+    /// when rendering long result lists.
+    pub fn search_hits(&self, text: &str) -> Result<Vec<SearchHit>> {
+        metrics::count_operation("search_hits");
+        Ok(self
+            .indexer
+            .search_with_tags(text)?
+            .into_iter()
+            .map(|(id, tags)| SearchHit { id, tags })
+            .collect())
+    }
+
+    /// Imports bookmarks, either in the Netscape bookmarks HTML format
+    /// exported by browsers or as an OPML document. One places resource is
+    /// created per bookmark under `container`, tagged with the names of its
+    /// folders. Bookmarks already imported are skipped.
+    pub async fn import_bookmarks(
+        &mut self,
+        content: &str,
+        container: &[String],
+    ) -> Result<Vec<Vec<String>>> {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let bookmarks = if is_opml(content) {
+            parse_opml(content)?
+        } else {
+            parse_netscape(content)
+        };
+
+        let mut created = vec![];
+        for bookmark in bookmarks {
+            let mut hasher = DefaultHasher::new();
+            bookmark.url.hash(&mut hasher);
+            let mut path = container.to_vec();
+            path.push(format!("{:x}", hasher.finish()));
+            if self.maybe_file(&path).await.is_ok() {
+                continue;
+            }
+
+            let json = serde_json::to_vec(&bookmark)?;
+            let variant = VariantMetadata::new(json.len() as _, PLACES_MIME_TYPE);
+            self.create_resource(
+                &path,
+                &bookmark.title,
+                &variant,
+                bookmark.folders.into_iter().collect(),
+                std::io::Cursor::new(json).compat(),
+            )
+            .await?;
+            created.push(path);
+        }
+
+        Ok(created)
+    }
+
+    /// Exports all the places resources to the Netscape bookmarks HTML
+    /// format, using their first tag as the folder.
+    pub async fn export_bookmarks(&self) -> Result<String> {
+        let mut bookmarks = vec![];
+        for (path, file, resource_metadata) in self.all_resources().await? {
+            match resource_metadata.get_variant("default") {
+                Some(variant) if variant.mime_type() == PLACES_MIME_TYPE => {}
+                _ => continue,
+            }
+
+            let content = self.file_variant_vec(&file, "default", &path).await?;
+            let mut bookmark: Bookmark = match serde_json::from_slice(&content) {
+                Ok(bookmark) => bookmark,
+                Err(_) => continue,
+            };
+            let mut tags: Vec<&String> = resource_metadata.tags().iter().collect();
+            tags.sort();
+            bookmark.folders = tags.into_iter().take(1).cloned().collect();
+            bookmarks.push(bookmark);
+        }
+
+        Ok(to_netscape(&bookmarks))
+    }
+
+    /// Like `search()`, but only matching the text indexed for these
+    /// variants, or for all of them if `variants` is empty. Each result
+    /// also has the names of the variants that matched.
+    pub async fn search_variants(
+        &self,
+        text: &str,
+        variants: &[&str],
+    ) -> Result<Vec<(ResourceId, ResourceMetadata, Vec<String>)>> {
+        metrics::count_operation("search_variants");
+        let (ids, matches): (Vec<_>, Vec<_>) = self
+            .indexer
+            .search_variants(text, variants)?
+            .into_iter()
+            .unzip();
+        Ok(self
+            .with_metadata(ids)
+            .await?
+            .into_iter()
+            .zip(matches)
+            .map(|((id, meta), variants)| (id, meta, variants))
+            .collect())
+    }
+
+    /// Returns up to `count` completions for `prefix`, for search as you
+    /// type. Candidates are the indexed words, tags and descriptions.
+    pub fn suggest(&self, prefix: &str, count: u32) -> Result<Vec<String>> {
+        metrics::count_operation("suggest");
+        Ok(self.indexer.suggest(prefix, count)?)
+    }
+
+    /// Returns up to `count` ids of resources whose path starts with
+    /// `prefix`, eg. to complete resource names in a shell.
+    pub fn ids_with_prefix(&self, prefix: &str, count: u32) -> Result<Vec<ResourceId>> {
+        metrics::count_operation("ids_with_prefix");
+        Ok(self.indexer.ids_with_prefix(prefix, count)?)
+    }
+
+    /// Returns the resources tagged with `tag`.
+    pub async fn ls_by_tag(&self, tag: &str) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("ls_by_tag");
+        let ids = self.indexer.by_tag(tag)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Returns the resources matching all the property filters, eg. images
+    /// wider than 4000px:
+    /// `PropertyFilter::GreaterThan("width".into(), PropertyValue::Integer(4000))`
+    pub async fn query_properties(
+        &self,
+        filters: &[PropertyFilter],
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("query_properties");
+        let ids = self.indexer.query_properties(filters)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Returns the name and metadata of the variant of the resource at
+    /// `path` that best suits a client accepting these mime types and
+    /// content of at most `max_size` bytes, see
+    /// `ResourceMetadata::best_variant()`. Returns None when none of its
+    /// variants has an accepted type.
+    pub async fn best_variant(
+        &self,
+        path: &[String],
+        accepted: &[&str],
+        max_size: Option<u64>,
+    ) -> Result<Option<(String, VariantMetadata)>> {
+        metrics::count_operation("best_variant");
+        let metadata = self.get_metadata(path).await?;
+        Ok(metadata
+            .best_variant(accepted, max_size)
+            .map(|(name, variant)| (name.to_owned(), variant.clone())))
+    }
+
+    /// Returns the properties extracted from a resource variant.
+    pub fn get_properties(&self, path: &[String], variant_name: &str) -> Result<Properties> {
+        Ok(self.indexer.properties(&path.into(), variant_name)?)
+    }
+
+    /// Returns the images looking like the image at `path`, closest first.
+    /// Images are compared with the perceptual hash of their default variant,
+    /// and `max_distance` is the number of differing bits allowed, out of 64.
+    /// Near-duplicates are usually less than 10 bits away.
+    pub async fn find_similar_images(
+        &self,
+        path: &[String],
+        max_distance: u32,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        metrics::count_operation("find_similar_images");
+        let id: ResourceId = path.into();
+        let hash = self
+            .indexer
+            .properties(&id, "default")?
+            .into_iter()
+            .find_map(|(name, value)| match value {
+                PropertyValue::Integer(hash) if name == IMAGE_HASH => Some(hash),
+                _ => None,
+            });
+        let ids = match hash {
+            Some(hash) => self
+                .indexer
+                .similar_images(hash, max_distance)?
+                .into_iter()
+                .filter(|item| *item != id)
+                .collect(),
+            None => vec![],
+        };
+        self.with_metadata(ids).await
+    }
+
+    /// Returns up to `count` resources, most recently modified first.
+    pub async fn recent(&self, count: u32) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        let ids = self.indexer.recent(count)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Returns the resources grouped by the day, month or year when they
+    /// were captured, most recent first. Photos are dated by their EXIF
+    /// DateTimeOriginal, and the other resources by their last modification.
+    pub async fn timeline(&self, bucket: TimelineBucket) -> Result<Vec<TimelineEntry>> {
+        metrics::count_operation("timeline");
+        Ok(self
+            .indexer
+            .timeline(bucket)?
+            .into_iter()
+            .map(|(bucket, ids)| TimelineEntry {
+                bucket,
+                count: ids.len(),
+                ids,
+            })
+            .collect())
+    }
+
+    /// Runs a custom read-only SQL query on the index, for the analytics
+    /// not covered by the other methods, eg.
+    /// `SELECT tag, COUNT(*) FROM tags GROUP BY tag`. Only the resources,
+    /// tags, properties, smart_folder_members and subtitles tables can be
+    /// read, and at most 10000 rows are returned. `params` are bound to the
+    /// `?` placeholders.
+    pub fn query_rows(&self, sql: &str, params: &[PropertyValue]) -> Result<QueryRows> {
+        metrics::count_operation("query_rows");
+        Ok(self.indexer.query_rows(sql, params)?)
+    }
+
+    /// Returns up to `count` resources ordered by their frecency score,
+    /// ie. how often and how recently they were used.
+    pub async fn suggested(&self, count: u32) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        let ids = self.indexer.suggested(count)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Returns the resources located less than `radius` meters away from
+    /// this point, closest first. Locations come from places and photos, and
+    /// the distance to GPX tracks is measured from their bounding box.
+    pub async fn search_near(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        let ids = self.indexer.near(latitude, longitude, radius)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Returns the resources located in this bounding box, or whose extent
+    /// overlaps it, with coordinates in degrees.
+    pub async fn search_within(
+        &self,
+        min_latitude: f64,
+        min_longitude: f64,
+        max_latitude: f64,
+        max_longitude: f64,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        let ids =
+            self.indexer
+                .within_bounds(min_latitude, min_longitude, max_latitude, max_longitude)?;
+        self.with_metadata(ids).await
+    }
+
+    // Returns the members of a smart folder, in order.
+    fn smart_folder_ids(&self, folder: &SmartFolder) -> Result<Vec<ResourceId>> {
+        let candidates =
+            self.indexer
+                .tagged_ordered(&folder.tag, folder.contains.as_deref(), folder.order)?;
+        Ok(folder.select(candidates))
+    }
+
+    /// Returns the resources of the smart folder named `name`, in the order
+    /// of its definition.
+    pub async fn list_smart_folder(
+        &self,
+        name: &str,
+    ) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
+        let folders = self
+            .get_settings::<SmartFolders>()
+            .await?
+            .unwrap_or_default();
+        let folder = folders
+            .get(name)
+            .ok_or_else(|| StoreError::NoSuchSmartFolder(name.to_owned()))?;
+        let ids = self.smart_folder_ids(folder)?;
+        self.with_metadata(ids).await
+    }
+
+    /// Re-evaluates the smart folders, recording an `EnterSmartFolder` or
+    /// `LeaveSmartFolder` change for each resource whose membership changed.
+    /// This runs after each mutation, call it after changing the folder
+    /// definitions to get their changes right away.
+    pub async fn update_smart_folders(&mut self) -> Result<()> {
+        let folders = self
+            .get_settings::<SmartFolders>()
             .await?
+            .unwrap_or_default();
+        let names: Vec<String> = folders.folders.iter().map(|f| f.name.clone()).collect();
+        self.indexer.retain_smart_folders(&names)?;
+
+        let mut updates = vec![];
+        for folder in &folders.folders {
+            let members: HashSet<ResourceId> = self.smart_folder_ids(folder)?.into_iter().collect();
+            let previous = self.indexer.smart_folder_members(&folder.name)?;
+            if members == previous {
+                continue;
+            }
+
+            let mut entered: Vec<Vec<String>> = members
+                .difference(&previous)
+                .map(|id| id.clone().into())
+                .collect();
+            let mut left: Vec<Vec<String>> = previous
+                .difference(&members)
+                .map(|id| id.clone().into())
+                .collect();
+            entered.sort();
+            left.sort();
+            for path in entered {
+                self.record_change(ChangeOp::EnterSmartFolder(folder.name.clone()), &path)
+                    .await?;
+            }
+            for path in left {
+                self.record_change(ChangeOp::LeaveSmartFolder(folder.name.clone()), &path)
+                    .await?;
+            }
+            updates.push((folder.name.clone(), members));
+        }
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        // Only record the new members once the changes are persisted.
+        self.save_state().await?;
+        for (name, members) in updates {
+            self.indexer.set_smart_folder_members(&name, &members)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the store to `dest_dir`, copying only the blocks that are still
+    /// reachable and verifying their hashes. The destination is switched to
+    /// atomically by writing its forest cid last, and the source is deleted
+    /// if `remove_source` is true. Returns the store opened from `dest_dir`.
+    pub async fn migrate<P: AsRef<Path>>(self, dest_dir: P, remove_source: bool) -> Result<Self> {
+        let dest_dir = dest_dir.as_ref();
+        Self::check_no_store(dest_dir)?;
+
+        if remove_source
+            && self.root_dir == self.base_dir
+            && !Self::list_profiles(&self.base_dir).await?.is_empty()
         {
-            Some(PrivateNode::File(file)) => Ok(file),
-            _ => Err(StoreError::NoSuchResource(path.to_vec())),
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{} also holds other profiles", self.base_dir.display()),
+            )
+            .into());
+        }
+
+        self.block_store.flush().await?;
+        self.indexer.checkpoint()?;
+
+        let forest_cid: Cid = from_cbor(subpath(&self.root_dir, "forest.cid")).await?;
+        let dest_store = FileStore::maybe_new(subpath(dest_dir, "blockstore")).await?;
+        let count = copy_reachable_blocks(&self.block_store, &dest_store, &forest_cid).await?;
+        debug!("Copied {} blocks to {}", count, dest_dir.display());
+
+        fs::copy(
+            subpath(&self.root_dir, "access.key"),
+            subpath(dest_dir, "access.key"),
+        )
+        .await?;
+        self.finish_copy(dest_dir, forest_cid).await?;
+
+        let (base_dir, source_dir) = (self.base_dir.clone(), self.root_dir.clone());
+        drop(self);
+        if remove_source {
+            fs::remove_dir_all(&source_dir).await?;
+            if source_dir != base_dir {
+                // The blockstore is still used by the other profiles.
+                let block_store = FileStore::maybe_new(subpath(&base_dir, "blockstore")).await?;
+                remove_unreachable_blocks(&base_dir, &block_store).await?;
+            }
+        }
+
+        Self::new(dest_dir).await
+    }
+
+    fn check_no_store(dest_dir: &Path) -> Result<()> {
+        if subpath(dest_dir, "forest.cid").exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("A store already exists in {}", dest_dir.display()),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Copies the index and the sync filter to a store whose blocks and access
+    // key are in place, and then switches it to `forest_cid` atomically.
+    async fn finish_copy(&self, dest_dir: &Path, forest_cid: Cid) -> Result<()> {
+        if self.indexer.is_in_memory() {
+            self.indexer.write_to(&subpath(dest_dir, "index.sqlite"))?;
+        } else {
+            fs::copy(
+                subpath(&self.root_dir, "index.sqlite"),
+                subpath(dest_dir, "index.sqlite"),
+            )
+            .await?;
+        }
+        to_cbor(subpath(dest_dir, SYNC_FILTER), &self.sync_filter).await?;
+        let pending = subpath(dest_dir, "forest.cid.pending");
+        to_cbor(&pending, forest_cid).await?;
+        fs::rename(&pending, subpath(dest_dir, "forest.cid")).await?;
+        Ok(())
+    }
+
+    /// Copies the current state of the store to `dest_dir` as an independent
+    /// store, for instance to seed another device or to share a snapshot.
+    /// With `CloneOptions::new_access_key`, the private file system is
+    /// rebuilt in a fresh forest with new keys, leaving out the previous
+    /// revisions of the files and the change journal. Otherwise the blocks
+    /// reachable from the current forest are copied, previous revisions
+    /// included, and the copy opens with the same access key.
+    pub async fn clone_to<P: AsRef<Path>>(
+        &mut self,
+        dest_dir: P,
+        options: CloneOptions,
+    ) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        Self::check_no_store(dest_dir)?;
+
+        // Make sure the index snapshot is up to date, since it is copied too.
+        self.save_state().await?;
+        self.indexer.checkpoint()?;
+
+        let dest_store = FileStore::maybe_new(subpath(dest_dir, "blockstore")).await?;
+        let forest_cid = if options.new_access_key {
+            let (files, dirs) = self.all_paths().await?;
+            let is_history = |path: &&Vec<String>| {
+                path.first()
+                    .map(|name| name == CHANGES_DIR)
+                    .unwrap_or(false)
+            };
+
+            let setup = AccumulatorSetup::trusted(&mut self.rng);
+            let mut forest = HamtForest::new(setup);
+            let now = self.clock.now();
+            let root = &mut Rc::new(PrivateDirectory::new(
+                &forest.empty_name(),
+                now,
+                &mut self.rng,
+            ));
+            for dir in dirs.iter().filter(|dir| !is_history(dir)) {
+                root.mkdir(dir, true, now, &forest, &dest_store, &mut self.rng)
+                    .await?;
+            }
+            let access_key = root
+                .as_node()
+                .store(&mut forest, &dest_store, &mut self.rng)
+                .await?;
+            let mut rng = StdRng::seed_from_u64(self.rng.gen());
+            for path in files.iter().filter(|path| !is_history(path)) {
+                debug!("Cloning {:?}", path);
+                self.reencrypt_file(path, &mut forest, &access_key, &dest_store, &mut rng)
+                    .await?;
+            }
+
+            to_cbor(subpath(dest_dir, "access.key"), &access_key).await?;
+            forest.store(&dest_store).await?
+        } else {
+            let forest_cid = from_cbor(subpath(&self.root_dir, "forest.cid")).await?;
+            copy_reachable_blocks(&self.block_store, &dest_store, &forest_cid).await?;
+            fs::copy(
+                subpath(&self.root_dir, "access.key"),
+                subpath(dest_dir, "access.key"),
+            )
+            .await?;
+            forest_cid
+        };
+        dest_store.flush().await?;
+
+        self.finish_copy(dest_dir, forest_cid).await
+    }
+
+    /// Uploads the blocks reachable from the current forest that `remote`
+    /// doesn't have yet. Other devices can then open a copy of the store
+    /// with the remote as their block fetcher. Returns the number of
+    /// uploaded blocks.
+    #[cfg(feature = "http-client")]
+    pub async fn upload_blocks(
+        &self,
+        remote: &crate::http_block_store::HttpBlockStore,
+    ) -> Result<u64> {
+        self.block_store.flush().await?;
+
+        let forest_cid: Cid = from_cbor(subpath(&self.root_dir, "forest.cid")).await?;
+        let mut count = 0;
+        for cid in reachable_blocks(&self.block_store, &forest_cid).await? {
+            if !remote.has_block(&cid).await? {
+                let bytes = self.block_store.get_block(&cid).await?;
+                remote.put_block(bytes, cid.codec()).await?;
+                count += 1;
+            }
+        }
+        debug!("Uploaded {} blocks to the remote store", count);
+        Ok(count)
+    }
+
+    // Completes the switch to a reencrypted forest if it was interrupted.
+    async fn finish_reencryption(
+        base_dir: &Path,
+        root_dir: &Path,
+        block_store: &FileStore,
+    ) -> Result<()> {
+        let journal: ReencryptJournal = match from_cbor(subpath(root_dir, REENCRYPT_JOURNAL)).await
+        {
+            Ok(journal) => journal,
+            Err(_) => return Ok(()),
+        };
+        if !journal.switching {
+            // The copy itself is resumed by calling `reencrypt_all()` again.
+            return Ok(());
+        }
+
+        info!("Finishing the switch to the reencrypted forest.");
+        let pending = subpath(root_dir, "access.key.pending");
+        to_cbor(&pending, &journal.access_key).await?;
+        fs::rename(&pending, subpath(root_dir, "access.key")).await?;
+        #[cfg(feature = "age")]
+        Self::rewrap_access_key(root_dir, &journal.access_key).await?;
+        let pending = subpath(root_dir, "forest.cid.pending");
+        to_cbor(&pending, journal.forest_cid).await?;
+        fs::rename(&pending, subpath(root_dir, "forest.cid")).await?;
+
+        fs::remove_file(subpath(root_dir, REENCRYPT_JOURNAL)).await?;
+
+        // Remove the blocks that are only reachable from the old forest,
+        // since they are encrypted with the old keys.
+        let count = remove_unreachable_blocks(base_dir, block_store).await?;
+        debug!("Removed {} blocks of the old forest", count);
+        Ok(())
+    }
+
+    // Returns the paths of all the files and directories from the root.
+    async fn all_paths(&self) -> Result<(Vec<Vec<String>>, Vec<Vec<String>>)> {
+        let root = self.root().await?;
+        let (mut files, mut dirs) = (vec![], vec![]);
+        let mut pending: Vec<Vec<String>> = vec![vec![]];
+        while let Some(dir_path) = pending.pop() {
+            for (name, _) in root
+                .ls(&dir_path, true, &self.forest, &self.block_store)
+                .await?
+            {
+                let mut path = dir_path.clone();
+                path.push(name);
+                match root
+                    .get_node(&path, true, &self.forest, &self.block_store)
+                    .await?
+                {
+                    Some(PrivateNode::Dir(_)) => {
+                        pending.push(path.clone());
+                        dirs.push(path);
+                    }
+                    Some(PrivateNode::File(_)) => files.push(path),
+                    None => {}
+                }
+            }
         }
+        Ok((files, dirs))
     }
 
-    /// Apply the output of variant transformers for this resource.
-    pub async fn apply_variant_transforms(
-        &mut self,
+    // Copies a file and the content of its variants to the new forest,
+    // encrypting them with the new keys and writing the blocks to `to`.
+    // `rng` is derived from the generator of the store, so that a seeded
+    // store creates the same copy.
+    async fn reencrypt_file(
+        &self,
         path: &[String],
-        transforms: Vec<TransformerResult>,
+        forest: &mut HamtForest,
+        access_key: &AccessKey,
+        to: &FileStore,
+        rng: &mut StdRng,
     ) -> Result<()> {
-        for transform in transforms {
-            match transform {
-                TransformerResult::Delete(variant_name) => {
-                    self.delete_variant(path, &variant_name).await?
-                }
-                TransformerResult::Create(variant) => {
-                    if variant.name != "default" {
-                        self.add_variant(path, &variant.name, &variant.meta, variant.content)
-                            .await?
-                    }
+        let source = match self
+            .root()
+            .await?
+            .get_node(path, true, &self.forest, &self.block_store)
+            .await?
+        {
+            Some(PrivateNode::File(file)) => file,
+            _ => return Err(StoreError::NoSuchResource(path.to_vec())),
+        };
+
+        let mut root = PrivateNode::load(access_key, &*forest, to, None)
+            .await?
+            .search_latest(&*forest, to)
+            .await?;
+        let root = root.as_dir_mut()?;
+        let root_name = root.header.get_name().clone();
+        let now = self.clock.now();
+
+        let file = root.open_file_mut(path, true, now, forest, to, rng).await?;
+        let content = PrivateFile::with_content_streaming(
+            &root_name,
+            now,
+            stream_reader(source.stream_content(0, &self.forest, &self.block_store)),
+            forest,
+            to,
+            rng,
+        )
+        .await?;
+        file.copy_content_from(&content, now);
+
+        let source_metadata = source.get_metadata();
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            source_metadata.get_deserializable("res_meta");
+        if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
+            for variant_name in resource_metadata.variants().keys() {
+                let key = format!("{}_variant", variant_name);
+                let variant_ipld = match source_metadata.get(&key) {
+                    Some(variant_ipld) => variant_ipld,
+                    None => continue,
+                };
+                // Inline content is encrypted with the node.
+                if let Ipld::Bytes(_) = variant_ipld {
+                    file.get_metadata_mut().put(&key, variant_ipld.clone());
+                    continue;
                 }
-                TransformerResult::Update(variant) => {
-                    if variant.name != "default" {
-                        self.update_variant(path, &variant.name, &variant.meta, variant.content)
-                            .await?
+                // Chunks are encrypted with keys derived from the new access key.
+                if let Ipld::List(chunks) = variant_ipld {
+                    let secret = chunking::chunk_secret(access_key)?;
+                    let mut values = vec![];
+                    for chunk in chunks {
+                        let data = PrivateForestContent::from_metadata_value(chunk)?
+                            .get_content(&self.forest, &self.block_store)
+                            .await?;
+                        values.push(
+                            chunking::write_chunk(&secret, &root_name, &data, forest, to).await?,
+                        );
                     }
+                    file.get_metadata_mut().put(&key, Ipld::List(values));
+                    continue;
                 }
+                let source_content = PrivateForestContent::from_metadata_value(variant_ipld)?;
+                let variant_content = PrivateForestContent::new_streaming(
+                    &root_name,
+                    stream_reader(source_content.stream(0, &self.forest, &self.block_store)),
+                    forest,
+                    to,
+                    rng,
+                )
+                .await?;
+                file.get_metadata_mut()
+                    .put(&key, variant_content.as_metadata_value()?);
             }
+            file.get_metadata_mut()
+                .put_serializable("res_meta", resource_metadata)?;
         }
+
+        root.as_node().store(forest, to, rng).await?;
         Ok(())
     }
 
-    /// Add a resource with a default variant content.
-    pub async fn create_resource(
-        &mut self,
-        path: &[String],
-        desc: &str,
-        default_variant: &VariantMetadata,
-        tags: HashSet<String>,
-        mut content: impl ContentReader,
-    ) -> Result<()> {
-        let mut dir = self.resources_dir().await?;
-        let now = Utc::now();
-
-        // Create the resource metadata.
-        let resource_metadata = ResourceMetadata::new(desc, default_variant, tags.clone());
+    /// Reports how the space of the store is used, and the differences
+    /// between the index and the file system. Nothing is modified.
+    pub async fn analyze(&self) -> Result<StoreReport> {
+        metrics::count_operation("analyze");
+        let mut report = StoreReport::default();
+        (report.blocks, report.block_bytes) = self.block_store.usage().await?;
+        report.reachable_blocks = reachable_from_forests(&self.base_dir, &self.block_store)
+            .await?
+            .len() as u64;
 
-        let id = path.into();
-        self.indexer.add_resource(&id)?;
-        for tag in tags {
-            self.indexer.add_tag(&id, &tag)?;
+        let mut resources: Vec<(Vec<String>, u64)> = vec![];
+        for (path, _, metadata) in self.all_resources().await? {
+            let mut size = 0;
+            for (name, variant) in metadata.variants() {
+                if name == "default" {
+                    report.default_variant_bytes += variant.size();
+                } else {
+                    report.other_variant_bytes += variant.size();
+                }
+                size += variant.size();
+            }
+            resources.push((path, size));
         }
-        self.indexer.add_text(&id, "default", desc)?;
-        self.indexer
-            .add_variant(&id, "default", default_variant, &mut content)
-            .await?;
 
-        // Collect the results from the variant transformers.
-        let mut variant_change = VariantChange::Created(default_variant.clone());
-        let transformer_results = run_transformers(&mut variant_change, &mut content).await;
+        let forest_ids: HashSet<Vec<String>> =
+            resources.iter().map(|(path, _)| path.clone()).collect();
+        let indexed_ids: HashSet<Vec<String>> = self
+            .indexer
+            .ids_with_prefix("", u32::MAX)?
+            .into_iter()
+            .map(|id| id.into())
+            .collect();
+        report.forest_resources = forest_ids.len() as u64;
+        report.indexed_resources = indexed_ids.len() as u64;
+        report.missing_from_index = forest_ids.difference(&indexed_ids).cloned().collect();
+        report.missing_from_index.sort();
+        report.stale_in_index = indexed_ids.difference(&forest_ids).cloned().collect();
+        report.stale_in_index.sort();
 
-        let dir_name = dir.header.get_name().clone();
-        let file = dir
-            .open_file_mut(
-                path,
-                false,
-                now,
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+        resources.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        resources.truncate(LARGEST_COUNT);
+        report.largest_resources = resources;
 
-        let source = PrivateFile::with_content_streaming(
-            &dir_name,
-            now,
-            content,
-            &mut self.forest,
-            &self.block_store,
-            &mut self.rng,
-        )
-        .await?;
+        (report.index_bytes, report.index_free_bytes) = self.indexer.file_usage()?;
+        Ok(report)
+    }
 
-        file.copy_content_from(&source, now);
+    /// Removes the blocks that no forest uses anymore and gives back the
+    /// free pages of the index, optionally dropping the previous revisions
+    /// of the files first. Like `delete_profile()`, no other profile should
+    /// be opened meanwhile since their uncommitted blocks would be removed.
+    pub async fn compact(&mut self, options: CompactOptions) -> Result<CompactReport> {
+        metrics::count_operation("compact");
+        let (blocks, block_bytes) = self.block_store.usage().await?;
 
-        // Set the resource metadata
-        let node_metadata = file.get_metadata_mut();
-        node_metadata.put_serializable("res_meta", resource_metadata)?;
+        if options.prune_history {
+            // This also removes the blocks of the old forest.
+            self.reencrypt_all(|_| {}).await?;
+        }
+        self.save_state().await?;
+        let count = remove_unreachable_blocks(&self.base_dir, &self.block_store).await?;
+        debug!("Removed {} unreachable blocks", count);
 
-        dir.as_node()
-            .store(&mut self.forest, &self.block_store, &mut self.rng)
-            .await?;
+        let (index_bytes, _) = self.indexer.file_usage()?;
+        self.indexer.vacuum()?;
+        let (compacted_index_bytes, _) = self.indexer.file_usage()?;
 
-        // Apply the variant transformers. This needs to be done after the
-        // resource is fully created.
-        self.apply_variant_transforms(path, transformer_results)
-            .await?;
+        let (remaining_blocks, remaining_bytes) = self.block_store.usage().await?;
+        Ok(CompactReport {
+            removed_blocks: blocks.saturating_sub(remaining_blocks),
+            removed_block_bytes: block_bytes.saturating_sub(remaining_bytes),
+            index_bytes_freed: index_bytes.saturating_sub(compacted_index_bytes),
+        })
+    }
 
-        self.save_state().await
+    /// Starts running the maintenance tasks of the shared `store` according to
+    /// `policy`, each one a period after the start and then a period after
+    /// its last run. Failures are logged, and the task is tried again after
+    /// its period. This must be called from a `LocalSet`.
+    pub fn start_maintenance(
+        store: Rc<tokio::sync::Mutex<ResourceStore>>,
+        policy: MaintenancePolicy,
+    ) -> MaintenanceHandle {
+        maintenance::spawn(store, policy)
     }
 
-    /// Add a variant to an existing resource.
-    pub async fn add_variant(
+    /// Optimizes the index for queries, which is worth doing once in a while
+    /// after many changes.
+    pub fn optimize_index(&self) -> Result<()> {
+        metrics::count_operation("optimize_index");
+        Ok(self.indexer.optimize()?)
+    }
+
+    /// Rebuilds the whole private file system with a fresh name accumulator
+    /// setup and new keys, for instance after a suspected compromise. All
+    /// the files and variants are rewritten, and the blocks of the old
+    /// forest are removed once the new one is in place.
+    /// `progress` is called after each copied file. An interrupted
+    /// reencryption is resumed by calling this again before making any
+    /// other change to the store.
+    pub async fn reencrypt_all(
         &mut self,
-        path: &[String],
-        variant_name: &str,
-        variant: &VariantMetadata,
-        mut content: impl ContentReader,
+        mut progress: impl FnMut(ReencryptProgress),
     ) -> Result<()> {
-        if variant_name == "default" {
-            return Err(StoreError::InvalidVariant(variant_name.to_owned()));
-        }
+        // Make sure the index snapshot is up to date, since it is copied too.
+        self.save_state().await?;
 
-        let mut dir = self.resources_dir().await?;
-        let file = dir
-            .open_file_mut(
-                path,
-                true,
-                Utc::now(),
-                &mut self.forest,
+        let journal_path = subpath(&self.root_dir, REENCRYPT_JOURNAL);
+        let (files, dirs) = self.all_paths().await?;
+
+        let (mut journal, mut forest) = match from_cbor::<ReencryptJournal, _>(&journal_path).await
+        {
+            Ok(journal) => {
+                info!(
+                    "Resuming reencryption, {} files already done",
+                    journal.done.len()
+                );
+                let forest = HamtForest::load(&journal.forest_cid, &self.block_store).await?;
+                (journal, forest)
+            }
+            Err(_) => {
+                let setup = AccumulatorSetup::trusted(&mut self.rng);
+                let mut forest = HamtForest::new(setup);
+                let now = self.clock.now();
+                let root = &mut Rc::new(PrivateDirectory::new(
+                    &forest.empty_name(),
+                    now,
+                    &mut self.rng,
+                ));
+                for dir in &dirs {
+                    root.mkdir(dir, true, now, &forest, &self.block_store, &mut self.rng)
+                        .await?;
+                }
+                let access_key = root
+                    .as_node()
+                    .store(&mut forest, &self.block_store, &mut self.rng)
+                    .await?;
+                let journal = ReencryptJournal {
+                    forest_cid: forest.store(&self.block_store).await?,
+                    access_key,
+                    done: HashSet::new(),
+                    switching: false,
+                };
+                (journal, forest)
+            }
+        };
+
+        let total = files.len();
+        let mut rng = StdRng::seed_from_u64(self.rng.gen());
+        for path in files {
+            if journal.done.contains(&path) {
+                continue;
+            }
+            debug!("Reencrypting {:?}", path);
+            self.reencrypt_file(
+                &path,
+                &mut forest,
+                &journal.access_key,
                 &self.block_store,
-                &mut self.rng,
+                &mut rng,
             )
             .await?;
 
-        let file_name = file.header.get_name().clone();
-        let file_metadata = file.get_metadata_mut();
-        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-            file_metadata.get_deserializable("res_meta");
-        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
-            resource_metadata.add_variant(variant_name, variant);
-            file_metadata.put_serializable("res_meta", resource_metadata)?;
+            journal.forest_cid = forest.store(&self.block_store).await?;
+            self.block_store.flush().await?;
+            journal.done.insert(path);
+            to_cbor(&journal_path, &journal).await?;
+            progress(ReencryptProgress {
+                done: journal.done.len(),
+                total,
+            });
+        }
 
-            self.indexer
-                .add_variant(&path.into(), variant_name, variant, &mut content)
-                .await?;
+        journal.switching = true;
+        to_cbor(&journal_path, &journal).await?;
+        Self::finish_reencryption(&self.base_dir, &self.root_dir, &self.block_store).await?;
 
-            let variant_content = PrivateForestContent::new_streaming(
-                &file_name,
-                content,
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+        self.forest = forest;
+        self.access_key = journal.access_key;
+        self.invalidate_cache();
+        // The previous revisions are dropped.
+        self.clear_undo_log().await
+    }
 
-            file_metadata.put(
-                &format!("{}_variant", variant_name),
-                variant_content.as_metadata_value()?,
-            );
+    async fn read_snapshots(&self) -> Result<BTreeMap<String, PinnedSnapshot>> {
+        let path = subpath(&self.root_dir, SNAPSHOTS);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        from_cbor(path).await
+    }
 
-            dir.as_node()
-                .store(&mut self.forest, &self.block_store, &mut self.rng)
-                .await?;
+    async fn write_snapshots(&self, snapshots: &BTreeMap<String, PinnedSnapshot>) -> Result<()> {
+        let pending = subpath(&self.root_dir, "snapshots.cbor.pending");
+        to_cbor(&pending, snapshots).await?;
+        fs::rename(&pending, subpath(&self.root_dir, SNAPSHOTS)).await?;
+        Ok(())
+    }
 
-            self.save_state().await
-        } else {
-            Err(StoreError::NoResourceMetadata(path.to_vec()))
+    /// Names the current state of the store, to restore it later with
+    /// `restore_snapshot()`. The blocks of a snapshot are kept by
+    /// compaction until it is deleted, including after a reencryption.
+    pub async fn tag_snapshot(&mut self, name: &str) -> Result<Snapshot> {
+        metrics::count_operation("tag_snapshot");
+        let mut snapshots = self.read_snapshots().await?;
+        if snapshots.contains_key(name) {
+            return Err(StoreError::SnapshotExists(name.to_owned()));
         }
+
+        let pinned = self.pin_current_state(name).await?;
+        let snapshot = pinned.snapshot.clone();
+        snapshots.insert(name.to_owned(), pinned);
+        self.write_snapshots(&snapshots).await?;
+        Ok(snapshot)
     }
 
-    /// Update a variant of an existing resource.
-    pub async fn update_variant(
-        &mut self,
-        path: &[String],
-        variant_name: &str,
-        variant: &VariantMetadata,
-        mut content: impl ContentReader,
-    ) -> Result<()> {
-        let mut dir = self.resources_dir().await?;
-        let dir_name = dir.header.get_name().clone();
-        let file = dir
-            .open_file_mut(
-                path,
-                true,
-                Utc::now(),
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+    // Returns the current state of the store as a snapshot named `name`.
+    async fn pin_current_state(&mut self, name: &str) -> Result<PinnedSnapshot> {
+        // Make sure the index snapshot is up to date.
+        self.save_state().await?;
+        Ok(PinnedSnapshot {
+            snapshot: Snapshot {
+                name: name.to_owned(),
+                forest_cid: self.forest_cid().await?,
+                revision: self.root_revision().await?,
+                timestamp: self.clock.now().timestamp(),
+            },
+            access_key: self.access_key.clone(),
+        })
+    }
 
-        if variant_name == "default" {
-            let now = Utc::now();
+    /// Returns the snapshots of the store, oldest first.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots: Vec<Snapshot> = self
+            .read_snapshots()
+            .await?
+            .into_values()
+            .map(|pinned| pinned.snapshot)
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.timestamp);
+        Ok(snapshots)
+    }
 
-            self.indexer
-                .update_variant(&path.into(), variant_name, variant, &mut content)
-                .await?;
+    /// Deletes a snapshot, returning whether it existed. Its blocks are
+    /// removed by the next compaction if the store doesn't use them anymore.
+    pub async fn delete_snapshot(&mut self, name: &str) -> Result<bool> {
+        let mut snapshots = self.read_snapshots().await?;
+        if snapshots.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.write_snapshots(&snapshots).await?;
+        Ok(true)
+    }
 
-            // Special case for the default variant, updating the main file content.
-            let source = PrivateFile::with_content_streaming(
-                &dir_name,
-                now,
-                content,
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+    /// Rolls the whole store back to a snapshot, and rebuilds the index.
+    /// The snapshots are kept, and the current state is kept too as the
+    /// `BEFORE_RESTORE_SNAPSHOT` snapshot, replacing the one of the previous
+    /// restore, so that restoring it undoes this restore. The blocks only
+    /// used by the older discarded states are removed.
+    pub async fn restore_snapshot(&mut self, name: &str) -> Result<()> {
+        metrics::count_operation("restore_snapshot");
+        let mut snapshots = self.read_snapshots().await?;
+        let pinned = snapshots
+            .get(name)
+            .cloned()
+            .ok_or_else(|| StoreError::NoSuchSnapshot(name.to_owned()))?;
+        if subpath(&self.root_dir, REENCRYPT_JOURNAL).exists() {
+            return Err(StoreError::ReencryptionInProgress);
+        }
 
-            file.copy_content_from(&source, now);
+        // Pin the current state before the blocks of the discarded states
+        // are removed.
+        let current = self.pin_current_state(BEFORE_RESTORE_SNAPSHOT).await?;
+        snapshots.insert(BEFORE_RESTORE_SNAPSHOT.to_owned(), current);
+        self.write_snapshots(&snapshots).await?;
 
-            dir.as_node()
-                .store(&mut self.forest, &self.block_store, &mut self.rng)
-                .await?;
+        // Switch to the snapshot like to a reencrypted forest, so that an
+        // interrupted switch is finished when the store is opened.
+        let journal = ReencryptJournal {
+            forest_cid: pinned.snapshot.forest_cid,
+            access_key: pinned.access_key,
+            done: HashSet::new(),
+            switching: true,
+        };
+        to_cbor(subpath(&self.root_dir, REENCRYPT_JOURNAL), &journal).await?;
+        Self::finish_reencryption(&self.base_dir, &self.root_dir, &self.block_store).await?;
 
-            return self.save_state().await;
+        self.forest = HamtForest::load(&journal.forest_cid, &self.block_store).await?;
+        self.access_key = journal.access_key;
+        self.invalidate_cache();
+        self.revision += 1;
+        self.clear_undo_log().await?;
+
+        self.indexer.clear()?;
+        self.reindex().await
+    }
+
+    // Returns the current forest, to record an undo entry once an operation
+    // succeeded, or None if undo is disabled.
+    async fn undo_point(&self) -> Result<Option<Cid>> {
+        if self.undo_depth == 0 {
+            return Ok(None);
         }
+        Ok(Some(self.forest_cid().await?))
+    }
 
-        let file_name = file.header.get_name().clone();
-        let file_metadata = file.get_metadata_mut();
-        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-            file_metadata.get_deserializable("res_meta");
-        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
-            resource_metadata.add_variant(variant_name, variant);
-            file_metadata.put_serializable("res_meta", resource_metadata)?;
+    // A log that can't be read is an error rather than an empty one, so
+    // that it isn't overwritten and its forests stay pinned.
+    async fn read_undo_log(&self) -> Result<Vec<PinnedUndo>> {
+        Ok(maybe_from_cbor(subpath(&self.root_dir, UNDO_LOG))
+            .await?
+            .unwrap_or_default())
+    }
 
-            self.indexer
-                .update_variant(&path.into(), variant_name, variant, &mut content)
-                .await?;
+    async fn write_undo_log(&self, log: &[PinnedUndo]) -> Result<()> {
+        let pending = subpath(&self.root_dir, "undo.cbor.pending");
+        to_cbor(&pending, log).await?;
+        fs::rename(&pending, subpath(&self.root_dir, UNDO_LOG)).await?;
+        Ok(())
+    }
 
-            let variant_content = PrivateForestContent::new_streaming(
-                &file_name,
-                content,
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+    // Records an operation that succeeded, forgetting the oldest ones past
+    // the undo depth. The operation is committed already, so failing to
+    // record it only makes it impossible to undo.
+    async fn push_undo(&self, op: UndoOp, path: &[String], before: Option<Cid>) {
+        let forest_cid = match before {
+            Some(forest_cid) => forest_cid,
+            None => return,
+        };
+        let mut log = match self.read_undo_log().await {
+            Ok(log) => log,
+            Err(err) => {
+                error!("Failed to read the undo log to record {:?}: {}", path, err);
+                return;
+            }
+        };
+        log.push(PinnedUndo {
+            entry: UndoEntry {
+                op,
+                path: path.to_vec(),
+                forest_cid,
+                timestamp: self.clock.now().timestamp(),
+            },
+            access_key: self.access_key.clone(),
+        });
+        let excess = log.len().saturating_sub(self.undo_depth);
+        log.drain(..excess);
+        if let Err(err) = self.write_undo_log(&log).await {
+            error!("Failed to record the undo entry of {:?}: {}", path, err);
+        }
+    }
 
-            file_metadata.put(
-                &format!("{}_variant", variant_name),
-                variant_content.as_metadata_value()?,
-            );
+    async fn clear_undo_log(&self) -> Result<()> {
+        let path = subpath(&self.root_dir, UNDO_LOG);
+        if path.exists() {
+            fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
 
-            dir.as_node()
-                .store(&mut self.forest, &self.block_store, &mut self.rng)
-                .await?;
+    /// Returns the operations that `undo()` can revert, the most recent
+    /// last.
+    pub async fn undo_log(&self) -> Result<Vec<UndoEntry>> {
+        Ok(self
+            .read_undo_log()
+            .await?
+            .into_iter()
+            .map(|pinned| pinned.entry)
+            .collect())
+    }
 
-            self.save_state().await
-        } else {
-            Err(StoreError::NoResourceMetadata(path.to_vec()))
+    /// Reverts the most recent operation of the undo log, using the state
+    /// of the resource in the forest before it, and returns it. Returns
+    /// None when there is nothing to undo. The operation is reverted as a
+    /// whole or not at all, and stays in the log when this fails. One that
+    /// can't be reverted anymore, eg. the deletion of a resource created
+    /// again since, is removed from the log all the same.
+    pub async fn undo(&mut self) -> Result<Option<UndoEntry>> {
+        metrics::count_operation("undo");
+        let mut log = self.read_undo_log().await?;
+        let pinned = match log.pop() {
+            Some(pinned) => pinned,
+            None => return Ok(None),
+        };
+        let path = pinned.entry.path.clone();
+        self.begin_mutation(&path).await?;
+        let result = self.revert(&pinned).await;
+        let result = self.end_mutation(result).await;
+        let obsolete = matches!(
+            result,
+            Err(StoreError::ResourceExists(_))
+                | Err(StoreError::NoSuchResource(_))
+                | Err(StoreError::NoSuchVariant(_, _))
+                | Err(StoreError::NoResourceMetadata(_))
+        );
+        if result.is_ok() || obsolete {
+            if let Err(err) = self.write_undo_log(&log).await {
+                error!("Failed to remove the undo entry of {:?}: {}", path, err);
+            }
+        }
+        result.with_context(|| ErrorContext::new("undo").path(&path))?;
+        Ok(Some(pinned.entry))
+    }
+
+    // Reverts an operation within the mutation started by `undo()`, so that
+    // the steps reverting a deletion are rolled back together.
+    async fn revert(&mut self, pinned: &PinnedUndo) -> Result<()> {
+        let path = &pinned.entry.path;
+        let forest = HamtForest::load(&pinned.entry.forest_cid, &self.block_store).await?;
+        let mut node_path = vec![".resources".to_owned()];
+        node_path.extend(path.iter().cloned());
+        let file = match PrivateNode::load(&pinned.access_key, &forest, &self.block_store, None)
+            .await?
+            .search_latest(&forest, &self.block_store)
+            .await?
+            .as_dir()?
+            .get_node(&node_path, true, &forest, &self.block_store)
+            .await?
+        {
+            Some(PrivateNode::File(file)) => file,
+            _ => return Err(StoreError::NoSuchResource(path.to_vec())),
+        };
+        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
+            file.get_metadata().get_deserializable("res_meta");
+        let previous = match maybe_resource_metadata {
+            Some(Ok(resource_metadata)) => resource_metadata,
+            _ => return Err(StoreError::NoResourceMetadata(path.to_vec())),
+        };
+
+        match &pinned.entry.op {
+            UndoOp::DeleteResource => {
+                if self.maybe_file(path).await.is_ok() {
+                    return Err(StoreError::ResourceExists(path.to_vec()));
+                }
+                let default_variant = previous.get_variant("default").ok_or_else(|| {
+                    StoreError::NoSuchVariant("default".to_owned(), path.to_vec())
+                })?;
+                let spool = self.spool_path().await?;
+                let result = async {
+                    self.spool_variant(&forest, &file, path, "default", &spool)
+                        .await?;
+                    self.create_resource_from(
+                        path,
+                        &previous.desc(),
+                        default_variant,
+                        previous.tags().clone(),
+                        fs::File::open(&spool).await?.compat(),
+                        previous.origin().cloned(),
+                    )
+                    .await?;
+                    // Skip the variants already derived by the transformers.
+                    let existing = self.get_metadata(path).await?;
+                    for (name, variant) in previous.variants() {
+                        if existing.has_variant(name) {
+                            continue;
+                        }
+                        self.spool_variant(&forest, &file, path, name, &spool)
+                            .await?;
+                        self.add_variant(
+                            path,
+                            name,
+                            variant,
+                            fs::File::open(&spool).await?.compat(),
+                        )
+                        .await?;
+                    }
+                    Ok::<(), StoreError>(())
+                }
+                .await;
+                let _ = fs::remove_file(&spool).await;
+                result?;
+                self.do_update_metadata(path, |metadata| *metadata = previous)
+                    .await
+            }
+            UndoOp::DeleteVariant(name) => {
+                let variant = previous
+                    .get_variant(name)
+                    .ok_or_else(|| StoreError::NoSuchVariant(name.clone(), path.to_vec()))?;
+                let spool = self.spool_path().await?;
+                let result = async {
+                    self.spool_variant(&forest, &file, path, name, &spool)
+                        .await?;
+                    self.add_variant(path, name, variant, fs::File::open(&spool).await?.compat())
+                        .await
+                }
+                .await;
+                let _ = fs::remove_file(&spool).await;
+                result
+            }
+            // Only this tag is reverted, keeping the later changes.
+            UndoOp::AddTag(tag) | UndoOp::RemoveTag(tag) => {
+                self.do_update_metadata(path, |metadata| {
+                    if previous.tags().contains(tag) {
+                        metadata.add_tag(tag);
+                    } else {
+                        metadata.remove_tag(tag);
+                    }
+                })
+                .await
+            }
+            UndoOp::UpdateMetadata => {
+                self.do_update_metadata(path, |metadata| metadata.restore_fields(&previous))
+                    .await
+            }
         }
     }
 
-    /// Deletes a single variant from an existing resource.
-    pub async fn delete_variant(&mut self, path: &[String], variant_name: &str) -> Result<()> {
-        // Deleting the default variant is not allowed.
-        if variant_name == "default" {
-            return Err(StoreError::InvalidVariant(variant_name.to_owned()));
+    // Returns a new path to spool content to, in the downloads directory.
+    async fn spool_path(&mut self) -> Result<PathBuf> {
+        let downloads = subpath(&self.root_dir, "downloads");
+        if !downloads.exists() {
+            fs::create_dir(&downloads).await?;
         }
+        Ok(subpath(
+            &downloads,
+            &format!("{}.spool", self.random_hex(8)),
+        ))
+    }
 
-        let mut dir = self.resources_dir().await?;
+    /// Creates a token giving access to the resource at `path` for
+    /// `duration`. Expired tokens are removed at the same time.
+    pub async fn share(
+        &mut self,
+        path: &[String],
+        duration: chrono::Duration,
+    ) -> Result<ShareToken> {
+        // Fail early for unknown resources.
+        let _ = self.maybe_file(path).await?;
 
-        let file = dir
-            .open_file_mut(
-                path,
-                true,
-                Utc::now(),
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+        let now = self.clock.now();
+        let token = ShareToken {
+            id: self.random_hex(16),
+            path: path.to_vec(),
+            expires: (now + duration).timestamp(),
+        };
 
-        let file_metadata = file.get_metadata_mut();
+        let mut shares = self.get_settings::<Shares>().await?.unwrap_or_default();
+        shares.purge_expired(now);
+        shares.tokens.insert(token.id.clone(), token.clone());
+        self.set_settings(&shares).await?;
+        Ok(token)
+    }
 
-        // Get the private forest content for that resource.
-        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-            file_metadata.get_deserializable("res_meta");
-        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
-            if !resource_metadata.has_variant(variant_name) {
-                return Err(StoreError::NoSuchVariant(
-                    variant_name.to_owned(),
-                    path.to_vec(),
-                ));
-            }
+    /// Revokes a share token before it expires.
+    /// Returns whether the token was still active.
+    pub async fn revoke_share(&mut self, id: &str) -> Result<bool> {
+        let mut shares = self.get_settings::<Shares>().await?.unwrap_or_default();
+        let removed = shares.tokens.remove(id);
+        let now = self.clock.now();
+        let purged = shares.purge_expired(now);
+        if removed.is_some() || purged {
+            self.set_settings(&shares).await?;
+        }
+        Ok(matches!(removed, Some(token) if !token.is_expired(now)))
+    }
 
-            resource_metadata.remove_variant(variant_name);
-            file_metadata.put_serializable("res_meta", resource_metadata)?;
+    /// Returns the active share tokens.
+    pub async fn shares(&self) -> Result<Vec<ShareToken>> {
+        let shares = self.get_settings::<Shares>().await?.unwrap_or_default();
+        Ok(shares
+            .tokens
+            .into_values()
+            .filter(|token| !token.is_expired(self.clock.now()))
+            .collect())
+    }
 
-            let _ = file_metadata.delete(&format!("{}_variant", variant_name));
-        } else {
-            return Err(StoreError::NoResourceMetadata(path.to_vec()));
+    /// Checks a share token, returning it if it is neither expired nor
+    /// revoked. Serving code must call this before giving access to the
+    /// token's resource.
+    pub async fn check_share(&self, id: &str) -> Result<ShareToken> {
+        let shares = self.get_settings::<Shares>().await?.unwrap_or_default();
+        match shares.tokens.get(id) {
+            Some(token) if !token.is_expired(self.clock.now()) => Ok(token.clone()),
+            _ => Err(StoreError::InvalidShareToken),
         }
+    }
 
-        dir.as_node()
-            .store(&mut self.forest, &self.block_store, &mut self.rng)
-            .await?;
+    // Returns a random hex encoded id of `len` bytes.
+    fn random_hex(&mut self, len: usize) -> String {
+        (0..len)
+            .map(|_| format!("{:02x}", self.rng.gen::<u8>()))
+            .collect()
+    }
 
-        self.indexer.delete_variant(&path.into(), variant_name)?;
+    /// Issues an access token with this scope, restricted to `containers`
+    /// unless empty, and valid for `duration` or until revoked.
+    /// Returns the token and its secret, which is not recorded.
+    pub async fn issue_access_token(
+        &mut self,
+        name: &str,
+        scope: Scope,
+        containers: &[Vec<String>],
+        duration: Option<chrono::Duration>,
+    ) -> Result<(AccessToken, String)> {
+        let now = self.clock.now();
+        let mut token = AccessToken {
+            id: self.random_hex(8),
+            name: name.to_owned(),
+            scope,
+            containers: containers.to_vec(),
+            expires: duration.map(|duration| (now + duration).timestamp()),
+            hash: String::new(),
+        };
+        let secret = format!("{}.{}", token.id, self.random_hex(32));
+        token.set_secret(&secret);
 
-        self.save_state().await
+        let mut tokens = self
+            .get_settings::<AccessTokens>()
+            .await?
+            .unwrap_or_default();
+        tokens.purge_expired(now);
+        tokens.tokens.insert(token.id.clone(), token.clone());
+        self.set_settings(&tokens).await?;
+        Ok((token.public(), secret))
     }
 
-    /// Removes a resource and all its variants from the store.
-    pub async fn delete_resource(&mut self, path: &[String]) -> Result<()> {
-        let mut dir = self.resources_dir().await?;
-
-        dir.rm(path, true, &self.forest, &self.block_store).await?;
-        dir.as_node()
-            .store(&mut self.forest, &self.block_store, &mut self.rng)
-            .await?;
+    /// Replaces the secret of an access token, for instance when it leaked.
+    /// Returns the new secret.
+    pub async fn rotate_access_token(&mut self, id: &str) -> Result<String> {
+        let mut tokens = self
+            .get_settings::<AccessTokens>()
+            .await?
+            .unwrap_or_default();
+        let secret = format!("{}.{}", id, self.random_hex(32));
+        match tokens.tokens.get_mut(id) {
+            Some(token) if !token.is_expired(self.clock.now()) => token.set_secret(&secret),
+            _ => return Err(StoreError::InvalidAccessToken),
+        }
+        self.set_settings(&tokens).await?;
+        Ok(secret)
+    }
 
-        self.indexer.delete_resource(&path.into())?;
+    /// Revokes an access token. Returns whether the token was still active.
+    pub async fn revoke_access_token(&mut self, id: &str) -> Result<bool> {
+        let mut tokens = self
+            .get_settings::<AccessTokens>()
+            .await?
+            .unwrap_or_default();
+        let removed = tokens.tokens.remove(id);
+        let now = self.clock.now();
+        let purged = tokens.purge_expired(now);
+        if removed.is_some() || purged {
+            self.set_settings(&tokens).await?;
+        }
+        Ok(matches!(removed, Some(token) if !token.is_expired(now)))
+    }
 
-        self.save_state().await
+    /// Returns the active access tokens, without their secret.
+    pub async fn access_tokens(&self) -> Result<Vec<AccessToken>> {
+        let tokens = self
+            .get_settings::<AccessTokens>()
+            .await?
+            .unwrap_or_default();
+        Ok(tokens
+            .tokens
+            .values()
+            .filter(|token| !token.is_expired(self.clock.now()))
+            .map(|token| token.public())
+            .collect())
     }
 
-    /// Add a tag to this resource.
-    pub async fn add_tag(&mut self, path: &[String], tag: &str) -> Result<()> {
-        let mut dir = self.resources_dir().await?;
+    /// Checks the secret of an access token, returning the token if it is
+    /// neither expired, revoked nor rotated. Serving code must call this
+    /// before each request, and limit the request to the token's scope.
+    pub async fn check_access_token(&self, secret: &str) -> Result<AccessToken> {
+        let tokens = self
+            .get_settings::<AccessTokens>()
+            .await?
+            .unwrap_or_default();
+        match token_id(secret).and_then(|id| tokens.tokens.get(id)) {
+            Some(token) if !token.is_expired(self.clock.now()) && token.matches(secret) => {
+                Ok(token.public())
+            }
+            _ => Err(StoreError::InvalidAccessToken),
+        }
+    }
 
-        let file = dir
-            .open_file_mut(
-                path,
-                true,
-                Utc::now(),
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
+    /// Writes a portable backup of the whole store to `dest`. The access key
+    /// and the index are encrypted with a key derived from `passphrase`.
+    pub async fn backup<P: AsRef<Path>>(&self, dest: P, passphrase: &str) -> Result<()> {
+        if self.indexer.is_in_memory() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Stores kept in memory can't be backed up",
             )
-            .await?;
-
-        let file_metadata = file.get_metadata_mut();
-
-        // Get the private forest content for that resource.
-        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-            file_metadata.get_deserializable("res_meta");
-        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
-            resource_metadata.add_tag(tag);
-            file_metadata.put_serializable("res_meta", resource_metadata)?;
-        } else {
-            return Err(StoreError::NoResourceMetadata(path.to_vec()));
+            .into());
         }
+        // Make sure all the blocks and index changes are persisted.
+        self.block_store.flush().await?;
+        self.indexer.checkpoint()?;
 
-        dir.as_node()
-            .store(&mut self.forest, &self.block_store, &mut self.rng)
-            .await?;
+        write_backup(
+            &self.root_dir,
+            &subpath(&self.base_dir, "blockstore"),
+            dest.as_ref(),
+            passphrase,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Splits the access key into `count` printable recovery shares, any
+    /// `threshold` of them restoring it with `recover_from_shares()`, for
+    /// instance to give to trusted people. Fewer shares reveal nothing about
+    /// the key. The shares have to be created again after `reencrypt_all()`.
+    pub fn create_recovery_shares(&mut self, count: u8, threshold: u8) -> Result<Vec<String>> {
+        let key = serde_cbor::to_vec(&self.access_key)?;
+        Ok(recovery_shares::split(
+            &key,
+            count,
+            threshold,
+            &mut self.rng,
+        )?)
+    }
 
-        self.indexer.add_tag(&path.into(), tag)?;
+    /// Restores `<root-dir>/access.key` from enough recovery shares created
+    /// by `create_recovery_shares()`. The store can then be opened as usual.
+    pub async fn recover_from_shares<P: AsRef<Path>>(root_dir: P, shares: &[String]) -> Result<()> {
+        let bytes = recovery_shares::combine(shares)?;
+        // Make sure that this is an access key before replacing the current one.
+        let _: AccessKey = serde_cbor::from_slice(&bytes)?;
 
-        self.save_state().await
+        let root_dir = root_dir.as_ref();
+        let pending = subpath(root_dir, "access.key.pending");
+        fs::write(&pending, bytes).await?;
+        fs::rename(&pending, subpath(root_dir, "access.key")).await?;
+        Ok(())
     }
 
-    /// Remove a tag from this resource.
-    pub async fn remove_tag(&mut self, path: &[String], tag: &str) -> Result<()> {
-        let mut dir = self.resources_dir().await?;
+    // Wraps the access key again after it changed, for the same recipients.
+    #[cfg(feature = "age")]
+    async fn rewrap_access_key(root_dir: &Path, access_key: &AccessKey) -> Result<()> {
+        use crate::recovery::{wrap, RECIPIENTS, WRAPPED_ACCESS_KEY};
 
-        let file = dir
-            .open_file_mut(
-                path,
-                true,
-                Utc::now(),
-                &mut self.forest,
-                &self.block_store,
-                &mut self.rng,
-            )
-            .await?;
+        let recipients = match fs::read_to_string(subpath(root_dir, RECIPIENTS)).await {
+            Ok(recipients) => recipients,
+            Err(_) => return Ok(()),
+        };
+        let recipients: Vec<String> = recipients.lines().map(|line| line.to_owned()).collect();
+        let wrapped = wrap(&serde_cbor::to_vec(access_key)?, &recipients)?;
+        fs::write(subpath(root_dir, WRAPPED_ACCESS_KEY), wrapped).await?;
+        Ok(())
+    }
 
-        let file_metadata = file.get_metadata_mut();
+    /// Wraps the access key to the age (`age1...`) or SSH public keys of
+    /// `recipients`, in `<root-dir>/access.key.age`. Any of the matching
+    /// private keys can then restore the access key with
+    /// `recover_access_key()`. An empty list removes the wrapped key.
+    #[cfg(feature = "age")]
+    pub async fn set_recovery_recipients(&self, recipients: &[String]) -> Result<()> {
+        use crate::recovery::{wrap, RECIPIENTS, WRAPPED_ACCESS_KEY};
 
-        // Get the private forest content for that resource.
-        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-            file_metadata.get_deserializable("res_meta");
-        if let Some(Ok(mut resource_metadata)) = maybe_resource_metadata {
-            resource_metadata.remove_tag(tag);
-            file_metadata.put_serializable("res_meta", resource_metadata)?;
-        } else {
-            return Err(StoreError::NoResourceMetadata(path.to_vec()));
+        if recipients.is_empty() {
+            for file in [WRAPPED_ACCESS_KEY, RECIPIENTS] {
+                let _ = fs::remove_file(subpath(&self.root_dir, file)).await;
+            }
+            return Ok(());
         }
 
-        dir.as_node()
-            .store(&mut self.forest, &self.block_store, &mut self.rng)
-            .await?;
+        let wrapped = wrap(&serde_cbor::to_vec(&self.access_key)?, recipients)?;
+        fs::write(subpath(&self.root_dir, WRAPPED_ACCESS_KEY), wrapped).await?;
+        fs::write(subpath(&self.root_dir, RECIPIENTS), recipients.join("\n")).await?;
+        Ok(())
+    }
 
-        self.indexer.remove_tag(&path.into(), tag)?;
+    /// Restores `<root-dir>/access.key` from its wrapped copy, using an age
+    /// secret key or an unencrypted SSH private key of one of the recovery
+    /// recipients. The store can then be opened as usual.
+    #[cfg(feature = "age")]
+    pub async fn recover_access_key<P: AsRef<Path>>(root_dir: P, identity: &str) -> Result<()> {
+        use crate::recovery::{unwrap, WRAPPED_ACCESS_KEY};
 
-        self.save_state().await
-    }
+        let root_dir = root_dir.as_ref();
+        let wrapped = fs::read(subpath(root_dir, WRAPPED_ACCESS_KEY)).await?;
+        let bytes = unwrap(&wrapped, identity)?;
+        // Make sure that this is an access key before replacing the current one.
+        let _: AccessKey = serde_cbor::from_slice(&bytes)?;
 
-    /// Retrieves the content for this path and variant as a bytes vector.
-    /// Should only be used for small variant sizes.
-    pub async fn get_variant_vec(&self, variant_name: &str, path: &[String]) -> Result<Vec<u8>> {
-        let file = self.maybe_file(path).await?;
+        let pending = subpath(root_dir, "access.key.pending");
+        fs::write(&pending, bytes).await?;
+        fs::rename(&pending, subpath(root_dir, "access.key")).await?;
+        Ok(())
+    }
 
-        if variant_name == "default" {
-            // For the default variant, get the "main" file content.
-            file.get_content(&self.forest, &self.block_store)
-                .await
-                .map_err(|e| e.into())
-        } else {
-            // Fetch the variant content from the node metadata.
-            let file_metadata = file.get_metadata();
-            let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-                file_metadata.get_deserializable("res_meta");
-            if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
-                if !resource_metadata.has_variant(variant_name) {
-                    return Err(StoreError::NoSuchVariant(
-                        variant_name.to_owned(),
-                        path.to_vec(),
-                    ));
-                }
-                match file_metadata.get(&format!("{}_variant", variant_name)) {
-                    Some(variant_ipld) => {
-                        let content = PrivateForestContent::from_metadata_value(variant_ipld)?;
-                        content
-                            .get_content(&self.forest, &self.block_store)
-                            .await
-                            .map_err(|e| e.into())
-                    }
-                    None => Err(StoreError::NoVariantContent(
-                        variant_name.to_owned(),
-                        path.to_vec(),
-                    )),
-                }
-            } else {
-                Err(StoreError::NoResourceMetadata(path.to_vec()))
-            }
-        }
+    /// Restores a backup created by `backup()` into `dest_dir` and opens
+    /// the restored store.
+    pub async fn restore<P: AsRef<Path>, Q: AsRef<Path>>(
+        archive: P,
+        passphrase: &str,
+        dest_dir: Q,
+    ) -> Result<Self> {
+        read_backup(archive.as_ref(), passphrase, dest_dir.as_ref()).await?;
+        Self::new(dest_dir).await
     }
 
-    /// Retrieves the content for this path and variant as a stream of byte chunks.
-    pub async fn get_variant<'a>(
-        &'a self,
-        variant_name: &str,
-        path: &[String],
-    ) -> Result<LocalBoxStream<'a, Result<Vec<u8>>>> {
-        let file = self.maybe_file(path).await?;
+    /// Merges the store located at `other_root_dir`, opened with its own
+    /// access key, into this one: its resources are copied under
+    /// `container` with their description, tags and variants, and their
+    /// content is encrypted again with the keys of this store. A resource
+    /// conflicting with an existing one is resolved with `policy`, and its
+    /// other variants are not copied when it is skipped.
+    /// Returns the source path of each resource and the action taken.
+    pub async fn import_store<P: AsRef<Path>>(
+        &mut self,
+        other_root_dir: P,
+        container: &[String],
+        policy: ConflictPolicy,
+    ) -> Result<Vec<(Vec<String>, ImportAction)>> {
+        metrics::count_operation("import_store");
+        let other = Self::new(other_root_dir).await?;
+        self.import_resources_of(&other, container, policy).await
+    }
 
-        if variant_name == "default" {
-            // For the default variant, get the "main" file content.
-            Ok(Box::pin(stream! {
-                for await value in file.stream_content(0, &self.forest, &self.block_store) {
-                    yield value.map_err(|e| e.into());
-                }
-            }))
-        } else {
-            // Fetch the variant content from the node metadata.
-            let file_metadata = file.get_metadata();
-            let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-                file_metadata.get_deserializable("res_meta");
-            if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
-                if !resource_metadata.has_variant(variant_name) {
-                    return Err(StoreError::NoSuchVariant(
-                        variant_name.to_owned(),
-                        path.to_vec(),
-                    ));
-                }
-                match file_metadata.get(&format!("variant_{}", variant_name)) {
-                    Some(variant_ipld) => {
-                        let content = PrivateForestContent::from_metadata_value(variant_ipld)?;
-                        Ok(Box::pin(stream! {
-                            for await value in content.stream(0, &self.forest, &self.block_store) {
-                                yield value.map_err(|e| e.into());
-                            }
-                        }))
-                    }
-                    None => Err(StoreError::NoVariantContent(
-                        variant_name.to_owned(),
-                        path.to_vec(),
-                    )),
-                }
-            } else {
-                Err(StoreError::NoResourceMetadata(path.to_vec()))
-            }
+    /// Merges the store saved by `backup()` in `archive` like
+    /// `import_store()`. The backup is restored under
+    /// `<root_dir>/downloads` first, and removed once imported.
+    pub async fn import_backup<P: AsRef<Path>>(
+        &mut self,
+        archive: P,
+        passphrase: &str,
+        container: &[String],
+        policy: ConflictPolicy,
+    ) -> Result<Vec<(Vec<String>, ImportAction)>> {
+        metrics::count_operation("import_backup");
+        let dir = subpath(
+            subpath(&self.root_dir, "downloads"),
+            &format!("{}.import", self.random_hex(8)),
+        );
+        let result = async {
+            let other = Self::restore(archive, passphrase, &dir).await?;
+            self.import_resources_of(&other, container, policy).await
         }
+        .await;
+
+        let _ = fs::remove_dir_all(&dir).await;
+        result
     }
 
-    /// Imports a local file to the private store.
-    pub async fn import_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        let full_path = path.as_ref();
+    async fn import_resources_of(
+        &mut self,
+        other: &ResourceStore,
+        container: &[String],
+        policy: ConflictPolicy,
+    ) -> Result<Vec<(Vec<String>, ImportAction)>> {
+        let downloads = subpath(&self.root_dir, "downloads");
+        if !downloads.exists() {
+            fs::create_dir(&downloads).await?;
+        }
 
-        let file_name = full_path
-            .file_name()
-            .unwrap_or(OsStr::new("noname.txt"))
-            .to_string_lossy();
+        let mut actions = vec![];
+        for (source_path, file, metadata) in other.all_resources().await? {
+            let default_variant = match metadata.get_variant("default") {
+                Some(variant) => variant,
+                None => continue,
+            };
+            let mut path = container.to_vec();
+            path.extend(source_path.iter().cloned());
+            debug!("Importing {:?} as {:?}", source_path, path);
 
-        let reader = fs::File::open(full_path).await?;
-        let reader_meta = reader.metadata().await?;
-        let mime = mime_guess::from_path(path.as_ref()).first_or_octet_stream();
+            let spool = subpath(&downloads, &format!("{}.spool", self.random_hex(8)));
+            let result: Result<ImportAction> = async {
+                other
+                    .spool_variant(&other.forest, &file, &source_path, "default", &spool)
+                    .await?;
+                let action = self
+                    .create_with_policy(
+                        &path,
+                        &metadata.desc(),
+                        default_variant,
+                        metadata.tags().clone(),
+                        fs::File::open(&spool).await?.compat(),
+                        policy,
+                        metadata.origin().cloned(),
+                    )
+                    .await?;
 
-        debug!("Mime type for {} is {}", path.as_ref().display(), mime);
-        let variant = VariantMetadata::new(reader_meta.len(), mime.as_ref());
+                let created = match &action {
+                    ImportAction::Skipped => return Ok(action),
+                    ImportAction::Renamed(new_path) => new_path.clone(),
+                    _ => path.clone(),
+                };
+                // Skip the variants already derived by the transformers.
+                let existing = self.get_metadata(&created).await?;
+                for (name, variant) in metadata.variants() {
+                    if existing.has_variant(name) {
+                        continue;
+                    }
+                    other
+                        .spool_variant(&other.forest, &file, &source_path, name, &spool)
+                        .await?;
+                    self.add_variant(
+                        &created,
+                        name,
+                        variant,
+                        fs::File::open(&spool).await?.compat(),
+                    )
+                    .await?;
+                }
+                Ok(action)
+            }
+            .await;
 
-        self.create_resource(
-            &[file_name.to_string()],
-            &full_path.display().to_string(),
-            &variant,
-            HashSet::new(),
-            reader.compat(),
-        )
-        .await
+            let _ = fs::remove_file(&spool).await;
+            actions.push((source_path, result?));
+        }
+        Ok(actions)
     }
 
-    pub async fn ls(&self, dir: Rc<PrivateDirectory>) -> Result<Vec<(String, ResourceMetadata)>> {
-        let children = dir.ls(&[], true, &self.forest, &self.block_store).await?;
-
-        let mut results = vec![];
-        for (path, metadata) in children {
-            let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-                metadata.get_deserializable("res_meta");
-            if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
-                results.push((path, resource_metadata));
-            } else {
-                return Err(StoreError::NoResourceMetadata(vec![path]));
-            }
-        }
-        Ok(results)
+    // Writes the content of a variant of `file`. Content of at most
+    // `inline_threshold` bytes is kept in the node metadata, under the same
+    // key as the content key of larger variants, which saves writing and
+    // fetching its blocks. Larger content is streamed to blocks, as the main
+    // content of the file for the default variant, or split in chunks kept
+    // under the content key with content-defined chunking. `name` is the
+    // name of the parent directory for the default variant, and of the file
+    // for the others.
+    async fn write_variant_content(
+        &mut self,
+        file: &mut PrivateFile,
+        name: &Name,
+        variant_name: &str,
+        content: &mut (impl AsyncRead + Unpin),
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut forest = self.forest.clone();
+        self.write_variant_content_to(&mut forest, file, name, variant_name, content, now)
+            .await?;
+        self.forest = forest;
+        Ok(())
     }
 
-    pub async fn get_metadata(&self, path: &[String]) -> Result<ResourceMetadata> {
-        let file = self.maybe_file(path).await?;
+    // Like `write_variant_content()`, with the blocks added to `forest`.
+    async fn write_variant_content_to(
+        &mut self,
+        forest: &mut HamtForest,
+        file: &mut PrivateFile,
+        name: &Name,
+        variant_name: &str,
+        content: &mut (impl AsyncRead + Unpin),
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let key = format!("{}_variant", variant_name);
+        let mut head = vec![];
+        (&mut *content)
+            .take(self.inline_threshold as u64 + 1)
+            .read_to_end(&mut head)
+            .await?;
+        let inline = self.inline_threshold > 0 && head.len() <= self.inline_threshold;
+        if variant_name == "default" && (inline || self.chunk_sizes.is_some()) {
+            // Don't keep the blocks of a previous content.
+            file.copy_content_from(&PrivateFile::new(name, now, &mut self.rng), now);
+        }
+        if inline {
+            file.get_metadata_mut().put(&key, Ipld::Bytes(head));
+            return Ok(());
+        }
 
-        let file_metadata = file.get_metadata();
-        let maybe_resource_metadata: Option<IpldResult<ResourceMetadata>> =
-            file_metadata.get_deserializable("res_meta");
-        if let Some(Ok(resource_metadata)) = maybe_resource_metadata {
-            Ok(resource_metadata)
+        let mut content = futures::io::Cursor::new(head).chain(content);
+        if let Some(sizes) = self.chunk_sizes {
+            let secret = chunking::chunk_secret(&self.access_key)?;
+            let chunks =
+                chunking::write_chunks(&secret, sizes, name, content, forest, &self.block_store)
+                    .await?;
+            file.get_metadata_mut().put(&key, Ipld::List(chunks));
+        } else if variant_name == "default" {
+            let source = PrivateFile::with_content_streaming(
+                name,
+                now,
+                &mut content,
+                forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+            file.copy_content_from(&source, now);
+            let _ = file.get_metadata_mut().delete(&key);
         } else {
-            Err(StoreError::NoResourceMetadata(path.to_vec()))
+            let variant_content = PrivateForestContent::new_streaming(
+                name,
+                &mut content,
+                forest,
+                &self.block_store,
+                &mut self.rng,
+            )
+            .await?;
+            file.get_metadata_mut()
+                .put(&key, variant_content.as_metadata_value()?);
         }
+        Ok(())
     }
 
-    pub async fn search(&self, text: &str) -> Result<Vec<(ResourceId, ResourceMetadata)>> {
-        let ids = self.indexer.search(text)?;
-
-        let mut result = vec![];
-        for id in ids {
-            let path: Vec<String> = id.clone().into();
-            result.push((id, self.get_metadata(&path).await?))
+    // Writes the content of a variant of `file` to `dest`, without
+    // recording a visit like `get_variant()` does.
+    async fn spool_variant(
+        &self,
+        forest: &HamtForest,
+        file: &PrivateFile,
+        path: &[String],
+        variant_name: &str,
+        dest: &Path,
+    ) -> Result<()> {
+        let mut chunks = variant_stream(
+            forest,
+            &self.block_store,
+            self.read_ahead,
+            file,
+            variant_name,
+            path,
+        )?;
+        let mut out = fs::File::create(dest).await?;
+        while let Some(chunk) = chunks.next().await {
+            out.write_all(&chunk?).await?;
         }
-        Ok(result)
+        out.flush().await?;
+        Ok(())
     }
 }
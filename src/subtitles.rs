@@ -0,0 +1,85 @@
+//! Subtitles and transcripts
+//! SubRip (`.srt`) and WebVTT (`.vtt`) files are made of cues: an optional
+//! number or identifier, a timing line like `00:00:01,000 --> 00:00:04,000`
+//! and the lines of text. Only the text is indexed, without the formatting
+//! tags.
+//!
+//! Subtitles belong to the video of the same container named like them
+//! without their extension and language, eg. `movie.mp4` for `movie.srt` or
+//! `movie.en.vtt`, unless they are linked to another video with
+//! `ResourceMetadata::set_subtitled_video()`. Searching their text also
+//! finds that video.
+
+pub(crate) const SRT_MIME_TYPE: &str = "application/x-subrip";
+pub(crate) const VTT_MIME_TYPE: &str = "text/vtt";
+
+// Language tags are short, eg. `en` or `pt-BR`.
+const MAX_LANGUAGE_LENGTH: usize = 5;
+
+pub(crate) fn is_subtitles(mime: &str) -> bool {
+    mime == SRT_MIME_TYPE || mime == VTT_MIME_TYPE
+}
+
+// Removes the `<i>`, `<v Speaker>` and similar tags, and the `{\an8}`
+// positioning overrides found in SubRip files.
+fn strip_tags(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut closing = None;
+    for c in line.chars() {
+        match (closing, c) {
+            (None, '<') => closing = Some('>'),
+            (None, '{') => closing = Some('}'),
+            (None, _) => text.push(c),
+            (Some(end), _) if c == end => closing = None,
+            _ => {}
+        }
+    }
+    text.replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+}
+
+/// Returns the text of the cues, one line per line of text.
+pub(crate) fn text(content: &str) -> String {
+    let content = content.trim_start_matches('\u{feff}');
+    let mut lines: Vec<String> = vec![];
+    let mut in_cue = false;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            // Cues end with a blank line.
+            in_cue = false;
+        } else if line.contains("-->") {
+            in_cue = true;
+        } else if in_cue {
+            let text = strip_tags(line);
+            let text = text.trim();
+            if !text.is_empty() {
+                lines.push(text.to_owned());
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+/// Whether the subtitles named `subtitles` belong to the video named
+/// `video`, both in the same container.
+pub(crate) fn belongs_to(subtitles: &str, video: &str) -> bool {
+    let (subtitles, video) = match (subtitles.rsplit_once('.'), video.rsplit_once('.')) {
+        (Some((subtitles, _)), Some((video, _))) => (subtitles, video),
+        _ => return false,
+    };
+    if subtitles == video {
+        return true;
+    }
+    match subtitles
+        .strip_prefix(video)
+        .and_then(|rest| rest.strip_prefix('.'))
+    {
+        Some(language) => {
+            !language.is_empty() && language.len() <= MAX_LANGUAGE_LENGTH && !language.contains('.')
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,42 @@
+//! Selective sync
+//! A device can exclude containers (sub-directories of the resources) from
+//! syncing, eg. so that a phone only carries the `notes` container while a
+//! NAS keeps everything. This choice belongs to each device, so the filter
+//! is stored in `<root-dir>/sync.filter` instead of the private file system
+//! that is shared with the other devices.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+pub(crate) const SYNC_FILTER: &str = "sync.filter";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SyncFilter {
+    excluded: BTreeSet<Vec<String>>,
+}
+
+impl SyncFilter {
+    /// Returns whether the resource or container at `path` is synced to
+    /// this device, ie. is not inside an excluded container.
+    pub fn is_synced(&self, path: &[String]) -> bool {
+        !self
+            .excluded
+            .iter()
+            .any(|container| path.starts_with(container))
+    }
+
+    /// Returns false if the container was already excluded.
+    pub fn exclude(&mut self, container: &[String]) -> bool {
+        self.excluded.insert(container.to_vec())
+    }
+
+    /// Returns false if the container was not excluded.
+    pub fn include(&mut self, container: &[String]) -> bool {
+        self.excluded.remove(container)
+    }
+
+    /// Returns the excluded containers.
+    pub fn excluded(&self) -> impl Iterator<Item = &Vec<String>> {
+        self.excluded.iter()
+    }
+}
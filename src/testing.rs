@@ -0,0 +1,139 @@
+//! Helpers for the integration tests of applications using the store.
+//! `TestStore` opens a store keeping its blocks and index in memory, see
+//! `ResourceStoreBuilder::in_memory()`, with a random generator seeded with
+//! a fixed value so that the keys and names it creates are the same on each
+//! run, including when it is cloned or reencrypted. The few files of its
+//! state are in its own temporary directory, removed when it is dropped.
+//!
+//! Its clock is a `ManualClock` starting at `START_TIMESTAMP`, which tests
+//! move with `TestStore::clock()` to check expirations or recency.
+//!
+//! Only available with the `testing` feature.
+
+use crate::clock::ManualClock;
+use crate::store::{ResourceStore, ResourceStoreBuilder, StoreError};
+use chrono::{TimeZone, Utc};
+use futures::io::Cursor;
+use std::io::Read;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The seed of the random generator of `TestStore::new()`.
+pub const DEFAULT_SEED: u64 = 0;
+
+/// The initial time of the clock of test stores, 2024-01-01T00:00:00Z.
+pub const START_TIMESTAMP: i64 = 1_704_067_200;
+
+type Result<T> = std::result::Result<T, StoreError>;
+
+// Distinguishes the stores created by the tests of a process.
+static STORE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A store in memory and a temporary directory, removed when it is dropped.
+pub struct TestStore {
+    // Always Some, until dropped.
+    store: Option<ResourceStore>,
+    clock: Arc<ManualClock>,
+    root_dir: PathBuf,
+}
+
+impl TestStore {
+    /// Opens an empty store with the default seed.
+    pub async fn new() -> Result<Self> {
+        Self::with_seed(DEFAULT_SEED).await
+    }
+
+    /// Opens an empty store whose random generator is seeded with `seed`.
+    pub async fn with_seed(seed: u64) -> Result<Self> {
+        Self::with_builder(seed, |builder| builder).await
+    }
+
+    /// Opens an empty store configured by `configure`, eg. to add
+    /// validators or change the index options.
+    pub async fn with_builder<F>(seed: u64, configure: F) -> Result<Self>
+    where
+        F: FnOnce(ResourceStoreBuilder) -> ResourceStoreBuilder,
+    {
+        let root_dir = std::env::temp_dir().join(format!(
+            "docstore-test-{}-{}",
+            std::process::id(),
+            STORE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        if root_dir.exists() {
+            let _ = std::fs::remove_dir_all(&root_dir);
+        }
+        let clock = Arc::new(ManualClock::new(
+            Utc.timestamp_opt(START_TIMESTAMP, 0).unwrap(),
+        ));
+        let builder = ResourceStore::builder(&root_dir)
+            .in_memory()
+            .rng_seed(seed)
+            .clock(clock.clone());
+        let store = configure(builder).build().await?;
+        Ok(Self {
+            store: Some(store),
+            clock,
+            root_dir,
+        })
+    }
+
+    /// The clock of the store, which only moves when told to.
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    /// The temporary directory of the store.
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// Imports all the files of `dir`, not recursively, like
+    /// `ResourceStore::import_file()`. Returns how many were imported.
+    pub async fn import_fixtures<P: AsRef<Path>>(&mut self, dir: P) -> Result<usize> {
+        let mut paths = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        // Imported in a stable order, for reproducible results.
+        paths.sort();
+        for path in &paths {
+            self.import_file(path).await?;
+        }
+        Ok(paths.len())
+    }
+}
+
+impl Deref for TestStore {
+    type Target = ResourceStore;
+
+    fn deref(&self) -> &ResourceStore {
+        self.store.as_ref().expect("The store is open")
+    }
+}
+
+impl DerefMut for TestStore {
+    fn deref_mut(&mut self) -> &mut ResourceStore {
+        self.store.as_mut().expect("The store is open")
+    }
+}
+
+impl Drop for TestStore {
+    fn drop(&mut self) {
+        // Closes the store before removing its directory.
+        drop(self.store.take());
+        let _ = std::fs::remove_dir_all(&self.root_dir);
+    }
+}
+
+/// Reads a fixture file, to be used as the content of a resource or
+/// variant.
+pub fn fixture<P: AsRef<Path>>(path: P) -> std::io::Result<Cursor<Vec<u8>>> {
+    let mut buffer = vec![];
+    std::fs::File::open(path)?.read_to_end(&mut buffer)?;
+    Ok(Cursor::new(buffer))
+}
@@ -0,0 +1,111 @@
+//! Optical character recognition, used to make the text of scanned
+//! documents and photos searchable. No recognizer is built in: register one
+//! with `register_text_recognizer()`, for instance a `CommandTextRecognizer`
+//! running `tesseract`.
+
+use log::error;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+pub trait TextRecognizer: Send + Sync {
+    /// Returns whether this recognizer handles content with this mime type.
+    fn supports(&self, mime_type: &str) -> bool;
+
+    /// Returns the text recognized in the content.
+    fn recognize(&self, content: &[u8]) -> Result<String, String>;
+}
+
+static RECOGNIZERS: RwLock<Vec<Box<dyn TextRecognizer>>> = RwLock::new(Vec::new());
+
+/// Registers a text recognizer for all the stores of this process.
+pub fn register_text_recognizer(recognizer: Box<dyn TextRecognizer>) {
+    RECOGNIZERS.write().unwrap().push(recognizer);
+}
+
+/// Returns whether a registered recognizer handles this mime type.
+pub(crate) fn has_text_recognizer(mime_type: &str) -> bool {
+    RECOGNIZERS
+        .read()
+        .unwrap()
+        .iter()
+        .any(|recognizer| recognizer.supports(mime_type))
+}
+
+/// Recognizes the text of the content with the first registered recognizer
+/// supporting this mime type. Returns None if no text was found.
+pub(crate) fn recognize_text(content: &[u8], mime_type: &str) -> Option<String> {
+    let recognizers = RECOGNIZERS.read().unwrap();
+    let recognizer = recognizers
+        .iter()
+        .find(|recognizer| recognizer.supports(mime_type))?;
+    match recognizer.recognize(content) {
+        Ok(text) if !text.trim().is_empty() => Some(text),
+        Ok(_) => None,
+        Err(err) => {
+            error!("Failed to recognize the text of {}: {}", mime_type, err);
+            None
+        }
+    }
+}
+
+// Makes the names of the temporary files unique in this process.
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A recognizer running an external command which prints the text on its
+/// standard output. The content is written to a temporary file, and in the
+/// arguments `{input}` is replaced by its path. For instance:
+/// `tesseract {input} stdout`
+pub struct CommandTextRecognizer {
+    program: String,
+    args: Vec<String>,
+    mime_types: Vec<String>,
+}
+
+impl CommandTextRecognizer {
+    /// Mime types ending with `/*` match all their subtypes.
+    pub fn new(program: &str, args: &[&str], mime_types: &[&str]) -> Self {
+        Self {
+            program: program.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+            mime_types: mime_types.iter().map(|mime| (*mime).to_owned()).collect(),
+        }
+    }
+}
+
+impl TextRecognizer for CommandTextRecognizer {
+    fn supports(&self, mime_type: &str) -> bool {
+        self.mime_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => mime_type.split_once('/').map(|(value, _)| value) == Some(kind),
+                None => pattern == mime_type,
+            })
+    }
+
+    fn recognize(&self, content: &[u8]) -> Result<String, String> {
+        let input = std::env::temp_dir().join(format!(
+            "docstore-ocr-{}-{}",
+            std::process::id(),
+            RUN_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&input, content).map_err(|e| e.to_string())?;
+
+        let args = self
+            .args
+            .iter()
+            .map(|arg| arg.replace("{input}", &input.to_string_lossy()));
+        let output = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        let _ = std::fs::remove_file(&input);
+
+        let output = output.map_err(|e| format!("Failed to run {}: {}", self.program, e))?;
+        if !output.status.success() {
+            return Err(format!("{} failed: {}", self.program, output.status));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
@@ -0,0 +1,132 @@
+//! Text normalization and tokenization
+//! The indexed text and the searched one are normalized the same way by
+//! `normalize()`: lowercased, and without the diacritics of latin, greek
+//! or cyrillic letters so that searching "cafe" finds "café". Kana and
+//! Hangul are kept as is since their marks change the sound, eg. "が" is
+//! not "か".
+//!
+//! Searches match substrings of the normalized text, so they don't depend
+//! on word boundaries, but the search suggestions are made of the words
+//! of the indexed text. Languages written without spaces need a
+//! `Tokenization` able to find these words, set with
+//! `ResourceStoreBuilder::tokenization()`.
+
+use std::collections::HashSet;
+#[cfg(feature = "jieba")]
+use std::sync::OnceLock;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// How the words of the indexed text are found.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Tokenization {
+    /// Words are separated by the characters which are neither letters nor
+    /// digits. Runs of CJK text are single words.
+    #[default]
+    Simple,
+    /// Words are found with the Unicode word boundaries (UAX #29), which
+    /// separate the scripts and the Katakana words, but split the Chinese
+    /// and Japanese ideographic text into single characters.
+    Unicode,
+    /// Chinese text is segmented with the jieba dictionary, and other text
+    /// like `Unicode`.
+    #[cfg(feature = "jieba")]
+    Jieba,
+}
+
+impl Tokenization {
+    /// The name recorded in the index, to find out that it must be rebuilt
+    /// when the tokenization changes.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Simple => "simple",
+            Self::Unicode => "unicode",
+            #[cfg(feature = "jieba")]
+            Self::Jieba => "jieba",
+        }
+    }
+
+    /// Returns the words of a normalized text.
+    pub fn words<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        match self {
+            Self::Simple => text
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            Self::Unicode => text.unicode_words().collect(),
+            #[cfg(feature = "jieba")]
+            Self::Jieba => {
+                static JIEBA: OnceLock<jieba_rs::Jieba> = OnceLock::new();
+                let jieba = JIEBA.get_or_init(jieba_rs::Jieba::new);
+                text.unicode_words()
+                    .flat_map(|word| {
+                        if word.chars().any(is_cjk) {
+                            jieba.cut(word, false)
+                        } else {
+                            vec![word]
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// Words shorter than this are not used as suggestions, or shorter than
+// the CJK one when they contain CJK characters, which are denser.
+const MIN_SUGGESTION_LENGTH: usize = 3;
+const MIN_CJK_SUGGESTION_LENGTH: usize = 2;
+
+/// Returns the distinct words of a normalized text that are long enough
+/// to be suggested.
+pub(crate) fn suggestion_terms(content: &str, tokenization: Tokenization) -> HashSet<&str> {
+    tokenization
+        .words(content)
+        .into_iter()
+        .filter(|word| {
+            let min = if word.chars().any(is_cjk) {
+                MIN_CJK_SUGGESTION_LENGTH
+            } else {
+                MIN_SUGGESTION_LENGTH
+            };
+            word.chars().count() >= min
+        })
+        .collect()
+}
+
+/// Whether `c` is a Chinese, Japanese or Korean character.
+pub(crate) fn is_cjk(c: char) -> bool {
+    is_kana_or_hangul(c)
+        || matches!(c,
+            '\u{3400}'..='\u{4dbf}' // CJK Unified Ideographs Extension A
+            | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+            | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+            | '\u{20000}'..='\u{2ebef}' // CJK Unified Ideographs Extensions B to F
+        )
+}
+
+fn is_kana_or_hangul(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11ff}' // Hangul Jamo
+        | '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3130}'..='\u{318f}' // Hangul Compatibility Jamo
+        | '\u{31f0}'..='\u{31ff}' // Katakana Phonetic Extensions
+        | '\u{ac00}'..='\u{d7af}' // Hangul Syllables
+        | '\u{ff66}'..='\u{ff9f}' // Halfwidth Katakana
+    )
+}
+
+/// Lowercases `text` and removes its diacritics, except for kana and
+/// Hangul.
+pub(crate) fn normalize(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        if is_kana_or_hangul(c) {
+            result.push_str(&secular::lower_lay_string(&text[start..index]));
+            result.push(c);
+            start = index + c.len_utf8();
+        }
+    }
+    result.push_str(&secular::lower_lay_string(&text[start..]));
+    result
+}
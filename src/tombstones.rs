@@ -0,0 +1,78 @@
+//! Tombstones
+//! Deleting a resource leaves a tombstone in the hidden `.tombstones`
+//! private file, so that syncing replicas propagate the deletion instead of
+//! restoring the resource from a replica that still has it. Tombstones are
+//! kept for the retention window of the `TombstoneSettings` section, which
+//! should be longer than the time a replica may stay offline.
+
+use crate::settings::Settings;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub(crate) const TOMBSTONES_FILE: &str = ".tombstones";
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Tombstone {
+    pub path: Vec<String>,
+    /// The revision of the change journal when the deletion was recorded.
+    pub revision: u64,
+    /// The time of the deletion, in seconds since the Unix epoch.
+    pub deleted_at: i64,
+}
+
+/// The settings section deciding how long tombstones are kept.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TombstoneSettings {
+    pub retention_days: u32,
+}
+
+impl Default for TombstoneSettings {
+    fn default() -> Self {
+        Self { retention_days: 90 }
+    }
+}
+
+impl Settings for TombstoneSettings {
+    const NAME: &'static str = "docstore.tombstones";
+    const VERSION: u32 = 1;
+}
+
+/// The tombstones of a store, by path.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Tombstones {
+    entries: BTreeMap<Vec<String>, Tombstone>,
+}
+
+impl Tombstones {
+    pub(crate) fn get(&self, path: &[String]) -> Option<&Tombstone> {
+        self.entries.get(path)
+    }
+
+    /// Records a tombstone, keeping the most recent deletion of a path.
+    /// Returns false if a tombstone as recent was known.
+    pub(crate) fn record(&mut self, tombstone: Tombstone) -> bool {
+        match self.entries.get(&tombstone.path) {
+            Some(known) if known.deleted_at >= tombstone.deleted_at => false,
+            _ => {
+                self.entries.insert(tombstone.path.clone(), tombstone);
+                true
+            }
+        }
+    }
+
+    /// Returns false if there was no tombstone for this path.
+    pub(crate) fn remove(&mut self, path: &[String]) -> bool {
+        self.entries.remove(path).is_some()
+    }
+
+    /// Removes the tombstones older than the retention window.
+    pub(crate) fn prune(&mut self, now: i64, settings: &TombstoneSettings) {
+        let oldest = now - settings.retention_days as i64 * 24 * 3600;
+        self.entries
+            .retain(|_, tombstone| tombstone.deleted_at >= oldest);
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<Tombstone> {
+        self.entries.into_values().collect()
+    }
+}
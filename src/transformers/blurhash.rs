@@ -0,0 +1,116 @@
+use super::TransformedVariant;
+/// Blurhash transformer: creates a tiny textual placeholder for images.
+use crate::image_decoders::decode_image;
+use crate::resource::{ContentReader, VariantMetadata};
+use crate::transformers::{
+    TransformedContent, TransformerResult, VariantChange, VariantTransformer,
+};
+use async_trait::async_trait;
+use futures::{AsyncReadExt, AsyncSeekExt};
+use log::{error, info};
+use std::io::{Cursor, SeekFrom};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+// The encoding cost grows with the image size, so we first downscale
+// the image to this size since the blurhash doesn't need more details.
+const SAMPLE_SIZE: u32 = 32;
+const DEFAULT_COMPONENTS_X: u32 = 4;
+const DEFAULT_COMPONENTS_Y: u32 = 3;
+
+pub const BLURHASH_MIME_TYPE: &str = "application/x-blurhash";
+
+pub const BLURHASH_VARIANT: &str = "blurhash";
+
+pub struct Blurhash {
+    components_x: u32,
+    components_y: u32,
+}
+
+impl Default for Blurhash {
+    fn default() -> Self {
+        Self {
+            components_x: DEFAULT_COMPONENTS_X,
+            components_y: DEFAULT_COMPONENTS_Y,
+        }
+    }
+}
+
+fn err_nop<T: std::fmt::Debug>(e: T) {
+    error!("Unexpected: {:?}", e);
+}
+
+async fn create_blurhash<C: ContentReader>(
+    content: &mut C,
+    mime_type: &str,
+    components_x: u32,
+    components_y: u32,
+) -> Result<TransformedVariant, ()> {
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await.map_err(err_nop)?;
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+
+    let img = decode_image(buffer, mime_type).ok_or(())?;
+
+    let sample = img.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgba8();
+    let hash = blurhash::encode(
+        components_x,
+        components_y,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    )
+    .map_err(err_nop)?;
+
+    info!(
+        "Blurhash for image {}x{} is {}",
+        img.width(),
+        img.height(),
+        hash
+    );
+
+    let bytes = hash.into_bytes();
+    let v = TransformedVariant::new(
+        BLURHASH_VARIANT,
+        &VariantMetadata::new(bytes.len() as _, BLURHASH_MIME_TYPE),
+        TransformedContent::new(Box::new(Cursor::new(bytes).compat())),
+    );
+
+    Ok(v)
+}
+
+#[async_trait(?Send)]
+impl VariantTransformer for Blurhash {
+    async fn transform_variant<C: ContentReader>(
+        &self,
+        change: &mut VariantChange,
+        content: &mut C,
+    ) -> Vec<TransformerResult> {
+        let meta = &change.metadata();
+
+        // Only process variants of image/*  mime type.
+        if !meta.mime_type().starts_with("image/") {
+            return vec![];
+        }
+
+        if change.is_deleted() {
+            return vec![TransformerResult::Delete(BLURHASH_VARIANT.into())];
+        }
+
+        match create_blurhash(
+            content,
+            &meta.mime_type(),
+            self.components_x,
+            self.components_y,
+        )
+        .await
+        {
+            Ok(v) => match change {
+                VariantChange::Created(_) => vec![TransformerResult::Create(v)],
+                VariantChange::Updated(_) => vec![TransformerResult::Update(v)],
+                _ => panic!("Unexpected variant change!"),
+            },
+            Err(_) => vec![],
+        }
+    }
+}
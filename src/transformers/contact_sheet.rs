@@ -0,0 +1,160 @@
+use super::TransformedVariant;
+/// Contact sheet transformer: tiles frames of videos or pages of documents
+/// in a single image, for instance for a scrubbing preview.
+use crate::frame_extractors::{extract_frames, has_frame_extractor};
+use crate::resource::{ContentReader, VariantMetadata};
+use crate::settings::Settings;
+use crate::transformers::{
+    TransformedContent, TransformerResult, VariantChange, VariantTransformer,
+};
+use async_trait::async_trait;
+use futures::{AsyncReadExt, AsyncSeekExt};
+use image::{imageops, DynamicImage, RgbImage};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, SeekFrom};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+pub const CONTACT_SHEET_VARIANT: &str = "contact_sheet";
+
+/// The settings section deciding the layout of contact sheets.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ContactSheetSettings {
+    /// The grid of video frames.
+    pub video_columns: u32,
+    pub video_rows: u32,
+    /// How many of the first pages of documents are included, laid out in
+    /// `document_columns` columns.
+    pub document_pages: u32,
+    pub document_columns: u32,
+    /// The width of the sheet in pixels. Tiles keep the aspect ratio of the
+    /// first image.
+    pub width: u32,
+}
+
+impl Default for ContactSheetSettings {
+    fn default() -> Self {
+        Self {
+            video_columns: 4,
+            video_rows: 4,
+            document_pages: 8,
+            document_columns: 4,
+            width: 1024,
+        }
+    }
+}
+
+impl Settings for ContactSheetSettings {
+    const NAME: &'static str = "docstore.contact_sheets";
+    const VERSION: u32 = 1;
+}
+
+impl ContactSheetSettings {
+    // Returns the number of tiles and columns for this mime type.
+    fn layout(&self, mime_type: &str) -> (u32, u32) {
+        if mime_type.starts_with("video/") {
+            (self.video_columns * self.video_rows, self.video_columns)
+        } else {
+            (self.document_pages, self.document_columns)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ContactSheet {
+    settings: ContactSheetSettings,
+}
+
+impl ContactSheet {
+    pub fn new(settings: ContactSheetSettings) -> Self {
+        Self { settings }
+    }
+}
+
+fn err_nop<T: std::fmt::Debug>(e: T) {
+    error!("Unexpected: {:?}", e);
+}
+
+/// Lays out the frames in rows of `columns` tiles, on a black background.
+fn tile(frames: &[DynamicImage], columns: u32, width: u32) -> RgbImage {
+    let columns = columns.clamp(1, frames.len() as u32);
+    let rows = (frames.len() as u32 + columns - 1) / columns;
+    let tile_width = (width / columns).max(1);
+    let tile_height = ((tile_width as u64 * frames[0].height() as u64)
+        / frames[0].width().max(1) as u64)
+        .max(1) as u32;
+
+    let mut sheet = RgbImage::new(tile_width * columns, tile_height * rows);
+    for (index, frame) in frames.iter().enumerate() {
+        let tile = frame.thumbnail(tile_width, tile_height).to_rgb8();
+        // Center the frames that don't have the aspect ratio of the first one.
+        let x = (index as u32 % columns) * tile_width + (tile_width - tile.width()) / 2;
+        let y = (index as u32 / columns) * tile_height + (tile_height - tile.height()) / 2;
+        imageops::overlay(&mut sheet, &tile, x as i64, y as i64);
+    }
+    sheet
+}
+
+async fn create_contact_sheet<C: ContentReader>(
+    content: &mut C,
+    mime_type: &str,
+    settings: &ContactSheetSettings,
+) -> Result<TransformedVariant, ()> {
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await.map_err(err_nop)?;
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+
+    let (count, columns) = settings.layout(mime_type);
+    if count == 0 {
+        return Err(());
+    }
+    let frames = extract_frames(&buffer, mime_type, count).ok_or(())?;
+    info!("Creating contact sheet of {} images", frames.len());
+    let sheet = tile(&frames, columns, settings.width);
+
+    let mut bytes: Vec<u8> = Vec::new();
+    DynamicImage::ImageRgb8(sheet)
+        .write_to(
+            &mut Cursor::new(&mut bytes),
+            image::ImageOutputFormat::Jpeg(85),
+        )
+        .map_err(err_nop)?;
+
+    let v = TransformedVariant::new(
+        CONTACT_SHEET_VARIANT,
+        &VariantMetadata::new(bytes.len() as _, "image/jpeg"),
+        TransformedContent::new(Box::new(Cursor::new(bytes).compat())),
+    );
+
+    Ok(v)
+}
+
+#[async_trait(?Send)]
+impl VariantTransformer for ContactSheet {
+    async fn transform_variant<C: ContentReader>(
+        &self,
+        change: &mut VariantChange,
+        content: &mut C,
+    ) -> Vec<TransformerResult> {
+        let meta = &change.metadata();
+
+        // Only process the content that a registered extractor supports.
+        if !has_frame_extractor(&meta.mime_type()) {
+            return vec![];
+        }
+
+        if change.is_deleted() {
+            return vec![TransformerResult::Delete(CONTACT_SHEET_VARIANT.into())];
+        }
+
+        match create_contact_sheet(content, &meta.mime_type(), &self.settings).await {
+            Ok(v) => match change {
+                VariantChange::Created(_) => vec![TransformerResult::Create(v)],
+                VariantChange::Updated(_) => vec![TransformerResult::Update(v)],
+                _ => panic!("Unexpected variant change!"),
+            },
+            Err(_) => vec![],
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use super::TransformedVariant;
+/// Cover transformer: extracts the cover image of EPUB books.
+use crate::epub::{cover_image, package, EPUB_MIME_TYPE};
+use crate::resource::{ContentReader, VariantMetadata};
+use crate::transformers::{
+    TransformedContent, TransformerResult, VariantChange, VariantTransformer,
+};
+use async_trait::async_trait;
+use futures::{AsyncReadExt, AsyncSeekExt};
+use log::{error, info};
+use std::io::{Cursor, SeekFrom};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+pub const COVER_VARIANT: &str = "cover";
+
+#[derive(Default)]
+pub struct Cover {}
+
+fn err_nop<T: std::fmt::Debug>(e: T) {
+    error!("Unexpected: {:?}", e);
+}
+
+async fn extract_cover<C: ContentReader>(content: &mut C) -> Result<TransformedVariant, ()> {
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await.map_err(err_nop)?;
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).map_err(err_nop)?;
+    let package = package(&mut archive).ok_or(())?;
+    let (bytes, mime_type) = cover_image(&mut archive, &package).ok_or(())?;
+
+    info!("Extracted {} cover of {}b", mime_type, bytes.len());
+
+    let v = TransformedVariant::new(
+        COVER_VARIANT,
+        &VariantMetadata::new(bytes.len() as _, &mime_type),
+        TransformedContent::new(Box::new(Cursor::new(bytes).compat())),
+    );
+
+    Ok(v)
+}
+
+#[async_trait(?Send)]
+impl VariantTransformer for Cover {
+    async fn transform_variant<C: ContentReader>(
+        &self,
+        change: &mut VariantChange,
+        content: &mut C,
+    ) -> Vec<TransformerResult> {
+        let meta = &change.metadata();
+
+        // Only process EPUB books.
+        if meta.mime_type() != EPUB_MIME_TYPE {
+            return vec![];
+        }
+
+        if change.is_deleted() {
+            return vec![TransformerResult::Delete(COVER_VARIANT.into())];
+        }
+
+        match extract_cover(content).await {
+            Ok(v) => match change {
+                VariantChange::Created(_) => vec![TransformerResult::Create(v)],
+                VariantChange::Updated(_) => vec![TransformerResult::Update(v)],
+                _ => panic!("Unexpected variant change!"),
+            },
+            Err(_) => vec![],
+        }
+    }
+}
@@ -1,14 +1,28 @@
 //! Variant transformers: code that runs when we create,
 //! update or delete default variants.
 
-use self::thumbnailer::Thumbnailer;
+use self::blurhash::{Blurhash, BLURHASH_VARIANT};
+use self::contact_sheet::{ContactSheet, ContactSheetSettings, CONTACT_SHEET_VARIANT};
+use self::cover::{Cover, COVER_VARIANT};
+use self::ocr::{Ocr, OCR_VARIANT};
+use self::thumbnailer::{
+    ThumbnailSettings, Thumbnailer, ANIMATED_PREVIEW_VARIANT, THUMBNAIL_VARIANT,
+};
 use crate::resource::{ContentReader, VariantMetadata};
+use crate::settings::Settings;
 use async_trait::async_trait;
 use futures::io::AsyncSeek;
 use futures::task::{Context, Poll};
 use futures::AsyncRead;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::pin::Pin;
 
+pub mod blurhash;
+pub mod contact_sheet;
+pub mod cover;
+pub mod ocr;
+pub(crate) mod queue;
 pub mod thumbnailer;
 
 /// A wrapper holding the returned content for a variant
@@ -44,8 +58,6 @@ impl AsyncSeek for TransformedContent {
     }
 }
 
-impl ContentReader for TransformedContent {}
-
 pub struct TransformedVariant {
     pub(crate) name: String, // The variant name.
     pub(crate) meta: VariantMetadata,
@@ -105,10 +117,88 @@ pub trait VariantTransformer {
     ) -> Vec<TransformerResult>;
 }
 
+/// The names of the built-in transformers, which are also the names of the
+/// variants they create.
+pub const TRANSFORMERS: [&str; 5] = [
+    THUMBNAIL_VARIANT,
+    BLURHASH_VARIANT,
+    COVER_VARIANT,
+    CONTACT_SHEET_VARIANT,
+    OCR_VARIANT,
+];
+
+/// The settings section listing the built-in transformers disabled for a
+/// store, for instance thumbnails on a server profile.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TransformerSettings {
+    pub disabled: BTreeSet<String>,
+}
+
+impl Settings for TransformerSettings {
+    const NAME: &'static str = "docstore.transformers";
+    const VERSION: u32 = 1;
+}
+
+/// The configuration of the built-in transformers of a store, gathered from
+/// the settings sections, see `ResourceStore::transformer_config()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransformerConfig {
+    pub transformers: TransformerSettings,
+    pub thumbnails: ThumbnailSettings,
+    pub contact_sheets: ContactSheetSettings,
+}
+
+impl TransformerConfig {
+    pub fn is_enabled(&self, name: &str) -> bool {
+        !self.transformers.disabled.contains(name)
+    }
+
+    /// Whether images are queued to get their thumbnails later.
+    pub fn lazy_thumbnails(&self) -> bool {
+        self.thumbnails.lazy && self.is_enabled(THUMBNAIL_VARIANT)
+    }
+}
+
+/// Runs the enabled built-in transformers. With lazy thumbnails, they are
+/// left to be created on demand. The variants of disabled transformers are
+/// deleted when the default variant is updated, since they would be outdated.
 pub async fn run_transformers<C: ContentReader>(
     change: &mut VariantChange,
     content: &mut C,
+    config: &TransformerConfig,
 ) -> Vec<TransformerResult> {
-    let thumbnailer = Thumbnailer::default();
-    thumbnailer.transform_variant(change, content).await
+    let mut results = vec![];
+    for name in TRANSFORMERS {
+        if !config.is_enabled(name) {
+            if change.is_updated() {
+                results.push(TransformerResult::Delete(name.to_owned()));
+                if name == THUMBNAIL_VARIANT {
+                    results.push(TransformerResult::Delete(ANIMATED_PREVIEW_VARIANT.into()));
+                }
+            }
+            continue;
+        }
+
+        let transformed = match name {
+            THUMBNAIL_VARIANT if config.thumbnails.lazy => {
+                Thumbnailer::lazy().transform_variant(change, content).await
+            }
+            THUMBNAIL_VARIANT => {
+                Thumbnailer::new(&config.thumbnails)
+                    .transform_variant(change, content)
+                    .await
+            }
+            BLURHASH_VARIANT => Blurhash::default().transform_variant(change, content).await,
+            COVER_VARIANT => Cover::default().transform_variant(change, content).await,
+            OCR_VARIANT => Ocr::default().transform_variant(change, content).await,
+            _ => {
+                ContactSheet::new(config.contact_sheets.clone())
+                    .transform_variant(change, content)
+                    .await
+            }
+        };
+        results.extend(transformed);
+    }
+
+    results
 }
@@ -0,0 +1,74 @@
+use super::TransformedVariant;
+/// OCR transformer: stores the text recognized in images and scanned
+/// documents as a plain text variant, which is indexed like any other. The
+/// text can then be displayed and copied, and reindexing doesn't run the
+/// recognition again.
+use crate::resource::{ContentReader, VariantMetadata};
+use crate::text_recognizers::{has_text_recognizer, recognize_text};
+use crate::transformers::{
+    TransformedContent, TransformerResult, VariantChange, VariantTransformer,
+};
+use async_trait::async_trait;
+use futures::{AsyncReadExt, AsyncSeekExt};
+use log::{error, info};
+use std::io::{Cursor, SeekFrom};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+pub const OCR_VARIANT: &str = "ocr";
+
+#[derive(Default)]
+pub struct Ocr {}
+
+fn err_nop<T: std::fmt::Debug>(e: T) {
+    error!("Unexpected: {:?}", e);
+}
+
+async fn create_ocr_variant<C: ContentReader>(
+    content: &mut C,
+    mime_type: &str,
+) -> Result<TransformedVariant, ()> {
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+    let mut buffer = vec![];
+    content.read_to_end(&mut buffer).await.map_err(err_nop)?;
+    content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
+
+    let text = recognize_text(&buffer, mime_type).ok_or(())?;
+    info!("Recognized {} bytes of text", text.len());
+
+    let bytes = text.into_bytes();
+    let v = TransformedVariant::new(
+        OCR_VARIANT,
+        &VariantMetadata::new(bytes.len() as _, "text/plain"),
+        TransformedContent::new(Box::new(Cursor::new(bytes).compat())),
+    );
+    Ok(v)
+}
+
+#[async_trait(?Send)]
+impl VariantTransformer for Ocr {
+    async fn transform_variant<C: ContentReader>(
+        &self,
+        change: &mut VariantChange,
+        content: &mut C,
+    ) -> Vec<TransformerResult> {
+        let meta = &change.metadata();
+        // Only process the content that a registered recognizer supports.
+        if !has_text_recognizer(&meta.mime_type()) {
+            return vec![];
+        }
+
+        if change.is_deleted() {
+            return vec![TransformerResult::Delete(OCR_VARIANT.into())];
+        }
+
+        match (create_ocr_variant(content, &meta.mime_type()).await, change) {
+            (Ok(v), VariantChange::Created(_)) => vec![TransformerResult::Create(v)],
+            (Ok(v), VariantChange::Updated(_)) => vec![TransformerResult::Update(v)],
+            // The text of the previous content is outdated.
+            (Err(_), VariantChange::Updated(_)) => {
+                vec![TransformerResult::Delete(OCR_VARIANT.into())]
+            }
+            _ => vec![],
+        }
+    }
+}
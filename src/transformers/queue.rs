@@ -0,0 +1,51 @@
+//! The queue of the resources waiting for lazily created variants.
+//! Resources are processed in the order they were added, except the ones
+//! whose priority was bumped, for instance because they are on screen:
+//! they come first, the most recently bumped one first.
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Default)]
+pub(crate) struct TransformQueue {
+    // The paths by (inverted priority, sequence number).
+    entries: BTreeMap<(u64, u64), Vec<String>>,
+    // The key of each path in `entries`.
+    keys: HashMap<Vec<String>, (u64, u64)>,
+    next_sequence: u64,
+    next_priority: u64,
+}
+
+impl TransformQueue {
+    fn insert(&mut self, path: &[String], priority: u64) {
+        self.remove(path);
+        let key = (u64::MAX - priority, self.next_sequence);
+        self.next_sequence += 1;
+        self.entries.insert(key, path.to_vec());
+        self.keys.insert(path.to_vec(), key);
+    }
+
+    /// Adds a resource at the end of the queue, unless it is already queued.
+    pub(crate) fn push(&mut self, path: &[String]) {
+        if !self.keys.contains_key(path) {
+            self.insert(path, 0);
+        }
+    }
+
+    /// Moves a resource to the front of the queue, adding it if needed.
+    pub(crate) fn prioritize(&mut self, path: &[String]) {
+        self.next_priority += 1;
+        self.insert(path, self.next_priority);
+    }
+
+    pub(crate) fn remove(&mut self, path: &[String]) {
+        if let Some(key) = self.keys.remove(path) {
+            self.entries.remove(&key);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Vec<String>> {
+        let (_, path) = self.entries.pop_first()?;
+        self.keys.remove(&path);
+        Some(path)
+    }
+}
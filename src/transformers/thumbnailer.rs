@@ -1,26 +1,81 @@
 use super::TransformedVariant;
 /// Thumbnailer transformer.
+/// Animated GIF and PNG images get the thumbnail of their first frame, and
+/// optionally a short animated preview.
+use crate::image_decoders::{animation_frames, decode_image};
 use crate::resource::{ContentReader, VariantMetadata};
+use crate::settings::Settings;
 use crate::transformers::{
     TransformedContent, TransformerResult, VariantChange, VariantTransformer,
 };
 use async_trait::async_trait;
 use futures::{AsyncReadExt, AsyncSeekExt};
-use image::io::Reader as ImageReader;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{DynamicImage, Frame};
 use log::{error, info};
+use serde::{Deserialize, Serialize};
 use std::io::{Cursor, SeekFrom};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
 const DEFAULT_THUMBNAIL_SIZE: u32 = 128;
 
+pub const THUMBNAIL_VARIANT: &str = "thumbnail";
+
+/// The variant holding the animated preview of animated images, as a GIF
+/// image since the `image` crate can't encode animated WebP images.
+pub const ANIMATED_PREVIEW_VARIANT: &str = "animated_preview";
+
+// Animated previews keep the first frames, up to these limits.
+const MAX_PREVIEW_FRAMES: usize = 50;
+const MAX_PREVIEW_MILLIS: u32 = 3000;
+
+/// The settings section deciding when thumbnails are created.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ThumbnailSettings {
+    /// When true, thumbnails are not created along with the images but on
+    /// the first `ResourceStore::get_thumbnail()` call, or by
+    /// `ResourceStore::backfill_thumbnails()`.
+    pub lazy: bool,
+    /// When true, animated images also get an `ANIMATED_PREVIEW_VARIANT`
+    /// made of their first seconds, at the size of the thumbnails.
+    #[serde(default)]
+    pub animated_previews: bool,
+}
+
+impl Settings for ThumbnailSettings {
+    const NAME: &'static str = "docstore.thumbnails";
+    const VERSION: u32 = 1;
+}
+
 pub struct Thumbnailer {
-    size: u32, // The size (max width & height) of the thumbnail
+    size: u32,               // The size (max width & height) of the thumbnail
+    lazy: bool,              // Only removes outdated thumbnails.
+    animated_previews: bool, // Also creates previews of animated images.
 }
 
 impl Default for Thumbnailer {
     fn default() -> Self {
         Self {
             size: DEFAULT_THUMBNAIL_SIZE,
+            lazy: false,
+            animated_previews: false,
+        }
+    }
+}
+
+impl Thumbnailer {
+    pub fn new(settings: &ThumbnailSettings) -> Self {
+        Self {
+            lazy: settings.lazy,
+            animated_previews: settings.animated_previews,
+            ..Default::default()
+        }
+    }
+
+    pub fn lazy() -> Self {
+        Self {
+            lazy: true,
+            ..Default::default()
         }
     }
 }
@@ -32,6 +87,7 @@ fn err_nop<T: std::error::Error>(e: T) -> () {
 
 async fn create_thumbnail<C: ContentReader>(
     content: &mut C,
+    mime_type: &str,
     thumbnail_size: u32,
 ) -> Result<TransformedVariant, ()> {
     content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
@@ -40,11 +96,7 @@ async fn create_thumbnail<C: ContentReader>(
     content.seek(SeekFrom::Start(0)).await.map_err(err_nop)?;
 
     info!("Image size is {}b", buffer.len());
-    let img = ImageReader::new(Cursor::new(buffer))
-        .with_guessed_format()
-        .map_err(err_nop)?
-        .decode()
-        .map_err(err_nop)?;
+    let img = decode_image(buffer, mime_type).ok_or(())?;
 
     info!(
         "Creating {}x{} thumbnail for image {}x{}",
@@ -65,7 +117,7 @@ async fn create_thumbnail<C: ContentReader>(
         .map_err(err_nop)?;
 
     let v = TransformedVariant::new(
-        "thumbnail",
+        THUMBNAIL_VARIANT,
         &VariantMetadata::new(bytes.len() as _, "image/jpeg"),
         TransformedContent::new(Box::new(Cursor::new(bytes).compat())),
     );
@@ -73,6 +125,45 @@ async fn create_thumbnail<C: ContentReader>(
     Ok(v)
 }
 
+// Creates the animated preview of an animated image, or returns None for
+// other images.
+fn create_animated_preview(
+    content: &[u8],
+    mime_type: &str,
+    thumbnail_size: u32,
+) -> Option<TransformedVariant> {
+    let frames = animation_frames(content, mime_type, MAX_PREVIEW_FRAMES)?;
+
+    let mut preview = vec![];
+    let mut elapsed = 0;
+    for frame in frames {
+        if elapsed >= MAX_PREVIEW_MILLIS {
+            break;
+        }
+        let delay = frame.delay();
+        let (numerator, denominator) = delay.numer_denom_ms();
+        elapsed += numerator / denominator.max(1);
+        let image = DynamicImage::ImageRgba8(frame.into_buffer())
+            .thumbnail(thumbnail_size, thumbnail_size)
+            .to_rgba8();
+        preview.push(Frame::from_parts(image, 0, 0, delay));
+    }
+    info!("Creating animated preview of {} frames", preview.len());
+
+    let mut bytes: Vec<u8> = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.set_repeat(Repeat::Infinite).map_err(err_nop).ok()?;
+        encoder.encode_frames(preview).map_err(err_nop).ok()?;
+    }
+
+    Some(TransformedVariant::new(
+        ANIMATED_PREVIEW_VARIANT,
+        &VariantMetadata::new(bytes.len() as _, "image/gif"),
+        TransformedContent::new(Box::new(Cursor::new(bytes).compat())),
+    ))
+}
+
 #[async_trait(?Send)]
 impl VariantTransformer for Thumbnailer {
     async fn transform_variant<C: ContentReader>(
@@ -88,16 +179,30 @@ impl VariantTransformer for Thumbnailer {
         }
 
         if change.is_deleted() {
-            return vec![TransformerResult::Delete("thumbnail".into())];
+            return vec![
+                TransformerResult::Delete(THUMBNAIL_VARIANT.into()),
+                TransformerResult::Delete(ANIMATED_PREVIEW_VARIANT.into()),
+            ];
+        }
+
+        if self.lazy {
+            // The thumbnail of the previous content, if any, is outdated.
+            return match change {
+                VariantChange::Updated(_) => vec![
+                    TransformerResult::Delete(THUMBNAIL_VARIANT.into()),
+                    TransformerResult::Delete(ANIMATED_PREVIEW_VARIANT.into()),
+                ],
+                _ => vec![],
+            };
         }
 
         info!(
             "Will create thumbnail for variant with mimeType '{}'",
             meta.mime_type()
         );
-        let res = {
+        let mut res = {
             // Return a new variant.
-            if let Ok(v) = create_thumbnail(content, self.size).await {
+            if let Ok(v) = create_thumbnail(content, &meta.mime_type(), self.size).await {
                 match change {
                     VariantChange::Created(_) => {
                         info!("Thumbnail variant created");
@@ -114,6 +219,24 @@ impl VariantTransformer for Thumbnailer {
             }
         };
 
+        if !res.is_empty() {
+            // The preview of the previous content is outdated, and is created
+            // again if the new content is still animated.
+            if change.is_updated() {
+                res.push(TransformerResult::Delete(ANIMATED_PREVIEW_VARIANT.into()));
+            }
+            if self.animated_previews {
+                let mut buffer = vec![];
+                if content.read_to_end(&mut buffer).await.is_ok() {
+                    if let Some(v) = create_animated_preview(&buffer, &meta.mime_type(), self.size)
+                    {
+                        res.push(TransformerResult::Create(v));
+                    }
+                }
+                let _ = content.seek(SeekFrom::Start(0)).await;
+            }
+        }
+
         res
     }
 }
@@ -0,0 +1,126 @@
+//! Content validation
+//! Validators are run when a variant is created or updated, before anything
+//! is written to the forest or the index, and can reject the content. This
+//! is where size caps, mime type policies or a malware scanner plug in.
+
+use crate::resource::{ContentReader, VariantMetadata};
+use async_trait::async_trait;
+use futures::AsyncSeekExt;
+use std::io::SeekFrom;
+
+#[async_trait(?Send)]
+pub trait Validator {
+    /// Returns whether this validator checks content with this mime type.
+    fn supports(&self, mime_type: &str) -> bool;
+
+    /// Returns the reason why the content is rejected, if it is. The content
+    /// can be read freely, the store rewinds it afterwards.
+    async fn validate(
+        &self,
+        path: &[String],
+        variant_name: &str,
+        variant: &VariantMetadata,
+        content: &mut dyn ContentReader,
+    ) -> Result<(), String>;
+}
+
+/// Rejects content larger than `max_size` bytes.
+pub struct SizeLimit {
+    max_size: u64,
+}
+
+impl SizeLimit {
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+}
+
+#[async_trait(?Send)]
+impl Validator for SizeLimit {
+    fn supports(&self, _mime_type: &str) -> bool {
+        true
+    }
+
+    async fn validate(
+        &self,
+        _path: &[String],
+        _variant_name: &str,
+        _variant: &VariantMetadata,
+        content: &mut dyn ContentReader,
+    ) -> Result<(), String> {
+        // The declared variant size can't be trusted.
+        let size = content
+            .seek(SeekFrom::End(0))
+            .await
+            .map_err(|e| e.to_string())?;
+        if size > self.max_size {
+            return Err(format!(
+                "Content is {} bytes, the limit is {}",
+                size, self.max_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Rejects content with one of these mime types. A type ending with `/*`
+/// matches all its subtypes.
+pub struct DeniedMimeTypes {
+    mime_types: Vec<String>,
+}
+
+impl DeniedMimeTypes {
+    pub fn new(mime_types: &[&str]) -> Self {
+        Self {
+            mime_types: mime_types.iter().map(|mime| (*mime).to_owned()).collect(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Validator for DeniedMimeTypes {
+    fn supports(&self, mime_type: &str) -> bool {
+        self.mime_types
+            .iter()
+            .any(|denied| match denied.strip_suffix('*') {
+                Some(prefix) => mime_type.starts_with(prefix),
+                None => mime_type == denied,
+            })
+    }
+
+    async fn validate(
+        &self,
+        _path: &[String],
+        _variant_name: &str,
+        variant: &VariantMetadata,
+        _content: &mut dyn ContentReader,
+    ) -> Result<(), String> {
+        Err(format!("{} content is not allowed", variant.mime_type()))
+    }
+}
+
+/// Runs the validators supporting the variant mime type, stopping at the
+/// first rejection.
+pub(crate) async fn validate<C: ContentReader>(
+    validators: &[Box<dyn Validator>],
+    path: &[String],
+    variant_name: &str,
+    variant: &VariantMetadata,
+    content: &mut C,
+) -> Result<(), String> {
+    let mime_type = variant.mime_type();
+    for validator in validators
+        .iter()
+        .filter(|validator| validator.supports(&mime_type))
+    {
+        let result = validator
+            .validate(path, variant_name, variant, content)
+            .await;
+        content
+            .seek(SeekFrom::Start(0))
+            .await
+            .map_err(|e| e.to_string())?;
+        result?;
+    }
+    Ok(())
+}
@@ -1,7 +1,22 @@
+use bytes::Bytes;
+use chrono::Duration;
 use core::future;
+use docstore::block_fetcher::BlockFetcher;
+use docstore::changes::ChangeOp;
+use docstore::health::CompactOptions;
+use docstore::image_decoders::{register_image_decoder, ImageDecoder};
+use docstore::properties::{PropertyFilter, PropertyValue};
 use docstore::resource::VariantMetadata;
-use docstore::store::ResourceStore;
+use docstore::rules::{TagRule, TagRules};
+use docstore::settings::Settings;
+use docstore::store::{ConflictPolicy, ExtractOptions, ImportAction, ResourceStore, StoreError};
+use docstore::transformers::blurhash::BLURHASH_VARIANT;
+use docstore::transformers::thumbnailer::{ThumbnailSettings, THUMBNAIL_VARIANT};
+use docstore::validators::{DeniedMimeTypes, SizeLimit};
 use futures::TryStreamExt;
+use image::DynamicImage;
+use libipld::Cid;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::io::{Cursor, Read};
 use std::path::{Path, PathBuf};
@@ -131,6 +146,14 @@ async fn store_variant() {
         let reverse_variant = store.get_variant_vec("reverse", &path).await.unwrap();
         assert_eq!(reverse_variant, variant_content.to_vec());
     }
+
+    {
+        // Step 3. Re-open the store and read the variant as a stream.
+        let store: ResourceStore = get_test_store(num_test).await;
+        let stream = store.get_variant("reverse", &path).await.unwrap();
+        let chunks: Vec<Vec<u8>> = stream.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), variant_content.to_vec());
+    }
 }
 
 #[tokio::test]
@@ -750,9 +773,10 @@ async fn image_transformer() {
 
         let metadata = store.get_metadata(&path).await.unwrap();
         let variants = metadata.variants();
-        assert_eq!(variants.len(), 2);
+        assert_eq!(variants.len(), 3);
         assert!(variants.contains_key("default"));
         assert!(variants.contains_key("thumbnail"));
+        assert!(variants.contains_key("blurhash"));
     }
 
     {
@@ -760,8 +784,5546 @@ async fn image_transformer() {
 
         let metadata = store.get_metadata(&path).await.unwrap();
         let variants = metadata.variants();
-        assert_eq!(variants.len(), 2);
+        assert_eq!(variants.len(), 3);
         assert!(variants.contains_key("default"));
         assert!(variants.contains_key("thumbnail"));
+        assert!(variants.contains_key("blurhash"));
+    }
+}
+
+#[tokio::test]
+async fn recent_and_suggested() {
+    let first = ["first".to_owned()];
+    let second = ["second".to_owned()];
+
+    let num_test = 13;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(0, "application/octet-stream");
+
+        for path in [&first, &second] {
+            store
+                .create_resource(
+                    path,
+                    "empty file",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(vec![]).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // The last created resource is the most recent one.
+        let results = store.recent(10).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0.to_string(), "second");
+
+        let results = store.recent(1).await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        // Accessing the first resource makes it the top suggestion.
+        store.get_variant_vec("default", &first).await.unwrap();
+        store.get_variant_vec("default", &first).await.unwrap();
+        let results = store.suggested(10).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0.to_string(), "first");
+
+        // Modifying the first resource makes it the most recent one.
+        store.add_tag(&first, "tag-1").await.unwrap();
+        let results = store.recent(10).await.unwrap();
+        assert_eq!(results[0].0.to_string(), "first");
+    }
+}
+
+#[tokio::test]
+async fn image_properties() {
+    let path = ["sticker_logo_small.png".to_owned()];
+
+    let num_test = 14;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/sticker_logo_small.png")
+            .await
+            .unwrap();
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.iter().any(|(name, _)| name == "width"));
+        assert!(properties.iter().any(|(name, _)| name == "height"));
+
+        let results = store
+            .query_properties(&[PropertyFilter::GreaterThan("width".into(), 1_i64.into())])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        let results = store
+            .query_properties(&[
+                PropertyFilter::GreaterThan("width".into(), 1_i64.into()),
+                PropertyFilter::GreaterThan("height".into(), 100000_i64.into()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 0);
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+
+        let results = store
+            .query_properties(&[PropertyFilter::Range(
+                "width".into(),
+                1_i64.into(),
+                100000_i64.into(),
+            )])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn zip_archive() {
+    let path = ["archive.zip".to_owned()];
+    let hello = ["archive".to_owned(), "hello.txt".to_owned()];
+    let lorem = [
+        "archive".to_owned(),
+        "docs".to_owned(),
+        "lorem.txt".to_owned(),
+    ];
+
+    let num_test = 15;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/archive.zip")
+            .await
+            .unwrap();
+
+        // Member names and small text members are indexed.
+        let results = store.search("lorem.txt").await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search("archive world").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        let created = store
+            .extract_archive(&path, ExtractOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 2);
+
+        let content = store.get_variant_vec("default", &hello).await.unwrap();
+        assert_eq!(content, b"Hello from the archive world!\n".to_vec());
+        let content = store.get_variant_vec("default", &lorem).await.unwrap();
+        assert_eq!(content, b"Lorem ipsum dolor sit amet.\n".to_vec());
+
+        let results = store.search("archive world").await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        // The container is not listed as a resource.
+        let files = store
+            .ls(store.resources_dir().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn metadata_many() {
+    let num_test = 16;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(0, "application/octet-stream");
+        let paths = vec![
+            vec!["first".to_owned()],
+            vec!["container".to_owned(), "second".to_owned()],
+            vec!["container".to_owned(), "third".to_owned()],
+        ];
+
+        for (index, path) in paths.iter().enumerate() {
+            let mut tags = HashSet::new();
+            if index > 0 {
+                tags.insert("in-container".to_owned());
+            }
+            store
+                .create_resource(
+                    path,
+                    &format!("resource {}", index),
+                    &variant,
+                    tags,
+                    Cursor::new(vec![]).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let metadata = store.get_metadata_many(&paths).await.unwrap();
+        assert_eq!(metadata.len(), 3);
+        for (index, meta) in metadata.iter().enumerate() {
+            assert_eq!(meta.desc(), format!("resource {}", index));
+        }
+
+        let results = store.ls_by_tag("in-container").await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        let missing = vec![vec!["container".to_owned(), "missing".to_owned()]];
+        assert!(store.get_metadata_many(&missing).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn concurrent_block_writes() {
+    let path = ["sticker_logo_small.png".to_owned()];
+
+    let num_test = 17;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .max_concurrent_writes(4)
+            .read_buffer_size(4096)
+            .build()
+            .await
+            .unwrap();
+
+        store
+            .import_file("./tests/fixtures/sticker_logo_small.png")
+            .await
+            .unwrap();
+
+        let content = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(
+            content,
+            fixture_file("./tests/fixtures/sticker_logo_small.png").into_inner()
+        );
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+
+        let content = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(
+            content,
+            fixture_file("./tests/fixtures/sticker_logo_small.png").into_inner()
+        );
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct AppSettings {
+    theme: String,
+    zoom: u32,
+}
+
+impl Settings for AppSettings {
+    const NAME: &'static str = "test.app";
+    const VERSION: u32 = 1;
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct AppSettingsV2 {
+    theme: String,
+}
+
+impl Settings for AppSettingsV2 {
+    const NAME: &'static str = "test.app";
+    const VERSION: u32 = 2;
+}
+
+#[tokio::test]
+async fn settings() {
+    let num_test = 18;
+    let settings = AppSettings {
+        theme: "dark".into(),
+        zoom: 120,
+    };
+    {
+        let mut store = init_test(num_test).await;
+
+        assert_eq!(store.get_settings::<AppSettings>().await.unwrap(), None);
+
+        store.set_settings(&settings).await.unwrap();
+        assert_eq!(
+            store.get_settings::<AppSettings>().await.unwrap(),
+            Some(AppSettings {
+                theme: "dark".into(),
+                zoom: 120,
+            })
+        );
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+        assert_eq!(
+            store.get_settings::<AppSettings>().await.unwrap(),
+            Some(settings)
+        );
+
+        // No migration path from version 1 to version 2.
+        assert!(store.get_settings::<AppSettingsV2>().await.is_err());
+
+        // The settings file is not listed as a resource.
+        let files = store
+            .ls(store.resources_dir().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 0);
+    }
+}
+
+#[tokio::test]
+async fn image_colors() {
+    let path = ["red_square.png".to_owned()];
+
+    let num_test = 19;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/red_square.png")
+            .await
+            .unwrap();
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&("width".to_owned(), PropertyValue::Integer(8))));
+        assert!(properties.contains(&("height".to_owned(), PropertyValue::Integer(8))));
+        assert!(properties.contains(&(
+            "dominant_color".to_owned(),
+            PropertyValue::Text("#ff0000".into())
+        )));
+
+        let results = store
+            .query_properties(&[PropertyFilter::Equal(
+                "dominant_color".into(),
+                "#ff0000".into(),
+            )])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn stream_variant() {
+    let path = ["small file".to_owned()];
+    let content = b"abcdef0123456789".as_slice();
+    let variant_content = b"9876543210fedcba".as_slice();
+
+    let num_test = 20;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(16, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "small file",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        store
+            .add_variant(
+                &path,
+                "reverse",
+                &variant,
+                Cursor::new(variant_content).compat(),
+            )
+            .await
+            .unwrap();
+
+        // Repeated reads use the cached directory handles.
+        for _ in 0..2 {
+            let stream = store.get_variant("reverse", &path).await.unwrap();
+            let chunks: Vec<Vec<u8>> = stream.try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), variant_content.to_vec());
+        }
+    }
+}
+
+#[tokio::test]
+async fn mime_sniffing() {
+    let path = ["red_square_no_extension".to_owned()];
+    let other = ["mislabeled".to_owned()];
+
+    let num_test = 21;
+    {
+        let mut store = init_test(num_test).await;
+
+        // The mime type is sniffed when the extension is missing.
+        store
+            .import_file("./tests/fixtures/red_square_no_extension")
+            .await
+            .unwrap();
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert_eq!(
+            meta.get_variant("default").unwrap().mime_type(),
+            "image/png"
+        );
+        assert!(meta.has_variant("thumbnail"));
+
+        // Fix the mime type of an existing resource.
+        let content = fixture_file("./tests/fixtures/red_square.png");
+        let variant =
+            VariantMetadata::new(content.get_ref().len() as _, "application/octet-stream");
+        store
+            .create_resource(
+                &other,
+                "mislabeled",
+                &variant,
+                HashSet::new(),
+                content.compat(),
+            )
+            .await
+            .unwrap();
+        let meta = store.get_metadata(&other).await.unwrap();
+        assert!(!meta.has_variant("thumbnail"));
+
+        let mime = store.refresh_mime_type(&other).await.unwrap();
+        assert_eq!(mime, Some("image/png".to_owned()));
+        let meta = store.get_metadata(&other).await.unwrap();
+        assert_eq!(
+            meta.get_variant("default").unwrap().mime_type(),
+            "image/png"
+        );
+        assert!(meta.has_variant("thumbnail"));
+
+        // Nothing changes the second time.
+        let mime = store.refresh_mime_type(&other).await.unwrap();
+        assert_eq!(mime, None);
+    }
+}
+
+#[tokio::test]
+async fn office_documents() {
+    let path = ["report.docx".to_owned()];
+
+    let num_test = 22;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/report.docx")
+            .await
+            .unwrap();
+
+        // The document text and its core properties are indexed.
+        let results = store.search("grew steadily").await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search("Jane Doe").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&(
+            "title".to_owned(),
+            PropertyValue::Text("Quarterly Report".into())
+        )));
+        assert!(properties.contains(&("author".to_owned(), PropertyValue::Text("Jane Doe".into()))));
+
+        let results = store
+            .query_properties(&[PropertyFilter::Equal("keywords".into(), "finance".into())])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn backup_restore() {
+    let path = ["backup me".to_owned()];
+    let content = b"Some precious content".as_slice();
+    let archive = PathBuf::from("./tests/backup23.zip");
+    let restored = PathBuf::from("./tests/data23_restored");
+
+    let num_test = 23;
+    {
+        let mut store = init_test(num_test).await;
+        let _ = std::fs::remove_file(&archive);
+        let _ = std::fs::remove_dir_all(&restored);
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "precious resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        store.backup(&archive, "correct horse").await.unwrap();
+    }
+
+    {
+        // A wrong passphrase doesn't restore anything.
+        assert!(ResourceStore::restore(&archive, "wrong horse", &restored)
+            .await
+            .is_err());
+        assert!(!restored.exists());
+
+        let store = ResourceStore::restore(&archive, "correct horse", &restored)
+            .await
+            .unwrap();
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+
+        // The index is restored too.
+        let results = store.search("precious").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        // Restoring over an existing store fails.
+        assert!(ResourceStore::restore(&archive, "correct horse", &restored)
+            .await
+            .is_err());
     }
 }
+
+#[tokio::test]
+async fn variant_writer() {
+    use futures::AsyncWriteExt;
+
+    let path = ["pushed".to_owned()];
+    let content = b"Default content".as_slice();
+
+    let num_test = 24;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "pushed resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        // The size is unknown upfront, and set when closing the writer.
+        let variant = VariantMetadata::new(0, "text/plain");
+        let mut writer = store.add_variant_writer(&path, "notes", &variant);
+        for _ in 0..1000 {
+            writer.write_all(b"streamed notes ").await.unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert_eq!(meta.get_variant("notes").unwrap().size(), 15000);
+
+        let result = store.get_variant_vec("notes", &path).await.unwrap();
+        assert_eq!(result, b"streamed notes ".repeat(1000));
+
+        // The streamed variant is indexed.
+        let results = store.search("streamed notes").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        // Dropping the writer without closing it doesn't add the variant,
+        // nor change the store.
+        {
+            let mut writer = store.add_variant_writer(&path, "discarded", &variant);
+            for _ in 0..1000 {
+                writer.write_all(b"lost content ").await.unwrap();
+            }
+        }
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert!(!meta.has_variant("discarded"));
+        store.update_desc(&path, "still pushed").await.unwrap();
+        let result = store.get_variant_vec("notes", &path).await.unwrap();
+        assert_eq!(result, b"streamed notes ".repeat(1000));
+    }
+}
+
+#[tokio::test]
+async fn geo_search() {
+    let places = [
+        ("paris", 48.8566, 2.3522),
+        ("versailles", 48.8049, 2.1204),
+        ("london", 51.5074, -0.1278),
+    ];
+
+    let num_test = 25;
+    {
+        let mut store = init_test(num_test).await;
+
+        for (name, latitude, longitude) in places {
+            let content = serde_json::json!({
+                "url": format!("https://{}.example.com", name),
+                "title": name,
+                "latitude": latitude,
+                "longitude": longitude,
+            })
+            .to_string();
+            let variant = VariantMetadata::new(content.len() as _, "application/x-places+json");
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    name,
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // Closest first.
+        let results = store.search_near(48.8566, 2.3522, 20_000.0).await.unwrap();
+        let names: Vec<String> = results.iter().map(|(id, _)| id.to_string()).collect();
+        assert_eq!(names, vec!["paris", "versailles"]);
+
+        let results = store.search_near(48.8566, 2.3522, 1_000.0).await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        let results = store.search_within(50.0, -1.0, 52.0, 1.0).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0.to_string(), "london");
+
+        store.delete_resource(&["london".to_owned()]).await.unwrap();
+        let results = store.search_within(50.0, -1.0, 52.0, 1.0).await.unwrap();
+        assert_eq!(results.len(), 0);
+    }
+}
+
+#[tokio::test]
+async fn corrupted_index() {
+    let path = ["survivor".to_owned()];
+    let content = b"I will survive the corruption".as_slice();
+
+    let num_test = 26;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "survivor",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    // Simulate a corruption of the index database.
+    let root = PathBuf::from(format!("./tests/data{}", num_test));
+    std::fs::write(root.join("index.sqlite"), b"not a database at all").unwrap();
+    let _ = std::fs::remove_file(root.join("index.sqlite-wal"));
+    let _ = std::fs::remove_file(root.join("index.sqlite-shm"));
+
+    {
+        // The index is restored from the snapshot.
+        let store = get_test_store(num_test).await;
+        let results = store.search("survive").await.unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn tag_rules() {
+    let invoice = ["invoice.txt".to_owned()];
+    let note = ["note.txt".to_owned()];
+
+    let num_test = 27;
+    {
+        let mut store = init_test(num_test).await;
+
+        let rules = TagRules {
+            rules: vec![
+                TagRule::new("text").with_mime_type("text/*"),
+                TagRule::new("invoice")
+                    .with_mime_type("text/plain")
+                    .with_text("invoice"),
+            ],
+        };
+        store.set_settings(&rules).await.unwrap();
+
+        for (path, content) in [
+            (&invoice, "Invoice #42, due next month".as_bytes()),
+            (&note, "Remember the milk".as_bytes()),
+        ] {
+            let variant = VariantMetadata::new(content.len() as _, "text/plain");
+            store
+                .create_resource(
+                    path,
+                    &path[0],
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let meta = store.get_metadata(&invoice).await.unwrap();
+        assert!(meta.tags().contains("text"));
+        assert!(meta.tags().contains("invoice"));
+        let meta = store.get_metadata(&note).await.unwrap();
+        assert!(meta.tags().contains("text"));
+        assert!(!meta.tags().contains("invoice"));
+
+        // New rules are applied to existing resources.
+        let mut rules = rules;
+        rules
+            .rules
+            .push(TagRule::new("groceries").with_text("milk"));
+        store.set_settings(&rules).await.unwrap();
+        assert_eq!(store.apply_tag_rules().await.unwrap(), 1);
+        assert_eq!(store.apply_tag_rules().await.unwrap(), 0);
+
+        let results = store.ls_by_tag("groceries").await.unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+struct DirectoryFetcher(PathBuf);
+
+#[async_trait::async_trait(?Send)]
+impl BlockFetcher for DirectoryFetcher {
+    async fn fetch_block(&self, cid: &Cid) -> Result<Option<Bytes>, std::io::Error> {
+        match std::fs::read(self.0.join(cid.to_string())) {
+            Ok(bytes) => Ok(Some(bytes.into())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[tokio::test]
+async fn block_fetcher() {
+    let path = ["remote".to_owned()];
+    let content = b"Content only available remotely".as_slice();
+    let source = PathBuf::from("./tests/data28");
+    let lazy = PathBuf::from("./tests/data28_lazy");
+
+    let num_test = 28;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "remote",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    {
+        // A second copy of the store, without any block.
+        let _ = std::fs::remove_dir_all(&lazy);
+        std::fs::create_dir(&lazy).unwrap();
+        for file in ["forest.cid", "access.key", "index.sqlite"] {
+            std::fs::copy(source.join(file), lazy.join(file)).unwrap();
+        }
+
+        let store = ResourceStore::builder(&lazy)
+            .block_fetcher(DirectoryFetcher(source.join("blockstore")))
+            .build()
+            .await
+            .unwrap();
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+
+        // Fetched blocks are kept locally.
+        assert!(std::fs::read_dir(lazy.join("blockstore")).unwrap().count() > 0);
+    }
+}
+
+#[cfg(feature = "bitswap")]
+#[tokio::test]
+async fn bitswap_fetcher() {
+    use docstore::bitswap::{BitswapFetcher, Keypair, Multiaddr};
+
+    let path = ["remote".to_owned()];
+    let content = b"Content fetched from a peer".as_slice();
+    let source = PathBuf::from("./tests/data104");
+    let lazy = PathBuf::from("./tests/data104_lazy");
+
+    let num_test = 104;
+    let mut store = init_test(num_test).await;
+    let variant = VariantMetadata::new(content.len() as _, "text/plain");
+    store
+        .create_resource(
+            &path,
+            "remote",
+            &variant,
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+
+    // The first device serves its blocks.
+    let source_addr: Multiaddr = "/ip4/127.0.0.1/tcp/47104".parse().unwrap();
+    let server = BitswapFetcher::start(
+        Keypair::generate_ed25519(),
+        &source,
+        source_addr.clone(),
+        vec![],
+    )
+    .unwrap();
+
+    // A second copy of the store, without any block, fetches them from it.
+    let _ = std::fs::remove_dir_all(&lazy);
+    std::fs::create_dir(&lazy).unwrap();
+    for file in ["forest.cid", "access.key", "index.sqlite"] {
+        std::fs::copy(source.join(file), lazy.join(file)).unwrap();
+    }
+    let fetcher = BitswapFetcher::start(
+        Keypair::generate_ed25519(),
+        &lazy,
+        "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+        vec![(server.peer_id(), source_addr)],
+    )
+    .unwrap();
+    let lazy_store = ResourceStore::builder(&lazy)
+        .block_fetcher(fetcher)
+        .build()
+        .await
+        .unwrap();
+    let result = lazy_store.get_variant_vec("default", &path).await.unwrap();
+    assert_eq!(result, content.to_vec());
+    assert!(std::fs::read_dir(lazy.join("blockstore")).unwrap().count() > 0);
+}
+
+#[tokio::test]
+async fn search_variants() {
+    let path = ["scan".to_owned()];
+    let content = b"alpha beta".as_slice();
+    let ocr = b"gamma alpha".as_slice();
+
+    let num_test = 29;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "scanned page",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let variant = VariantMetadata::new(ocr.len() as _, "text/plain");
+        store
+            .add_variant(&path, "ocr", &variant, Cursor::new(ocr).compat())
+            .await
+            .unwrap();
+
+        let results = store.search_variants("alpha", &[]).await.unwrap();
+        assert_eq!(results.len(), 1);
+        let mut variants = results[0].2.clone();
+        variants.sort();
+        assert_eq!(variants, vec!["default", "ocr"]);
+
+        let results = store.search_variants("alpha", &["ocr"]).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].2, vec!["ocr"]);
+
+        let results = store.search_variants("beta", &["ocr"]).await.unwrap();
+        assert_eq!(results.len(), 0);
+    }
+}
+
+#[tokio::test]
+async fn bookmarks() {
+    let container = ["bookmarks".to_owned()];
+
+    let num_test = 30;
+    {
+        let mut store = init_test(num_test).await;
+
+        let html = std::fs::read_to_string("./tests/fixtures/bookmarks.html").unwrap();
+        let created = store.import_bookmarks(&html, &container).await.unwrap();
+        assert_eq!(created.len(), 3);
+
+        // Importing again doesn't create duplicates.
+        let created = store.import_bookmarks(&html, &container).await.unwrap();
+        assert_eq!(created.len(), 0);
+
+        let results = store.ls_by_tag("Tech").await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.desc(), "Hacker News & friends");
+        let results = store.ls_by_tag("News").await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        let results = store.search("lwn.net").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        let exported = store.export_bookmarks().await.unwrap();
+        assert!(exported.contains(r#"<A HREF="https://www.rust-lang.org/">"#));
+        assert!(exported.contains(r#"HREF="https://news.ycombinator.com/?p=1&amp;q=2""#));
+
+        let opml = r#"<?xml version="1.0"?>
+<opml version="2.0">
+  <body>
+    <outline text="Blogs">
+      <outline text="Fabrice" htmlUrl="https://example.com/blog" xmlUrl="https://example.com/feed"/>
+    </outline>
+  </body>
+</opml>"#;
+        let created = store.import_bookmarks(opml, &container).await.unwrap();
+        assert_eq!(created.len(), 1);
+        let results = store.ls_by_tag("Blogs").await.unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+struct FakeDecoder;
+
+impl ImageDecoder for FakeDecoder {
+    fn supports(&self, mime_type: &str) -> bool {
+        mime_type == "image/x-fake"
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<DynamicImage, String> {
+        if content != b"FAKE IMAGE" {
+            return Err("Not a fake image".into());
+        }
+        Ok(DynamicImage::new_rgb8(40, 20))
+    }
+}
+
+#[tokio::test]
+async fn image_decoders() {
+    let path = ["fake image".to_owned()];
+    let content = b"FAKE IMAGE".as_slice();
+
+    register_image_decoder(Box::new(FakeDecoder));
+
+    let num_test = 31;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "image/x-fake");
+        store
+            .create_resource(
+                &path,
+                "fake image",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert!(meta.has_variant("thumbnail"));
+        assert!(meta.has_variant("blurhash"));
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&("width".to_owned(), PropertyValue::Integer(40))));
+    }
+}
+
+#[tokio::test]
+async fn migrate() {
+    let path = ["migrated".to_owned()];
+    let content = b"Moving to a new home".as_slice();
+    let dest = PathBuf::from("./tests/data32_migrated");
+
+    let num_test = 32;
+    {
+        let mut store = init_test(num_test).await;
+        let _ = std::fs::remove_dir_all(&dest);
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "migrated resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        store.add_tag(&path, "moving").await.unwrap();
+
+        let store = store.migrate(&dest, true).await.unwrap();
+        assert!(!PathBuf::from(format!("./tests/data{}", num_test)).exists());
+
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+        let results = store.ls_by_tag("moving").await.unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn epub_books() {
+    let path = ["book.epub".to_owned()];
+
+    let num_test = 33;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/book.epub")
+            .await
+            .unwrap();
+
+        // The chapters text and the book metadata are indexed.
+        let results = store.search("spiral staircase").await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search("Marlow").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&(
+            "title".to_owned(),
+            PropertyValue::Text("The Lighthouse Keeper".into())
+        )));
+        assert!(properties.contains(&(
+            "author".to_owned(),
+            PropertyValue::Text("Ada Marlow".into())
+        )));
+
+        // The cover image is extracted as a variant.
+        let metadata = store.get_metadata(&path).await.unwrap();
+        let cover = metadata.get_variant("cover").unwrap();
+        assert_eq!(cover.mime_type(), "image/png");
+        let expected = fixture_file("./tests/fixtures/red_square.png").into_inner();
+        let result = store.get_variant_vec("cover", &path).await.unwrap();
+        assert_eq!(result, expected);
+    }
+}
+
+#[tokio::test]
+async fn tokio_content() {
+    let path = ["tokio.txt".to_owned()];
+
+    let num_test = 34;
+    {
+        let mut store = init_test(num_test).await;
+
+        let file = tokio::fs::File::open("./tests/fixtures/hello.txt")
+            .await
+            .unwrap();
+        let size = file.metadata().await.unwrap().len();
+        let variant = VariantMetadata::new(size, "text/plain");
+        store
+            .create_resource_tokio(&path, "tokio file", &variant, HashSet::new(), file)
+            .await
+            .unwrap();
+
+        let content = b"Read with tokio".as_slice();
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .add_variant_tokio(&path, "copy", &variant, Cursor::new(content))
+            .await
+            .unwrap();
+
+        let expected = fixture_file("./tests/fixtures/hello.txt").into_inner();
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, expected);
+        let result = store.get_variant_vec("copy", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+    }
+}
+
+#[tokio::test]
+async fn share_tokens() {
+    let path = ["shared.txt".to_owned()];
+    let content = b"Shared for a week".as_slice();
+
+    let num_test = 35;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "shared resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        // Sharing an unknown resource fails.
+        assert!(store
+            .share(&["unknown".to_owned()], Duration::days(7))
+            .await
+            .is_err());
+
+        let token = store.share(&path, Duration::days(7)).await.unwrap();
+        let expired = store.share(&path, Duration::seconds(-1)).await.unwrap();
+        assert_eq!(store.check_share(&token.id).await.unwrap().path, path);
+        assert!(store.check_share(&expired.id).await.is_err());
+        assert_eq!(store.shares().await.unwrap(), vec![token.clone()]);
+
+        assert!(store.revoke_share(&token.id).await.unwrap());
+        assert!(store.check_share(&token.id).await.is_err());
+        assert!(!store.revoke_share(&token.id).await.unwrap());
+    }
+}
+
+#[tokio::test]
+async fn metrics() {
+    let num_test = 36;
+    {
+        let store = init_test(num_test).await;
+
+        store.search("nothing").await.unwrap();
+
+        // Metrics are process wide, so other tests also update them.
+        let metrics = docstore::metrics::render();
+        assert!(metrics.contains("docstore_operations_total{operation=\"search\"}"));
+        assert!(metrics.contains("# TYPE docstore_index_query_seconds histogram"));
+        assert!(metrics.contains("docstore_block_reads_total{source=\"disk\"}"));
+    }
+}
+
+#[tokio::test]
+async fn reencrypt_all() {
+    let path = ["secret.txt".to_owned()];
+    let content = b"Keep this private".as_slice();
+    let variant_content = b"A private variant".as_slice();
+
+    let num_test = 37;
+    let root_dir = PathBuf::from(format!("./tests/data{}", num_test));
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "secret resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let variant = VariantMetadata::new(variant_content.len() as _, "text/plain");
+        store
+            .add_variant(
+                &path,
+                "copy",
+                &variant,
+                Cursor::new(variant_content).compat(),
+            )
+            .await
+            .unwrap();
+        store.add_tag(&path, "secret").await.unwrap();
+
+        let access_key = std::fs::read(root_dir.join("access.key")).unwrap();
+        let mut progress = vec![];
+        store
+            .reencrypt_all(|p| progress.push((p.done, p.total)))
+            .await
+            .unwrap();
+        assert!(!progress.is_empty());
+        assert!(progress.iter().all(|(done, total)| done <= total));
+        assert_ne!(
+            std::fs::read(root_dir.join("access.key")).unwrap(),
+            access_key
+        );
+        assert!(!root_dir.join("reencrypt.journal").exists());
+
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+        let result = store.get_variant_vec("copy", &path).await.unwrap();
+        assert_eq!(result, variant_content.to_vec());
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+
+        let metadata = store.get_metadata(&path).await.unwrap();
+        assert!(metadata.tags().contains("secret"));
+        let result = store.get_variant_vec("copy", &path).await.unwrap();
+        assert_eq!(result, variant_content.to_vec());
+        let results = store.search("secret").await.unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn suggestions() {
+    let num_test = 38;
+    {
+        let mut store = init_test(num_test).await;
+
+        for (name, desc, text) in [
+            ("garden.txt", "Garden plans", "Tomato, potatoes, tomatoes"),
+            ("recipes.txt", "Grandma recipes", "Tomato soup"),
+        ] {
+            let content = text.as_bytes();
+            let variant = VariantMetadata::new(content.len() as _, "text/plain");
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    desc,
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+        store
+            .add_tag(&["garden.txt".to_owned()], "gardening")
+            .await
+            .unwrap();
+
+        // Words used by more resources come first.
+        let results = store.suggest("tom", 10).unwrap();
+        assert_eq!(results, vec!["tomato", "tomatoes"]);
+        let results = store.suggest("pot", 10).unwrap();
+        assert_eq!(results, vec!["potatoes"]);
+        let results = store.suggest("Tom", 1).unwrap();
+        assert_eq!(results, vec!["tomato"]);
+
+        // Tags and full descriptions are suggested too.
+        let results = store.suggest("gar", 10).unwrap();
+        assert_eq!(results, vec!["garden", "garden plans", "gardening"]);
+
+        store
+            .delete_resource(&["garden.txt".to_owned()])
+            .await
+            .unwrap();
+        let results = store.suggest("gar", 10).unwrap();
+        assert!(results.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn change_journal() {
+    let path = ["notes.txt".to_owned()];
+    let content = b"Some notes".as_slice();
+
+    let num_test = 39;
+    {
+        let mut store = init_test(num_test).await;
+        assert!(store.changes_since(0).await.unwrap().is_empty());
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "notes",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        store.add_tag(&path, "work").await.unwrap();
+        store
+            .add_variant(&path, "copy", &variant, Cursor::new(content).compat())
+            .await
+            .unwrap();
+        store.remove_tag(&path, "work").await.unwrap();
+
+        let changes = store.changes_since(0).await.unwrap();
+        let ops: Vec<ChangeOp> = changes.iter().map(|change| change.op.clone()).collect();
+        assert_eq!(
+            ops,
+            vec![
+                ChangeOp::CreateResource,
+                ChangeOp::AddTag("work".to_owned()),
+                ChangeOp::AddVariant("copy".to_owned()),
+                ChangeOp::RemoveTag("work".to_owned()),
+            ]
+        );
+        let revisions: Vec<u64> = changes.iter().map(|change| change.revision).collect();
+        assert_eq!(revisions, vec![1, 2, 3, 4]);
+        assert!(changes.iter().all(|change| change.path == path));
+    }
+
+    {
+        // The journal survives a restart.
+        let mut store = get_test_store(num_test).await;
+        let changes = store.changes_since(2).await.unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].revision, 3);
+
+        store.delete_resource(&path).await.unwrap();
+        let changes = store.changes_since(4).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].revision, 5);
+        assert_eq!(changes[0].op, ChangeOp::DeleteResource);
+        assert!(store.changes_since(5).await.unwrap().is_empty());
+    }
+}
+
+// Encodes a PNG horizontal gradient, getting darker from left to right when
+// `reversed` is true.
+fn gradient_png(size: u32, reversed: bool) -> Vec<u8> {
+    let img = image::RgbImage::from_fn(size, size, |x, _| {
+        let value = (x * 255 / (size - 1)) as u8;
+        let value = if reversed { 255 - value } else { value };
+        image::Rgb([value, value / 2, 255 - value / 2])
+    });
+    let mut buffer = Cursor::new(vec![]);
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut buffer, image::ImageOutputFormat::Png)
+        .unwrap();
+    buffer.into_inner()
+}
+
+#[tokio::test]
+async fn similar_images() {
+    let num_test = 40;
+    {
+        let mut store = init_test(num_test).await;
+
+        for (name, content) in [
+            ("original.png", gradient_png(64, false)),
+            ("resized.png", gradient_png(40, false)),
+            ("reversed.png", gradient_png(64, true)),
+        ] {
+            let variant = VariantMetadata::new(content.len() as _, "image/png");
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    name,
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let results = store
+            .find_similar_images(&["original.png".to_owned()], 5)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0.to_string(), "resized.png");
+
+        let results = store
+            .find_similar_images(&["original.png".to_owned()], 64)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].0.to_string(), "reversed.png");
+
+        // Resources without an image hash have no similar images.
+        let content = b"Not an image".as_slice();
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &["text.txt".to_owned()],
+                "text",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let results = store
+            .find_similar_images(&["text.txt".to_owned()], 64)
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn selective_sync() {
+    use docstore::sync::SyncFilter;
+
+    let notes = vec!["notes".to_owned()];
+    let photos = vec!["photos".to_owned()];
+
+    let num_test = 41;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(0, "application/octet-stream");
+        for path in [
+            vec!["notes".to_owned(), "todo".to_owned()],
+            vec!["photos".to_owned(), "beach".to_owned()],
+            vec!["photos".to_owned(), "mountain".to_owned()],
+        ] {
+            store
+                .create_resource(
+                    &path,
+                    "resource",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(vec![]).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        store.set_container_synced(&photos, false).await.unwrap();
+        assert!(store.sync_filter().is_synced(&notes));
+        assert!(!store.sync_filter().is_synced(&photos));
+
+        let filter = store.sync_filter().clone();
+        let changes = store.changes_since_filtered(0, &filter).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, vec!["notes".to_owned(), "todo".to_owned()]);
+        // All the changes are still recorded.
+        assert_eq!(store.changes_since(0).await.unwrap().len(), 3);
+        // The filter of the pulling device applies, not the one of the
+        // store pulled from.
+        let everything = SyncFilter::default();
+        let changes = store.changes_since_filtered(0, &everything).await.unwrap();
+        assert_eq!(changes.len(), 3);
+    }
+
+    {
+        // The filter is kept across restarts.
+        let mut store = get_test_store(num_test).await;
+        assert!(!store.sync_filter().is_synced(&photos));
+
+        store.set_container_synced(&photos, true).await.unwrap();
+        let filter = store.sync_filter().clone();
+        assert_eq!(
+            store
+                .changes_since_filtered(0, &filter)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+}
+
+#[cfg(feature = "svg")]
+#[tokio::test]
+async fn svg_thumbnail() {
+    let path = ["logo.svg".to_owned()];
+    let content = br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10">
+        <rect width="20" height="10" fill="blue"/>
+    </svg>"#
+        .as_slice();
+
+    let num_test = 42;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "image/svg+xml");
+        store
+            .create_resource(
+                &path,
+                "logo",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert!(meta.has_variant("thumbnail"));
+
+        // The image is rasterized to fit in 512x512 pixels.
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&("width".to_owned(), PropertyValue::Integer(512))));
+        assert!(properties.contains(&("height".to_owned(), PropertyValue::Integer(256))));
+    }
+}
+
+#[tokio::test]
+async fn search_hits() {
+    let num_test = 43;
+    {
+        let mut store = init_test(num_test).await;
+
+        for (name, tags) in [
+            ("tagged.txt", vec!["work", "draft"]),
+            ("untagged.txt", vec![]),
+        ] {
+            let content = b"Meeting notes".as_slice();
+            let variant = VariantMetadata::new(content.len() as _, "text/plain");
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    name,
+                    &variant,
+                    tags.into_iter().map(|tag| tag.to_owned()).collect(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let hits = store.search_hits("meeting").unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].id.to_string(), "tagged.txt");
+        assert_eq!(hits[0].tags, vec!["draft", "work"]);
+        assert_eq!(hits[1].id.to_string(), "untagged.txt");
+        assert!(hits[1].tags.is_empty());
+
+        assert!(store.search_hits("nothing").unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn validators() {
+    let path = ["notes.txt".to_owned()];
+    let content = b"Some short notes".as_slice();
+
+    let num_test = 44;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .validator(DeniedMimeTypes::new(&[
+                "application/x-executable",
+                "video/*",
+            ]))
+            .validator(SizeLimit::new(20))
+            .build()
+            .await
+            .unwrap();
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "notes",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        // The content was rewound after validation.
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+
+        // The declared size is not trusted.
+        let large = b"Some notes that are too long".as_slice();
+        let result = store
+            .update_variant(&path, "default", &variant, Cursor::new(large).compat())
+            .await;
+        assert!(matches!(result, Err(StoreError::Rejected(_))));
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+
+        let variant = VariantMetadata::new(content.len() as _, "video/mp4");
+        let result = store
+            .add_variant(&path, "video", &variant, Cursor::new(content).compat())
+            .await;
+        assert!(matches!(result, Err(StoreError::Rejected(_))));
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert!(!meta.has_variant("video"));
+
+        let result = store
+            .create_resource(
+                &["tool".to_owned()],
+                "tool",
+                &VariantMetadata::new(content.len() as _, "application/x-executable"),
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await;
+        assert!(matches!(result, Err(StoreError::Rejected(_))));
+        let results = store.search("short").await.unwrap();
+        assert_eq!(results.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn profiles() {
+    let path = ["notes.txt".to_owned()];
+    let content = b"Profile notes".as_slice();
+
+    let num_test = 45;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        assert!(ResourceStore::list_profiles(&root_dir)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            ResourceStore::open_profile(&root_dir, "work").await,
+            Err(StoreError::NoSuchProfile(_))
+        ));
+        assert!(matches!(
+            ResourceStore::create_profile(&root_dir, "../work").await,
+            Err(StoreError::InvalidProfileName(_))
+        ));
+
+        let mut work = ResourceStore::create_profile(&root_dir, "work")
+            .await
+            .unwrap();
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        work.create_resource(
+            &path,
+            "work notes",
+            &variant,
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+        drop(work);
+
+        let personal = ResourceStore::create_profile(&root_dir, "personal")
+            .await
+            .unwrap();
+        assert!(personal.get_metadata(&path).await.is_err());
+        assert!(personal.search("notes").await.unwrap().is_empty());
+        drop(personal);
+
+        assert!(matches!(
+            ResourceStore::create_profile(&root_dir, "work").await,
+            Err(StoreError::ProfileExists(_))
+        ));
+        assert_eq!(
+            ResourceStore::list_profiles(&root_dir).await.unwrap(),
+            vec!["personal", "work"]
+        );
+    }
+
+    {
+        let work = ResourceStore::open_profile(&root_dir, "work")
+            .await
+            .unwrap();
+        let result = work.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+        drop(work);
+
+        let blocks = || {
+            std::fs::read_dir(format!("{}/blockstore", root_dir))
+                .unwrap()
+                .count()
+        };
+        let count = blocks();
+        ResourceStore::delete_profile(&root_dir, "work")
+            .await
+            .unwrap();
+        assert!(blocks() < count);
+        assert_eq!(
+            ResourceStore::list_profiles(&root_dir).await.unwrap(),
+            vec!["personal"]
+        );
+
+        // The remaining profile is still usable.
+        let personal = ResourceStore::open_profile(&root_dir, "personal")
+            .await
+            .unwrap();
+        assert!(personal.get_metadata(&path).await.is_err());
+    }
+}
+
+#[cfg(feature = "age")]
+#[tokio::test]
+async fn access_key_recovery() {
+    use age::secrecy::ExposeSecret;
+
+    let path = ["secret.txt".to_owned()];
+    let content = b"Recover me".as_slice();
+    let identity = age::x25519::Identity::generate();
+    let other = age::x25519::Identity::generate();
+
+    let num_test = 46;
+    let root_dir = PathBuf::from(format!("./tests/data{}", num_test));
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "secret",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        store
+            .set_recovery_recipients(&[
+                identity.to_public().to_string(),
+                other.to_public().to_string(),
+            ])
+            .await
+            .unwrap();
+    }
+
+    std::fs::remove_file(root_dir.join("access.key")).unwrap();
+
+    let unknown = age::x25519::Identity::generate();
+    assert!(
+        ResourceStore::recover_access_key(&root_dir, unknown.to_string().expose_secret())
+            .await
+            .is_err()
+    );
+    ResourceStore::recover_access_key(&root_dir, identity.to_string().expose_secret())
+        .await
+        .unwrap();
+
+    {
+        let store = get_test_store(num_test).await;
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+    }
+}
+
+#[tokio::test]
+async fn lazy_thumbnails() {
+    let logo = ["sticker_logo_small.png".to_owned()];
+    let square = ["red_square.png".to_owned()];
+
+    let num_test = 47;
+    {
+        let mut store = init_test(num_test).await;
+        store
+            .set_settings(&ThumbnailSettings {
+                lazy: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        for file in ["sticker_logo_small.png", "red_square.png"] {
+            store
+                .import_file(format!("./tests/fixtures/{}", file))
+                .await
+                .unwrap();
+        }
+        let meta = store.get_metadata(&logo).await.unwrap();
+        assert!(!meta.has_variant("thumbnail"));
+        assert!(meta.has_variant("blurhash"));
+
+        // The thumbnail is created on the first request, and persisted.
+        let thumbnail: Vec<Vec<u8>> = store
+            .get_thumbnail(&logo)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(!thumbnail.concat().is_empty());
+        let meta = store.get_metadata(&logo).await.unwrap();
+        assert!(meta.has_variant("thumbnail"));
+
+        // Updating the image removes the outdated thumbnail.
+        let content = std::fs::read("./tests/fixtures/red_square.png").unwrap();
+        let variant = VariantMetadata::new(content.len() as _, "image/png");
+        store
+            .update_variant(&logo, "default", &variant, Cursor::new(content).compat())
+            .await
+            .unwrap();
+        let meta = store.get_metadata(&logo).await.unwrap();
+        assert!(!meta.has_variant("thumbnail"));
+
+        assert_eq!(store.backfill_thumbnails(1).await.unwrap(), 1);
+        assert_eq!(store.backfill_thumbnails(10).await.unwrap(), 1);
+        assert_eq!(store.backfill_thumbnails(10).await.unwrap(), 0);
+        for path in [&logo, &square] {
+            let meta = store.get_metadata(path).await.unwrap();
+            assert!(meta.has_variant("thumbnail"));
+        }
+    }
+}
+
+#[tokio::test]
+async fn search_in_container() {
+    let num_test = 48;
+    {
+        let mut store = init_test(num_test).await;
+
+        let content = b"Budget review".as_slice();
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        for path in [
+            vec!["budget.txt"],
+            vec!["projects", "alpha", "budget.txt"],
+            vec!["projects", "alpha", "q1", "budget.txt"],
+            vec!["projects", "alphabet", "budget.txt"],
+            vec!["projects", "beta", "budget.txt"],
+        ] {
+            let path: Vec<String> = path.into_iter().map(|s| s.to_owned()).collect();
+            store
+                .create_resource(
+                    &path,
+                    "budget",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let alpha = ["projects".to_owned(), "alpha".to_owned()];
+        let mut results: Vec<String> = store
+            .search_in("review", &alpha)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id.to_string())
+            .collect();
+        results.sort();
+        assert_eq!(
+            results,
+            vec!["projects/alpha/budget.txt", "projects/alpha/q1/budget.txt"]
+        );
+
+        let results = store
+            .search_in("review", &["projects".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        let results = store.search_in("review", &[]).await.unwrap();
+        assert_eq!(results.len(), 5);
+        let results = store.search_in("missing", &alpha).await.unwrap();
+        assert!(results.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn gpx_tracks() {
+    let path = ["track.gpx".to_owned()];
+
+    let num_test = 49;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/track.gpx")
+            .await
+            .unwrap();
+
+        // Track and waypoint names and descriptions are indexed, but not
+        // the generated track point names.
+        let results = store.search("Aiguille").await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search("summit").await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search("TP002").await.unwrap();
+        assert_eq!(results.len(), 0);
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&(
+            "title".to_owned(),
+            PropertyValue::Text("Aiguille du Midi loop".into())
+        )));
+        assert!(properties.contains(&("min_latitude".to_owned(), PropertyValue::Real(45.8326))));
+        assert!(properties.contains(&("max_longitude".to_owned(), PropertyValue::Real(6.9010))));
+
+        // A map area crossed by the track, without containing it.
+        let results = store.search_within(45.85, 6.88, 45.90, 6.95).await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search_within(46.0, 7.0, 46.1, 7.1).await.unwrap();
+        assert_eq!(results.len(), 0);
+
+        // Close to the track bounding box, but far from its first point.
+        let results = store.search_near(45.85, 6.86, 1_000.0).await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        store.delete_resource(&path).await.unwrap();
+        let results = store.search_within(45.85, 6.88, 45.90, 6.95).await.unwrap();
+        assert_eq!(results.len(), 0);
+    }
+}
+
+#[tokio::test]
+async fn read_ahead_streaming() {
+    let path = ["large.bin".to_owned()];
+    // Spans several blocks.
+    let content: Vec<u8> = (0..1_500_000u32).map(|i| (i % 251) as u8).collect();
+    let variant_content: Vec<u8> = content.iter().rev().cloned().collect();
+
+    let num_test = 50;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .read_ahead(3)
+            .build()
+            .await
+            .unwrap();
+
+        let variant = VariantMetadata::new(content.len() as _, "application/octet-stream");
+        store
+            .create_resource(
+                &path,
+                "large file",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content.clone()).compat(),
+            )
+            .await
+            .unwrap();
+        store
+            .add_variant(
+                &path,
+                "reverse",
+                &variant,
+                Cursor::new(variant_content.clone()).compat(),
+            )
+            .await
+            .unwrap();
+
+        let chunks: Vec<Vec<u8>> = store
+            .get_variant("default", &path)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), content);
+
+        let chunks: Vec<Vec<u8>> = store
+            .get_variant("reverse", &path)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), variant_content);
+    }
+
+    {
+        // Without read ahead.
+        let store = ResourceStore::builder(&root_dir)
+            .read_ahead(0)
+            .build()
+            .await
+            .unwrap();
+
+        let chunks: Vec<Vec<u8>> = store
+            .get_variant("default", &path)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), content);
+    }
+}
+
+#[tokio::test]
+async fn variant_hashes() {
+    use futures::AsyncWriteExt;
+
+    let first = ["first".to_owned()];
+    let second = ["second".to_owned()];
+    let content = b"Some content worth hashing".as_slice();
+
+    let num_test = 51;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        for path in [&first, &second] {
+            store
+                .create_resource(
+                    path,
+                    "hashed",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // The same content has the same hash.
+        let meta = store.get_metadata(&first).await.unwrap();
+        let hash = meta
+            .get_variant("default")
+            .unwrap()
+            .hash()
+            .unwrap()
+            .to_owned();
+        assert_eq!(hash.len(), 64);
+        let meta = store.get_metadata(&second).await.unwrap();
+        assert_eq!(
+            meta.get_variant("default").unwrap().hash(),
+            Some(hash.as_str())
+        );
+        assert!(store.verify_variant(&first, "default").await.unwrap());
+
+        // Updating the content updates the hash.
+        let updated = b"Other content".as_slice();
+        let variant = VariantMetadata::new(updated.len() as _, "text/plain");
+        store
+            .update_variant(&second, "default", &variant, Cursor::new(updated).compat())
+            .await
+            .unwrap();
+        let meta = store.get_metadata(&second).await.unwrap();
+        assert_ne!(
+            meta.get_variant("default").unwrap().hash(),
+            Some(hash.as_str())
+        );
+        assert!(store.verify_variant(&second, "default").await.unwrap());
+
+        // Added and streamed variants are hashed as well.
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .add_variant(&first, "copy", &variant, Cursor::new(content).compat())
+            .await
+            .unwrap();
+        let mut writer = store.add_variant_writer(&first, "pushed", &variant);
+        writer.write_all(content).await.unwrap();
+        writer.close().await.unwrap();
+
+        let meta = store.get_metadata(&first).await.unwrap();
+        assert_eq!(
+            meta.get_variant("copy").unwrap().hash(),
+            Some(hash.as_str())
+        );
+        assert_eq!(
+            meta.get_variant("pushed").unwrap().hash(),
+            Some(hash.as_str())
+        );
+        assert!(store.verify_variant(&first, "pushed").await.unwrap());
+
+        assert!(matches!(
+            store.verify_variant(&first, "missing").await,
+            Err(StoreError::NoSuchVariant(_, _))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn config_files() {
+    let yaml = "services:\n  web:\n    image: nginx\n    ports: [80]\n---\nreplicas: 3\nowner: platformteam\n";
+    let toml = "[package]\nname = \"lighthouse\"\n\n[dependencies]\nserde = \"1.0\"\n";
+
+    let num_test = 52;
+    {
+        let mut store = init_test(num_test).await;
+
+        for (name, mime, content) in [
+            ("compose.yaml", "application/yaml", yaml),
+            ("Cargo.toml", "application/toml", toml),
+        ] {
+            let variant = VariantMetadata::new(content.len() as _, mime);
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    "config snippet",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+        store.import_file("./tests/fixtures/app.env").await.unwrap();
+
+        // Keys and string values are indexed, in all the YAML documents.
+        for (text, expected) in [
+            ("nginx", "compose.yaml"),
+            ("services", "compose.yaml"),
+            ("platformteam", "compose.yaml"),
+            ("lighthouse", "Cargo.toml"),
+            ("dependencies", "Cargo.toml"),
+            ("DATABASE_URL", "app.env"),
+            ("verbose", "app.env"),
+        ] {
+            let results = store.search(text).await.unwrap();
+            assert_eq!(results.len(), 1, "{}", text);
+            assert_eq!(results[0].0.to_string(), expected);
+        }
+
+        let meta = store.get_metadata(&["app.env".to_owned()]).await.unwrap();
+        assert_eq!(
+            meta.get_variant("default").unwrap().mime_type(),
+            "text/x-dotenv"
+        );
+    }
+}
+
+#[tokio::test]
+async fn create_from_reader() {
+    let content = b"Piped from a shell".as_slice();
+
+    let num_test = 53;
+    {
+        let mut store = init_test(num_test).await;
+
+        // The mime type is guessed from the name.
+        store
+            .create_resource_from_reader("notes.txt", None, None, content)
+            .await
+            .unwrap();
+        let path = ["notes.txt".to_owned()];
+        let meta = store.get_metadata(&path).await.unwrap();
+        let variant = meta.get_variant("default").unwrap();
+        assert_eq!(variant.mime_type(), "text/plain");
+        assert_eq!(variant.size(), content.len() as u64);
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+
+        store
+            .create_resource_from_reader(
+                "piped",
+                Some("text/plain"),
+                Some(content.len() as _),
+                content,
+            )
+            .await
+            .unwrap();
+        let results = store.search("shell").await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        // Truncated content is rejected.
+        assert!(store
+            .create_resource_from_reader("truncated", Some("text/plain"), Some(100), content)
+            .await
+            .is_err());
+        assert!(store.get_metadata(&["truncated".to_owned()]).await.is_err());
+
+        // The spooled content is removed.
+        let spooled = std::fs::read_dir(format!("./tests/data{}/downloads", num_test))
+            .unwrap()
+            .count();
+        assert_eq!(spooled, 0);
+    }
+}
+
+#[tokio::test]
+async fn search_ordering() {
+    use docstore::resource::{SearchOrder, SortKey};
+
+    let files = [
+        ("a.txt", "alpha alpha alpha"),
+        ("b.txt", "alpha beta gamma delta epsilon"),
+        ("c.txt", "an alpha"),
+    ];
+
+    let num_test = 54;
+    {
+        let mut store = init_test(num_test).await;
+
+        for (name, content) in files {
+            let variant = VariantMetadata::new(content.len() as _, "text/plain");
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    "file",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+        store
+            .get_variant_vec("default", &["b.txt".to_owned()])
+            .await
+            .unwrap();
+
+        for (key, descending, expected) in [
+            (SortKey::Relevance, true, ["a.txt", "b.txt", "c.txt"]),
+            (SortKey::Size, false, ["c.txt", "a.txt", "b.txt"]),
+            (SortKey::Size, true, ["b.txt", "a.txt", "c.txt"]),
+            (SortKey::Modified, true, ["c.txt", "b.txt", "a.txt"]),
+            (SortKey::Frecency, true, ["b.txt", "a.txt", "c.txt"]),
+        ] {
+            let results = store
+                .search_ordered("alpha", SearchOrder { key, descending })
+                .await
+                .unwrap();
+            let names: Vec<String> = results.iter().map(|(id, _)| id.to_string()).collect();
+            assert_eq!(names, expected, "{:?} {}", key, descending);
+        }
+    }
+}
+
+#[tokio::test]
+async fn batched_block_writes() {
+    let path = ["sticker_logo_small.png".to_owned()];
+
+    let num_test = 55;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .write_batch_size(16)
+            .build()
+            .await
+            .unwrap();
+
+        store
+            .import_file("./tests/fixtures/sticker_logo_small.png")
+            .await
+            .unwrap();
+
+        // Blocks are readable before and after being written.
+        let content = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(
+            content,
+            fixture_file("./tests/fixtures/sticker_logo_small.png").into_inner()
+        );
+    }
+
+    {
+        // All the blocks were written when the state was saved.
+        let store = get_test_store(num_test).await;
+
+        let content = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(
+            content,
+            fixture_file("./tests/fixtures/sticker_logo_small.png").into_inner()
+        );
+    }
+}
+
+#[tokio::test]
+async fn contact_fields() {
+    use docstore::contacts::ContactFields;
+
+    let path = ["ann".to_owned()];
+    let content = serde_json::json!({
+        "fullName": "Ann Lee",
+        "mobile": "+33 6 12-34-56-78",
+        "workEmail": "ann.lee@corp.example",
+        "company": "Lighthouse Inc",
+        "notes": "Not indexed"
+    })
+    .to_string();
+
+    let num_test = 56;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .contact_fields(ContactFields {
+                names: vec!["fullName".into()],
+                phones: vec!["mobile".into()],
+                emails: vec!["workEmail".into()],
+                other: vec!["company".into()],
+            })
+            .build()
+            .await
+            .unwrap();
+
+        let variant = VariantMetadata::new(content.len() as _, "application/x-contact+json");
+        store
+            .create_resource(
+                &path,
+                "contact",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        for text in [
+            "Ann Lee",
+            "^^^^a",
+            // Without separators, and the digits shared with the national form.
+            "+33612345678",
+            "612345678",
+            "corp.example",
+            "Lighthouse",
+        ] {
+            let results = store.search(text).await.unwrap();
+            assert_eq!(results.len(), 1, "{}", text);
+        }
+
+        let results = store.search("Not indexed").await.unwrap();
+        assert_eq!(results.len(), 0);
+    }
+}
+
+#[tokio::test]
+async fn mutation_rollback() {
+    use docstore::resource::ContentReader;
+    use docstore::validators::Validator;
+
+    // Lets the default variant in, but rejects the thumbnail created by the
+    // image transformer once the resource is already indexed.
+    struct NoThumbnails;
+
+    #[async_trait::async_trait(?Send)]
+    impl Validator for NoThumbnails {
+        fn supports(&self, _mime_type: &str) -> bool {
+            true
+        }
+
+        async fn validate(
+            &self,
+            _path: &[String],
+            variant_name: &str,
+            _variant: &VariantMetadata,
+            _content: &mut dyn ContentReader,
+        ) -> Result<(), String> {
+            if variant_name == "thumbnail" {
+                return Err("No thumbnails".into());
+            }
+            Ok(())
+        }
+    }
+
+    let path = ["logo.png".to_owned()];
+    let content = fixture_file("./tests/fixtures/sticker_logo_small.png");
+    let tags: HashSet<String> = ["logo".to_owned()].into();
+
+    let num_test = 57;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .validator(NoThumbnails)
+            .build()
+            .await
+            .unwrap();
+
+        let variant = VariantMetadata::new(content.get_ref().len() as _, "image/png");
+        let result = store
+            .create_resource(&path, "sticker logo", &variant, tags, content.compat())
+            .await;
+        assert!(matches!(result, Err(StoreError::Rejected(_))));
+
+        // Neither the forest nor the index kept the resource.
+        assert!(store.get_metadata(&path).await.is_err());
+        assert!(store.ls_by_tag("logo").await.unwrap().is_empty());
+        assert!(store.search("sticker").await.unwrap().is_empty());
+        assert!(!Path::new(&root_dir).join("mutation.journal").exists());
+    }
+
+    {
+        let store = ResourceStore::new(&root_dir).await.unwrap();
+        assert!(store.get_metadata(&path).await.is_err());
+        assert!(store.search("sticker").await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn smart_folders() {
+    use docstore::resource::{ResourceId, SearchOrder, SortKey};
+    use docstore::smart_folders::{SmartFolder, SmartFolders};
+
+    let num_test = 58;
+    {
+        let mut store = init_test(num_test).await;
+
+        let folders = SmartFolders {
+            folders: vec![SmartFolder::new("carry-on", "trip")
+                .with_order(SearchOrder {
+                    key: SortKey::Size,
+                    descending: false,
+                })
+                .with_max_count(2)
+                .with_max_size(25)],
+        };
+        store.set_settings(&folders).await.unwrap();
+
+        let trip: HashSet<String> = ["trip".to_owned()].into();
+        for (name, content, tags) in [
+            ("map.txt", "0123456789", trip.clone()),
+            ("tickets.txt", "012345678901", trip.clone()),
+            ("guide.txt", "012345678901234567890123456789", trip.clone()),
+            ("notes.txt", "01234", HashSet::new()),
+        ] {
+            let variant = VariantMetadata::new(content.len() as _, "text/plain");
+            store
+                .create_resource(
+                    &[name.to_owned()],
+                    name,
+                    &variant,
+                    tags,
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let ids = |resources: Vec<(ResourceId, _)>| -> Vec<String> {
+            resources
+                .into_iter()
+                .map(|(id, _)| id.to_string())
+                .collect()
+        };
+        let resources = store.list_smart_folder("carry-on").await.unwrap();
+        assert_eq!(ids(resources), vec!["map.txt", "tickets.txt"]);
+
+        // The guide doesn't fit once the map is deleted.
+        store
+            .delete_resource(&["map.txt".to_owned()])
+            .await
+            .unwrap();
+        let resources = store.list_smart_folder("carry-on").await.unwrap();
+        assert_eq!(ids(resources), vec!["tickets.txt"]);
+
+        let changes: Vec<(ChangeOp, Vec<String>)> = store
+            .changes_since(0)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|change| {
+                matches!(
+                    change.op,
+                    ChangeOp::EnterSmartFolder(_) | ChangeOp::LeaveSmartFolder(_)
+                )
+            })
+            .map(|change| (change.op, change.path))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    ChangeOp::EnterSmartFolder("carry-on".into()),
+                    vec!["map.txt".to_owned()]
+                ),
+                (
+                    ChangeOp::EnterSmartFolder("carry-on".into()),
+                    vec!["tickets.txt".to_owned()]
+                ),
+                (
+                    ChangeOp::LeaveSmartFolder("carry-on".into()),
+                    vec!["map.txt".to_owned()]
+                ),
+            ]
+        );
+
+        let result = store.list_smart_folder("nothing").await;
+        assert!(matches!(result, Err(StoreError::NoSuchSmartFolder(_))));
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+        let resources = store.list_smart_folder("carry-on").await.unwrap();
+        assert_eq!(resources.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn contact_sheets() {
+    use docstore::frame_extractors::{register_frame_extractor, FrameExtractor};
+    use docstore::transformers::contact_sheet::ContactSheetSettings;
+
+    // Returns the requested number of 16:9 frames.
+    struct TestFrames;
+
+    impl FrameExtractor for TestFrames {
+        fn supports(&self, mime_type: &str) -> bool {
+            mime_type == "video/x-docstore-test"
+        }
+
+        fn extract(&self, _content: &[u8], count: u32) -> Result<Vec<DynamicImage>, String> {
+            Ok((0..count)
+                .map(|_| DynamicImage::new_rgb8(160, 90))
+                .collect())
+        }
+    }
+    register_frame_extractor(Box::new(TestFrames));
+
+    let path = ["clip".to_owned()];
+    let content = b"Not really a video".as_slice();
+
+    let num_test = 59;
+    {
+        let mut store = init_test(num_test).await;
+        store
+            .set_settings(&ContactSheetSettings {
+                video_columns: 2,
+                video_rows: 3,
+                width: 200,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let variant = VariantMetadata::new(content.len() as _, "video/x-docstore-test");
+        store
+            .create_resource(
+                &path,
+                "clip",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        let metadata = store.get_metadata(&path).await.unwrap();
+        let sheet = metadata.get_variant("contact_sheet").unwrap();
+        assert_eq!(sheet.mime_type(), "image/jpeg");
+
+        // 2 columns of 100x56 tiles, on 3 rows.
+        let bytes = store.get_variant_vec("contact_sheet", &path).await.unwrap();
+        let image = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((image.width(), image.height()), (200, 168));
+
+        // Other content doesn't get a contact sheet.
+        let path = ["notes.txt".to_owned()];
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "notes",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let metadata = store.get_metadata(&path).await.unwrap();
+        assert!(!metadata.has_variant("contact_sheet"));
+    }
+}
+
+#[tokio::test]
+async fn clone_store() {
+    use docstore::store::CloneOptions;
+
+    let path = ["cloned".to_owned()];
+    let content = b"The first draft".as_slice();
+    let updated = b"The final version".as_slice();
+    let same_key = PathBuf::from("./tests/data60_same_key");
+    let new_key = PathBuf::from("./tests/data60_new_key");
+
+    let num_test = 60;
+    {
+        let mut store = init_test(num_test).await;
+        let _ = std::fs::remove_dir_all(&same_key);
+        let _ = std::fs::remove_dir_all(&new_key);
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "cloned resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let variant = VariantMetadata::new(updated.len() as _, "text/plain");
+        store
+            .update_variant(&path, "default", &variant, Cursor::new(updated).compat())
+            .await
+            .unwrap();
+
+        store
+            .clone_to(&same_key, CloneOptions::default())
+            .await
+            .unwrap();
+        store
+            .clone_to(
+                &new_key,
+                CloneOptions {
+                    new_access_key: true,
+                },
+            )
+            .await
+            .unwrap();
+
+        // Existing stores are not overwritten.
+        assert!(store
+            .clone_to(&new_key, CloneOptions::default())
+            .await
+            .is_err());
+    }
+
+    let access_key = |dir: &Path| std::fs::read(dir.join("access.key")).unwrap();
+    let source_dir = PathBuf::from(format!("./tests/data{}", num_test));
+    assert_eq!(access_key(&same_key), access_key(&source_dir));
+    assert_ne!(access_key(&new_key), access_key(&source_dir));
+
+    for (dir, changes) in [(&same_key, 2), (&new_key, 0)] {
+        let mut store = ResourceStore::new(dir).await.unwrap();
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, updated.to_vec());
+        let results = store.search("final").await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(store.changes_since(0).await.unwrap().len(), changes);
+
+        // The copies are independent.
+        store.delete_resource(&path).await.unwrap();
+    }
+
+    let store = get_test_store(num_test).await;
+    let result = store.get_variant_vec("default", &path).await.unwrap();
+    assert_eq!(result, updated.to_vec());
+}
+
+#[tokio::test]
+async fn long_root_paths() {
+    let path = ["deep".to_owned()];
+    let content = b"Far from the root".as_slice();
+
+    // Over the 260 characters of MAX_PATH on Windows once the block file
+    // names are added, and with components that prefixed Windows paths
+    // don't resolve.
+    let num_test = 61;
+    let base = PathBuf::from(format!("./tests/data{}", num_test));
+    let _ = std::fs::remove_dir_all(&base);
+    let mut root_dir = base.join(".");
+    for index in 0..6 {
+        root_dir.push(format!("{}{}", index, "d".repeat(40)));
+    }
+    root_dir.push("skipped");
+    root_dir.push("..");
+    assert!(root_dir.to_string_lossy().len() > 260);
+    {
+        let mut store = ResourceStore::new(&root_dir).await.unwrap();
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "deep resource",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    {
+        let mut normalized = base.clone();
+        for index in 0..6 {
+            normalized.push(format!("{}{}", index, "d".repeat(40)));
+        }
+        assert!(normalized.join("blockstore").exists());
+        assert!(!normalized.join("skipped").exists());
+
+        let store = ResourceStore::new(&normalized).await.unwrap();
+        let result = store.get_variant_vec("default", &path).await.unwrap();
+        assert_eq!(result, content.to_vec());
+    }
+}
+
+#[tokio::test]
+async fn prioritized_transforms() {
+    let paths: Vec<Vec<String>> = (0..3).map(|i| vec![format!("photo{}.png", i)]).collect();
+    let content = std::fs::read("./tests/fixtures/red_square.png").unwrap();
+
+    let num_test = 62;
+    {
+        let mut store = init_test(num_test).await;
+        store
+            .set_settings(&ThumbnailSettings {
+                lazy: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let variant = VariantMetadata::new(content.len() as _, "image/png");
+        for path in &paths {
+            store
+                .create_resource(
+                    path,
+                    "photo",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content.clone()).compat(),
+                )
+                .await
+                .unwrap();
+        }
+        let has_thumbnail =
+            |metadata: docstore::resource::ResourceMetadata| metadata.has_variant("thumbnail");
+
+        // The last photo is on screen.
+        store.prioritize_transforms(&paths[2]).await.unwrap();
+        assert_eq!(store.backfill_thumbnails(1).await.unwrap(), 1);
+        let metadata = store.get_metadata(&paths[2]).await.unwrap();
+        assert!(has_thumbnail(metadata));
+        let metadata = store.get_metadata(&paths[0]).await.unwrap();
+        assert!(!has_thumbnail(metadata));
+
+        // Then the others, in order.
+        assert_eq!(store.backfill_thumbnails(1).await.unwrap(), 1);
+        let metadata = store.get_metadata(&paths[0]).await.unwrap();
+        assert!(has_thumbnail(metadata));
+        let metadata = store.get_metadata(&paths[1]).await.unwrap();
+        assert!(!has_thumbnail(metadata));
+
+        let result = store
+            .prioritize_transforms(&["missing.png".to_owned()])
+            .await;
+        assert!(matches!(result, Err(StoreError::NoSuchResource(_))));
+    }
+}
+
+#[tokio::test]
+async fn update_metadata() {
+    let path = ["notes.txt".to_owned()];
+    let content = b"Meeting notes".as_slice();
+
+    let num_test = 63;
+    {
+        let mut store = init_test(num_test).await;
+
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "Draft agenda",
+                &variant,
+                ["draft".to_owned()].into(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        store.update_desc(&path, "Final minutes").await.unwrap();
+        assert_eq!(
+            store.get_metadata(&path).await.unwrap().desc(),
+            "Final minutes"
+        );
+        assert!(store.search("agenda").await.unwrap().is_empty());
+        assert_eq!(store.search("minutes").await.unwrap().len(), 1);
+        // The content is still indexed.
+        assert_eq!(store.search("meeting").await.unwrap().len(), 1);
+
+        store
+            .update_metadata(&path, |metadata| {
+                metadata.remove_tag("draft");
+                metadata.add_tag("archived");
+                // Ignored, the variants match the stored content.
+                metadata.remove_variant("default");
+            })
+            .await
+            .unwrap();
+        let metadata = store.get_metadata(&path).await.unwrap();
+        assert!(metadata.has_variant("default"));
+        assert!(store.ls_by_tag("draft").await.unwrap().is_empty());
+        assert_eq!(store.ls_by_tag("archived").await.unwrap().len(), 1);
+
+        let ops: Vec<ChangeOp> = store
+            .changes_since(1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|change| change.op)
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                ChangeOp::UpdateDescription,
+                ChangeOp::AddTag("archived".into()),
+                ChangeOp::RemoveTag("draft".into()),
+            ]
+        );
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+        assert_eq!(
+            store.get_metadata(&path).await.unwrap().desc(),
+            "Final minutes"
+        );
+        assert_eq!(store.search("minutes").await.unwrap().len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn import_conflicts() {
+    let fixture = "./tests/fixtures/hello.txt";
+    let path = ["hello.txt".to_owned()];
+
+    let num_test = 64;
+    let mut store = init_test(num_test).await;
+
+    assert_eq!(
+        store
+            .import_file_with_policy(fixture, ConflictPolicy::Fail)
+            .await
+            .unwrap(),
+        ImportAction::Created
+    );
+    assert!(matches!(
+        store.import_file(fixture).await,
+        Err(StoreError::ResourceExists(_))
+    ));
+    assert_eq!(
+        store
+            .import_file_with_policy(fixture, ConflictPolicy::SkipIfIdentical)
+            .await
+            .unwrap(),
+        ImportAction::Skipped
+    );
+    assert_eq!(
+        store
+            .import_file_with_policy(fixture, ConflictPolicy::KeepBoth)
+            .await
+            .unwrap(),
+        ImportAction::Renamed(vec!["hello (1).txt".to_owned()])
+    );
+
+    // Different content at the same path.
+    let content = b"Something else".as_slice();
+    let variant = VariantMetadata::new(content.len() as _, "text/plain");
+    assert!(matches!(
+        store
+            .create_resource_with_policy(
+                &path,
+                "Other",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+                ConflictPolicy::SkipIfIdentical,
+            )
+            .await,
+        Err(StoreError::ResourceExists(_))
+    ));
+    assert_eq!(
+        store
+            .create_resource_with_policy(
+                &path,
+                "Other",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+                ConflictPolicy::Overwrite,
+            )
+            .await
+            .unwrap(),
+        ImportAction::Overwritten
+    );
+    let metadata = store.get_metadata(&path).await.unwrap();
+    assert_eq!(metadata.desc(), "Other");
+    assert_eq!(metadata.get_variant("default").unwrap().size(), 14);
+    assert_eq!(store.search("else").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn analyze_and_compact() {
+    let small = ["small.txt".to_owned()];
+    let large = ["large.txt".to_owned()];
+
+    let num_test = 65;
+    let mut store = init_test(num_test).await;
+
+    for (path, content) in [(&small, "short".to_owned()), (&large, "long ".repeat(1000))] {
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                path,
+                "",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content.into_bytes()).compat(),
+            )
+            .await
+            .unwrap();
+    }
+    for content in ["first", "second", "third"] {
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .update_variant(&small, "default", &variant, Cursor::new(content).compat())
+            .await
+            .unwrap();
+    }
+
+    let report = store.analyze().await.unwrap();
+    assert_eq!(report.forest_resources, 2);
+    assert_eq!(report.indexed_resources, 2);
+    assert!(report.index_is_consistent());
+    assert_eq!(report.largest_resources[0], (large.to_vec(), 5000));
+    assert_eq!(report.default_variant_bytes, 5005);
+    assert!(report.unreachable_blocks() > 0);
+    assert!(report.index_bytes > 0);
+
+    let compacted = store.compact(CompactOptions::default()).await.unwrap();
+    assert_eq!(compacted.removed_blocks, report.unreachable_blocks());
+
+    let report = store.analyze().await.unwrap();
+    assert_eq!(report.unreachable_blocks(), 0);
+    assert_eq!(
+        store.get_variant_vec("default", &small).await.unwrap(),
+        b"third"
+    );
+
+    // Pruning the history keeps the resources.
+    store
+        .compact(CompactOptions {
+            prune_history: true,
+        })
+        .await
+        .unwrap();
+    drop(store);
+    let mut store = get_test_store(num_test).await;
+    assert_eq!(
+        store.get_variant_vec("default", &small).await.unwrap(),
+        b"third"
+    );
+    assert_eq!(store.analyze().await.unwrap().forest_resources, 2);
+
+    // A root that can't be read stops the compaction instead of losing
+    // the blocks it pins.
+    let snapshots = PathBuf::from(format!("./tests/data{}/snapshots.cbor", num_test));
+    std::fs::write(&snapshots, b"torn").unwrap();
+    assert!(store.compact(CompactOptions::default()).await.is_err());
+    std::fs::remove_file(&snapshots).unwrap();
+    store.compact(CompactOptions::default()).await.unwrap();
+}
+
+#[tokio::test]
+async fn max_indexed_size() {
+    let num_test = 66;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+
+    let mut store = ResourceStore::builder(&root_dir)
+        .max_indexed_size(16)
+        .build()
+        .await
+        .unwrap();
+
+    let resources = [
+        (
+            "notes.txt",
+            "text/plain",
+            "Short beginning, then a long tail",
+        ),
+        (
+            "place.json",
+            "application/x-places+json",
+            r#"{"url": "https://example.com", "title": "Faraway"}"#,
+        ),
+    ];
+    for (name, mime, content) in resources {
+        let variant = VariantMetadata::new(content.len() as _, mime);
+        store
+            .create_resource(
+                &[name.to_owned()],
+                "",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    // Only the beginning of the text is indexed.
+    assert_eq!(store.search("beginning").await.unwrap().len(), 1);
+    assert!(store.search("tail").await.unwrap().is_empty());
+    // Larger json is not indexed, but still stored.
+    assert!(store.search("faraway").await.unwrap().is_empty());
+    assert_eq!(
+        store
+            .get_metadata(&["place.json".to_owned()])
+            .await
+            .unwrap()
+            .get_variant("default")
+            .unwrap()
+            .size(),
+        resources[1].2.len() as u64
+    );
+}
+
+#[tokio::test]
+async fn transformer_config() {
+    let image = ["sticker_logo_small.png".to_owned()];
+
+    let num_test = 67;
+    let mut store = init_test(num_test).await;
+
+    assert!(store
+        .transformer_config()
+        .await
+        .unwrap()
+        .is_enabled(THUMBNAIL_VARIANT));
+    store
+        .set_transformer_enabled(THUMBNAIL_VARIANT, false)
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.set_transformer_enabled("ocr", false).await,
+        Err(StoreError::NoSuchTransformer(_))
+    ));
+
+    store
+        .import_file("./tests/fixtures/sticker_logo_small.png")
+        .await
+        .unwrap();
+    let metadata = store.get_metadata(&image).await.unwrap();
+    assert!(!metadata.has_variant(THUMBNAIL_VARIANT));
+    assert!(metadata.has_variant(BLURHASH_VARIANT));
+    assert!(store.get_thumbnail(&image).await.is_err());
+
+    // The configuration is persisted.
+    drop(store);
+    let mut store = get_test_store(num_test).await;
+    let config = store.transformer_config().await.unwrap();
+    assert!(!config.is_enabled(THUMBNAIL_VARIANT));
+    assert!(config.is_enabled(BLURHASH_VARIANT));
+
+    store
+        .set_transformer_enabled(THUMBNAIL_VARIANT, true)
+        .await
+        .unwrap();
+    assert_eq!(store.backfill_thumbnails(10).await.unwrap(), 1);
+    let metadata = store.get_metadata(&image).await.unwrap();
+    assert!(metadata.has_variant(THUMBNAIL_VARIANT));
+}
+
+#[tokio::test]
+async fn search_cache() {
+    let num_test = 68;
+    let mut store = init_test(num_test).await;
+
+    let create = |name: &str| {
+        let content = format!("{} likes apples", name);
+        (
+            vec![format!("{}.txt", name)],
+            VariantMetadata::new(content.len() as _, "text/plain"),
+            content,
+        )
+    };
+
+    let (path, variant, content) = create("alice");
+    store
+        .create_resource(
+            &path,
+            "",
+            &variant,
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(store.search("apples").await.unwrap().len(), 1);
+    // Cached under the normalized text.
+    assert_eq!(store.search("APPLES").await.unwrap().len(), 1);
+
+    // Changes to the index invalidate the cached results.
+    let (other, variant, content) = create("bob");
+    store
+        .create_resource(
+            &other,
+            "",
+            &variant,
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(store.search("apples").await.unwrap().len(), 2);
+    store.delete_resource(&path).await.unwrap();
+    assert_eq!(store.search("Apples").await.unwrap().len(), 1);
+
+    assert!(docstore::metrics::render().contains("docstore_search_cache_hits_total"));
+}
+
+#[tokio::test]
+async fn pdf_metadata() {
+    use docstore::properties::PAGE_COUNT;
+    use docstore::resource::SearchOrder;
+
+    let path = ["survey.pdf".to_owned()];
+
+    let num_test = 69;
+    {
+        let mut store = init_test(num_test).await;
+
+        store
+            .import_file("./tests/fixtures/survey.pdf")
+            .await
+            .unwrap();
+
+        // The XMP values replace the ones of the information dictionary.
+        let results = store.search("Charles Babbage").await.unwrap();
+        assert_eq!(results.len(), 1);
+        let results = store.search("garden survey").await.unwrap();
+        assert_eq!(results.len(), 1);
+
+        let properties = store.get_properties(&path, "default").unwrap();
+        assert!(properties.contains(&(
+            "title".to_owned(),
+            PropertyValue::Text("Quarterly Garden Survey".into())
+        )));
+        assert!(properties.contains(&(
+            "author".to_owned(),
+            PropertyValue::Text("Ada Lovelace".into())
+        )));
+        assert!(properties.contains(&(
+            "keywords".to_owned(),
+            PropertyValue::Text("tomatoes, zucchini".into())
+        )));
+
+        let results = store
+            .query_properties(&[PropertyFilter::Range(
+                PAGE_COUNT.into(),
+                2_i64.into(),
+                10_i64.into(),
+            )])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        // Metadata matches weigh more than occurrences in other text.
+        let content = "zucchini and more zucchini";
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &["notes.txt".to_owned()],
+                "",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let results = store
+            .search_ordered("zucchini", SearchOrder::default())
+            .await
+            .unwrap();
+        let names: Vec<String> = results.iter().map(|(id, _)| id.to_string()).collect();
+        assert_eq!(names, ["survey.pdf", "notes.txt"]);
+    }
+}
+
+#[tokio::test]
+async fn raw_block_access() {
+    use wnfs::common::BlockStore;
+
+    let num_test = 70;
+    {
+        let mut store = init_test(num_test).await;
+        assert_eq!(store.root_revision().await.unwrap(), 0);
+        let initial_cid = store.forest_cid().await.unwrap();
+
+        store
+            .import_file("./tests/fixtures/hello.txt")
+            .await
+            .unwrap();
+        let changes = store.changes_since(0).await.unwrap();
+        assert_eq!(
+            store.root_revision().await.unwrap(),
+            changes.last().unwrap().revision
+        );
+
+        let forest_cid = store.forest_cid().await.unwrap();
+        assert_ne!(forest_cid, initial_cid);
+        let block = store.block_store().get_block(&forest_cid).await.unwrap();
+        assert!(!block.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn background_maintenance() {
+    use docstore::maintenance::MaintenancePolicy;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    let logo = ["sticker_logo_small.png".to_owned()];
+
+    let num_test = 71;
+    let local = tokio::task::LocalSet::new();
+    local
+        .run_until(async move {
+            let mut store = init_test(num_test).await;
+            store
+                .set_settings(&ThumbnailSettings {
+                    lazy: true,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            store
+                .import_file("./tests/fixtures/sticker_logo_small.png")
+                .await
+                .unwrap();
+            let meta = store.get_metadata(&logo).await.unwrap();
+            assert!(!meta.has_variant(THUMBNAIL_VARIANT));
+
+            let store = Rc::new(tokio::sync::Mutex::new(store));
+            let maintenance = ResourceStore::start_maintenance(
+                store.clone(),
+                MaintenancePolicy {
+                    compact: Some(Duration::from_millis(20)),
+                    optimize_index: Some(Duration::from_millis(20)),
+                    backfill_thumbnails: Some(Duration::from_millis(10)),
+                    thumbnail_batch: 1,
+                    prune_history: None,
+                },
+            );
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            maintenance.stop().await;
+
+            let store = store.lock().await;
+            let meta = store.get_metadata(&logo).await.unwrap();
+            assert!(meta.has_variant(THUMBNAIL_VARIANT));
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn import_origin() {
+    use docstore::properties::{IMPORTED_AT, ORIGINAL_NAME, ORIGIN_PROPERTIES};
+
+    let path = ["hello.txt".to_owned()];
+
+    let num_test = 72;
+    {
+        let mut store = init_test(num_test).await;
+        let before = chrono::Utc::now().timestamp();
+        store
+            .import_file("./tests/fixtures/hello.txt")
+            .await
+            .unwrap();
+
+        let metadata = store.get_metadata(&path).await.unwrap();
+        assert_eq!(metadata.desc(), "hello.txt");
+        let origin = metadata.origin().unwrap();
+        assert_eq!(origin.file_name, "hello.txt");
+        let source_path = PathBuf::from(origin.source_path.as_ref().unwrap());
+        assert!(source_path.is_absolute());
+        assert!(source_path.ends_with("tests/fixtures/hello.txt"));
+        assert!(origin.imported_at >= before);
+
+        // The local path is not searchable.
+        assert!(store.search("fixtures").await.unwrap().is_empty());
+        assert_eq!(store.search("hello.txt").await.unwrap().len(), 1);
+
+        let properties = store.get_properties(&path, ORIGIN_PROPERTIES).unwrap();
+        assert!(properties.contains(&(
+            ORIGINAL_NAME.to_owned(),
+            PropertyValue::Text("hello.txt".into())
+        )));
+        let results = store
+            .query_properties(&[PropertyFilter::GreaterThan(
+                IMPORTED_AT.into(),
+                (before - 1).into(),
+            )])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+
+        // Resources created from content have no origin.
+        let content = b"No origin".as_slice();
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &["created.txt".to_owned()],
+                "",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        let metadata = store
+            .get_metadata(&["created.txt".to_owned()])
+            .await
+            .unwrap();
+        assert!(metadata.origin().is_none());
+    }
+
+    {
+        let store = get_test_store(num_test).await;
+        let metadata = store.get_metadata(&path).await.unwrap();
+        assert_eq!(metadata.origin().unwrap().file_name, "hello.txt");
+    }
+}
+
+#[tokio::test]
+async fn resource_locks() {
+    use futures::AsyncWriteExt;
+
+    let path = ["docs".to_owned(), "draft.txt".to_owned()];
+    let other = ["docs".to_owned(), "other.txt".to_owned()];
+    let content = b"Work in progress".as_slice();
+    let variant = VariantMetadata::new(content.len() as _, "text/plain");
+
+    let num_test = 73;
+    {
+        let mut store = init_test(num_test).await;
+        store
+            .create_resource(
+                &path,
+                "Draft",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        let locks = store.resource_locks();
+        let lock = store.try_lock_resource(&path).unwrap();
+        assert!(locks.is_locked(&path));
+        assert!(store.try_lock_resource(&path).is_none());
+        // The container can't be locked either.
+        assert!(store.try_lock_resource(&["docs".to_owned()]).is_none());
+
+        let result = store.update_desc(&path, "Final").await;
+        assert!(matches!(result, Err(StoreError::ResourceLocked(_))));
+        assert_eq!(store.get_metadata(&path).await.unwrap().desc(), "Draft");
+
+        // The holder of the lock changes the resource through it.
+        {
+            let _allowed = lock.allow_changes();
+            store.update_desc(&path, "Edited").await.unwrap();
+            assert!(locks.is_locked(&path));
+            assert!(store.try_lock_resource(&path).is_none());
+        }
+        assert_eq!(store.get_metadata(&path).await.unwrap().desc(), "Edited");
+        let result = store.update_desc(&path, "Final").await;
+        assert!(matches!(result, Err(StoreError::ResourceLocked(_))));
+
+        drop(lock);
+        assert!(!locks.is_locked(&path));
+        store.update_desc(&path, "Final").await.unwrap();
+
+        // Locking a container locks the resources under it.
+        let lock = store.try_lock_resource(&["docs".to_owned()]).unwrap();
+        let result = store
+            .create_resource(
+                &other,
+                "Other",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await;
+        assert!(matches!(result, Err(StoreError::ResourceLocked(_))));
+        drop(lock);
+        store
+            .create_resource(
+                &other,
+                "Other",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        assert!(!locks.is_locked(&other));
+
+        // Variants pushed through a writer lock the resource until the
+        // writer is closed, and are refused on locked resources.
+        let notes = VariantMetadata::new(0, "text/plain");
+        {
+            let mut writer = store.add_variant_writer(&path, "notes", &notes);
+            writer.write_all(b"Some notes").await.unwrap();
+            assert!(locks.is_locked(&path));
+            assert!(!locks.is_locked(&other));
+            writer.close().await.unwrap();
+        }
+        assert!(!locks.is_locked(&path));
+
+        let lock = store.try_lock_resource(&path).unwrap();
+        {
+            let mut writer = store.add_variant_writer(&path, "refused", &notes);
+            assert!(writer.write_all(b"Refused notes").await.is_err());
+        }
+        assert!(!store
+            .get_metadata(&path)
+            .await
+            .unwrap()
+            .has_variant("refused"));
+        {
+            let _allowed = lock.allow_changes();
+            let mut writer = store.add_variant_writer(&path, "allowed", &notes);
+            writer.write_all(b"Allowed notes").await.unwrap();
+            writer.close().await.unwrap();
+        }
+        assert!(store
+            .get_metadata(&path)
+            .await
+            .unwrap()
+            .has_variant("allowed"));
+        drop(lock);
+        assert!(!locks.is_locked(&path));
+    }
+}
+
+#[tokio::test]
+async fn ocr_variant() {
+    use docstore::text_recognizers::{register_text_recognizer, TextRecognizer};
+    use docstore::transformers::ocr::OCR_VARIANT;
+
+    // "Recognizes" the content as text.
+    struct TestRecognizer;
+
+    impl TextRecognizer for TestRecognizer {
+        fn supports(&self, mime_type: &str) -> bool {
+            mime_type == "application/x-docstore-scan"
+        }
+
+        fn recognize(&self, content: &[u8]) -> Result<String, String> {
+            Ok(String::from_utf8_lossy(content).to_uppercase())
+        }
+    }
+    register_text_recognizer(Box::new(TestRecognizer));
+
+    let path = ["scan".to_owned()];
+
+    let num_test = 74;
+    {
+        let mut store = init_test(num_test).await;
+
+        let content = b"invoice 1234".as_slice();
+        let variant = VariantMetadata::new(content.len() as _, "application/x-docstore-scan");
+        store
+            .create_resource(
+                &path,
+                "",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        let text = store.get_variant_vec(OCR_VARIANT, &path).await.unwrap();
+        assert_eq!(text, b"INVOICE 1234");
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert_eq!(
+            meta.get_variant(OCR_VARIANT).unwrap().mime_type(),
+            "text/plain"
+        );
+        assert_eq!(store.search("invoice").await.unwrap().len(), 1);
+
+        // The text of new content replaces the previous one.
+        let content = b"receipt".as_slice();
+        let variant = VariantMetadata::new(content.len() as _, "application/x-docstore-scan");
+        store
+            .update_variant(&path, "default", &variant, Cursor::new(content).compat())
+            .await
+            .unwrap();
+        let text = store.get_variant_vec(OCR_VARIANT, &path).await.unwrap();
+        assert_eq!(text, b"RECEIPT");
+        assert!(store.search("invoice").await.unwrap().is_empty());
+
+        // And is removed when nothing is recognized.
+        let variant = VariantMetadata::new(0, "application/x-docstore-scan");
+        store
+            .update_variant(
+                &path,
+                "default",
+                &variant,
+                Cursor::new(b"".as_slice()).compat(),
+            )
+            .await
+            .unwrap();
+        let meta = store.get_metadata(&path).await.unwrap();
+        assert!(!meta.has_variant(OCR_VARIANT));
+    }
+}
+
+#[tokio::test]
+async fn tombstones() {
+    use docstore::tombstones::{Tombstone, TombstoneSettings};
+
+    let x = ["x.txt".to_owned()];
+    let y = ["y.txt".to_owned()];
+    let content = b"Replicated".as_slice();
+    let variant = VariantMetadata::new(content.len() as _, "text/plain");
+
+    let mut replicas = vec![];
+    for num_test in [75, 76] {
+        let mut store = init_test(num_test).await;
+        for path in [&x, &y] {
+            store
+                .create_resource(
+                    path,
+                    "",
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+        }
+        replicas.push(store);
+    }
+    let (mut first, mut second) = (replicas.remove(0), replicas.remove(0));
+
+    first.delete_resource(&x).await.unwrap();
+    let tombstones = first.tombstones().await.unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].path, x);
+
+    // The deletion propagates, while resources modified after their
+    // deletion on the other replica are kept.
+    let now = chrono::Utc::now().timestamp();
+    let mut remote = tombstones.clone();
+    remote.push(Tombstone {
+        path: y.to_vec(),
+        revision: 1,
+        deleted_at: now - 3600,
+    });
+    remote.push(Tombstone {
+        path: vec!["z.txt".to_owned()],
+        revision: 1,
+        deleted_at: now,
+    });
+    let deleted = second.apply_tombstones(&remote).await.unwrap();
+    assert_eq!(deleted, vec![x.to_vec()]);
+    assert!(matches!(
+        second.get_metadata(&x).await,
+        Err(StoreError::NoSuchResource(_))
+    ));
+    second.get_metadata(&y).await.unwrap();
+    assert_eq!(
+        second.tombstone(&x).await.unwrap().unwrap().deleted_at,
+        tombstones[0].deleted_at
+    );
+    // Kept to be propagated further.
+    assert!(second
+        .tombstone(&["z.txt".to_owned()])
+        .await
+        .unwrap()
+        .is_some());
+    assert!(second.tombstone(&y).await.unwrap().is_none());
+
+    // Tombstones past the retention window are dropped.
+    second
+        .set_settings(&TombstoneSettings { retention_days: 1 })
+        .await
+        .unwrap();
+    second
+        .apply_tombstones(&[Tombstone {
+            path: vec!["old.txt".to_owned()],
+            revision: 1,
+            deleted_at: now - 2 * 24 * 3600,
+        }])
+        .await
+        .unwrap();
+    assert!(second
+        .tombstone(&["old.txt".to_owned()])
+        .await
+        .unwrap()
+        .is_none());
+
+    // Creating a resource again removes its tombstone.
+    first
+        .create_resource(
+            &x,
+            "",
+            &variant,
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    assert!(first.tombstone(&x).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn actual_sizes() {
+    let root_dir = "./tests/data77";
+    let _ = std::fs::remove_dir_all(root_dir);
+    // Keep the content in blocks, to measure their size.
+    let mut store = ResourceStore::builder(root_dir)
+        .inline_threshold(0)
+        .build()
+        .await
+        .unwrap();
+
+    let path = ["sizes.txt".to_owned()];
+    let content = b"Twenty three bytes long".as_slice();
+    // Callers can't be trusted with sizes.
+    store
+        .create_resource(
+            &path,
+            "",
+            &VariantMetadata::new(3, "text/plain"),
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    store
+        .add_variant(
+            &path,
+            "copy",
+            &VariantMetadata::new(0, "text/plain"),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+
+    let meta = store.get_metadata(&path).await.unwrap();
+    for name in ["default", "copy"] {
+        let variant = meta.get_variant(name).unwrap();
+        assert_eq!(variant.size(), content.len() as u64);
+        // Encryption adds a nonce and a tag to each block.
+        assert!(variant.stored_size().unwrap() > variant.size());
+    }
+}
+
+#[tokio::test]
+async fn subtitles() {
+    use docstore::resource::{ResourceId, ResourceMetadata};
+
+    let mut store = init_test(78).await;
+
+    let create = |name: &str, mime: &str, content: &'static [u8]| {
+        (
+            vec!["films".to_owned(), name.to_owned()],
+            VariantMetadata::new(content.len() as _, mime),
+            content,
+        )
+    };
+    let resources = [
+        create("movie.mp4", "video/mp4", b"Not really a video"),
+        create(
+            "movie.en.srt",
+            "application/x-subrip",
+            b"1\r\n00:00:01,000 --> 00:00:04,000\r\n<i>Hello there.</i>\r\n\r\n2\r\n00:00:05,000 --> 00:00:07,500\r\nGeneral Kenobi!\r\n",
+        ),
+        create(
+            "commentary.vtt",
+            "text/vtt",
+            b"WEBVTT\n\nNOTE Recorded in 2005\n\nintro\n00:01.000 --> 00:04.000 align:start\n<v Director>We shot this scene twice.</v>\n",
+        ),
+        create("bonus.mkv", "video/x-matroska", b"Not really a video either"),
+    ];
+    for (path, variant, content) in &resources {
+        store
+            .create_resource(
+                path,
+                "",
+                variant,
+                HashSet::new(),
+                Cursor::new(*content).compat(),
+            )
+            .await
+            .unwrap();
+    }
+    let movie = &resources[0].0;
+    let subtitles = &resources[1].0;
+    let commentary = &resources[2].0;
+    let bonus = &resources[3].0;
+
+    let search = |results: Vec<(ResourceId, ResourceMetadata)>| {
+        let mut ids: Vec<String> = results.into_iter().map(|(id, _)| id.to_string()).collect();
+        ids.sort();
+        ids
+    };
+
+    // Dialogue lines find the video named like the subtitles.
+    assert_eq!(
+        search(store.search("general kenobi").await.unwrap()),
+        vec!["films/movie.en.srt", "films/movie.mp4"]
+    );
+    // Timings and tags are not indexed.
+    assert!(store.search("00:00:05").await.unwrap().is_empty());
+    assert!(store.search("Director").await.unwrap().is_empty());
+    assert_eq!(
+        search(store.search("shot this scene").await.unwrap()),
+        vec!["films/commentary.vtt"]
+    );
+
+    // Only the resources of the same container are linked.
+    let extras = vec![
+        "films".to_owned(),
+        "movie.extras".to_owned(),
+        "movie.fr.srt".to_owned(),
+    ];
+    let content = b"1\r\n00:00:01,000 --> 00:00:02,000\r\nBonjour.\r\n";
+    store
+        .create_resource(
+            &extras,
+            "",
+            &VariantMetadata::new(content.len() as _, "application/x-subrip"),
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        search(store.search("bonjour").await.unwrap()),
+        vec!["films/movie.extras/movie.fr.srt"]
+    );
+    let ids: Vec<String> = store
+        .ids_with_prefix("films/movie", 10)
+        .unwrap()
+        .into_iter()
+        .map(|id| id.to_string())
+        .collect();
+    assert_eq!(
+        ids,
+        vec![
+            "films/movie.en.srt",
+            "films/movie.extras/movie.fr.srt",
+            "films/movie.mp4"
+        ]
+    );
+
+    // Subtitles can be linked to a video explicitly.
+    store
+        .update_metadata(commentary, |metadata| {
+            metadata.set_subtitled_video(Some(bonus.as_slice()))
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        search(store.search("shot this scene").await.unwrap()),
+        vec!["films/bonus.mkv", "films/commentary.vtt"]
+    );
+
+    // The link is kept while the video is deleted and created again.
+    store.delete_resource(movie).await.unwrap();
+    assert_eq!(
+        search(store.search("general kenobi").await.unwrap()),
+        vec!["films/movie.en.srt"]
+    );
+    let (_, variant, content) = &resources[0];
+    store
+        .create_resource(
+            movie,
+            "",
+            variant,
+            HashSet::new(),
+            Cursor::new(*content).compat(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        search(store.search("general kenobi").await.unwrap()),
+        vec!["films/movie.en.srt", "films/movie.mp4"]
+    );
+
+    store.delete_resource(subtitles).await.unwrap();
+    assert!(store.search("general kenobi").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn access_tokens() {
+    use docstore::access::Scope;
+
+    let mut store = init_test(79).await;
+
+    let photos = vec!["photos".to_owned()];
+    let (token, secret) = store
+        .issue_access_token("phone", Scope::ReadOnly, &[photos.clone()], None)
+        .await
+        .unwrap();
+    assert_eq!(token.name, "phone");
+    assert!(token.expires.is_none());
+
+    let checked = store.check_access_token(&secret).await.unwrap();
+    assert_eq!(checked, token);
+    assert!(checked.allows(Scope::SearchOnly));
+    assert!(checked.allows(Scope::ReadOnly));
+    assert!(!checked.allows(Scope::ReadWrite));
+    assert!(checked.allows_path(&["photos".to_owned(), "cat.jpg".to_owned()]));
+    assert!(!checked.allows_path(&["documents".to_owned(), "taxes.pdf".to_owned()]));
+
+    // Secrets are only valid as issued.
+    assert!(matches!(
+        store
+            .check_access_token(&format!("{}.0000", token.id))
+            .await,
+        Err(StoreError::InvalidAccessToken)
+    ));
+    assert!(store.check_access_token("garbage").await.is_err());
+
+    // Rotating the token invalidates its previous secret.
+    let rotated = store.rotate_access_token(&token.id).await.unwrap();
+    assert!(store.check_access_token(&secret).await.is_err());
+    assert_eq!(store.check_access_token(&rotated).await.unwrap(), token);
+
+    let (expired, expired_secret) = store
+        .issue_access_token("laptop", Scope::ReadWrite, &[], Some(Duration::seconds(-1)))
+        .await
+        .unwrap();
+    assert!(expired.is_unrestricted());
+    assert!(store.check_access_token(&expired_secret).await.is_err());
+    assert_eq!(store.access_tokens().await.unwrap(), vec![token.clone()]);
+
+    assert!(store.revoke_access_token(&token.id).await.unwrap());
+    assert!(store.check_access_token(&rotated).await.is_err());
+    assert!(store.access_tokens().await.unwrap().is_empty());
+    assert!(matches!(
+        store.rotate_access_token(&token.id).await,
+        Err(StoreError::InvalidAccessToken)
+    ));
+}
+
+#[tokio::test]
+async fn timeline() {
+    use docstore::resource::TimelineBucket;
+
+    let mut store = init_test(80).await;
+
+    let photo = ["photos".to_owned(), "summer.png".to_owned()];
+    store
+        .create_resource(
+            &photo,
+            "summer photo",
+            &VariantMetadata::new(0, "image/png"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/dated_photo.png").compat(),
+        )
+        .await
+        .unwrap();
+    let note = ["note.txt".to_owned()];
+    store
+        .create_resource(
+            &note,
+            "note",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/hello.txt").compat(),
+        )
+        .await
+        .unwrap();
+
+    let properties = store.get_properties(&photo, "default").unwrap();
+    assert!(properties.contains(&("captured_at".to_owned(), PropertyValue::Integer(1626255000))));
+
+    // Resources without a capture date are dated by their modification.
+    let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let days = store.timeline(TimelineBucket::Day).await.unwrap();
+    assert_eq!(days.len(), 2);
+    assert_eq!(days[0].bucket, today);
+    assert_eq!(days[0].count, 1);
+    assert_eq!(days[0].ids[0].to_string(), "note.txt");
+    assert_eq!(days[1].bucket, "2021-07-14");
+    assert_eq!(days[1].ids[0].to_string(), "photos/summer.png");
+
+    let months = store.timeline(TimelineBucket::Month).await.unwrap();
+    assert_eq!(months[1].bucket, "2021-07");
+    let years = store.timeline(TimelineBucket::Year).await.unwrap();
+    assert_eq!(years[1].bucket, "2021");
+    assert_eq!(years[1].count, 1);
+}
+
+#[tokio::test]
+async fn import_store() {
+    let notes = ["notes".to_owned(), "todo.txt".to_owned()];
+    let hello = ["hello.txt".to_owned()];
+    let content = b"Buy some oranges".as_slice();
+
+    let other_dir = PathBuf::from("./tests/data81");
+    {
+        let mut other = init_test(81).await;
+        other
+            .create_resource(
+                &notes,
+                "shopping list",
+                &VariantMetadata::new(content.len() as _, "text/plain"),
+                HashSet::from(["errands".to_owned()]),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+        other
+            .add_variant(
+                &notes,
+                "summary",
+                &VariantMetadata::new(7, "text/plain"),
+                Cursor::new(b"oranges".as_slice()).compat(),
+            )
+            .await
+            .unwrap();
+        other
+            .import_file("./tests/fixtures/hello.txt")
+            .await
+            .unwrap();
+    }
+
+    let num_test = 82;
+    let mut store = init_test(num_test).await;
+    store
+        .import_file("./tests/fixtures/hello.txt")
+        .await
+        .unwrap();
+
+    let container = ["imported".to_owned()];
+    let mut actions = store
+        .import_store(&other_dir, &container, ConflictPolicy::KeepBoth)
+        .await
+        .unwrap();
+    actions.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        actions,
+        vec![
+            (hello.to_vec(), ImportAction::Created),
+            (notes.to_vec(), ImportAction::Created),
+        ]
+    );
+
+    let imported = [
+        "imported".to_owned(),
+        "notes".to_owned(),
+        "todo.txt".to_owned(),
+    ];
+    let metadata = store.get_metadata(&imported).await.unwrap();
+    assert_eq!(metadata.desc(), "shopping list");
+    assert!(metadata.tags().contains("errands"));
+    assert_eq!(
+        store.get_variant_vec("summary", &imported).await.unwrap(),
+        b"oranges".to_vec()
+    );
+    assert_eq!(store.search("oranges").await.unwrap().len(), 1);
+
+    // Importing again resolves the conflicts with the policy.
+    let actions = store
+        .import_store(&other_dir, &container, ConflictPolicy::SkipIfIdentical)
+        .await
+        .unwrap();
+    assert!(actions
+        .iter()
+        .all(|(_, action)| *action == ImportAction::Skipped));
+    let actions = store
+        .import_store(&other_dir, &[], ConflictPolicy::KeepBoth)
+        .await
+        .unwrap();
+    assert!(actions.contains(&(
+        hello.to_vec(),
+        ImportAction::Renamed(vec!["hello (1).txt".to_owned()])
+    )));
+}
+
+#[tokio::test]
+async fn ls_summaries() {
+    let num_test = 83;
+    let mut store = init_test(num_test).await;
+
+    let note = ["notes".to_owned(), "todo.txt".to_owned()];
+    store
+        .create_resource(
+            &note,
+            "todo",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/hello.txt").compat(),
+        )
+        .await
+        .unwrap();
+    store
+        .import_file("./tests/fixtures/hello.txt")
+        .await
+        .unwrap();
+
+    let root = store.ls_summaries(&[]).unwrap();
+    assert_eq!(root.len(), 1);
+    assert_eq!(root[0].name, "hello.txt");
+    assert_eq!(root[0].mime_type, "text/plain");
+
+    let notes = store.ls_summaries(&["notes".to_owned()]).unwrap();
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].id.to_string(), "notes/todo.txt");
+    let metadata = store.get_metadata(&note).await.unwrap();
+    assert_eq!(
+        notes[0].size,
+        metadata.get_variant("default").unwrap().size()
+    );
+
+    // The summaries follow the updates of the default variant.
+    let content = b"{}".as_slice();
+    store
+        .update_variant(
+            &note,
+            "default",
+            &VariantMetadata::new(content.len() as _, "application/json"),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    let notes = store.ls_summaries(&["notes".to_owned()]).unwrap();
+    assert_eq!(notes[0].mime_type, "application/json");
+    assert_eq!(notes[0].size, 2);
+}
+
+#[tokio::test]
+async fn animated_images() {
+    use docstore::transformers::thumbnailer::ANIMATED_PREVIEW_VARIANT;
+
+    let num_test = 84;
+    let mut store = init_test(num_test).await;
+
+    let still = ["red_square.png".to_owned()];
+    let animated = ["animated.gif".to_owned()];
+    for path in [&still, &animated] {
+        store
+            .import_file(format!("./tests/fixtures/{}", path[0]))
+            .await
+            .unwrap();
+    }
+
+    // Only the first frame is decoded for the thumbnail and the properties.
+    let properties = store.get_properties(&animated, "default").unwrap();
+    assert!(properties.contains(&("animated".to_owned(), PropertyValue::Integer(1))));
+    assert!(properties.contains(&("width".to_owned(), PropertyValue::Integer(4))));
+    let metadata = store.get_metadata(&animated).await.unwrap();
+    assert!(metadata.has_variant(THUMBNAIL_VARIANT));
+    assert!(!metadata.has_variant(ANIMATED_PREVIEW_VARIANT));
+
+    let properties = store.get_properties(&still, "default").unwrap();
+    assert!(!properties.iter().any(|(name, _)| name == "animated"));
+
+    // Animated previews are only created when enabled.
+    store
+        .set_settings(&ThumbnailSettings {
+            animated_previews: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let content = std::fs::read("./tests/fixtures/animated.gif").unwrap();
+    store
+        .create_resource(
+            &["preview.gif".to_owned()],
+            "animated",
+            &VariantMetadata::new(content.len() as _, "image/gif"),
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    let metadata = store
+        .get_metadata(&["preview.gif".to_owned()])
+        .await
+        .unwrap();
+    let preview = metadata.get_variant(ANIMATED_PREVIEW_VARIANT).unwrap();
+    assert_eq!(preview.mime_type(), "image/gif");
+    let bytes = store
+        .get_variant_vec(ANIMATED_PREVIEW_VARIANT, &["preview.gif".to_owned()])
+        .await
+        .unwrap();
+    assert!(bytes.starts_with(b"GIF89a"));
+}
+
+#[tokio::test]
+async fn recovery_shares() {
+    use docstore::recovery_shares::ShareError;
+
+    let path = ["precious.txt".to_owned()];
+    let root_dir = PathBuf::from("./tests/data85");
+
+    let shares = {
+        let mut store = init_test(85).await;
+        store
+            .create_resource(
+                &path,
+                "precious",
+                &VariantMetadata::new(0, "text/plain"),
+                HashSet::new(),
+                fixture_file("./tests/fixtures/hello.txt").compat(),
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            store.create_recovery_shares(3, 4),
+            Err(StoreError::Shares(ShareError::InvalidThreshold(4, 3)))
+        ));
+        store.create_recovery_shares(5, 3).unwrap()
+    };
+    assert_eq!(shares.len(), 5);
+    assert!(shares
+        .iter()
+        .all(|share| share.starts_with("docstore-share-")));
+
+    std::fs::remove_file(root_dir.join("access.key")).unwrap();
+
+    // Fewer shares than the threshold, or a mistyped one, restore nothing.
+    assert!(matches!(
+        ResourceStore::recover_from_shares(&root_dir, &shares[..2]).await,
+        Err(StoreError::Shares(ShareError::NotEnoughShares(3, 2)))
+    ));
+    let mut mistyped = shares[0].clone();
+    mistyped.replace_range(20..21, if &mistyped[20..21] == "0" { "1" } else { "0" });
+    assert!(matches!(
+        ResourceStore::recover_from_shares(
+            &root_dir,
+            &[mistyped, shares[1].clone(), shares[2].clone()]
+        )
+        .await,
+        Err(StoreError::Shares(ShareError::InvalidShare(_)))
+    ));
+    assert!(!root_dir.join("access.key").exists());
+
+    // Any 3 shares restore the access key, even typed back in uppercase.
+    ResourceStore::recover_from_shares(
+        &root_dir,
+        &[
+            shares[4].to_uppercase(),
+            shares[1].clone(),
+            shares[3].clone(),
+        ],
+    )
+    .await
+    .unwrap();
+    let store = get_test_store(85).await;
+    assert_eq!(
+        store.get_variant_vec("default", &path).await.unwrap(),
+        std::fs::read("./tests/fixtures/hello.txt").unwrap()
+    );
+}
+
+#[tokio::test]
+async fn diff_revisions() {
+    use docstore::changes::DiffKind;
+
+    let num_test = 86;
+    let mut store = init_test(num_test).await;
+
+    let paths: Vec<Vec<String>> = ["a.txt", "b.txt", "c.txt"]
+        .iter()
+        .map(|name| vec![(*name).to_owned()])
+        .collect();
+    for path in &paths[..2] {
+        store
+            .create_resource(
+                path,
+                "some text",
+                &VariantMetadata::new(0, "text/plain"),
+                HashSet::new(),
+                fixture_file("./tests/fixtures/hello.txt").compat(),
+            )
+            .await
+            .unwrap();
+    }
+    let created = store.root_revision().await.unwrap();
+
+    store.update_desc(&paths[0], "other text").await.unwrap();
+    store
+        .add_variant(
+            &paths[1],
+            "summary",
+            &VariantMetadata::new(0, "text/plain"),
+            Cursor::new(b"hello".as_slice()).compat(),
+        )
+        .await
+        .unwrap();
+    // Created and deleted meanwhile.
+    store
+        .create_resource(
+            &paths[2],
+            "short lived",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/hello.txt").compat(),
+        )
+        .await
+        .unwrap();
+    store.delete_resource(&paths[2]).await.unwrap();
+    let head = store.root_revision().await.unwrap();
+
+    let diffs = store.diff(created, head).await.unwrap();
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(diffs[0].path, paths[0]);
+    assert_eq!(diffs[0].kind, DiffKind::Updated);
+    assert!(diffs[0].metadata_changed);
+    assert!(diffs[0].variants.is_empty());
+    assert_eq!(diffs[1].path, paths[1]);
+    assert_eq!(diffs[1].kind, DiffKind::Updated);
+    assert!(diffs[1].variants.contains("summary"));
+
+    let diffs = store.diff(0, head).await.unwrap();
+    assert_eq!(diffs.len(), 2);
+    assert!(diffs.iter().all(|diff| diff.kind == DiffKind::Created));
+    assert!(diffs[1].variants.contains("default"));
+
+    store.delete_resource(&paths[0]).await.unwrap();
+    let last = store.root_revision().await.unwrap();
+    let diffs = store.diff(last - 1, last).await.unwrap();
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].kind, DiffKind::Deleted);
+
+    assert!(matches!(
+        store.diff(last, created).await,
+        Err(StoreError::InvalidRevisions(_, _))
+    ));
+    assert!(store.diff(0, last + 1).await.is_err());
+}
+
+#[tokio::test]
+async fn route_imports() {
+    use docstore::rules::{RouteRule, RouteRules};
+
+    let num_test = 87;
+    let mut store = init_test(num_test).await;
+
+    let mut rules = RouteRules::categories();
+    rules
+        .rules
+        .insert(0, RouteRule::new("image/png", &["photos", "png"]));
+    store.set_settings(&rules).await.unwrap();
+
+    for fixture in ["red_square.png", "hello.txt", "archive.zip"] {
+        store
+            .import_file(format!("./tests/fixtures/{}", fixture))
+            .await
+            .unwrap();
+    }
+    for path in [
+        vec!["photos", "png", "red_square.png"],
+        vec!["documents", "hello.txt"],
+        // No rule matches zip archives.
+        vec!["archive.zip"],
+    ] {
+        let path: Vec<String> = path.into_iter().map(|name| name.to_owned()).collect();
+        assert!(store.get_metadata(&path).await.is_ok(), "{:?}", path);
+    }
+
+    // Conflicts are detected in the routed container.
+    assert_eq!(
+        store
+            .import_file_with_policy("./tests/fixtures/hello.txt", ConflictPolicy::KeepBoth)
+            .await
+            .unwrap(),
+        ImportAction::Renamed(vec!["documents".to_owned(), "hello (1).txt".to_owned()])
+    );
+
+    // Explicit paths are not routed.
+    store
+        .create_resource(
+            &["notes.txt".to_owned()],
+            "notes",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/hello.txt").compat(),
+        )
+        .await
+        .unwrap();
+    assert!(store.get_metadata(&["notes.txt".to_owned()]).await.is_ok());
+}
+
+#[tokio::test]
+async fn query_rows() {
+    let num_test = 88;
+    let mut store = init_test(num_test).await;
+
+    for (name, tag) in [
+        ("one.txt", "work"),
+        ("two.txt", "work"),
+        ("three.txt", "home"),
+    ] {
+        store
+            .create_resource(
+                &[name.to_owned()],
+                name,
+                &VariantMetadata::new(0, "text/plain"),
+                HashSet::from([tag.to_owned()]),
+                fixture_file("./tests/fixtures/hello.txt").compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let result = store
+        .query_rows(
+            "SELECT tag, COUNT(*) AS count FROM tags GROUP BY tag ORDER BY tag",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(result.columns, vec!["tag", "count"]);
+    assert_eq!(
+        result.rows,
+        vec![
+            vec![Some("home".into()), Some(PropertyValue::Integer(1))],
+            vec![Some("work".into()), Some(PropertyValue::Integer(2))],
+        ]
+    );
+
+    let result = store
+        .query_rows(
+            "SELECT COUNT(*) FROM resources WHERE mime = ?",
+            &["text/plain".into()],
+        )
+        .unwrap();
+    assert_eq!(result.rows, vec![vec![Some(PropertyValue::Integer(3))]]);
+
+    // Writes, and reads of the other tables, are refused.
+    for sql in [
+        "DELETE FROM tags",
+        "UPDATE resources SET size = 0",
+        "SELECT * FROM fts",
+        "SELECT * FROM sqlite_master",
+        "PRAGMA table_info(resources)",
+    ] {
+        assert!(
+            matches!(store.query_rows(sql, &[]), Err(StoreError::QueryRefused(_))),
+            "{}",
+            sql
+        );
+    }
+    assert_eq!(
+        store
+            .query_rows("SELECT COUNT(*) FROM tags", &[])
+            .unwrap()
+            .rows,
+        vec![vec![Some(PropertyValue::Integer(3))]]
+    );
+}
+
+#[tokio::test]
+async fn inline_content() {
+    let num_test = 89;
+    let mut store = init_test(num_test).await;
+
+    let path = ["contact.json".to_owned()];
+    let content = b"{\"name\": \"Jane\"}".as_slice();
+    store
+        .create_resource(
+            &path,
+            "Jane",
+            &VariantMetadata::new(0, "application/json"),
+            HashSet::new(),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+    store
+        .add_variant(
+            &path,
+            "copy",
+            &VariantMetadata::new(0, "application/json"),
+            Cursor::new(content).compat(),
+        )
+        .await
+        .unwrap();
+
+    // Small content doesn't need blocks of its own.
+    let meta = store.get_metadata(&path).await.unwrap();
+    for name in ["default", "copy"] {
+        let variant = meta.get_variant(name).unwrap();
+        assert_eq!(variant.size(), content.len() as u64);
+        assert_eq!(variant.stored_size(), Some(0));
+        assert_eq!(store.get_variant_vec(name, &path).await.unwrap(), content);
+        let chunks: Vec<Vec<u8>> = store
+            .get_variant(name, &path)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), content);
+        assert!(store.verify_variant(&path, name).await.unwrap());
+    }
+    assert_eq!(store.search("Jane").await.unwrap().len(), 1);
+
+    // Content growing past the threshold moves to blocks, and back.
+    let large = vec![b'a'; 10_000];
+    for content in [large.as_slice(), b"small".as_slice()] {
+        for name in ["default", "copy"] {
+            store
+                .update_variant(
+                    &path,
+                    name,
+                    &VariantMetadata::new(0, "text/plain"),
+                    Cursor::new(content).compat(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(store.get_variant_vec(name, &path).await.unwrap(), content);
+        }
+    }
+    drop(store);
+
+    let store = get_test_store(num_test).await;
+    assert_eq!(
+        store.get_variant_vec("default", &path).await.unwrap(),
+        b"small"
+    );
+}
+
+#[tokio::test]
+async fn index_options() {
+    use docstore::store::{IndexOptions, JournalMode, Synchronous};
+
+    let num_test = 90;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    let wal = Path::new(&root_dir).join("index.sqlite-wal");
+
+    {
+        let mut store = ResourceStore::builder(&root_dir)
+            .index_options(IndexOptions {
+                journal_mode: JournalMode::Delete,
+                synchronous: Synchronous::Off,
+                cache_size: Some(512),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        store
+            .import_file("./tests/fixtures/hello.txt")
+            .await
+            .unwrap();
+        assert_eq!(store.search("hello").await.unwrap().len(), 1);
+        assert!(!wal.exists());
+    }
+
+    // The journal mode is switched back when reopening with the defaults.
+    let mut store = get_test_store(num_test).await;
+    store
+        .import_file("./tests/fixtures/lorem_ipsum.txt")
+        .await
+        .unwrap();
+    assert!(wal.exists());
+    assert_eq!(store.search("hello").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn search_facets() {
+    let num_test = 91;
+    let mut store = init_test(num_test).await;
+
+    for (name, mime, tags) in [
+        ("report.txt", "text/plain", vec!["work", "draft"]),
+        ("report.md", "text/markdown", vec!["work"]),
+        ("report.json", "application/json", vec![]),
+        ("other.txt", "text/plain", vec!["work"]),
+    ] {
+        store
+            .create_resource(
+                &[name.to_owned()],
+                name,
+                &VariantMetadata::new(0, mime),
+                tags.into_iter().map(|tag| tag.to_owned()).collect(),
+                fixture_file("./tests/fixtures/hello.txt").compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let (results, facets) = store.search_with_facets("report").await.unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        facets.tags,
+        vec![("work".to_owned(), 2), ("draft".to_owned(), 1)]
+    );
+    assert_eq!(
+        facets.mime_categories,
+        vec![("text".to_owned(), 2), ("application".to_owned(), 1)]
+    );
+    assert_eq!(
+        facets.years,
+        vec![(chrono::Utc::now().format("%Y").to_string(), 3)]
+    );
+
+    let (results, facets) = store.search_with_facets("nothing").await.unwrap();
+    assert!(results.is_empty());
+    assert!(facets.tags.is_empty() && facets.years.is_empty());
+}
+
+#[tokio::test]
+async fn snapshots() {
+    use docstore::store::BEFORE_RESTORE_SNAPSHOT;
+
+    let num_test = 92;
+    let mut store = init_test(num_test).await;
+
+    let kept = ["kept.txt".to_owned()];
+    let added = ["added.txt".to_owned()];
+    store
+        .create_resource(
+            &kept,
+            "kept",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/hello.txt").compat(),
+        )
+        .await
+        .unwrap();
+    let snapshot = store.tag_snapshot("before-cleanup").await.unwrap();
+    assert_eq!(snapshot.forest_cid, store.forest_cid().await.unwrap());
+    assert!(matches!(
+        store.tag_snapshot("before-cleanup").await,
+        Err(StoreError::SnapshotExists(_))
+    ));
+
+    store.delete_resource(&kept).await.unwrap();
+    store
+        .create_resource(
+            &added,
+            "added",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture_file("./tests/fixtures/hello.txt").compat(),
+        )
+        .await
+        .unwrap();
+
+    // The blocks of the snapshot survive compaction.
+    store.compact(CompactOptions::default()).await.unwrap();
+    store.restore_snapshot("before-cleanup").await.unwrap();
+    assert!(store.get_metadata(&added).await.is_err());
+    assert_eq!(
+        store.get_variant_vec("default", &kept).await.unwrap(),
+        std::fs::read("./tests/fixtures/hello.txt").unwrap()
+    );
+    assert_eq!(store.search("kept").await.unwrap().len(), 1);
+    assert!(store.search("added").await.unwrap().is_empty());
+    assert!(matches!(
+        store.restore_snapshot("unknown").await,
+        Err(StoreError::NoSuchSnapshot(_))
+    ));
+    drop(store);
+
+    let mut store = get_test_store(num_test).await;
+    assert!(store.get_metadata(&kept).await.is_ok());
+    let names: Vec<String> = store
+        .snapshots()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|snapshot| snapshot.name)
+        .collect();
+    assert_eq!(names, vec!["before-cleanup", BEFORE_RESTORE_SNAPSHOT]);
+
+    // The replaced state is kept, to undo the restore.
+    store.compact(CompactOptions::default()).await.unwrap();
+    store
+        .restore_snapshot(BEFORE_RESTORE_SNAPSHOT)
+        .await
+        .unwrap();
+    assert!(store.get_metadata(&kept).await.is_err());
+    assert_eq!(
+        store.get_variant_vec("default", &added).await.unwrap(),
+        std::fs::read("./tests/fixtures/hello.txt").unwrap()
+    );
+    store
+        .restore_snapshot(BEFORE_RESTORE_SNAPSHOT)
+        .await
+        .unwrap();
+    assert!(store.get_metadata(&kept).await.is_ok());
+
+    assert!(store.delete_snapshot("before-cleanup").await.unwrap());
+    assert!(store
+        .delete_snapshot(BEFORE_RESTORE_SNAPSHOT)
+        .await
+        .unwrap());
+    assert!(store.snapshots().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn import_photo_folder() {
+    use docstore::photos::{BURST_VARIANT, MOTION_VARIANT};
+
+    let num_test = 93;
+    let mut store = init_test(num_test).await;
+
+    let folder = PathBuf::from(format!("./tests/data{}-dcim", num_test));
+    let _ = std::fs::remove_dir_all(&folder);
+    let camera = folder.join("100APPLE");
+    std::fs::create_dir_all(&camera).unwrap();
+    for name in [
+        "IMG_0001.PNG",
+        "IMG_20240101_120000_BURST001_COVER.png",
+        "IMG_20240101_120000_BURST002.png",
+        "IMG_0003.png",
+    ] {
+        std::fs::copy("./tests/fixtures/red_square.png", camera.join(name)).unwrap();
+    }
+    std::fs::write(camera.join("IMG_0001.MOV"), b"not really a video").unwrap();
+    std::fs::write(camera.join("IMG_0001.AAE"), b"<plist/>").unwrap();
+
+    let container = ["photos".to_owned()];
+    let actions = store
+        .import_photo_folder(&folder, &container, ConflictPolicy::Fail)
+        .await
+        .unwrap();
+    assert_eq!(actions.len(), 3);
+    assert!(actions
+        .iter()
+        .all(|(_, action)| *action == ImportAction::Created));
+
+    let live = ["photos".to_owned(), "IMG_0001.PNG".to_owned()];
+    let metadata = store.get_metadata(&live).await.unwrap();
+    let motion = metadata.get_variant(MOTION_VARIANT).unwrap();
+    assert_eq!(motion.mime_type(), "video/quicktime");
+    assert_eq!(
+        store.get_variant_vec(MOTION_VARIANT, &live).await.unwrap(),
+        b"not really a video"
+    );
+
+    let burst = [
+        "photos".to_owned(),
+        "IMG_20240101_120000_BURST001_COVER.png".to_owned(),
+    ];
+    let metadata = store.get_metadata(&burst).await.unwrap();
+    assert!(metadata.has_variant(BURST_VARIANT));
+    assert!(!metadata.has_variant(MOTION_VARIANT));
+
+    let single = ["photos".to_owned(), "IMG_0003.png".to_owned()];
+    assert!(store.get_metadata(&single).await.is_ok());
+
+    // Identical captures are skipped when importing again.
+    let actions = store
+        .import_photo_folder(&folder, &container, ConflictPolicy::SkipIfIdentical)
+        .await
+        .unwrap();
+    assert!(actions
+        .iter()
+        .all(|(_, action)| *action == ImportAction::Skipped));
+    let _ = std::fs::remove_dir_all(&folder);
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn testing_helpers() {
+    use docstore::access::{AccessTokens, Scope};
+    use docstore::clock::Clock;
+    use docstore::store::CloneOptions;
+    use docstore::testing::{fixture, TestStore, START_TIMESTAMP};
+
+    // Stores with the same seed create the same ids.
+    let mut ids = vec![];
+    let mut root_dir = PathBuf::new();
+    for _ in 0..2 {
+        let mut store = TestStore::with_seed(7).await.unwrap();
+        let (token, secret) = store
+            .issue_access_token("phone", Scope::ReadOnly, &[], Some(Duration::hours(1)))
+            .await
+            .unwrap();
+        assert_eq!(token.expires, Some(START_TIMESTAMP + 3600));
+        assert!(secret.starts_with(&token.id));
+        ids.push(token.id);
+        root_dir = store.root_dir().to_path_buf();
+    }
+    assert_eq!(ids[0], ids[1]);
+    // The directory is removed with the store.
+    assert!(!root_dir.exists());
+
+    let mut store = TestStore::new().await.unwrap();
+    let path = ["hello.txt".to_owned()];
+    store
+        .create_resource(
+            &path,
+            "Hello",
+            &VariantMetadata::new(0, "text/plain"),
+            HashSet::new(),
+            fixture("tests/fixtures/hello.txt").unwrap(),
+        )
+        .await
+        .unwrap();
+    store.clock().advance(Duration::minutes(5));
+    store.delete_resource(&path).await.unwrap();
+
+    let changes = store.changes_since(0).await.unwrap();
+    assert_eq!(changes[0].timestamp, START_TIMESTAMP);
+    assert_eq!(changes.last().unwrap().timestamp, START_TIMESTAMP + 300);
+
+    // Tokens expire with the store clock.
+    let (_, secret) = store
+        .issue_access_token("laptop", Scope::ReadOnly, &[], Some(Duration::hours(1)))
+        .await
+        .unwrap();
+    store.clock().advance(Duration::hours(2));
+    assert!(store.check_access_token(&secret).await.is_err());
+    let now = store.clock().now();
+    let tokens: AccessTokens = store.get_settings().await.unwrap().unwrap_or_default();
+    assert!(tokens.tokens.values().all(|token| token.is_expired(now)));
+
+    let fixtures = store.root_dir().join("fixtures");
+    std::fs::create_dir_all(&fixtures).unwrap();
+    std::fs::write(fixtures.join("a.txt"), "first").unwrap();
+    std::fs::write(fixtures.join("b.txt"), "second").unwrap();
+    assert_eq!(store.import_fixtures(&fixtures).await.unwrap(), 2);
+    assert!(store.get_metadata(&["b.txt".to_owned()]).await.is_ok());
+
+    // The blocks and the index are kept in memory, and can be copied to
+    // a store in files.
+    assert!(!store.root_dir().join("blockstore").exists());
+    assert!(!store.root_dir().join("index.sqlite").exists());
+    assert_eq!(store.search("second").await.unwrap().len(), 1);
+    let copy_dir = store.root_dir().join("copy");
+    store
+        .clone_to(&copy_dir, CloneOptions::default())
+        .await
+        .unwrap();
+    let copy = ResourceStore::new(&copy_dir).await.unwrap();
+    assert!(copy.get_metadata(&["b.txt".to_owned()]).await.is_ok());
+    assert_eq!(copy.search("second").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn manual_clock() {
+    use chrono::{TimeZone, Utc};
+    use docstore::clock::ManualClock;
+    use docstore::properties::PropertyValue;
+    use std::sync::Arc;
+
+    let num_test = 94;
+    let path = PathBuf::from(format!("./tests/data{}", num_test));
+    let _ = std::fs::remove_dir_all(&path);
+    let clock = Arc::new(ManualClock::new(
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    ));
+    let mut store = ResourceStore::builder(&path)
+        .clock(clock.clone())
+        .build()
+        .await
+        .unwrap();
+
+    let old = ["old".to_owned()];
+    let new = ["new".to_owned()];
+    let variant = VariantMetadata::new(0, "text/plain");
+    store
+        .create_resource(
+            &old,
+            "old",
+            &variant,
+            HashSet::new(),
+            Cursor::new(vec![]).compat(),
+        )
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        store.get_variant_vec("default", &old).await.unwrap();
+    }
+
+    clock.advance(Duration::days(100));
+    store
+        .create_resource(
+            &new,
+            "new",
+            &variant,
+            HashSet::new(),
+            Cursor::new(vec![]).compat(),
+        )
+        .await
+        .unwrap();
+    store.get_variant_vec("default", &new).await.unwrap();
+
+    // The modification dates come from the clock.
+    let rows = store
+        .query_rows(
+            "SELECT strftime('%Y-%m-%d', modified) FROM resources ORDER BY id",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(
+        rows.rows,
+        vec![
+            vec![Some(PropertyValue::Text("2024-04-10".into()))],
+            vec![Some(PropertyValue::Text("2024-01-01".into()))],
+        ]
+    );
+
+    // Frecency scores decay with the time of the clock: the old resource,
+    // visited more but 100 days ago, comes second.
+    let results = store.suggested(10).await.unwrap();
+    assert_eq!(results[0].0.to_string(), "new");
+
+    // Access tokens expire with the clock.
+    let (_, secret) = store
+        .issue_access_token(
+            "phone",
+            docstore::access::Scope::ReadOnly,
+            &[],
+            Some(Duration::days(1)),
+        )
+        .await
+        .unwrap();
+    assert!(store.check_access_token(&secret).await.is_ok());
+    clock.advance(Duration::days(2));
+    assert!(store.check_access_token(&secret).await.is_err());
+
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn tokenization() {
+    use docstore::tokenizer::Tokenization;
+
+    let num_test = 95;
+    let path = ["case.txt".to_owned()];
+    {
+        let mut store = init_test(num_test).await;
+        let content = "iPhone用ケース かがみ".as_bytes();
+        let variant = VariantMetadata::new(content.len() as _, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "東京タワー",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content).compat(),
+            )
+            .await
+            .unwrap();
+
+        // Runs of CJK text are single words by default.
+        assert!(store.suggest("ケー", 10).unwrap().is_empty());
+        assert_eq!(store.suggest("東京", 10).unwrap(), vec!["東京タワー"]);
+
+        // Kana keep their marks.
+        assert_eq!(store.search("かが").await.unwrap().len(), 1);
+        assert!(store.search("かか").await.unwrap().is_empty());
+    }
+    {
+        // Opening the store with another tokenization rebuilds the index.
+        let store = ResourceStore::builder(&format!("./tests/data{}", num_test))
+            .tokenization(Tokenization::Unicode)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(store.suggest("ケー", 10).unwrap(), vec!["ケース"]);
+        assert_eq!(store.suggest("iph", 10).unwrap(), vec!["iphone"]);
+        assert_eq!(store.search("タワー").await.unwrap().len(), 1);
+    }
+    assert_eq!(
+        Tokenization::Unicode.words("iphone用ケース"),
+        vec!["iphone", "用", "ケース"]
+    );
+}
+
+#[tokio::test]
+async fn search_limits() {
+    use docstore::store::SearchLimits;
+
+    let num_test = 96;
+    let path = PathBuf::from(format!("./tests/data{}", num_test));
+    let _ = std::fs::remove_dir_all(&path);
+    let mut store = ResourceStore::builder(&path)
+        .search_limits(SearchLimits {
+            max_results: Some(2),
+            ..Default::default()
+        })
+        .build()
+        .await
+        .unwrap();
+
+    for (name, text) in [
+        ("a.txt", "common one"),
+        ("b.txt", "common two"),
+        ("c.txt", "common three"),
+    ] {
+        let variant = VariantMetadata::new(text.len() as _, "text/plain");
+        store
+            .create_resource(
+                &[name.to_owned()],
+                name,
+                &variant,
+                HashSet::new(),
+                Cursor::new(text.as_bytes()).compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    assert!(matches!(
+        store.search("common").await,
+        Err(StoreError::TooManyResults(2))
+    ));
+    assert!(matches!(
+        store.search_in("common", &[]).await,
+        Err(StoreError::TooManyResults(2))
+    ));
+    assert_eq!(store.search("two").await.unwrap().len(), 1);
+    assert_eq!(store.search("three").await.unwrap().len(), 1);
+    drop(store);
+
+    // Without limits, all the results are returned.
+    let store = ResourceStore::builder(&path)
+        .search_limits(SearchLimits {
+            max_results: None,
+            timeout: None,
+        })
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(store.search("common").await.unwrap().len(), 3);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+#[tokio::test]
+async fn error_context() {
+    let mut store = init_test(97).await;
+
+    // Low level errors tell which operation failed, and on what.
+    let missing = Path::new("./tests/fixtures/missing.txt");
+    let err = store.import_file(missing).await.unwrap_err();
+    let context = err.context().unwrap();
+    assert_eq!(context.operation, "import_file");
+    assert_eq!(context.file.as_deref(), Some(missing));
+    assert!(matches!(err.root(), StoreError::IO(_)));
+    assert!(err
+        .to_string()
+        .starts_with("import_file (./tests/fixtures/missing.txt): "));
+    assert!(std::error::Error::source(&err).is_some());
+
+    // Errors which already describe the failure are left as is.
+    let err = store
+        .get_variant_vec("default", &["nothing".to_owned()])
+        .await
+        .unwrap_err();
+    assert!(err.context().is_none());
+    assert!(matches!(err, StoreError::NoSuchResource(_)));
+}
+
+#[tokio::test]
+async fn relations() {
+    use docstore::resource::{Relation, ALBUM_MEMBER, ATTACHMENT_OF};
+
+    let num_test = 98;
+    let note = ["note.md".to_owned()];
+    let scan = ["scan.pdf".to_owned()];
+    let photo = ["photo.jpg".to_owned()];
+    let album = ["holidays".to_owned()];
+    {
+        let mut store = init_test(num_test).await;
+        let variant = VariantMetadata::new(0, "application/octet-stream");
+        for path in [&note, &scan, &photo, &album] {
+            store
+                .create_resource(
+                    path,
+                    &path[0],
+                    &variant,
+                    HashSet::new(),
+                    Cursor::new(vec![]).compat(),
+                )
+                .await
+                .unwrap();
+        }
+
+        store
+            .add_relation(&scan, ATTACHMENT_OF, &note)
+            .await
+            .unwrap();
+        store
+            .add_relation(&photo, ATTACHMENT_OF, &note)
+            .await
+            .unwrap();
+        store
+            .add_relation(&photo, ALBUM_MEMBER, &album)
+            .await
+            .unwrap();
+        assert!(matches!(
+            store
+                .add_relation(&photo, ALBUM_MEMBER, &["missing".to_owned()])
+                .await,
+            Err(StoreError::NoSuchResource(_))
+        ));
+
+        let metadata = store.get_metadata(&photo).await.unwrap();
+        assert_eq!(
+            metadata.relations(),
+            &[
+                Relation::new(ATTACHMENT_OF, &note),
+                Relation::new(ALBUM_MEMBER, &album)
+            ]
+        );
+
+        // Relations are found from both ends.
+        let names = |results: Vec<(docstore::resource::ResourceId, _)>| -> Vec<String> {
+            results.into_iter().map(|(id, _)| id.to_string()).collect()
+        };
+        assert_eq!(
+            names(store.get_related(&note, Some(ATTACHMENT_OF)).await.unwrap()),
+            vec!["photo.jpg", "scan.pdf"]
+        );
+        assert_eq!(
+            names(store.get_related(&photo, None).await.unwrap()),
+            vec!["holidays", "note.md"]
+        );
+        assert!(store
+            .get_related(&note, Some(ALBUM_MEMBER))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let changes = store.changes_since(0).await.unwrap();
+        assert!(changes
+            .iter()
+            .any(|change| change.op
+                == ChangeOp::AddRelation(ALBUM_MEMBER.to_owned(), album.to_vec())));
+
+        store
+            .remove_relation(&scan, ATTACHMENT_OF, &note)
+            .await
+            .unwrap();
+        store.delete_resource(&album).await.unwrap();
+        assert_eq!(
+            names(store.get_related(&photo, None).await.unwrap()),
+            vec!["note.md"]
+        );
+    }
+    {
+        // Relations are rebuilt with the index.
+        let mut store = get_test_store(num_test).await;
+        store.reindex().await.unwrap();
+        assert_eq!(
+            store
+                .get_related(&note, None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id.to_string())
+                .collect::<Vec<_>>(),
+            vec!["photo.jpg"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn revision_stamps() {
+    let num_test = 99;
+    let path = ["note.txt".to_owned()];
+    let revision;
+    {
+        let mut store = init_test(num_test).await;
+        assert_eq!(store.revision(), 0);
+
+        let variant = VariantMetadata::new(0, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "first note",
+                &variant,
+                HashSet::new(),
+                Cursor::new(vec![]).compat(),
+            )
+            .await
+            .unwrap();
+
+        let results = store.stamp(store.search("note").await.unwrap());
+        assert_eq!(results.results.len(), 1);
+        assert!(store.is_current(results.revision));
+
+        // Searching doesn't change the revision, changing a resource does.
+        let _ = store.search("first").await.unwrap();
+        assert!(store.is_current(results.revision));
+        store.add_tag(&path, "work").await.unwrap();
+        assert!(!store.is_current(results.revision));
+        assert!(store.revision() > results.revision);
+
+        // Failed changes leave the results current.
+        let results = store.stamp(store.search("note").await.unwrap());
+        assert!(store
+            .add_tag(&["missing".to_owned()], "work")
+            .await
+            .is_err());
+        assert!(store.is_current(results.revision));
+        revision = store.revision();
+    }
+    {
+        // The revision continues from the change journal.
+        let store = get_test_store(num_test).await;
+        assert_eq!(store.revision(), revision);
+        assert_eq!(store.revision(), store.root_revision().await.unwrap());
+    }
+}
+
+#[tokio::test]
+async fn streamed_json_indexing() {
+    let num_test = 100;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+
+    let mut store = ResourceStore::builder(&root_dir)
+        .max_indexed_size(1024)
+        .build()
+        .await
+        .unwrap();
+
+    // An export much larger than the limit, whose text is cut.
+    let places: Vec<String> = (0..3000)
+        .map(|i| format!(r#"{{"url": "https://example.com/{i}", "title": "title{i:04}"}}"#))
+        .collect();
+    // A single place larger than the limit is skipped.
+    let large = format!(
+        r#"{{"url": "https://example.com", "title": "{}"}}"#,
+        "x".repeat(2000)
+    );
+    let resources = [
+        ("export.json", format!("[\n{}\n]", places.join(",\n"))),
+        (
+            "mixed.json",
+            format!(
+                r#"[{{"title": "Alpha, [or] \"first\""}}, {}, {{"title": "Gamma"}}]"#,
+                large
+            ),
+        ),
+    ];
+    for (name, content) in &resources {
+        let variant = VariantMetadata::new(content.len() as _, "application/x-places+json");
+        store
+            .create_resource(
+                &[name.to_string()],
+                "",
+                &variant,
+                HashSet::new(),
+                Cursor::new(content.clone().into_bytes()).compat(),
+            )
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(store.search("title0001").await.unwrap().len(), 1);
+    assert!(store.search("title2999").await.unwrap().is_empty());
+    assert_eq!(store.search("first").await.unwrap().len(), 1);
+    assert_eq!(store.search("gamma").await.unwrap().len(), 1);
+    assert!(store.search("xxxx").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn container_metadata() {
+    let num_test = 101;
+    let taxes = ["archive".to_owned(), "Taxes 2023".to_owned()];
+    let archive = ["archive".to_owned()];
+    {
+        let mut store = init_test(num_test).await;
+        let variant = VariantMetadata::new(0, "text/plain");
+        let mut path = taxes.to_vec();
+        path.push("return.txt".to_owned());
+        store
+            .create_resource(
+                &path,
+                "Tax return",
+                &variant,
+                HashSet::new(),
+                Cursor::new(vec![]).compat(),
+            )
+            .await
+            .unwrap();
+
+        // Containers are found by name once they hold resources.
+        let found = store.search_containers("taxes 2023").await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, taxes.to_vec());
+        assert!(found[0].1.desc().is_empty());
+
+        store
+            .update_container_metadata(&archive, |metadata| {
+                metadata.set_desc("Paperwork for the accountant");
+                metadata.add_tag("finance");
+            })
+            .await
+            .unwrap();
+        let metadata = store.get_container_metadata(&archive).await.unwrap();
+        assert_eq!(metadata.desc(), "Paperwork for the accountant");
+        assert!(metadata.tags().contains("finance"));
+        for text in ["accountant", "finance", "archive"] {
+            let found = store.search_containers(text).await.unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].0, archive.to_vec());
+        }
+
+        // Containers are not resources.
+        assert!(store.search("accountant").await.unwrap().is_empty());
+        let changes = store.changes_since(0).await.unwrap();
+        assert_eq!(changes.last().unwrap().op, ChangeOp::UpdateContainer);
+        assert_eq!(changes.last().unwrap().path, archive.to_vec());
+        let revision = store.root_revision().await.unwrap();
+        assert!(store.diff(revision - 1, revision).await.unwrap().is_empty());
+
+        assert!(matches!(
+            store.get_container_metadata(&["missing".to_owned()]).await,
+            Err(StoreError::NoSuchContainer(_))
+        ));
+        assert!(matches!(
+            store
+                .update_container_metadata(&["missing".to_owned()], |metadata| {
+                    metadata.set_desc("nothing");
+                })
+                .await,
+            Err(StoreError::NoSuchContainer(_))
+        ));
+    }
+    {
+        // The container metadata is indexed again when rebuilding the index.
+        let mut store = get_test_store(num_test).await;
+        store.reindex().await.unwrap();
+        let found = store.search_containers("finance").await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1.desc(), "Paperwork for the accountant");
+    }
+}
+
+#[tokio::test]
+async fn undo() {
+    use docstore::store::UndoOp;
+
+    let num_test = 102;
+    let path = ["notes".to_owned(), "todo.txt".to_owned()];
+    {
+        let mut store = init_test(num_test).await;
+        assert!(store.undo().await.unwrap().is_none());
+
+        let variant = VariantMetadata::new(11, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "Todo list",
+                &variant,
+                HashSet::new(),
+                Cursor::new(b"buy flowers".to_vec()).compat(),
+            )
+            .await
+            .unwrap();
+        store
+            .add_variant(
+                &path,
+                "copy",
+                &variant,
+                Cursor::new(b"buy flowers".to_vec()).compat(),
+            )
+            .await
+            .unwrap();
+        store.add_tag(&path, "home").await.unwrap();
+        store.update_desc(&path, "Errands").await.unwrap();
+        store.delete_variant(&path, "copy").await.unwrap();
+        store.delete_resource(&path).await.unwrap();
+
+        let ops: Vec<UndoOp> = store
+            .undo_log()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.op)
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                UndoOp::AddTag("home".to_owned()),
+                UndoOp::UpdateMetadata,
+                UndoOp::DeleteVariant("copy".to_owned()),
+                UndoOp::DeleteResource,
+            ]
+        );
+
+        // The deleted resource comes back with its metadata.
+        let entry = store.undo().await.unwrap().unwrap();
+        assert_eq!(entry.op, UndoOp::DeleteResource);
+        assert_eq!(entry.path, path.to_vec());
+        let metadata = store.get_metadata(&path).await.unwrap();
+        assert_eq!(metadata.desc(), "Errands");
+        assert!(metadata.tags().contains("home"));
+        assert!(!metadata.has_variant("copy"));
+        assert_eq!(
+            store.get_variant_vec("default", &path).await.unwrap(),
+            b"buy flowers"
+        );
+        assert_eq!(store.search("errands").await.unwrap().len(), 1);
+    }
+    {
+        // The undo log persists.
+        let mut store = get_test_store(num_test).await;
+        store.undo().await.unwrap();
+        assert_eq!(
+            store.get_variant_vec("copy", &path).await.unwrap(),
+            b"buy flowers"
+        );
+        store.undo().await.unwrap();
+        assert_eq!(store.get_metadata(&path).await.unwrap().desc(), "Todo list");
+        store.undo().await.unwrap();
+        assert!(store.get_metadata(&path).await.unwrap().tags().is_empty());
+        assert!(store.undo().await.unwrap().is_none());
+
+        // An operation that can't be reverted is dropped.
+        store.delete_resource(&path).await.unwrap();
+        let variant = VariantMetadata::new(0, "text/plain");
+        store
+            .create_resource(
+                &path,
+                "New list",
+                &variant,
+                HashSet::new(),
+                Cursor::new(vec![]).compat(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.undo().await.unwrap_err().root(),
+            StoreError::ResourceExists(_)
+        ));
+        assert!(store.undo_log().await.unwrap().is_empty());
+    }
+    {
+        // Only the last operations are kept.
+        let root_dir = format!("./tests/data{}", num_test);
+        let mut store = ResourceStore::builder(&root_dir)
+            .undo_depth(2)
+            .build()
+            .await
+            .unwrap();
+        for tag in ["a", "b", "c"] {
+            store.add_tag(&path, tag).await.unwrap();
+        }
+        let log = store.undo_log().await.unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].op, UndoOp::AddTag("b".to_owned()));
+    }
+    {
+        // A torn log is reported instead of being replaced by an empty one.
+        let undo_log = format!("./tests/data{}/undo.cbor", num_test);
+        std::fs::write(&undo_log, b"torn").unwrap();
+        let mut store = get_test_store(num_test).await;
+        assert!(store.undo_log().await.is_err());
+        store.add_tag(&path, "d").await.unwrap();
+        assert!(store.undo().await.is_err());
+        assert_eq!(std::fs::read(&undo_log).unwrap(), b"torn");
+    }
+}
+
+#[tokio::test]
+async fn best_variant() {
+    use docstore::resource::ResourceMetadata;
+
+    let mut metadata = ResourceMetadata::new(
+        "photo",
+        &VariantMetadata::new(4_000_000, "image/jpeg"),
+        HashSet::new(),
+    );
+    metadata.add_variant("webp", &VariantMetadata::new(1_500_000, "image/webp"));
+    metadata.add_variant("thumbnail", &VariantMetadata::new(20_000, "image/jpeg"));
+    metadata.add_variant("text", &VariantMetadata::new(100, "text/plain"));
+    let best = |accepted: &[&str], max_size| {
+        metadata
+            .best_variant(accepted, max_size)
+            .map(|(name, _)| name.to_owned())
+    };
+
+    // The largest variant of the most preferred type.
+    assert_eq!(best(&[], None).unwrap(), "default");
+    assert_eq!(best(&["image/webp", "image/*"], None).unwrap(), "webp");
+    assert_eq!(
+        best(&["image/*;q=0.5", "image/webp"], None).unwrap(),
+        "webp"
+    );
+    assert_eq!(
+        best(&["image/*", "image/webp;q=0"], None).unwrap(),
+        "default"
+    );
+    assert_eq!(best(&["text/*", "*/*;q=0.1"], None).unwrap(), "text");
+    assert!(best(&["video/*"], None).is_none());
+
+    // Variants fitting the size limit are preferred.
+    assert_eq!(best(&["image/*"], Some(2_000_000)).unwrap(), "webp");
+    assert_eq!(best(&["image/jpeg"], Some(50_000)).unwrap(), "thumbnail");
+    assert_eq!(best(&["image/jpeg"], Some(1_000)).unwrap(), "thumbnail");
+
+    let num_test = 103;
+    let path = ["notes.txt".to_owned()];
+    let mut store = init_test(num_test).await;
+    store
+        .create_resource(
+            &path,
+            "notes",
+            &VariantMetadata::new(5, "text/plain"),
+            HashSet::new(),
+            Cursor::new(b"notes".to_vec()).compat(),
+        )
+        .await
+        .unwrap();
+    let (name, variant) = store
+        .best_variant(&path, &["text/plain"], None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(name, "default");
+    assert_eq!(variant.size(), 5);
+    assert!(store
+        .best_variant(&path, &["image/*"], None)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[cfg(feature = "http-client")]
+#[tokio::test]
+async fn import_url_resume() {
+    use docstore::store::ImportUrlOptions;
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::{Arc, Mutex};
+
+    let content: Vec<u8> = (0..500)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+
+    // A local server honoring range requests, recording the requested
+    // offsets.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/notes.txt", listener.local_addr().unwrap());
+    let offsets = Arc::new(Mutex::new(vec![]));
+    {
+        let content = content.clone();
+        let offsets = offsets.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut offset = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some(range) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        offset = range.trim().trim_end_matches('-').parse::<usize>().ok();
+                    }
+                }
+                offsets.lock().unwrap().push(offset);
+
+                let start = offset.unwrap_or(0);
+                let status = match offset {
+                    Some(_) => format!(
+                        "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                        start,
+                        content.len() - 1,
+                        content.len()
+                    ),
+                    None => "200 OK".to_owned(),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content.len() - start
+                )
+                .unwrap();
+                stream.write_all(&content[start..]).unwrap();
+            }
+        });
+    }
+
+    let num_test = 105;
+    let mut store = init_test(num_test).await;
+
+    // An interrupted download left the beginning of the content.
+    let downloads = PathBuf::from(format!("./tests/data{}/downloads", num_test));
+    std::fs::create_dir_all(&downloads).unwrap();
+    let partial = downloads.join(format!("{}.part", blake3::hash(url.as_bytes()).to_hex()));
+    std::fs::write(&partial, &content[..1000]).unwrap();
+
+    store
+        .import_url(
+            &url,
+            ImportUrlOptions {
+                resume: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(*offsets.lock().unwrap(), vec![Some(1000)]);
+    assert!(!partial.exists());
+
+    let path = ["notes.txt".to_owned()];
+    assert_eq!(
+        store.get_variant_vec("default", &path).await.unwrap(),
+        content
+    );
+    let metadata = store.get_metadata(&path).await.unwrap();
+    assert_eq!(
+        metadata.get_variant("default").unwrap().mime_type(),
+        "text/plain"
+    );
+    assert_eq!(metadata.desc(), url);
+
+    // Without resuming, the whole content is downloaded again.
+    std::fs::write(&partial, &content[..1000]).unwrap();
+    store.delete_resource(&path).await.unwrap();
+    store
+        .import_url(&url, ImportUrlOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(*offsets.lock().unwrap(), vec![Some(1000), None]);
+    assert_eq!(
+        store.get_variant_vec("default", &path).await.unwrap(),
+        content
+    );
+}
+
+#[tokio::test]
+async fn content_defined_chunking() {
+    use docstore::chunking::ChunkSizes;
+
+    // Pseudo random content, which doesn't compress to repeated chunks.
+    let mut state = 0x2545f4914f6cdd1du64;
+    let content: Vec<u8> = (0..3_000_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    // Insert a few bytes near the beginning, shifting all the blocks.
+    let mut edited = content.clone();
+    edited.splice(1000..1000, b"an edit".iter().cloned());
+
+    let num_test = 106;
+    let root_dir = format!("./tests/data{}", num_test);
+    let _ = std::fs::remove_dir_all(&root_dir);
+    let mut store = ResourceStore::builder(&root_dir)
+        .content_defined_chunking(ChunkSizes {
+            min: 16 * 1024,
+            avg: 64 * 1024,
+            max: 256 * 1024,
+        })
+        .build()
+        .await
+        .unwrap();
+
+    let path = ["disk.img".to_owned()];
+    let variant = VariantMetadata::new(0, "application/octet-stream");
+    store
+        .create_resource(
+            &path,
+            "disk image",
+            &variant,
+            HashSet::new(),
+            Cursor::new(content.clone()).compat(),
+        )
+        .await
+        .unwrap();
+    store
+        .add_variant(
+            &path,
+            "copy",
+            &variant,
+            Cursor::new(content.clone()).compat(),
+        )
+        .await
+        .unwrap();
+    store
+        .update_variant(
+            &path,
+            "default",
+            &variant,
+            Cursor::new(edited.clone()).compat(),
+        )
+        .await
+        .unwrap();
+
+    let meta = store.get_metadata(&path).await.unwrap();
+    // An identical copy doesn't need new blocks.
+    assert_eq!(meta.get_variant("copy").unwrap().stored_size(), Some(0));
+    // Only the chunks around the edit are stored again.
+    let default_variant = meta.get_variant("default").unwrap();
+    assert_eq!(default_variant.size(), edited.len() as u64);
+    assert!(default_variant.stored_size().unwrap() < default_variant.size() / 4);
+
+    assert_eq!(
+        store.get_variant_vec("default", &path).await.unwrap(),
+        edited
+    );
+    let chunks: Vec<Vec<u8>> = store
+        .get_variant("copy", &path)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(chunks.concat(), content);
+    assert!(store.verify_variant(&path, "default").await.unwrap());
+}